@@ -1,17 +1,21 @@
-use crate::cartridge_metadata::get_cartridge_metadata;
-use crate::logging::debug_println;
+use crate::cartridge_metadata::{get_cartridge_metadata, get_game_compatibility};
+use crate::logging::{debug_println, info_println};
+use crate::save_formats;
+use crate::save_formats::SaveFormat;
 use crate::utils;
-use crate::utils::{rgb5_to_rgb8, HeapMemU8, NoHashMap};
+use crate::utils::{rgb5_to_rgb8, set_thread_prio_affinity, HeapMemU8, NoHashMap, ThreadAffinity, ThreadPriority};
 use static_assertions::const_assert_eq;
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::{ErrorKind, Seek};
 use std::ops::{Deref, DerefMut};
 use std::os::unix::fs::FileExt;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
-use std::{io, mem};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io, mem, thread};
 
 #[repr(C, packed)]
 pub struct ArmValues {
@@ -75,9 +79,21 @@ const HEADER_SIZE: usize = size_of::<CartridgeHeader>();
 pub const HEADER_IN_RAM_SIZE: usize = 0x170;
 const_assert_eq!(HEADER_SIZE, HEADER_IN_RAM_SIZE + 0x90);
 
-const SAVE_SIZES: [u32; 9] = [0x000200, 0x002000, 0x008000, 0x010000, 0x020000, 0x040000, 0x080000, 0x100000, 0x800000];
+pub const SAVE_SIZES: [u32; 9] = [0x000200, 0x002000, 0x008000, 0x010000, 0x020000, 0x040000, 0x080000, 0x100000, 0x800000];
+const SAVE_BACKUP_INTERVAL: Duration = Duration::from_secs(30 * 60);
 const CARTRIDGE_PAGE_SIZE: usize = 4096;
+
+// Only the first 2KB of the secure area are encrypted with KEY1
+const SECURE_AREA_OFFSET: u32 = 0x4000;
+const SECURE_AREA_ENCRYPTED_WORDS: usize = 0x200;
+// "encryObj", replaced by the bios after decrypting the secure area
+const SECURE_AREA_ID: [u32; 2] = [0x72636E65, 0x6A624F79];
+const SECURE_AREA_DESTROYED: [u32; 2] = [0xE7FFDEFF, 0xE7FFDEFF];
 const MAX_CARTRIDGE_CACHE: usize = 16 * 1024 * 1024;
+const CARTRIDGE_CACHE_PAGES: usize = MAX_CARTRIDGE_CACHE / CARTRIDGE_PAGE_SIZE;
+// Sequential reads of at least this size are streamed, the following pages are read in the background
+const READAHEAD_TRIGGER: u32 = 16 * 1024;
+const READAHEAD_SIZE: u32 = 256 * 1024;
 
 pub struct CartridgePreview {
     file_path: PathBuf,
@@ -98,6 +114,10 @@ impl CartridgePreview {
         })
     }
 
+    pub fn game_code(&self) -> u32 {
+        u32::from_le_bytes(self.header.game_code)
+    }
+
     pub fn read_icon(&self) -> io::Result<[u32; 32 * 32]> {
         let mut icon = [0u32; 32 * 32];
 
@@ -162,16 +182,153 @@ impl CartridgePreview {
     }
 }
 
+#[derive(Copy, Clone, Default)]
+pub struct PageCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub readahead_hits: u64,
+}
+
+impl Display for PageCacheStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "hits {} misses {} evictions {} readahead hits {}", self.hits, self.misses, self.evictions, self.readahead_hits)
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct PageSlot {
+    page_addr: u32,
+    referenced: bool,
+    prefetched: bool,
+}
+
+// Evicts pages with the CLOCK algorithm, pages which weren't accessed since the last sweep go first
+struct PageCache {
+    pages: NoHashMap<u32, u16>,
+    slots: Vec<PageSlot>,
+    hand: usize,
+    data: HeapMemU8<MAX_CARTRIDGE_CACHE>,
+    stats: PageCacheStats,
+}
+
+impl PageCache {
+    fn new() -> Self {
+        PageCache {
+            pages: NoHashMap::default(),
+            slots: Vec::with_capacity(CARTRIDGE_CACHE_PAGES),
+            hand: 0,
+            data: HeapMemU8::new(),
+            stats: PageCacheStats::default(),
+        }
+    }
+
+    fn slot_ptr(&self, slot: usize) -> *const [u8; CARTRIDGE_PAGE_SIZE] {
+        self.data[slot * CARTRIDGE_PAGE_SIZE..].as_ptr() as _
+    }
+
+    fn get(&mut self, page_addr: u32) -> Option<*const [u8; CARTRIDGE_PAGE_SIZE]> {
+        let slot = *self.pages.get(&page_addr)? as usize;
+        let page_slot = &mut self.slots[slot];
+        page_slot.referenced = true;
+        if page_slot.prefetched {
+            page_slot.prefetched = false;
+            self.stats.readahead_hits += 1;
+        }
+        self.stats.hits += 1;
+        Some(self.slot_ptr(slot))
+    }
+
+    fn insert(&mut self, page_addr: u32, prefetched: bool) -> &mut [u8] {
+        let slot = if self.slots.len() < CARTRIDGE_CACHE_PAGES {
+            self.slots.push(PageSlot::default());
+            self.slots.len() - 1
+        } else {
+            loop {
+                let slot = self.hand;
+                self.hand = (self.hand + 1) % CARTRIDGE_CACHE_PAGES;
+                if self.slots[slot].referenced {
+                    self.slots[slot].referenced = false;
+                } else {
+                    // Slots of pages which failed to load aren't mapped anymore
+                    let page_addr = self.slots[slot].page_addr;
+                    if self.pages.get(&page_addr) == Some(&(slot as u16)) {
+                        self.pages.remove(&page_addr);
+                    }
+                    self.stats.evictions += 1;
+                    break slot;
+                }
+            }
+        };
+
+        // Prefetched pages are evicted on the next sweep, unless they get used
+        self.slots[slot] = PageSlot {
+            page_addr,
+            referenced: !prefetched,
+            prefetched,
+        };
+        self.pages.insert(page_addr, slot as u16);
+        let start = slot * CARTRIDGE_PAGE_SIZE;
+        &mut self.data[start..start + CARTRIDGE_PAGE_SIZE]
+    }
+
+    fn remove(&mut self, page_addr: u32) {
+        if let Some(slot) = self.pages.remove(&page_addr) {
+            self.slots[slot as usize].referenced = false;
+        }
+    }
+}
+
+struct Readahead {
+    requests: Sender<u32>,
+    pages: Receiver<(u32, Box<[u8; CARTRIDGE_PAGE_SIZE]>)>,
+    // First page which wasn't requested yet
+    end: u32,
+    last_read_end: u32,
+    sequential_len: u32,
+}
+
+impl Readahead {
+    fn new(file: File) -> Self {
+        let (requests, requests_rx) = mpsc::channel::<u32>();
+        let (pages_tx, pages) = mpsc::channel();
+        thread::Builder::new()
+            .name("rom_readahead".to_owned())
+            .spawn(move || {
+                set_thread_prio_affinity(ThreadPriority::Low, ThreadAffinity::Core0);
+                while let Ok(page_addr) = requests_rx.recv() {
+                    let mut page = Box::new([0u8; CARTRIDGE_PAGE_SIZE]);
+                    if file.read_at(page.as_mut_slice(), page_addr as u64).is_ok() && pages_tx.send((page_addr, page)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+        Readahead {
+            requests,
+            pages,
+            end: 0,
+            last_read_end: 0,
+            sequential_len: 0,
+        }
+    }
+}
+
 pub struct CartridgeIo {
     file: File,
     pub file_name: String,
     pub file_size: u32,
     pub header: CartridgeHeader,
-    content_pages: NoHashMap<u32, u16>,
-    content_cache: HeapMemU8<MAX_CARTRIDGE_CACHE>,
+    page_cache: PageCache,
+    readahead: Readahead,
     save_file_path: PathBuf,
     pub save_file_size: u32,
     save_buf: Mutex<(Vec<u8>, bool)>,
+    pub save_backups: u8,
+    last_save_backup: Option<Instant>,
+    key1: Option<Box<Key1>>,
+    // Encrypted and decrypted secure area, only set when the key table is available
+    secure_area: Option<(Vec<u8>, Vec<u8>)>,
 }
 
 unsafe impl Send for CartridgeIo {}
@@ -182,59 +339,183 @@ impl CartridgeIo {
         let file_size = file.stream_len().unwrap() as u32;
         let mut save_buf = Vec::new();
 
-        let mut save_file_size = File::open(&save_file_path).map_or(0, |mut file| {
-            let save_file_size = file.stream_len().unwrap();
-            save_buf.resize(save_file_size as usize, 0u8);
-            match file.read_at(&mut save_buf, 0) {
-                Ok(_) => save_file_size as u32,
-                Err(_) => {
-                    save_buf.clear();
-                    0
+        let game_code = preview.game_code();
+        utils::recover_tmp_file(&save_file_path);
+
+        // Saves of other emulators are picked up when there is no save yet
+        let mut import_path = save_file_path.clone();
+        if !save_file_path.exists() {
+            let stem = preview.file_path.file_stem().unwrap().to_str().unwrap();
+            if let Some(path) = [SaveFormat::Desmume, SaveFormat::Raw]
+                .iter()
+                .map(|format| save_file_path.with_file_name(format!("{stem}.{}", format.file_extension())))
+                .find(|path| path.is_file())
+            {
+                import_path = path;
+            }
+        }
+
+        let mut save_file_size = match fs::read(&import_path).and_then(save_formats::import_save) {
+            Ok((raw, format)) => {
+                if format != SaveFormat::Raw || import_path != save_file_path {
+                    info_println!("Imported {format:?} save {import_path:?}");
                 }
+                save_buf = raw;
+                let save_file_size = expected_save_size(game_code).unwrap_or_else(|| save_formats::fit_save_size(save_buf.len()));
+                if save_buf.len() != save_file_size as usize {
+                    info_println!("Resized save from {:x} to {save_file_size:x}", save_buf.len());
+                    save_buf.resize(save_file_size as usize, 0xFF);
+                }
+                save_file_size
             }
-        });
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    info_println!("Failed to read save {import_path:?}: {e}");
+                }
+                0
+            }
+        };
 
-        let game_code = u32::from_le_bytes(preview.header.game_code);
-        if let Some(metadata) = get_cartridge_metadata(game_code) {
-            save_buf.resize(metadata.save_size as usize, 0xFF);
-            save_file_size = metadata.save_size;
+        if let Some(save_size) = expected_save_size(game_code) {
+            save_buf.resize(save_size as usize, 0xFF);
+            save_file_size = save_size;
+        }
+        if let Some(compatibility) = get_game_compatibility(game_code).filter(|compatibility| !compatibility.known_issues.is_empty()) {
+            info_println!("Known issues: {}", compatibility.known_issues);
         }
 
         if !SAVE_SIZES.contains(&save_file_size) {
             save_file_size = 0;
         }
 
+        if is_save_file_corrupt(&save_file_path, game_code) {
+            match save_backup_paths(&save_file_path).first() {
+                Some(backup) => {
+                    info_println!("Save file {save_file_path:?} looks corrupt, newest backup is {backup:?}");
+                }
+                None => {
+                    info_println!("Save file {save_file_path:?} looks corrupt and there are no backups");
+                }
+            }
+        }
+
+        let readahead = Readahead::new(file.try_clone()?);
         Ok(CartridgeIo {
             file,
             file_name: preview.file_name,
             file_size,
             header: preview.header,
-            content_pages: NoHashMap::default(),
-            content_cache: HeapMemU8::new(),
+            page_cache: PageCache::new(),
+            readahead,
             save_file_path,
             save_file_size,
             save_buf: Mutex::new((save_buf, false)),
+            save_backups: 0,
+            last_save_backup: None,
+            key1: None,
+            secure_area: None,
         })
     }
 
-    fn get_page(&mut self, page_addr: u32) -> io::Result<*const [u8; CARTRIDGE_PAGE_SIZE]> {
-        debug_assert_eq!(page_addr & (CARTRIDGE_PAGE_SIZE as u32 - 1), 0);
-        match self.content_pages.get(&page_addr) {
+    // The KEY1 key table is part of the ARM7 bios
+    pub fn set_key1_table(&mut self, bios7: &[u8]) {
+        let key_table = &bios7[KEY1_TABLE_OFFSET..KEY1_TABLE_OFFSET + KEY1_BUF_SIZE * 4];
+        let id_code = u32::from_le_bytes(self.header.game_code);
+        self.key1 = Some(Box::new(Key1::new(key_table, id_code, 2, 2)));
+
+        if self.file_size < SECURE_AREA_OFFSET + SECURE_AREA_ENCRYPTED_WORDS as u32 * 4 {
+            return;
+        }
+        let mut raw = [0u8; SECURE_AREA_ENCRYPTED_WORDS * 4];
+        self.read_slice(SECURE_AREA_OFFSET, &mut raw).unwrap();
+        let raw: [u32; SECURE_AREA_ENCRYPTED_WORDS] = unsafe { mem::transmute(raw) };
+
+        let (mut encrypted, mut decrypted) = (raw, raw);
+        if raw[..2] == SECURE_AREA_DESTROYED || raw[..2] == SECURE_AREA_ID {
+            encrypted[..2].copy_from_slice(&SECURE_AREA_ID);
+            Key1::encrypt_secure_area(key_table, id_code, &mut encrypted);
+        } else {
+            Key1::decrypt_secure_area(key_table, id_code, &mut decrypted);
+            if decrypted[..2] != SECURE_AREA_ID {
+                // Not a secure area, e.g. homebrew placing its code there
+                return;
+            }
+            info_println!("Decrypted secure area");
+        }
+        decrypted[..2].copy_from_slice(&SECURE_AREA_DESTROYED);
+
+        let to_bytes = |words: [u32; SECURE_AREA_ENCRYPTED_WORDS]| words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        self.secure_area = Some((to_bytes(encrypted), to_bytes(decrypted)));
+    }
+
+    pub fn key1_decrypt_cmd(&self, cmd: u64) -> u64 {
+        match &self.key1 {
+            Some(key1) => {
+                let mut data = [cmd as u32, (cmd >> 32) as u32];
+                key1.decrypt(&mut data);
+                ((data[1] as u64) << 32) | data[0] as u64
+            }
             None => {
-                if self.content_pages.len() >= MAX_CARTRIDGE_CACHE / CARTRIDGE_PAGE_SIZE {
-                    debug_println!("clear cartridge pages");
-                    self.content_pages.clear();
-                }
+                debug_println!("Can't decrypt KEY1 command {cmd:x} without bios7");
+                cmd
+            }
+        }
+    }
+
+    fn receive_readahead(&mut self) {
+        while let Ok((page_addr, page)) = self.readahead.pages.try_recv() {
+            if !self.page_cache.pages.contains_key(&page_addr) {
+                self.page_cache.insert(page_addr, true).copy_from_slice(page.as_slice());
+            }
+        }
+    }
+
+    fn request_readahead(&mut self, offset: u32, len: u32) {
+        let read_end = offset + len;
+        if offset == self.readahead.last_read_end {
+            self.readahead.sequential_len += len;
+        } else {
+            self.readahead.sequential_len = len;
+            self.readahead.end = 0;
+        }
+        self.readahead.last_read_end = read_end;
 
-                let content_offset = self.content_pages.len() as u16;
-                let start = content_offset as usize * CARTRIDGE_PAGE_SIZE;
-                let buf = &mut self.content_cache[start..start + CARTRIDGE_PAGE_SIZE];
-                self.file.read_at(buf, page_addr as u64)?;
-                self.content_pages.insert(page_addr, content_offset);
-                Ok(buf.as_ptr() as _)
+        if self.readahead.sequential_len < READAHEAD_TRIGGER {
+            return;
+        }
+        let next_page = utils::align_up(read_end as usize, CARTRIDGE_PAGE_SIZE) as u32;
+        let start = max(next_page, self.readahead.end);
+        let end = min(next_page.saturating_add(READAHEAD_SIZE), self.file_size);
+        for page_addr in (start..end).step_by(CARTRIDGE_PAGE_SIZE) {
+            if !self.page_cache.pages.contains_key(&page_addr) {
+                let _ = self.readahead.requests.send(page_addr);
             }
-            Some(page) => Ok(self.content_cache[*page as usize * CARTRIDGE_PAGE_SIZE..].as_ptr() as _),
         }
+        self.readahead.end = max(end, self.readahead.end);
+    }
+
+    fn get_page(&mut self, page_addr: u32) -> io::Result<*const [u8; CARTRIDGE_PAGE_SIZE]> {
+        debug_assert_eq!(page_addr & (CARTRIDGE_PAGE_SIZE as u32 - 1), 0);
+        self.receive_readahead();
+        if let Some(page) = self.page_cache.get(page_addr) {
+            return Ok(page);
+        }
+
+        self.page_cache.stats.misses += 1;
+        // Logged in release builds too, once every 4096 misses
+        if self.page_cache.stats.misses % 0x1000 == 0 {
+            info_println!("Rom page cache {}", self.page_cache_stats());
+        }
+        let buf = self.page_cache.insert(page_addr, false);
+        if let Err(e) = self.file.read_at(buf, page_addr as u64) {
+            self.page_cache.remove(page_addr);
+            return Err(e);
+        }
+        Ok(buf.as_ptr() as _)
+    }
+
+    pub fn page_cache_stats(&self) -> PageCacheStats {
+        self.page_cache.stats
     }
 
     pub fn read_slice(&mut self, offset: u32, slice: &mut [u8]) -> io::Result<()> {
@@ -253,30 +534,46 @@ impl CartridgeIo {
             slice[slice_start..slice_end].copy_from_slice(&page_slice[..read_amount]);
             remaining -= read_amount;
         }
+        self.request_readahead(offset, slice.len() as u32);
+        Ok(())
+    }
+
+    // Copies the part of the secure area overlapping with the slice
+    fn overlay_secure_area(offset: u32, slice: &mut [u8], secure_area: &[u8]) {
+        let start = max(offset, SECURE_AREA_OFFSET);
+        let end = min(offset + slice.len() as u32, SECURE_AREA_OFFSET + secure_area.len() as u32);
+        if start < end {
+            slice[(start - offset) as usize..(end - offset) as usize].copy_from_slice(&secure_area[(start - SECURE_AREA_OFFSET) as usize..(end - SECURE_AREA_OFFSET) as usize]);
+        }
+    }
+
+    // Secure area reads always return the encrypted data, like a retail cartridge
+    pub fn read_secure_area(&mut self, offset: u32, slice: &mut [u8]) -> io::Result<()> {
+        self.read_slice(offset, slice)?;
+        if let Some((encrypted, _)) = &self.secure_area {
+            Self::overlay_secure_area(offset, slice, encrypted);
+        }
         Ok(())
     }
 
     pub fn read_arm9_code(&mut self) -> Vec<u8> {
+        let rom_offset = self.header.arm9_values.rom_offset;
         let mut boot_code = vec![0u8; self.header.arm9_values.size as usize];
-        self.read_slice(self.header.arm9_values.rom_offset, &mut boot_code).unwrap();
-
-        // if (0x4000..0x8000).contains(&(self.header.arm9_values.rom_offset as i32)) {
-        //     let (_, boot_code_aligned, _) = unsafe { boot_code.align_to_mut::<u32>() };
-        //     let (_, game_code_aligned, _) = unsafe { self.header.game_code.align_to::<u32>() };
-        //     let id_code = game_code_aligned[0];
-        //
-        //     {
-        //         let key1 = Key1::new(id_code, 2, 2);
-        //         key1.decrypt((&mut boot_code_aligned[..2]).try_into().unwrap());
-        //     }
-        //
-        //     {
-        //         let key1 = Key1::new(id_code, 3, 2);
-        //         for i in (0..0x200).step_by(2) {
-        //             key1.decrypt((&mut boot_code_aligned[i..i + 2]).try_into().unwrap());
-        //         }
-        //     }
-        // }
+        self.read_slice(rom_offset, &mut boot_code).unwrap();
+
+        match &self.secure_area {
+            Some((_, decrypted)) => Self::overlay_secure_area(rom_offset, &mut boot_code, decrypted),
+            None => {
+                if rom_offset == SECURE_AREA_OFFSET && boot_code.len() >= 8 {
+                    let id: [u32; 2] = utils::read_from_mem(&boot_code, 0);
+                    if id == SECURE_AREA_ID {
+                        boot_code[..8].copy_from_slice(unsafe { &mem::transmute::<_, [u8; 8]>(SECURE_AREA_DESTROYED) });
+                    } else if id != SECURE_AREA_DESTROYED {
+                        info_println!("Secure area might be encrypted, bios7.bin is required to decrypt it");
+                    }
+                }
+            }
+        }
 
         boot_code
     }
@@ -287,6 +584,65 @@ impl CartridgeIo {
         boot_code
     }
 
+    // Lists all NitroFS files with their path and FAT index
+    pub fn read_nitro_files(&mut self) -> io::Result<Vec<(String, u16)>> {
+        let mut fnt = vec![0u8; self.header.file_name_table_size as usize];
+        self.read_slice(self.header.file_name_table_offset, &mut fnt)?;
+        if fnt.len() < 8 {
+            return Ok(Vec::new());
+        }
+
+        let mut files = Vec::new();
+        let mut dirs = vec![(0xF000u16, String::new())];
+        while let Some((dir_id, dir_path)) = dirs.pop() {
+            let entry = (dir_id & 0xFFF) as usize * 8;
+            if entry + 8 > fnt.len() {
+                continue;
+            }
+            let mut offset = utils::read_from_mem::<u32>(&fnt, entry as u32) as usize;
+            let mut file_id = utils::read_from_mem::<u16>(&fnt, entry as u32 + 4);
+
+            while offset < fnt.len() && fnt[offset] != 0 {
+                let len = (fnt[offset] & 0x7F) as usize;
+                let is_dir = fnt[offset] & 0x80 != 0;
+                let name_end = min(offset + 1 + len, fnt.len());
+                let path = format!("{dir_path}/{}", String::from_utf8_lossy(&fnt[offset + 1..name_end]));
+                offset = name_end;
+                if is_dir {
+                    if offset + 2 > fnt.len() {
+                        break;
+                    }
+                    dirs.push((u16::from_le_bytes([fnt[offset], fnt[offset + 1]]), path));
+                    offset += 2;
+                } else {
+                    files.push((path, file_id));
+                    file_id += 1;
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    pub fn read_nitro_file(&mut self, file_id: u16) -> io::Result<Vec<u8>> {
+        if file_id as u32 * 8 + 8 > self.header.file_allocation_table_size {
+            return Err(io::Error::from(ErrorKind::NotFound));
+        }
+        let mut entry = [0u8; 8];
+        self.read_slice(self.header.file_allocation_table_offset + file_id as u32 * 8, &mut entry)?;
+        let start = utils::read_from_mem::<u32>(&entry, 0);
+        let end = utils::read_from_mem::<u32>(&entry, 4);
+        if end < start || end > self.file_size {
+            return Err(io::Error::from(ErrorKind::InvalidData));
+        }
+        let mut data = vec![0u8; (end - start) as usize];
+        self.read_slice(start, &mut data)?;
+        Ok(data)
+    }
+
+    pub fn save_state_path(&self) -> PathBuf {
+        self.save_file_path.with_extension("ds0")
+    }
+
     pub fn resize_save_file(&mut self, new_size: u32) {
         let mut lock = self.save_buf.lock().unwrap();
         let (save_buf, _) = lock.deref_mut();
@@ -315,18 +671,93 @@ impl CartridgeIo {
         *dirty = true;
     }
 
+    pub fn erase_save_buf(&self, addr: u32, size: u32) {
+        let mut lock = self.save_buf.lock().unwrap();
+        let (save_buf, dirty) = lock.deref_mut();
+        let end = min(addr as usize + size as usize, save_buf.len());
+        save_buf[addr as usize..end].fill(0xFF);
+        *dirty = true;
+    }
+
     pub fn flush_save_buf(&mut self, last_save_time: &Arc<Mutex<Option<(Instant, bool)>>>) {
         let mut lock = self.save_buf.lock().unwrap();
         let (save_buf, dirty) = lock.deref_mut();
         if *dirty {
-            let success = File::create(&self.save_file_path).is_ok_and(|file| file.write_at(save_buf, 0).is_ok());
+            if self.save_backups > 0 && self.last_save_backup.is_none_or(|time| time.elapsed() >= SAVE_BACKUP_INTERVAL) {
+                backup_save_file(&self.save_file_path, u32::from_le_bytes(self.header.game_code), self.save_backups);
+                self.last_save_backup = Some(Instant::now());
+            }
+            let success = utils::write_file_atomic(&self.save_file_path, save_buf).is_ok();
             *last_save_time.lock().unwrap() = Some((Instant::now(), success));
             *dirty = false;
         }
     }
 }
 
+fn expected_save_size(game_code: u32) -> Option<u32> {
+    get_game_compatibility(game_code)
+        .and_then(|compatibility| compatibility.save_size)
+        .or_else(|| get_cartridge_metadata(game_code).map(|metadata| metadata.save_size))
+}
+
+// Only checks the size, a save can't be told apart from garbage otherwise
+// Trimmed raw saves of other emulators are still loaded, but might as well be cut off while writing
+pub fn is_save_file_corrupt(save_file_path: &Path, game_code: u32) -> bool {
+    let Ok(metadata) = fs::metadata(save_file_path) else {
+        return false;
+    };
+    let is_size_valid = |size: usize| SAVE_SIZES.contains(&(size as u32)) && expected_save_size(game_code).is_none_or(|expected| size >= expected as usize);
+    if is_size_valid(metadata.len() as usize) {
+        return false;
+    }
+    match fs::read(save_file_path).and_then(save_formats::import_save) {
+        Ok((raw, _)) => !is_size_valid(raw.len()),
+        Err(_) => true,
+    }
+}
+
+// Backups are named <save file>.<timestamp>.bak, newest first
+pub fn save_backup_paths(save_file_path: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(file_name)) = (save_file_path.parent(), save_file_path.file_name().and_then(|name| name.to_str())) else {
+        return Vec::new();
+    };
+    let prefix = format!("{file_name}.");
+    let mut backups: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".bak")))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    backups.sort_by(|a, b| b.cmp(a));
+    backups
+}
+
+// Copies the save file on disk into a new backup, corrupt save files never replace older backups
+fn backup_save_file(save_file_path: &Path, game_code: u32, max_backups: u8) {
+    if !save_file_path.is_file() || is_save_file_corrupt(save_file_path, game_code) {
+        return;
+    }
+    let mut backup_path = save_file_path.as_os_str().to_owned();
+    backup_path.push(format!(".{}.bak", chrono::Local::now().format("%Y%m%d-%H%M%S")));
+    if let Err(e) = fs::copy(save_file_path, &backup_path) {
+        info_println!("Failed to back up save file: {e}");
+        return;
+    }
+    for backup in save_backup_paths(save_file_path).iter().skip(max_backups as usize) {
+        let _ = fs::remove_file(backup);
+    }
+}
+
+pub fn restore_save_backup(save_file_path: &Path) -> io::Result<PathBuf> {
+    let backup = save_backup_paths(save_file_path).into_iter().next().ok_or(io::Error::from(ErrorKind::NotFound))?;
+    utils::write_file_atomic(save_file_path, &fs::read(&backup)?)?;
+    info_println!("Restored {save_file_path:?} from {backup:?}");
+    Ok(backup)
+}
+
 const KEY1_BUF_SIZE: usize = 0x412;
+const KEY1_TABLE_OFFSET: usize = 0x30;
 
 pub struct Key1 {
     key_buf: [u32; KEY1_BUF_SIZE],
@@ -373,8 +804,11 @@ impl Key1 {
         }
     }
 
-    fn new(id_code: u32, level: u8, modulo: u32) -> Self {
+    fn new(key_table: &[u8], id_code: u32, level: u8, modulo: u32) -> Self {
         let mut instance = Key1 { key_buf: [0u32; KEY1_BUF_SIZE] };
+        for (i, value) in instance.key_buf.iter_mut().enumerate() {
+            *value = utils::read_from_mem(key_table, i as u32 * 4);
+        }
 
         let mut keycode = [id_code, id_code >> 1, id_code << 1];
         if level >= 1 {
@@ -390,4 +824,19 @@ impl Key1 {
         }
         instance
     }
+    fn decrypt_secure_area(key_table: &[u8], id_code: u32, secure_area: &mut [u32; SECURE_AREA_ENCRYPTED_WORDS]) {
+        Key1::new(key_table, id_code, 2, 2).decrypt((&mut secure_area[..2]).try_into().unwrap());
+        let key1 = Key1::new(key_table, id_code, 3, 2);
+        for words in secure_area.chunks_exact_mut(2) {
+            key1.decrypt(words.try_into().unwrap());
+        }
+    }
+
+    fn encrypt_secure_area(key_table: &[u8], id_code: u32, secure_area: &mut [u32; SECURE_AREA_ENCRYPTED_WORDS]) {
+        let key1 = Key1::new(key_table, id_code, 3, 2);
+        for words in secure_area.chunks_exact_mut(2) {
+            key1.encrypt(words.try_into().unwrap());
+        }
+        Key1::new(key_table, id_code, 2, 2).encrypt((&mut secure_area[..2]).try_into().unwrap());
+    }
 }
@@ -1,3 +1,5 @@
+use crate::settings::{Arm7Emu, SettingValue, Settings};
+
 pub struct CartridgeMetadata {
     pub game_code: u32,
     pub save_size: u32,
@@ -16,6 +18,60 @@ pub fn get_cartridge_metadata(game_code: u32) -> Option<&'static CartridgeMetada
         .map(|index| &CARTRIDGE_METADATA[index])
 }
 
+// Per title hints, matched without the region letter of the game code
+pub struct GameCompatibility {
+    pub title_code: [u8; 3],
+    pub arm7_emu: Option<Arm7Emu>,
+    pub arm7_block_validation: bool,
+    // Overrides the save size of the rom list
+    pub save_size: Option<u32>,
+    pub known_issues: &'static str,
+}
+
+impl GameCompatibility {
+    const fn new(title_code: &[u8; 3]) -> Self {
+        GameCompatibility {
+            title_code: *title_code,
+            arm7_emu: None,
+            arm7_block_validation: false,
+            save_size: None,
+            known_issues: "",
+        }
+    }
+
+    const fn known_issues(mut self, known_issues: &'static str) -> Self {
+        self.known_issues = known_issues;
+        self
+    }
+
+    // Seeds the settings of games without a settings file
+    pub fn apply_defaults(&self, settings: &mut Settings) {
+        if let Some(arm7_emu) = self.arm7_emu {
+            settings.setting_arm7_hle_mut().value = SettingValue::Arm7Emu(arm7_emu);
+        }
+        if self.arm7_block_validation {
+            settings.setting_arm7_block_validation_mut().value = SettingValue::Bool(true);
+        }
+    }
+}
+
+pub fn get_game_compatibility(game_code: u32) -> Option<&'static GameCompatibility> {
+    let title_code = game_code.to_le_bytes();
+    GAME_COMPATIBILITY
+        .binary_search_by_key(&[title_code[0], title_code[1], title_code[2]], |compatibility| compatibility.title_code)
+        .ok()
+        .map(|index| &GAME_COMPATIBILITY[index])
+}
+
+// Sorted by title code, only add games with confirmed reports and note where they come from
+const GAME_COMPATIBILITY: [GameCompatibility; 2] = [
+    // Pokemon SoulSilver, the aux spi in core/memory/cartridge.rs only talks to the save chip,
+    // the infrared chip of the cartridge sits in front of it and is not emulated
+    GameCompatibility::new(b"IPG").known_issues("Pokewalker infrared communication is not emulated"),
+    // Pokemon HeartGold, same cartridge as SoulSilver
+    GameCompatibility::new(b"IPK").known_issues("Pokewalker infrared communication is not emulated"),
+];
+
 // Take from https://github.com/melonDS-emu/melonDS/blob/master/src/ROMList.cpp
 const CARTRIDGE_METADATA: [CartridgeMetadata; 6778] = [
     CartridgeMetadata::new(0x41464141, 0x00020000),
@@ -6797,3 +6853,43 @@ const CARTRIDGE_METADATA: [CartridgeMetadata; 6778] = [
     CartridgeMetadata::new(0x5A5A4341, 0x00000200),
     CartridgeMetadata::new(0x5A5A5242, 0x00010000),
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::{get_game_compatibility, GameCompatibility, GAME_COMPATIBILITY};
+    use crate::settings::{Arm7Emu, DEFAULT_SETTINGS};
+
+    #[test]
+    fn game_compatibility_is_sorted() {
+        assert!(GAME_COMPATIBILITY.windows(2).all(|pair| pair[0].title_code < pair[1].title_code));
+    }
+
+    #[test]
+    fn game_compatibility_ignores_the_region() {
+        for game_code in [b"IPGE", b"IPGJ", b"IPKD"] {
+            let compatibility = get_game_compatibility(u32::from_le_bytes(*game_code)).unwrap();
+            assert!(!compatibility.known_issues.is_empty());
+        }
+        assert!(get_game_compatibility(u32::from_le_bytes(*b"AMCE")).is_none());
+    }
+
+    #[test]
+    fn game_compatibility_seeds_settings() {
+        let compatibility = GameCompatibility {
+            arm7_emu: Some(Arm7Emu::PartialHle),
+            arm7_block_validation: true,
+            ..GameCompatibility::new(b"ZZZ")
+        };
+        let mut settings = DEFAULT_SETTINGS.clone();
+        assert_eq!(settings.arm7_hle(), Arm7Emu::AccurateLle);
+        assert!(!settings.arm7_block_validation());
+        compatibility.apply_defaults(&mut settings);
+        assert_eq!(settings.arm7_hle(), Arm7Emu::PartialHle);
+        assert!(settings.arm7_block_validation());
+
+        let mut settings = DEFAULT_SETTINGS.clone();
+        GameCompatibility::new(b"ZZZ").apply_defaults(&mut settings);
+        assert_eq!(settings.arm7_hle(), Arm7Emu::AccurateLle);
+        assert!(!settings.arm7_block_validation());
+    }
+}
@@ -170,9 +170,13 @@ impl Emu {
     pub fn cpu_set_halt_cnt(&mut self, cpu: CpuType, value: u8) {
         self.cpu[cpu].halt_cnt = value & 0xC0;
 
-        match self.cpu[cpu].halt_cnt {
-            1 => todo!("gba mode"),
-            2 => todo!("halt"),
+        match self.cpu[cpu].halt_cnt >> 6 {
+            1 => debug_println!("{cpu:?} gba mode is not supported"),
+            // Sleep only differs by the wake up sources, which games restrict with IE beforehand
+            2 | 3 => {
+                self.cpu_halt(cpu, 0);
+                self.breakout_imm = true;
+            }
             _ => {}
         }
     }
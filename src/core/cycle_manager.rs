@@ -1,4 +1,5 @@
 use crate::core::emu::Emu;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType::{ARM7, ARM9};
 use crate::linked_list::{LinkedList, LinkedListAllocator, LinkedListEntry};
 use bilge::prelude::*;
@@ -74,6 +75,7 @@ pub enum EventType {
     TimerArm9 = 11,
     TimerArm7 = 12,
     WifiScanHle = 13,
+    MicHle = 14,
 }
 
 pub struct CycleManager {
@@ -123,6 +125,34 @@ impl CycleManager {
     pub fn jump_to_next_event(&mut self) {
         self.cycle_count = LinkedList::<_, CycleEventsListAllocator>::deref(self.events.root).value.cycle_count;
     }
+
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.cycle_count);
+        state.write(&(self.events.len() as u32));
+        for entry in self.events.iter() {
+            state.write(&entry.value.event_type_entry);
+            state.write(&entry.value.cycle_count);
+        }
+        state.write_vec(&self.imm_events);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.cycle_count);
+        while !self.events.is_empty() {
+            self.events.remove_begin();
+        }
+        let events_len = state.read_value::<u32>();
+        for _ in 0..events_len {
+            let event_type_entry = state.read_value::<EventTypeEntry>();
+            let cycle_count = state.read_value::<u64>();
+            self.events.insert_end(CycleEventEntry { event_type_entry, cycle_count });
+        }
+        self.imm_events.clear();
+        let imm_events_len = state.read_value::<u32>();
+        for _ in 0..imm_events_len {
+            self.imm_events.push(state.read_value::<EventTypeEntry>());
+        }
+    }
 }
 
 impl Emu {
@@ -130,7 +160,7 @@ impl Emu {
         #[cfg(feature = "profiling")]
         let _frame = tracy_client::secondary_frame_mark!("Cycle manager check events");
 
-        static LUT: [fn(&mut Emu, u16); EventType::WifiScanHle as usize + 1] = [
+        static LUT: [fn(&mut Emu, u16); EventType::MicHle as usize + 1] = [
             Emu::cpu_on_interrupt_event::<{ ARM9 }>,
             Emu::cpu_on_interrupt_event::<{ ARM7 }>,
             Emu::gpu_on_scanline256_event,
@@ -145,6 +175,7 @@ impl Emu {
             Emu::timers_on_overflow_event::<{ ARM9 }>,
             Emu::timers_on_overflow_event::<{ ARM7 }>,
             Emu::wifi_hle_on_scan_event,
+            Emu::mic_hle_on_sample_event,
         ];
 
         self.cm.imm_events_swap.clear();
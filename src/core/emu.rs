@@ -10,6 +10,7 @@ use crate::core::ipc::Ipc;
 use crate::core::memory::cartridge::Cartridge;
 use crate::core::memory::dma::Dma;
 use crate::core::memory::mem::Memory;
+use crate::core::mic::{Mic, MicBackend};
 use crate::core::rtc::Rtc;
 use crate::core::spi::Spi;
 use crate::core::spu::{SoundSampler, Spu};
@@ -18,7 +19,7 @@ use crate::core::wifi::Wifi;
 use crate::jit::jit_memory::JitMemory;
 use crate::settings::Settings;
 use std::ptr::NonNull;
-use std::sync::atomic::{AtomicU16, AtomicU32};
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU8};
 use std::sync::Arc;
 
 pub struct Emu {
@@ -41,6 +42,7 @@ pub struct Emu {
     pub jit: JitMemory,
     pub breakout_imm: bool,
     pub settings: Settings,
+    pub save_state_request: Arc<AtomicU8>,
 }
 
 impl Emu {
@@ -52,6 +54,8 @@ impl Emu {
         sound_sampler: NonNull<SoundSampler>,
         jit: JitMemory,
         settings: Settings,
+        save_state_request: Arc<AtomicU8>,
+        mic_backend: Box<dyn MicBackend>,
     ) -> Self {
         Emu {
             ipc: Ipc::new(&settings),
@@ -60,12 +64,12 @@ impl Emu {
             cm: CycleManager::new(),
             cpu: [CpuRegs::new(), CpuRegs::new()],
             cp15: Cp15::new(),
-            input: Input::new(key_map),
+            input: Input::new(key_map.clone()),
             mem: Memory::new(),
             hle: Arm7Hle::new(),
             div_sqrt: DivSqrt::new(),
-            spi: Spi::new(touch_points),
-            rtc: Rtc::new(),
+            spi: Spi::new(touch_points, Mic::new(mic_backend, key_map)),
+            rtc: Rtc::new(&settings),
             spu: Spu::new(sound_sampler),
             dma: [Dma::new(), Dma::new()],
             timers: [Timers::new(), Timers::new()],
@@ -73,6 +77,7 @@ impl Emu {
             jit,
             breakout_imm: false,
             settings,
+            save_state_request,
         }
     }
 }
@@ -213,6 +213,17 @@ pub unsafe fn create_fb_depth_tex(fbo: GLuint, width: u32, height: u32) -> GLuin
     }
 }
 
+pub unsafe fn create_fb_depth_stencil(fbo: GLuint, width: u32, height: u32) -> GLuint {
+    gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+    let mut buf = 0;
+    gl::GenRenderbuffers(1, &mut buf);
+    gl::BindRenderbuffer(gl::RENDERBUFFER, buf);
+    gl::RenderbufferStorage(gl::RENDERBUFFER, gl::DEPTH24_STENCIL8, width as _, height as _);
+    gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, buf);
+    gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::STENCIL_ATTACHMENT, gl::RENDERBUFFER, buf);
+    buf
+}
+
 pub struct GpuFbo {
     pub color: GLuint,
     pub depth: Option<GLuint>,
@@ -222,23 +233,39 @@ pub struct GpuFbo {
 impl GpuFbo {
     pub fn new(width: u32, height: u32, depth: bool) -> Result<Self, StrErr> {
         unsafe {
-            let color = create_fb_color(width, height);
+            let (color, fbo) = Self::create_color(width, height);
+            let depth = if depth { Some(create_fb_depth_tex(fbo, width, height)) } else { None };
+            Self::check(color, depth, fbo)
+        }
+    }
+
+    // The depth stencil attachment is a renderbuffer and can't be sampled, depth is None
+    pub fn new_with_stencil(width: u32, height: u32) -> Result<Self, StrErr> {
+        unsafe {
+            let (color, fbo) = Self::create_color(width, height);
+            create_fb_depth_stencil(fbo, width, height);
+            Self::check(color, None, fbo)
+        }
+    }
 
-            let mut fbo = 0;
-            gl::GenFramebuffers(1, &mut fbo);
-            gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
-            gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, color, 0);
+    unsafe fn create_color(width: u32, height: u32) -> (GLuint, GLuint) {
+        let color = create_fb_color(width, height);
 
-            let depth = if depth { Some(create_fb_depth_tex(fbo, width, height)) } else { None };
+        let mut fbo = 0;
+        gl::GenFramebuffers(1, &mut fbo);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+        gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, color, 0);
+        (color, fbo)
+    }
 
-            let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
-            gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
-            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
-            if status != gl::FRAMEBUFFER_COMPLETE {
-                Err(StrErr::new(format!("Failed to create fbo: {status}")))
-            } else {
-                Ok(GpuFbo { color, depth, fbo })
-            }
+    unsafe fn check(color: GLuint, depth: Option<GLuint>, fbo: GLuint) -> Result<Self, StrErr> {
+        let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+        gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            Err(StrErr::new(format!("Failed to create fbo: {status}")))
+        } else {
+            Ok(GpuFbo { color, depth, fbo })
         }
     }
 }
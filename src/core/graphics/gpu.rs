@@ -6,12 +6,15 @@ use crate::core::graphics::gpu_2d::Gpu2DEngine::{A, B};
 use crate::core::graphics::gpu_3d::registers_3d::Gpu3DRegisters;
 use crate::core::graphics::gpu_renderer::GpuRenderer;
 use crate::core::memory::dma::DmaTransferMode;
+use crate::core::memory::regions;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType;
 use crate::core::CpuType::ARM9;
 use crate::logging::debug_println;
 use crate::profiling::profiling_frame_mark;
 use crate::settings::Arm7Emu;
 use bilge::prelude::*;
+use std::cmp::min;
 use std::intrinsics::unlikely;
 use std::ptr::NonNull;
 use std::sync::atomic::{AtomicU16, Ordering};
@@ -122,6 +125,28 @@ impl Gpu {
         }
     }
 
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.disp_stat);
+        state.write(&self.pow_cnt1);
+        state.write(&self.disp_cap_cnt);
+        state.write(&self.v_count);
+        state.write(&self.gpu_2d_regs_a);
+        state.write(&self.gpu_2d_regs_b);
+        self.gpu_3d_regs.save_state(state);
+        self.get_renderer().renderer_3d.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.disp_stat);
+        state.read(&mut self.pow_cnt1);
+        state.read(&mut self.disp_cap_cnt);
+        state.read(&mut self.v_count);
+        state.read(&mut self.gpu_2d_regs_a);
+        state.read(&mut self.gpu_2d_regs_b);
+        self.gpu_3d_regs.load_state(state);
+        self.get_renderer_mut().renderer_3d.load_state(state);
+    }
+
     pub fn initialize_schedule(cm: &mut CycleManager) {
         cm.schedule(
             // 8 pixel delay according to https://melonds.kuribo64.net/board/thread.php?id=13
@@ -164,6 +189,73 @@ impl Gpu {
 }
 
 impl Emu {
+    // The whole capture is done at the end of the frame, the renderer has to provide the output of engine a first
+    fn gpu_display_capture(&mut self) {
+        let disp_cap_cnt = self.gpu.disp_cap_cnt;
+        let (width, height) = match u8::from(disp_cap_cnt.capture_size()) {
+            0 => (128, 128),
+            1 => (256, 64),
+            2 => (256, 128),
+            _ => (256, 192),
+        };
+
+        let eva = min(u8::from(disp_cap_cnt.eva()) as u32, 16);
+        let evb = min(u8::from(disp_cap_cnt.evb()) as u32, 16);
+        let disp_cnt = self.gpu.gpu_2d_regs_a.disp_cnt;
+        let read_base = regions::VRAM_OFFSET + 0x800000 + u8::from(disp_cnt.vram_block()) as u32 * 0x20000;
+        let read_offset = if u8::from(disp_cnt.display_mode()) == 2 {
+            0
+        } else {
+            u8::from(disp_cap_cnt.vram_read_offset()) as u32 * 0x8000
+        };
+        let write_base = regions::VRAM_OFFSET + 0x800000 + u8::from(disp_cap_cnt.vram_write_block()) as u32 * 0x20000;
+        let write_offset = u8::from(disp_cap_cnt.vram_write_offset()) as u32 * 0x8000;
+
+        if disp_cap_cnt.source_b() && u8::from(disp_cap_cnt.capture_source()) != 0 {
+            debug_println!("Display capture from the main memory display fifo is unsupported, capturing from vram instead");
+        }
+
+        let (graphics, frame_3d) = self.gpu.get_renderer().renderer_2d.get_capture_sources();
+        for y in 0..height {
+            for x in 0..width {
+                let source_a = if disp_cap_cnt.source_a() {
+                    // 3d output is RGBA8 with the rows starting at the bottom
+                    let color = frame_3d[(DISPLAY_HEIGHT - 1 - y) * DISPLAY_WIDTH + x];
+                    let r = (color >> 3) & 0x1F;
+                    let g = (color >> 11) & 0x1F;
+                    let b = (color >> 19) & 0x1F;
+                    (r | (g << 5) | (b << 10) | if color >> 24 != 0 { 1 << 15 } else { 0 }) as u16
+                } else {
+                    graphics[y * DISPLAY_WIDTH + x] | (1 << 15)
+                };
+
+                // Main memory display fifo isn't emulated, always read vram
+                let offset = (read_offset + (y * DISPLAY_WIDTH + x) as u32 * 2) & 0x1FFFF;
+                let source_b = self.mem.vram.read::<{ ARM9 }, u16>(read_base + offset);
+
+                let color = match u8::from(disp_cap_cnt.capture_source()) {
+                    0 => source_a,
+                    1 => source_b,
+                    _ => {
+                        let alpha_a = (source_a >> 15) as u32;
+                        let alpha_b = (source_b >> 15) as u32;
+                        let mut color = 0;
+                        for shift in [0, 5, 10] {
+                            let channel_a = ((source_a >> shift) & 0x1F) as u32 * alpha_a * eva;
+                            let channel_b = ((source_b >> shift) & 0x1F) as u32 * alpha_b * evb;
+                            color |= min((channel_a + channel_b + 8) >> 4, 0x1F) << shift;
+                        }
+                        let alpha = (alpha_a != 0 && eva != 0) || (alpha_b != 0 && evb != 0);
+                        color as u16 | ((alpha as u16) << 15)
+                    }
+                };
+
+                let offset = (write_offset + (y * width + x) as u32 * 2) & 0x1FFFF;
+                self.mem.vram.write::<{ ARM9 }, u16>(write_base + offset, color);
+            }
+        }
+    }
+
     pub fn gpu_on_scanline256_event(&mut self, _: u16) {
         if self.gpu.v_count < 192 {
             unsafe {
@@ -171,7 +263,7 @@ impl Emu {
                     .gpu_renderer
                     .unwrap_unchecked()
                     .as_mut()
-                    .on_scanline(&mut self.gpu.gpu_2d_regs_a, &mut self.gpu.gpu_2d_regs_b, self.gpu.v_count as u8)
+                    .on_scanline(&mut self.gpu.gpu_2d_regs_a, &mut self.gpu.gpu_2d_regs_b, &mut self.mem, self.gpu.v_count as u8)
             }
             self.dma_trigger_all(ARM9, DmaTransferMode::StartAtHBlank);
         }
@@ -193,8 +285,17 @@ impl Emu {
             192 => {
                 self.gpu.gpu_3d_regs.current_pow_cnt1 = self.gpu.pow_cnt1;
 
-                let pow_cnt1 = PowCnt1::from(self.gpu.pow_cnt1);
-                self.gpu.get_renderer_mut().on_scanline_finish(&mut self.mem, pow_cnt1, &mut self.gpu.gpu_3d_regs);
+                let mut pow_cnt1 = PowCnt1::from(self.gpu.pow_cnt1);
+                // Blank both screens once all backlights are off, e.g. while sleeping
+                if !self.spi.backlights_on() {
+                    pow_cnt1.set_enable(false);
+                }
+                let capture = self.gpu.disp_cap_cnt.capture_enabled();
+                // Keep the capture request latched until the renderer provides a frame for it
+                if self.gpu.get_renderer_mut().on_scanline_finish(&mut self.mem, pow_cnt1, &mut self.gpu.gpu_3d_regs, capture) {
+                    self.gpu_display_capture();
+                    self.gpu.disp_cap_cnt.set_capture_enabled(false);
+                }
 
                 if self.gpu.gpu_3d_regs.flushed {
                     self.gpu.gpu_3d_regs.swap_buffers();
@@ -209,8 +310,6 @@ impl Emu {
                         self.dma_trigger_all(CpuType::from(i as u8), DmaTransferMode::StartAtVBlank);
                     }
                 }
-
-                self.gpu.disp_cap_cnt.set_capture_enabled(false);
             }
             262 => {
                 for i in 0..2 {
@@ -220,10 +319,13 @@ impl Emu {
             }
             263 => {
                 self.gpu.v_count = 0;
+                self.rtc_on_frame();
+                self.input_on_frame();
                 if self.settings.arm7_hle() == Arm7Emu::Hle {
                     self.arm7_hle_on_frame();
                 }
-                self.gpu.get_renderer_mut().reload_registers();
+                let capture = self.gpu.disp_cap_cnt.capture_enabled();
+                self.gpu.get_renderer_mut().reload_registers(capture);
 
                 profiling_frame_mark!();
             }
@@ -4,10 +4,12 @@ use crate::core::graphics::gl_utils::{
 };
 use crate::core::graphics::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
 use crate::core::graphics::gpu_2d::registers_2d::{BgCnt, DispCnt, Gpu2DRegisters};
+use crate::core::graphics::gpu_2d::renderer_soft_2d::{Gpu2DScanlineRenderer, Gpu2DSoftRenderer};
 use crate::core::graphics::gpu_2d::Gpu2DEngine;
 use crate::core::graphics::gpu_2d::Gpu2DEngine::{A, B};
 use crate::core::graphics::gpu_mem_buf::GpuMemBuf;
 use crate::core::graphics::gpu_renderer::GpuRendererCommon;
+use crate::core::memory::mem::Memory;
 use crate::core::memory::oam::{OamAttrib0, OamAttrib1, OamAttrib2, OamAttribs, OamGfxMode, OamObjMode};
 use crate::core::memory::regions;
 use crate::utils;
@@ -19,17 +21,17 @@ use std::intrinsics::unlikely;
 use std::{mem, ptr, slice};
 
 pub struct Gpu2DMem {
-    lcdc_ptr: *const u8,
-    bg_ptr: *const u8,
-    obj_ptr: *const u8,
-    pal_ptr: *const u8,
-    oam_ptr: *const u8,
-    bg_ext_pal_ptr: *const u8,
-    obj_ext_pal_ptr: *const u8,
+    pub(super) lcdc_ptr: *const u8,
+    pub(super) bg_ptr: *const u8,
+    pub(super) obj_ptr: *const u8,
+    pub(super) pal_ptr: *const u8,
+    pub(super) oam_ptr: *const u8,
+    pub(super) bg_ext_pal_ptr: *const u8,
+    pub(super) obj_ext_pal_ptr: *const u8,
 }
 
 impl Gpu2DMem {
-    fn new<const ENGINE: Gpu2DEngine>(buf: &GpuMemBuf) -> Self {
+    pub(super) fn new<const ENGINE: Gpu2DEngine>(buf: &GpuMemBuf) -> Self {
         match ENGINE {
             A => Gpu2DMem {
                 lcdc_ptr: buf.lcdc.as_ptr(),
@@ -60,38 +62,39 @@ const OBJ_B_TEX_HEIGHT: u32 = 128;
 
 #[derive(Clone)]
 #[repr(C)]
-struct WinBgUbo {
-    win_h: [u32; DISPLAY_HEIGHT * 2],
-    win_v: [u32; DISPLAY_HEIGHT * 2],
-    win_in: [u32; DISPLAY_HEIGHT],
-    win_out: [u32; DISPLAY_HEIGHT],
+pub(super) struct WinBgUbo {
+    pub(super) win_h: [u32; DISPLAY_HEIGHT * 2],
+    pub(super) win_v: [u32; DISPLAY_HEIGHT * 2],
+    pub(super) win_in: [u32; DISPLAY_HEIGHT],
+    pub(super) win_out: [u32; DISPLAY_HEIGHT],
 }
 
 const_assert!(size_of::<WinBgUbo>() <= 16 * 1024);
 
 #[derive(Clone)]
 #[repr(C)]
-struct BgUbo {
-    ofs: [u32; DISPLAY_HEIGHT * 4],
-    x: [i32; DISPLAY_HEIGHT * 2],
-    y: [i32; DISPLAY_HEIGHT * 2],
-    pa: [i32; DISPLAY_HEIGHT * 2],
-    pb: [i32; DISPLAY_HEIGHT * 2],
-    pc: [i32; DISPLAY_HEIGHT * 2],
-    pd: [i32; DISPLAY_HEIGHT * 2],
+pub(super) struct BgUbo {
+    pub(super) ofs: [u32; DISPLAY_HEIGHT * 4],
+    pub(super) x: [i32; DISPLAY_HEIGHT * 2],
+    pub(super) y: [i32; DISPLAY_HEIGHT * 2],
+    pub(super) pa: [i32; DISPLAY_HEIGHT * 2],
+    pub(super) pb: [i32; DISPLAY_HEIGHT * 2],
+    pub(super) pc: [i32; DISPLAY_HEIGHT * 2],
+    pub(super) pd: [i32; DISPLAY_HEIGHT * 2],
 }
 
 const_assert!(size_of::<BgUbo>() <= 16 * 1024);
 
 #[derive(Clone)]
 pub struct Gpu2DRenderRegs {
-    disp_cnts: [u32; DISPLAY_HEIGHT],
-    bg_cnts: [u16; DISPLAY_HEIGHT * 4],
-    win_bg_ubo: WinBgUbo,
-    bg_ubo: BgUbo,
-    blend_ubo: BlendUbo,
-    batch_counts: [u8; DISPLAY_HEIGHT],
-    current_batch_count_index: usize,
+    pub(super) disp_cnts: [u32; DISPLAY_HEIGHT],
+    pub(super) bg_cnts: [u16; DISPLAY_HEIGHT * 4],
+    pub(super) win_bg_ubo: WinBgUbo,
+    pub(super) bg_ubo: BgUbo,
+    pub(super) blend_ubo: BlendUbo,
+    pub(super) master_brights: [u16; DISPLAY_HEIGHT],
+    pub(super) batch_counts: [u8; DISPLAY_HEIGHT],
+    pub(super) current_batch_count_index: usize,
 }
 
 impl Gpu2DRenderRegs {
@@ -166,6 +169,8 @@ impl Gpu2DRenderRegs {
         self.blend_ubo.bld_cnts[line] = inner.bld_cnt as u32;
         self.blend_ubo.bld_alphas[line] = inner.bld_alpha as u32;
         self.blend_ubo.bld_ys[line] = inner.bld_y as u32;
+
+        self.master_brights[line] = inner.master_bright;
     }
 }
 
@@ -223,10 +228,10 @@ impl Default for ObjUbo {
 
 #[derive(Clone)]
 #[repr(C)]
-struct BlendUbo {
-    bld_cnts: [u32; DISPLAY_HEIGHT],
-    bld_alphas: [u32; DISPLAY_HEIGHT],
-    bld_ys: [u32; DISPLAY_HEIGHT],
+pub(super) struct BlendUbo {
+    pub(super) bld_cnts: [u32; DISPLAY_HEIGHT],
+    pub(super) bld_alphas: [u32; DISPLAY_HEIGHT],
+    pub(super) bld_ys: [u32; DISPLAY_HEIGHT],
 }
 
 const_assert!(size_of::<BlendUbo>() <= 16 * 1024);
@@ -386,7 +391,7 @@ impl Gpu2DCommon {
 
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq)]
-enum BgMode {
+pub(super) enum BgMode {
     Text = 0,
     Affine = 1,
     Extended = 2,
@@ -1122,10 +1127,14 @@ pub struct Gpu2DRenderer {
     pub common: Gpu2DCommon,
     program_a: Gpu2DProgram,
     program_b: Gpu2DProgram,
+    // Separate per engine, display capture must only see the layers of engine A
+    soft_renderer_a: Gpu2DSoftRenderer,
+    soft_renderer_b: Gpu2DSoftRenderer,
+    scanline_renderer: Option<Box<Gpu2DScanlineRenderer>>,
 }
 
 impl Gpu2DRenderer {
-    pub fn new() -> Self {
+    pub fn new(scanline: bool) -> Self {
         unsafe {
             let obj_vert_shader = create_shader("obj", shader_source!("obj_vert"), gl::VERTEX_SHADER).unwrap();
             let bg_vert_shader = create_shader("bg", shader_source!("bg_vert"), gl::VERTEX_SHADER).unwrap();
@@ -1143,6 +1152,9 @@ impl Gpu2DRenderer {
                 common: Gpu2DCommon::new(),
                 program_a: Gpu2DProgram::new::<{ A }>(obj_vert_shader, bg_vert_shader, bg_vert_affine_extended_shader, bg_vert_bitmap_shader),
                 program_b: Gpu2DProgram::new::<{ B }>(obj_vert_shader, bg_vert_shader, bg_vert_affine_extended_shader, bg_vert_bitmap_shader),
+                soft_renderer_a: Gpu2DSoftRenderer::new(),
+                soft_renderer_b: Gpu2DSoftRenderer::new(),
+                scanline_renderer: if scanline { Some(Box::new(Gpu2DScanlineRenderer::new())) } else { None },
             };
 
             gl::DeleteShader(obj_vert_shader);
@@ -1154,18 +1166,24 @@ impl Gpu2DRenderer {
         }
     }
 
-    pub fn on_scanline(&mut self, inner_a: &mut Gpu2DRegisters, inner_b: &mut Gpu2DRegisters, line: u8) {
+    pub fn on_scanline(&mut self, inner_a: &mut Gpu2DRegisters, inner_b: &mut Gpu2DRegisters, mem: &mut Memory, line: u8) {
         self.regs_a[1].on_scanline(inner_a, line);
         self.regs_b[1].on_scanline(inner_b, line);
         if u8::from(DispCnt::from(self.regs_a[1].disp_cnts[line as usize]).display_mode()) == 2 {
             self.has_vram_display[1] = true;
         }
+        if let Some(scanline_renderer) = &mut self.scanline_renderer {
+            scanline_renderer.on_scanline(&self.regs_a[1], &self.regs_b[1], mem, line as usize);
+        }
     }
 
     pub fn on_scanline_finish(&mut self) {
         self.regs_a.swap(0, 1);
         self.regs_b.swap(0, 1);
         self.has_vram_display[0] = self.has_vram_display[1];
+        if let Some(scanline_renderer) = &mut self.scanline_renderer {
+            scanline_renderer.on_scanline_finish();
+        }
     }
 
     pub fn reload_registers(&mut self) {
@@ -1174,7 +1192,41 @@ impl Gpu2DRenderer {
         self.has_vram_display[1] = false;
     }
 
-    pub unsafe fn render<const ENGINE: Gpu2DEngine>(&mut self, common: &GpuRendererCommon, fb_tex_3d: GLuint, rotate_screen: bool) {
+    pub unsafe fn render<const ENGINE: Gpu2DEngine>(&mut self, common: &GpuRendererCommon, fb_3d: Option<&GpuFbo>, rotate_screen: bool, soft: bool, capture: bool) {
+        // Display capture reads back the layers of the software renderer
+        let soft = soft || capture;
+        if let Some(fb_3d) = fb_3d {
+            if soft || self.scanline_renderer.is_some() {
+                self.read_3d(fb_3d, capture);
+            }
+        }
+
+        if ENGINE == A {
+            if let Some(scanline_renderer) = &mut self.scanline_renderer {
+                scanline_renderer.compose_3d(&self.soft_renderer_a.frame_3d);
+            }
+        }
+
+        let (regs, soft_renderer) = match ENGINE {
+            A => (&self.regs_a[0], &mut self.soft_renderer_a),
+            B => (&self.regs_b[0], &mut self.soft_renderer_b),
+        };
+        if soft {
+            soft_renderer.render::<ENGINE>(regs, &Gpu2DMem::new::<ENGINE>(&common.mem_buf));
+        }
+
+        if let Some(scanline_renderer) = &self.scanline_renderer {
+            self.upload_frame::<ENGINE>(scanline_renderer.get_frame::<ENGINE>(), rotate_screen);
+            return;
+        } else if soft {
+            let soft_renderer = match ENGINE {
+                A => &self.soft_renderer_a,
+                B => &self.soft_renderer_b,
+            };
+            self.upload_frame::<ENGINE>(soft_renderer.get_frame_rgba(), rotate_screen);
+            return;
+        }
+
         match ENGINE {
             A => {
                 self.program_a.draw(
@@ -1182,7 +1234,7 @@ impl Gpu2DRenderer {
                     &self.regs_a[0],
                     &self.tex_a,
                     Gpu2DMem::new::<{ A }>(&common.mem_buf),
-                    fb_tex_3d,
+                    fb_3d.map_or(0, |fbo| fbo.color),
                     if self.has_vram_display[0] { self.lcdc_pal } else { 0 },
                     &self.vram_display_program,
                     rotate_screen,
@@ -1200,4 +1252,43 @@ impl Gpu2DRenderer {
             ),
         }
     }
+
+    // Composed layers of the last software render of engine A and the 3d output, used by display capture
+    pub fn get_capture_sources(&self) -> (&[u16; DISPLAY_WIDTH * DISPLAY_HEIGHT], &[u32; DISPLAY_WIDTH * DISPLAY_HEIGHT]) {
+        (self.soft_renderer_a.get_graphics(), &self.soft_renderer_a.frame_3d)
+    }
+
+    unsafe fn read_3d(&mut self, fb_3d: &GpuFbo, force: bool) {
+        let uses_3d = force
+            || self.regs_a[0].disp_cnts.iter().any(|disp_cnt| {
+                let disp_cnt = DispCnt::from(*disp_cnt);
+                disp_cnt.bg0_3d() && disp_cnt.screen_display_bg0()
+            });
+        if uses_3d {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, fb_3d.fbo);
+            gl::ReadPixels(
+                0,
+                0,
+                DISPLAY_WIDTH as _,
+                DISPLAY_HEIGHT as _,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                self.soft_renderer_a.frame_3d.as_mut_ptr() as _,
+            );
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+        }
+    }
+
+    unsafe fn upload_frame<const ENGINE: Gpu2DEngine>(&self, frame: &[u32; DISPLAY_WIDTH * DISPLAY_HEIGHT], rotate_screen: bool) {
+        gl::BindTexture(gl::TEXTURE_2D, self.common.blend_fbo.color);
+        gl::TexSubImage2D(gl::TEXTURE_2D, 0, 0, 0, DISPLAY_WIDTH as _, DISPLAY_HEIGHT as _, gl::RGBA, gl::UNSIGNED_BYTE, frame.as_ptr() as _);
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+
+        if rotate_screen {
+            match ENGINE {
+                A => self.program_a.rotate(&self.common),
+                B => self.program_b.rotate(&self.common),
+            }
+        }
+    }
 }
@@ -1,14 +1,843 @@
-use crate::core::graphics::gpu_2d::renderer_2d::{Gpu2DCommon, Gpu2DMem, Gpu2DRenderRegs};
+use crate::core::graphics::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::core::graphics::gpu_2d::registers_2d::{BgCnt, DispCnt};
+use crate::core::graphics::gpu_2d::renderer_2d::{BgMode, Gpu2DMem, Gpu2DRenderRegs};
 use crate::core::graphics::gpu_2d::Gpu2DEngine;
+use crate::core::graphics::gpu_2d::Gpu2DEngine::{A, B};
+use crate::core::graphics::gpu_mem_buf::GpuMemBuf;
+use crate::core::memory::mem::Memory;
+use crate::core::memory::oam::{OamAttrib0, OamAttrib1, OamAttrib2, OamAttribs, OamGfxMode, OamObjMode};
+use crate::core::memory::{regions, vram};
+use crate::utils;
+use crate::utils::{rgb5_to_rgb8, HeapMem, HeapMemU32};
+use std::cmp::min;
+use std::slice;
 
-pub struct Gpu2DSoftRenderer {}
+const LAYER_OBJ: u8 = 4;
+const LAYER_BACKDROP: u8 = 5;
+
+// Bit 15 marks an opaque pixel in the layer lines
+const PIXEL_OPAQUE: u16 = 1 << 15;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum ObjPixelMode {
+    Normal,
+    SemiTransparent,
+    Bitmap(u8),
+}
+
+// Blend inputs of a line, sampled when the line is drawn
+#[derive(Copy, Clone)]
+struct LineBlend {
+    backdrop: u16,
+    bld_cnt: u16,
+    bld_alpha: u16,
+    bld_y: u16,
+}
+
+impl LineBlend {
+    fn new(regs: &Gpu2DRenderRegs, mem: &Gpu2DSoftMem, y: usize) -> Self {
+        LineBlend {
+            backdrop: Gpu2DSoftMem::read16(mem.pal, 0) & 0x7FFF,
+            bld_cnt: regs.blend_ubo.bld_cnts[y] as u16,
+            bld_alpha: regs.blend_ubo.bld_alphas[y] as u16,
+            bld_y: regs.blend_ubo.bld_ys[y] as u16,
+        }
+    }
+}
+
+// Layers of a line with a 3d bg0, composed again once the 3d output of the frame is rendered
+#[derive(Clone)]
+pub struct Gpu2DLine3D {
+    bg_lines: [[u16; DISPLAY_WIDTH]; 4],
+    bg_prios: [u8; 4],
+    obj_line: [u16; DISPLAY_WIDTH],
+    obj_prios: [u8; DISPLAY_WIDTH],
+    obj_modes: [ObjPixelMode; DISPLAY_WIDTH],
+    win_masks: [u8; DISPLAY_WIDTH],
+    disp_cnt: DispCnt,
+    blend: LineBlend,
+    master_bright: u16,
+}
+
+struct Gpu2DSoftMem<'a> {
+    lcdc: &'a [u8],
+    bg: &'a [u8],
+    obj: &'a [u8],
+    pal: &'a [u8],
+    oam: &'a [u8],
+    bg_ext_pal: &'a [u8],
+    obj_ext_pal: &'a [u8],
+}
+
+impl<'a> Gpu2DSoftMem<'a> {
+    fn new<const ENGINE: Gpu2DEngine>(mem: &'a Gpu2DMem) -> Self {
+        let (bg_size, obj_size) = match ENGINE {
+            A => (vram::BG_A_SIZE, vram::OBJ_A_SIZE),
+            B => (vram::BG_B_SIZE, vram::OBJ_B_SIZE),
+        };
+        unsafe {
+            Gpu2DSoftMem {
+                lcdc: if mem.lcdc_ptr.is_null() { &[] } else { slice::from_raw_parts(mem.lcdc_ptr, vram::TOTAL_SIZE) },
+                bg: slice::from_raw_parts(mem.bg_ptr, bg_size as usize),
+                obj: slice::from_raw_parts(mem.obj_ptr, obj_size as usize),
+                pal: slice::from_raw_parts(mem.pal_ptr, regions::STANDARD_PALETTES_SIZE as usize / 2),
+                oam: slice::from_raw_parts(mem.oam_ptr, regions::OAM_SIZE as usize / 2),
+                bg_ext_pal: slice::from_raw_parts(mem.bg_ext_pal_ptr, vram::BG_EXT_PAL_SIZE as usize),
+                obj_ext_pal: slice::from_raw_parts(mem.obj_ext_pal_ptr, vram::OBJ_EXT_PAL_SIZE as usize),
+            }
+        }
+    }
+
+    // All regions are a power of 2, mirror out of bounds accesses
+    fn read8(mem: &[u8], addr: u32) -> u8 {
+        mem[addr as usize & (mem.len() - 1)]
+    }
+
+    fn read16(mem: &[u8], addr: u32) -> u16 {
+        utils::read_from_mem(mem, addr & (mem.len() as u32 - 2))
+    }
+}
+
+fn alpha_blend(top: u16, bottom: u16, eva: u16, evb: u16) -> u16 {
+    let mut color = 0;
+    for shift in [0, 5, 10] {
+        let channel = (((top >> shift) & 0x1F) * eva + ((bottom >> shift) & 0x1F) * evb) >> 4;
+        color |= min(channel, 0x1F) << shift;
+    }
+    color
+}
+
+fn brightness_up(color: u16, evy: u16) -> u16 {
+    let mut ret = 0;
+    for shift in [0, 5, 10] {
+        let channel = (color >> shift) & 0x1F;
+        ret |= (channel + (((0x1F - channel) * evy) >> 4)) << shift;
+    }
+    ret
+}
+
+fn brightness_down(color: u16, evy: u16) -> u16 {
+    let mut ret = 0;
+    for shift in [0, 5, 10] {
+        let channel = (color >> shift) & 0x1F;
+        ret |= (channel - ((channel * evy) >> 4)) << shift;
+    }
+    ret
+}
+
+// Cpu side reference of the gl 2d renderer, doesn't need a gpu
+pub struct Gpu2DSoftRenderer {
+    bg_lines: [[u16; DISPLAY_WIDTH]; 4],
+    bg_prios: [u8; 4],
+    alphas_3d: [u8; DISPLAY_WIDTH],
+    obj_line: [u16; DISPLAY_WIDTH],
+    obj_prios: [u8; DISPLAY_WIDTH],
+    obj_modes: [ObjPixelMode; DISPLAY_WIDTH],
+    obj_window: [bool; DISPLAY_WIDTH],
+    win_masks: [u8; DISPLAY_WIDTH],
+    // RGBA8 of the 3d renderer, rows bottom up as read from gl
+    pub frame_3d: HeapMemU32<{ DISPLAY_WIDTH * DISPLAY_HEIGHT }>,
+    // Composed layers before display mode and master brightness, the source of display capture
+    graphics: HeapMem<u16, { DISPLAY_WIDTH * DISPLAY_HEIGHT }>,
+    frame: HeapMem<u16, { DISPLAY_WIDTH * DISPLAY_HEIGHT }>,
+    frame_rgba: HeapMemU32<{ DISPLAY_WIDTH * DISPLAY_HEIGHT }>,
+    // Only filled when lines with 3d are recorded, see record_lines_3d
+    lines_3d: Vec<Option<Gpu2DLine3D>>,
+}
 
 impl Gpu2DSoftRenderer {
     pub fn new() -> Gpu2DSoftRenderer {
-        Gpu2DSoftRenderer {}
+        Gpu2DSoftRenderer {
+            bg_lines: [[0; DISPLAY_WIDTH]; 4],
+            bg_prios: [0; 4],
+            alphas_3d: [0; DISPLAY_WIDTH],
+            obj_line: [0; DISPLAY_WIDTH],
+            obj_prios: [0; DISPLAY_WIDTH],
+            obj_modes: [ObjPixelMode::Normal; DISPLAY_WIDTH],
+            obj_window: [false; DISPLAY_WIDTH],
+            win_masks: [0; DISPLAY_WIDTH],
+            frame_3d: HeapMemU32::new(),
+            graphics: HeapMem::new(),
+            frame: HeapMem::new(),
+            frame_rgba: HeapMemU32::new(),
+            lines_3d: Vec::new(),
+        }
+    }
+
+    // Keep the layers of lines with a 3d bg0, the 3d output is composed later on with compose_line_3d
+    pub fn record_lines_3d(&mut self) {
+        self.lines_3d = vec![None; DISPLAY_HEIGHT];
+    }
+
+    // RGB555 of the composed layers, rows top down
+    pub fn get_graphics(&self) -> &[u16; DISPLAY_WIDTH * DISPLAY_HEIGHT] {
+        &self.graphics
     }
 
-    pub fn start_render<const ENGINE: Gpu2DEngine>(&mut self, common: &Gpu2DCommon, regs: &Gpu2DRenderRegs, mem: Gpu2DMem) {}
+    // RGB555, rows top down
+    pub fn get_frame(&self) -> &[u16; DISPLAY_WIDTH * DISPLAY_HEIGHT] {
+        &self.frame
+    }
+
+    // RGBA8, rows bottom up to match the gl framebuffers
+    pub fn get_frame_rgba(&self) -> &[u32; DISPLAY_WIDTH * DISPLAY_HEIGHT] {
+        &self.frame_rgba
+    }
+
+    pub fn render<const ENGINE: Gpu2DEngine>(&mut self, regs: &Gpu2DRenderRegs, mem: &Gpu2DMem) {
+        let mem = Gpu2DSoftMem::new::<ENGINE>(mem);
+
+        let mut line = 0;
+        while line < DISPLAY_HEIGHT {
+            let from_line = line;
+            let to_line = min(line + regs.batch_counts[line] as usize + 1, DISPLAY_HEIGHT);
+            line = to_line;
+
+            for y in from_line..to_line {
+                self.render_line::<ENGINE>(regs, &mem, from_line, y);
+            }
+        }
+
+        for y in 0..DISPLAY_HEIGHT {
+            self.convert_line(y);
+        }
+    }
+
+    // Renders a single line with the registers sampled so far
+    pub fn render_scanline<const ENGINE: Gpu2DEngine>(&mut self, regs: &Gpu2DRenderRegs, mem: &Gpu2DMem, line: usize) {
+        let mem = Gpu2DSoftMem::new::<ENGINE>(mem);
+        self.render_line::<ENGINE>(regs, &mem, regs.current_batch_count_index, line);
+        self.convert_line(line);
+    }
+
+    fn convert_line(&mut self, y: usize) {
+        let src = &self.frame[y * DISPLAY_WIDTH..(y + 1) * DISPLAY_WIDTH];
+        let dst = &mut self.frame_rgba[(DISPLAY_HEIGHT - 1 - y) * DISPLAY_WIDTH..(DISPLAY_HEIGHT - y) * DISPLAY_WIDTH];
+        for (dst, src) in dst.iter_mut().zip(src) {
+            *dst = rgb5_to_rgb8(*src);
+        }
+    }
+
+    fn render_line<const ENGINE: Gpu2DEngine>(&mut self, regs: &Gpu2DRenderRegs, mem: &Gpu2DSoftMem, batch_line: usize, y: usize) {
+        let disp_cnt = DispCnt::from(regs.disp_cnts[batch_line]);
+
+        // Always draw the layers, display capture doesn't care about the display mode
+        if disp_cnt.forced_blank() {
+            self.graphics[y * DISPLAY_WIDTH..(y + 1) * DISPLAY_WIDTH].fill(0x7FFF);
+        } else {
+            self.draw_objects(mem, disp_cnt, y);
+            self.draw_windows(regs, disp_cnt, y);
+            self.draw_bgs(regs, mem, disp_cnt, batch_line, y);
+            let blend = LineBlend::new(regs, mem, y);
+            self.compose(disp_cnt, blend, y);
+
+            if !self.lines_3d.is_empty() && disp_cnt.bg0_3d() && disp_cnt.screen_display_bg0() && u8::from(disp_cnt.display_mode()) == 1 {
+                self.lines_3d[y] = Some(Gpu2DLine3D {
+                    bg_lines: self.bg_lines,
+                    bg_prios: self.bg_prios,
+                    obj_line: self.obj_line,
+                    obj_prios: self.obj_prios,
+                    obj_modes: self.obj_modes,
+                    win_masks: self.win_masks,
+                    disp_cnt,
+                    blend,
+                    master_bright: regs.master_brights[y],
+                });
+            }
+        }
+
+        let line = &mut self.frame[y * DISPLAY_WIDTH..(y + 1) * DISPLAY_WIDTH];
+        match u8::from(disp_cnt.display_mode()) {
+            0 => line.fill(0x7FFF),
+            // Only engine A maps the lcdc vram, engine B has no vram display
+            2 if ENGINE == A && !mem.lcdc.is_empty() => {
+                let addr = u8::from(disp_cnt.vram_block()) as u32 * vram::BANK_A_SIZE as u32 + (y * DISPLAY_WIDTH * 2) as u32;
+                for (x, pixel) in line.iter_mut().enumerate() {
+                    *pixel = utils::read_from_mem::<u16>(mem.lcdc, addr + x as u32 * 2) & 0x7FFF;
+                }
+            }
+            // Main memory display is fed through a dma fifo, which isn't captured, show the layers instead
+            _ => line.copy_from_slice(&self.graphics[y * DISPLAY_WIDTH..(y + 1) * DISPLAY_WIDTH]),
+        }
+
+        Self::apply_master_bright(line, regs.master_brights[y]);
+    }
+
+    fn apply_master_bright(line: &mut [u16], master_bright: u16) {
+        let factor = min(master_bright & 0x1F, 16);
+        match master_bright >> 14 {
+            1 => line.iter_mut().for_each(|pixel| *pixel = brightness_up(*pixel, factor)),
+            2 => line.iter_mut().for_each(|pixel| *pixel = brightness_down(*pixel, factor)),
+            _ => {}
+        }
+    }
+
+    // Composes a recorded line with the given 3d output, returns the RGBA8 row
+    pub fn compose_line_3d(&mut self, line: &Gpu2DLine3D, frame_3d: &[u32; DISPLAY_WIDTH * DISPLAY_HEIGHT], y: usize) -> &[u32] {
+        self.bg_lines = line.bg_lines;
+        self.bg_prios = line.bg_prios;
+        self.obj_line = line.obj_line;
+        self.obj_prios = line.obj_prios;
+        self.obj_modes = line.obj_modes;
+        self.win_masks = line.win_masks;
+
+        self.bg_lines[0].fill(0);
+        Self::draw_bg_3d(&mut self.bg_lines[0], &mut self.alphas_3d, frame_3d, y);
+        self.compose(line.disp_cnt, line.blend, y);
+
+        let frame_line = &mut self.frame[y * DISPLAY_WIDTH..(y + 1) * DISPLAY_WIDTH];
+        frame_line.copy_from_slice(&self.graphics[y * DISPLAY_WIDTH..(y + 1) * DISPLAY_WIDTH]);
+        Self::apply_master_bright(frame_line, line.master_bright);
+        self.convert_line(y);
+        &self.frame_rgba[(DISPLAY_HEIGHT - 1 - y) * DISPLAY_WIDTH..(DISPLAY_HEIGHT - y) * DISPLAY_WIDTH]
+    }
+
+    fn draw_windows(&mut self, regs: &Gpu2DRenderRegs, disp_cnt: DispCnt, y: usize) {
+        if !disp_cnt.is_any_window_enabled() {
+            self.win_masks.fill(0x3F);
+            return;
+        }
+
+        let win_in = regs.win_bg_ubo.win_in[y];
+        let win_out = regs.win_bg_ubo.win_out[y];
+        self.win_masks.fill((win_out & 0x3F) as u8);
+
+        if disp_cnt.obj_window_display_flag() {
+            for x in 0..DISPLAY_WIDTH {
+                if self.obj_window[x] {
+                    self.win_masks[x] = ((win_out >> 8) & 0x3F) as u8;
+                }
+            }
+        }
+
+        let within = |pos: usize, start: usize, end: usize| if start <= end { pos >= start && pos < end } else { pos >= start || pos < end };
+
+        // Window 0 has priority over window 1
+        for win in [1, 0] {
+            let enabled = match win {
+                0 => disp_cnt.window0_display_flag(),
+                _ => disp_cnt.window1_display_flag(),
+            };
+            if !enabled {
+                continue;
+            }
+
+            let h = regs.win_bg_ubo.win_h[win * DISPLAY_HEIGHT + y];
+            let v = regs.win_bg_ubo.win_v[win * DISPLAY_HEIGHT + y];
+            if !within(y, ((v >> 8) & 0xFF) as usize, (v & 0xFF) as usize) {
+                continue;
+            }
+
+            let (x1, x2) = (((h >> 8) & 0xFF) as usize, (h & 0xFF) as usize);
+            let mask = ((win_in >> (win * 8)) & 0x3F) as u8;
+            for x in 0..DISPLAY_WIDTH {
+                if within(x, x1, x2) {
+                    self.win_masks[x] = mask;
+                }
+            }
+        }
+    }
+
+    fn draw_objects(&mut self, mem: &Gpu2DSoftMem, disp_cnt: DispCnt, y: usize) {
+        self.obj_line.fill(0);
+        self.obj_window.fill(false);
+        if !disp_cnt.screen_display_obj() {
+            return;
+        }
+
+        const OAM_COUNT: usize = regions::OAM_SIZE as usize / 2 / size_of::<OamAttribs>();
+        let oams = unsafe { slice::from_raw_parts(mem.oam.as_ptr() as *const OamAttribs, OAM_COUNT) };
+
+        for oam in oams {
+            let attrib0 = OamAttrib0::from(oam.attr0);
+            let obj_mode = attrib0.get_obj_mode();
+            if obj_mode == OamObjMode::Disabled {
+                continue;
+            }
+            let gfx_mode = attrib0.get_gfx_mode();
+            if gfx_mode == OamGfxMode::Window && !disp_cnt.obj_window_display_flag() {
+                continue;
+            }
 
-    pub fn wait_for_render() {}
+            let attrib1 = OamAttrib1::from(oam.attr1);
+            let attrib2 = OamAttrib2::from(oam.attr2);
+            let (width, height) = match (u8::from(attrib0.shape()) << 2) | u8::from(attrib1.size()) {
+                0x0 => (8, 8),
+                0x1 => (16, 16),
+                0x2 => (32, 32),
+                0x3 => (64, 64),
+                0x4 => (16, 8),
+                0x5 => (32, 8),
+                0x6 => (32, 16),
+                0x7 => (64, 32),
+                0x8 => (8, 16),
+                0x9 => (8, 32),
+                0xA => (16, 32),
+                0xB => (32, 64),
+                _ => continue,
+            };
+            let (box_width, box_height) = if obj_mode == OamObjMode::AffineDouble { (width * 2, height * 2) } else { (width, height) };
+
+            let row = (y as i32 - attrib0.y() as i32) & 0xFF;
+            if row >= box_height {
+                continue;
+            }
+
+            let mut obj_x = u16::from(attrib1.x()) as i32;
+            if obj_x >= DISPLAY_WIDTH as i32 {
+                obj_x -= 512;
+            }
+
+            let affine = obj_mode == OamObjMode::Affine || obj_mode == OamObjMode::AffineDouble;
+            let (pa, pb, pc, pd) = if affine {
+                let base = ((oam.attr1 >> 9) & 0x1F) as u32 * 0x20;
+                let param = |offset| Gpu2DSoftMem::read16(mem.oam, base + offset) as i16 as i32;
+                (param(6), param(14), param(22), param(30))
+            } else {
+                (0, 0, 0, 0)
+            };
+
+            let priority = u8::from(attrib2.priority());
+            let pixel_mode = match gfx_mode {
+                OamGfxMode::AlphaBlending => ObjPixelMode::SemiTransparent,
+                OamGfxMode::Bitmap => {
+                    let alpha = u8::from(attrib2.pal_bank());
+                    if alpha == 0 {
+                        continue;
+                    }
+                    ObjPixelMode::Bitmap(alpha)
+                }
+                _ => ObjPixelMode::Normal,
+            };
+
+            for box_x in 0..box_width {
+                let screen_x = obj_x + box_x;
+                if screen_x < 0 || screen_x >= DISPLAY_WIDTH as i32 {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+                if gfx_mode != OamGfxMode::Window && self.obj_line[screen_x] & PIXEL_OPAQUE != 0 && priority >= self.obj_prios[screen_x] {
+                    continue;
+                }
+
+                let (tex_x, tex_y) = if affine {
+                    let center_x = box_x - box_width / 2;
+                    let center_y = row - box_height / 2;
+                    let tex_x = ((pa * center_x + pb * center_y) >> 8) + width / 2;
+                    let tex_y = ((pc * center_x + pd * center_y) >> 8) + height / 2;
+                    if tex_x < 0 || tex_x >= width || tex_y < 0 || tex_y >= height {
+                        continue;
+                    }
+                    (tex_x, tex_y)
+                } else {
+                    let flip = u8::from(attrib1.flip());
+                    (if flip & 1 != 0 { width - 1 - box_x } else { box_x }, if flip & 2 != 0 { height - 1 - row } else { row })
+                };
+
+                let color = if gfx_mode == OamGfxMode::Bitmap {
+                    Self::read_obj_bitmap(mem, disp_cnt, oam.attr2, width, tex_x as u32, tex_y as u32)
+                } else {
+                    Self::read_obj_tile(mem, disp_cnt, &attrib0, &attrib2, width, tex_x as u32, tex_y as u32)
+                };
+
+                if let Some(color) = color {
+                    if gfx_mode == OamGfxMode::Window {
+                        self.obj_window[screen_x] = true;
+                    } else {
+                        self.obj_line[screen_x] = color | PIXEL_OPAQUE;
+                        self.obj_prios[screen_x] = priority;
+                        self.obj_modes[screen_x] = pixel_mode;
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_obj_bitmap(mem: &Gpu2DSoftMem, disp_cnt: DispCnt, attr2: u16, width: i32, x: u32, y: u32) -> Option<u16> {
+        let (map_width, base) = if disp_cnt.bitmap_obj_mapping() {
+            (width as u32, (attr2 & 0x3FF) as u32 * if disp_cnt.bitmap_obj_1d_boundary() { 256 } else { 128 })
+        } else {
+            let x_mask = if disp_cnt.bitmap_obj_2d() { 0x1F } else { 0x0F };
+            (
+                if disp_cnt.bitmap_obj_2d() { 256 } else { 128 },
+                (attr2 & x_mask) as u32 * 0x10 + (attr2 & 0x3FF & !x_mask) as u32 * 0x80,
+            )
+        };
+        let color = Gpu2DSoftMem::read16(mem.obj, base + (y * map_width + x) * 2);
+        if color & (1 << 15) != 0 {
+            Some(color & 0x7FFF)
+        } else {
+            None
+        }
+    }
+
+    fn read_obj_tile(mem: &Gpu2DSoftMem, disp_cnt: DispCnt, attrib0: &OamAttrib0, attrib2: &OamAttrib2, width: i32, x: u32, y: u32) -> Option<u16> {
+        let (map_width, bound) = if disp_cnt.tile_1d_obj_mapping() {
+            (width as u32, 32 << u8::from(disp_cnt.tile_obj_1d_boundary()))
+        } else {
+            (if attrib0.is_8bit() { 128 } else { 256 }, 32)
+        };
+        let tile_addr = u16::from(attrib2.tile_index()) as u32 * bound;
+
+        if attrib0.is_8bit() {
+            let addr = tile_addr + ((y & 7) + (y >> 3) * map_width) * 8 + (x >> 3) * 64 + (x & 7);
+            let index = Gpu2DSoftMem::read8(mem.obj, addr) as u32;
+            if index == 0 {
+                None
+            } else if disp_cnt.obj_extended_palettes() {
+                Some(Gpu2DSoftMem::read16(mem.obj_ext_pal, u8::from(attrib2.pal_bank()) as u32 * 0x200 + index * 2) & 0x7FFF)
+            } else {
+                Some(Gpu2DSoftMem::read16(mem.pal, 0x200 + index * 2) & 0x7FFF)
+            }
+        } else {
+            let addr = tile_addr + ((y & 7) + (y >> 3) * map_width) * 4 + (x >> 3) * 32 + (x & 7) / 2;
+            let index = (Gpu2DSoftMem::read8(mem.obj, addr) >> (4 * (x & 1))) as u32 & 0xF;
+            if index == 0 {
+                None
+            } else {
+                Some(Gpu2DSoftMem::read16(mem.pal, 0x200 + u8::from(attrib2.pal_bank()) as u32 * 32 + index * 2) & 0x7FFF)
+            }
+        }
+    }
+
+    fn draw_bgs(&mut self, regs: &Gpu2DRenderRegs, mem: &Gpu2DSoftMem, disp_cnt: DispCnt, batch_line: usize, y: usize) {
+        for bg_num in 0..4 {
+            self.bg_lines[bg_num].fill(0);
+
+            let enabled = match bg_num {
+                0 => disp_cnt.screen_display_bg0(),
+                1 => disp_cnt.screen_display_bg1(),
+                2 => disp_cnt.screen_display_bg2(),
+                _ => disp_cnt.screen_display_bg3(),
+            };
+            if !enabled {
+                continue;
+            }
+
+            let bg_cnt = BgCnt::from(regs.bg_cnts[batch_line * 4 + bg_num]);
+            self.bg_prios[bg_num] = u8::from(bg_cnt.priority());
+
+            let bg_mode = match (u8::from(disp_cnt.bg_mode()), bg_num) {
+                (0..=6, 0) if disp_cnt.bg0_3d() => BgMode::Display3d,
+                (0..=6, 0) | (0..=5, 1) | (0 | 1 | 3, 2) | (0, 3) => BgMode::Text,
+                (2 | 4, 2) | (1 | 2, 3) => BgMode::Affine,
+                (5, 2) | (3..=5, 3) => BgMode::Extended,
+                (6, 2) => BgMode::Large,
+                _ => continue,
+            };
+
+            match bg_mode {
+                BgMode::Text => self.draw_bg_text(regs, mem, disp_cnt, bg_cnt, bg_num, y),
+                BgMode::Affine => self.draw_bg_affine(regs, mem, disp_cnt, bg_cnt, bg_num, y),
+                BgMode::Extended => {
+                    if bg_cnt.color_256_palettes() {
+                        self.draw_bg_bitmap(regs, mem, bg_cnt, bg_num, y)
+                    } else {
+                        self.draw_bg_extended(regs, mem, disp_cnt, bg_cnt, bg_num, y)
+                    }
+                }
+                BgMode::Large => self.draw_bg_large(regs, mem, bg_cnt, y),
+                BgMode::Display3d => Self::draw_bg_3d(&mut self.bg_lines[0], &mut self.alphas_3d, &self.frame_3d, y),
+            }
+        }
+    }
+
+    fn draw_bg_3d(bg_line: &mut [u16; DISPLAY_WIDTH], alphas_3d: &mut [u8; DISPLAY_WIDTH], frame_3d: &[u32; DISPLAY_WIDTH * DISPLAY_HEIGHT], y: usize) {
+        let row = &frame_3d[(DISPLAY_HEIGHT - 1 - y) * DISPLAY_WIDTH..(DISPLAY_HEIGHT - y) * DISPLAY_WIDTH];
+        for x in 0..DISPLAY_WIDTH {
+            let color = row[x];
+            let alpha = (color >> 27) as u8;
+            if color >> 24 != 0 {
+                let r = (color >> 3) & 0x1F;
+                let g = (color >> 11) & 0x1F;
+                let b = (color >> 19) & 0x1F;
+                bg_line[x] = (r | (g << 5) | (b << 10)) as u16 | PIXEL_OPAQUE;
+                alphas_3d[x] = alpha;
+            }
+        }
+    }
+
+    fn bg_bases(disp_cnt: DispCnt, bg_cnt: BgCnt) -> (u32, u32) {
+        let screen_base = u8::from(disp_cnt.screen_base()) as u32 * 0x10000 + u8::from(bg_cnt.screen_base_block()) as u32 * 0x800;
+        let char_base = u8::from(disp_cnt.char_base()) as u32 * 0x10000 + u8::from(bg_cnt.char_base_block()) as u32 * 0x4000;
+        (screen_base, char_base)
+    }
+
+    fn read_ext_pal(mem: &Gpu2DSoftMem, slot: usize, entry: u16, index: u32) -> u16 {
+        Gpu2DSoftMem::read16(mem.bg_ext_pal, slot as u32 * 0x2000 + (entry >> 12) as u32 * 0x200 + index * 2) & 0x7FFF
+    }
+
+    fn draw_bg_text(&mut self, regs: &Gpu2DRenderRegs, mem: &Gpu2DSoftMem, disp_cnt: DispCnt, bg_cnt: BgCnt, bg_num: usize, y: usize) {
+        let (screen_base, char_base) = Self::bg_bases(disp_cnt, bg_cnt);
+        let ofs = regs.bg_ubo.ofs[bg_num * DISPLAY_HEIGHT + y];
+        let screen_size = u8::from(bg_cnt.screen_size());
+
+        let y = (y as u32 + (ofs >> 16)) & 0x1FF;
+        let mut screen_base = screen_base + ((y & 0xF8) << 3);
+        if y > 255 && screen_size & 2 != 0 {
+            screen_base += if screen_size & 1 != 0 { 0x1000 } else { 0x800 };
+        }
+
+        let ext_pal_slot = if bg_num < 2 && u8::from(bg_cnt.ext_palette_slot_display_area_overflow()) != 0 {
+            bg_num + 2
+        } else {
+            bg_num
+        };
+
+        for x in 0..DISPLAY_WIDTH {
+            let x_ofs = (x as u32 + (ofs & 0xFFFF)) & 0x1FF;
+            let mut screen_addr = screen_base + ((x_ofs & 0xF8) >> 2);
+            if x_ofs > 255 && screen_size & 1 != 0 {
+                screen_addr += 0x800;
+            }
+
+            let entry = Gpu2DSoftMem::read16(mem.bg, screen_addr);
+            let tile_x = if entry & (1 << 10) != 0 { 7 - (x_ofs & 7) } else { x_ofs & 7 };
+            let tile_y = if entry & (1 << 11) != 0 { 7 - (y & 7) } else { y & 7 };
+            let tile = (entry & 0x3FF) as u32;
+
+            let color = if bg_cnt.color_256_palettes() {
+                let index = Gpu2DSoftMem::read8(mem.bg, char_base + tile * 64 + tile_y * 8 + tile_x) as u32;
+                if index == 0 {
+                    continue;
+                }
+                if disp_cnt.bg_extended_palettes() {
+                    Self::read_ext_pal(mem, ext_pal_slot, entry, index)
+                } else {
+                    Gpu2DSoftMem::read16(mem.pal, index * 2) & 0x7FFF
+                }
+            } else {
+                let index = (Gpu2DSoftMem::read8(mem.bg, char_base + tile * 32 + tile_y * 4 + tile_x / 2) >> (4 * (tile_x & 1))) as u32 & 0xF;
+                if index == 0 {
+                    continue;
+                }
+                Gpu2DSoftMem::read16(mem.pal, (entry >> 12) as u32 * 32 + index * 2) & 0x7FFF
+            };
+            self.bg_lines[bg_num][x] = color | PIXEL_OPAQUE;
+        }
+    }
+
+    // Calls draw for every pixel within the affine bounds, with the coordinates within the bg
+    fn for_each_affine_pixel(regs: &Gpu2DRenderRegs, bg_num: usize, y: usize, width: i32, height: i32, wrap: bool, mut draw: impl FnMut(usize, u32, u32)) {
+        let index = (bg_num - 2) * DISPLAY_HEIGHT + y;
+        let ref_x = regs.bg_ubo.x[index] + regs.bg_ubo.pb[index];
+        let ref_y = regs.bg_ubo.y[index] + regs.bg_ubo.pd[index];
+        let pa = regs.bg_ubo.pa[index];
+        let pc = regs.bg_ubo.pc[index];
+
+        for x in 0..DISPLAY_WIDTH {
+            let mut bg_x = (ref_x + pa * x as i32) >> 8;
+            let mut bg_y = (ref_y + pc * x as i32) >> 8;
+            if wrap {
+                bg_x &= width - 1;
+                bg_y &= height - 1;
+            } else if bg_x < 0 || bg_x >= width || bg_y < 0 || bg_y >= height {
+                continue;
+            }
+            draw(x, bg_x as u32, bg_y as u32);
+        }
+    }
+
+    fn draw_bg_affine(&mut self, regs: &Gpu2DRenderRegs, mem: &Gpu2DSoftMem, disp_cnt: DispCnt, bg_cnt: BgCnt, bg_num: usize, y: usize) {
+        let (screen_base, char_base) = Self::bg_bases(disp_cnt, bg_cnt);
+        let size = 128 << u8::from(bg_cnt.screen_size());
+        let wrap = u8::from(bg_cnt.ext_palette_slot_display_area_overflow()) != 0;
+        let line = &mut self.bg_lines[bg_num];
+
+        Self::for_each_affine_pixel(regs, bg_num, y, size, size, wrap, |x, bg_x, bg_y| {
+            let tile = Gpu2DSoftMem::read8(mem.bg, screen_base + (bg_y >> 3) * (size as u32 >> 3) + (bg_x >> 3)) as u32;
+            let index = Gpu2DSoftMem::read8(mem.bg, char_base + tile * 64 + (bg_y & 7) * 8 + (bg_x & 7)) as u32;
+            if index != 0 {
+                line[x] = (Gpu2DSoftMem::read16(mem.pal, index * 2) & 0x7FFF) | PIXEL_OPAQUE;
+            }
+        });
+    }
+
+    fn draw_bg_extended(&mut self, regs: &Gpu2DRenderRegs, mem: &Gpu2DSoftMem, disp_cnt: DispCnt, bg_cnt: BgCnt, bg_num: usize, y: usize) {
+        let (screen_base, char_base) = Self::bg_bases(disp_cnt, bg_cnt);
+        let size = 128 << u8::from(bg_cnt.screen_size());
+        let wrap = u8::from(bg_cnt.ext_palette_slot_display_area_overflow()) != 0;
+        let line = &mut self.bg_lines[bg_num];
+
+        Self::for_each_affine_pixel(regs, bg_num, y, size, size, wrap, |x, bg_x, bg_y| {
+            let entry = Gpu2DSoftMem::read16(mem.bg, screen_base + ((bg_y >> 3) * (size as u32 >> 3) + (bg_x >> 3)) * 2);
+            let tile_x = if entry & (1 << 10) != 0 { 7 - (bg_x & 7) } else { bg_x & 7 };
+            let tile_y = if entry & (1 << 11) != 0 { 7 - (bg_y & 7) } else { bg_y & 7 };
+            let index = Gpu2DSoftMem::read8(mem.bg, char_base + (entry & 0x3FF) as u32 * 64 + tile_y * 8 + tile_x) as u32;
+            if index != 0 {
+                let color = if disp_cnt.bg_extended_palettes() {
+                    Self::read_ext_pal(mem, bg_num, entry, index)
+                } else {
+                    Gpu2DSoftMem::read16(mem.pal, index * 2) & 0x7FFF
+                };
+                line[x] = color | PIXEL_OPAQUE;
+            }
+        });
+    }
+
+    fn draw_bg_bitmap(&mut self, regs: &Gpu2DRenderRegs, mem: &Gpu2DSoftMem, bg_cnt: BgCnt, bg_num: usize, y: usize) {
+        let (width, height) = match u8::from(bg_cnt.screen_size()) {
+            0 => (128, 128),
+            1 => (256, 256),
+            2 => (512, 256),
+            _ => (512, 512),
+        };
+        let wrap = u8::from(bg_cnt.ext_palette_slot_display_area_overflow()) != 0;
+        let data_base = u8::from(bg_cnt.screen_base_block()) as u32 * 0x4000;
+        let direct_color = u8::from(bg_cnt.char_base_block()) & 1 != 0;
+        let line = &mut self.bg_lines[bg_num];
+
+        Self::for_each_affine_pixel(regs, bg_num, y, width, height, wrap, |x, bg_x, bg_y| {
+            let offset = bg_y * width as u32 + bg_x;
+            if direct_color {
+                let color = Gpu2DSoftMem::read16(mem.bg, data_base + offset * 2);
+                if color & (1 << 15) != 0 {
+                    line[x] = color;
+                }
+            } else {
+                let index = Gpu2DSoftMem::read8(mem.bg, data_base + offset) as u32;
+                if index != 0 {
+                    line[x] = (Gpu2DSoftMem::read16(mem.pal, index * 2) & 0x7FFF) | PIXEL_OPAQUE;
+                }
+            }
+        });
+    }
+
+    fn draw_bg_large(&mut self, regs: &Gpu2DRenderRegs, mem: &Gpu2DSoftMem, bg_cnt: BgCnt, y: usize) {
+        let (width, height) = match u8::from(bg_cnt.screen_size()) {
+            0 => (512, 1024),
+            1 => (1024, 512),
+            _ => return,
+        };
+        let wrap = u8::from(bg_cnt.ext_palette_slot_display_area_overflow()) != 0;
+        let line = &mut self.bg_lines[2];
+
+        Self::for_each_affine_pixel(regs, 2, y, width, height, wrap, |x, bg_x, bg_y| {
+            let index = Gpu2DSoftMem::read8(mem.bg, bg_y * width as u32 + bg_x) as u32;
+            if index != 0 {
+                line[x] = (Gpu2DSoftMem::read16(mem.pal, index * 2) & 0x7FFF) | PIXEL_OPAQUE;
+            }
+        });
+    }
+
+    fn compose(&mut self, disp_cnt: DispCnt, blend: LineBlend, y: usize) {
+        let LineBlend { backdrop, bld_cnt, bld_alpha, bld_y } = blend;
+        let bld_mode = (bld_cnt >> 6) & 3;
+        let eva = min(bld_alpha & 0x1F, 16);
+        let evb = min((bld_alpha >> 8) & 0x1F, 16);
+        let evy = min(bld_y & 0x1F, 16);
+
+        for x in 0..DISPLAY_WIDTH {
+            let win_mask = self.win_masks[x];
+
+            // Find the two front most layers, objects win over bgs with the same priority
+            let mut layers = [(LAYER_BACKDROP, backdrop); 2];
+            let mut layer_count = 0;
+            'outer: for priority in 0..4 {
+                if self.obj_line[x] & PIXEL_OPAQUE != 0 && self.obj_prios[x] == priority && win_mask & (1 << LAYER_OBJ) != 0 {
+                    layers[layer_count] = (LAYER_OBJ, self.obj_line[x] & 0x7FFF);
+                    layer_count += 1;
+                    if layer_count == 2 {
+                        break;
+                    }
+                }
+                for bg_num in 0..4 {
+                    if self.bg_lines[bg_num][x] & PIXEL_OPAQUE != 0 && self.bg_prios[bg_num] == priority && win_mask & (1 << bg_num) != 0 {
+                        layers[layer_count] = (bg_num as u8, self.bg_lines[bg_num][x] & 0x7FFF);
+                        layer_count += 1;
+                        if layer_count == 2 {
+                            break 'outer;
+                        }
+                    }
+                }
+            }
+
+            let [(top_layer, top), (bottom_layer, bottom)] = layers;
+            let first_target = bld_cnt & (1 << top_layer) != 0;
+            let second_target = (bld_cnt >> 8) & (1 << bottom_layer) != 0;
+
+            let mut color = top;
+            if win_mask & (1 << 5) != 0 {
+                let obj_mode = if top_layer == LAYER_OBJ { self.obj_modes[x] } else { ObjPixelMode::Normal };
+                color = match obj_mode {
+                    ObjPixelMode::SemiTransparent if second_target => alpha_blend(top, bottom, eva, evb),
+                    ObjPixelMode::Bitmap(alpha) if second_target => alpha_blend(top, bottom, alpha as u16 + 1, 15 - alpha as u16),
+                    _ if top_layer == 0 && disp_cnt.bg0_3d() && second_target => {
+                        // 3d pixels blend with their own alpha
+                        let alpha = self.alphas_3d[x] as u16;
+                        let mut blended = 0;
+                        for shift in [0, 5, 10] {
+                            blended |= ((((top >> shift) & 0x1F) * (alpha + 1) + ((bottom >> shift) & 0x1F) * (31 - alpha)) >> 5) << shift;
+                        }
+                        blended
+                    }
+                    _ if first_target => match bld_mode {
+                        1 if second_target => alpha_blend(top, bottom, eva, evb),
+                        2 => brightness_up(top, evy),
+                        3 => brightness_down(top, evy),
+                        _ => top,
+                    },
+                    _ => top,
+                };
+            }
+
+            self.graphics[y * DISPLAY_WIDTH + x] = color;
+        }
+    }
+}
+
+// Renders every line on the cpu thread with the memory of that line
+pub struct Gpu2DScanlineRenderer {
+    mem_buf: GpuMemBuf,
+    renderer_a: Gpu2DSoftRenderer,
+    renderer_b: Gpu2DSoftRenderer,
+    // Latched at the end of a frame for the render thread
+    frames: [HeapMemU32<{ DISPLAY_WIDTH * DISPLAY_HEIGHT }>; 2],
+    lines_3d: Vec<Option<Gpu2DLine3D>>,
+    // Only used on the render thread to compose the 3d output into the latched lines
+    composer_3d: Gpu2DSoftRenderer,
+}
+
+impl Gpu2DScanlineRenderer {
+    pub fn new() -> Self {
+        let mut renderer_a = Gpu2DSoftRenderer::new();
+        renderer_a.record_lines_3d();
+        Gpu2DScanlineRenderer {
+            mem_buf: GpuMemBuf::default(),
+            renderer_a,
+            renderer_b: Gpu2DSoftRenderer::new(),
+            frames: [HeapMemU32::new(), HeapMemU32::new()],
+            lines_3d: vec![None; DISPLAY_HEIGHT],
+            composer_3d: Gpu2DSoftRenderer::new(),
+        }
+    }
+
+    pub fn on_scanline(&mut self, regs_a: &Gpu2DRenderRegs, regs_b: &Gpu2DRenderRegs, mem: &mut Memory, line: usize) {
+        self.mem_buf.read_2d_scanline(mem);
+        self.renderer_a.render_scanline::<{ A }>(regs_a, &Gpu2DMem::new::<{ A }>(&self.mem_buf), line);
+        self.renderer_b.render_scanline::<{ B }>(regs_b, &Gpu2DMem::new::<{ B }>(&self.mem_buf), line);
+    }
+
+    // Only call this while the render thread is idle
+    pub fn on_scanline_finish(&mut self) {
+        self.frames[0].copy_from_slice(self.renderer_a.get_frame_rgba());
+        self.frames[1].copy_from_slice(self.renderer_b.get_frame_rgba());
+        for (latched, line) in self.lines_3d.iter_mut().zip(&mut self.renderer_a.lines_3d) {
+            *latched = line.take();
+        }
+    }
+
+    // The 3d output of a frame is only available after rendering, compose it on the render thread
+    pub fn compose_3d(&mut self, frame_3d: &[u32; DISPLAY_WIDTH * DISPLAY_HEIGHT]) {
+        for (y, line) in self.lines_3d.iter().enumerate() {
+            if let Some(line) = line {
+                let row = self.composer_3d.compose_line_3d(line, frame_3d, y);
+                self.frames[0][(DISPLAY_HEIGHT - 1 - y) * DISPLAY_WIDTH..(DISPLAY_HEIGHT - y) * DISPLAY_WIDTH].copy_from_slice(row);
+            }
+        }
+    }
+
+    pub fn get_frame<const ENGINE: Gpu2DEngine>(&self) -> &[u32; DISPLAY_WIDTH * DISPLAY_HEIGHT] {
+        &self.frames[ENGINE as usize]
+    }
 }
@@ -3,6 +3,7 @@ use crate::core::emu::Emu;
 use crate::core::graphics::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
 use crate::core::graphics::gpu_3d::renderer_3d::Gpu3DRendererContent;
 use crate::core::memory::dma::DmaTransferMode;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType::ARM9;
 use crate::fixed_fifo::FixedFifo;
 use crate::math::{vmult_mat4, Matrix, Vectori16, Vectori32};
@@ -163,6 +164,10 @@ pub struct SwapBuffers {
     not_used: u6,
 }
 
+const BOX_TEST_CYCLES: u32 = 103;
+const POS_TEST_CYCLES: u32 = 9;
+const VEC_TEST_CYCLES: u32 = 5;
+
 // 6th bit indicates if cmd is skippable
 #[rustfmt::skip]
 const FIFO_PARAM_COUNTS: [u8; 128] = [
@@ -378,6 +383,10 @@ pub struct Gpu3DRegisters {
 
     material_color0: MaterialColor0,
     material_color1: MaterialColor1,
+    light_vectors: [Vectori32<3>; 4],
+    half_vectors: [Vectori32<3>; 4],
+    light_colors: [u16; 4],
+    shininess_table: [u32; 32],
 
     pos_result: Vectori32<4>,
     vec_result: Vectori16<3>,
@@ -444,7 +453,12 @@ impl Emu {
                     regs_3d.cmd_fifo.pop_front_multiple(count);
                 }
 
-                executed_cycles += 4;
+                executed_cycles += match cmd {
+                    0x70 => BOX_TEST_CYCLES,
+                    0x71 => POS_TEST_CYCLES,
+                    0x72 => VEC_TEST_CYCLES,
+                    _ => 4,
+                };
                 if executed_cycles >= cycle_diff || cmd == 0x50 {
                     let remaining_cmds = value.unbounded_shr((i + 1) << 3);
                     if remaining_cmds != 0 {
@@ -655,6 +669,80 @@ impl Gpu3DRegisters {
         }
     }
 
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.cmd_fifo);
+        state.write(&self.cmd_remaining_params);
+        state.write(&self.test_queue);
+        state.write(&self.last_total_cycles);
+        state.write(&self.flushed);
+        state.write(&self.swap_buffers);
+        state.write(&self.gx_stat);
+        state.write(&self.mtx_mode);
+        state.write(&self.matrices);
+        state.write(&self.cur_viewport);
+        state.write(&self.vertex_list_primitive_type);
+        state.write(&self.vertex_list_size);
+        state.write_vec(&self.vertices[..self.vertices_size as usize]);
+        state.write(&self.cur_vtx);
+        state.write_vec(&self.polygons[..self.polygons_size as usize]);
+        state.write(&self.cur_polygon);
+        state.write(&self.clip_matrix);
+        state.write_vec(&self.clip_matrices);
+        state.write_vec(&self.tex_matrices);
+        state.write(&self.mtx_flags);
+        state.write(&self.cur_polygon_attr);
+        state.write(&self.material_color0);
+        state.write(&self.material_color1);
+        state.write(&self.light_vectors);
+        state.write(&self.half_vectors);
+        state.write(&self.light_colors);
+        state.write(&self.shininess_table);
+        state.write(&self.pos_result);
+        state.write(&self.vec_result);
+        state.write(&self.skip);
+        state.write(&self.consume);
+        state.write(&self.pow_cnt1);
+        state.write(&self.current_pow_cnt1);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.cmd_fifo);
+        state.read(&mut self.cmd_remaining_params);
+        state.read(&mut self.test_queue);
+        state.read(&mut self.last_total_cycles);
+        state.read(&mut self.flushed);
+        state.read(&mut self.swap_buffers);
+        state.read(&mut self.gx_stat);
+        state.read(&mut self.mtx_mode);
+        state.read(&mut self.matrices);
+        state.read(&mut self.cur_viewport);
+        state.read(&mut self.vertex_list_primitive_type);
+        state.read(&mut self.vertex_list_size);
+        self.vertices_size = state.read_value::<u32>() as u16;
+        state.read_slice(&mut self.vertices[..self.vertices_size as usize]);
+        state.read(&mut self.cur_vtx);
+        self.polygons_size = state.read_value::<u32>() as u16;
+        state.read_slice(&mut self.polygons[..self.polygons_size as usize]);
+        state.read(&mut self.cur_polygon);
+        state.read(&mut self.clip_matrix);
+        state.read_vec(&mut self.clip_matrices);
+        state.read_vec(&mut self.tex_matrices);
+        state.read(&mut self.mtx_flags);
+        state.read(&mut self.cur_polygon_attr);
+        state.read(&mut self.material_color0);
+        state.read(&mut self.material_color1);
+        state.read(&mut self.light_vectors);
+        state.read(&mut self.half_vectors);
+        state.read(&mut self.light_colors);
+        state.read(&mut self.shininess_table);
+        state.read(&mut self.pos_result);
+        state.read(&mut self.vec_result);
+        state.read(&mut self.skip);
+        state.read(&mut self.consume);
+        state.read(&mut self.pow_cnt1);
+        state.read(&mut self.current_pow_cnt1);
+    }
+
     fn is_cmd_fifo_full(&self) -> bool {
         self.cmd_fifo.len() >= 260
     }
@@ -977,6 +1065,7 @@ impl Gpu3DRegisters {
         self.cur_polygon.normal[0] = ((u16::from(normal_vector_param.x()) << 6) as i16) >> 3;
         self.cur_polygon.normal[1] = ((u16::from(normal_vector_param.y()) << 6) as i16) >> 3;
         self.cur_polygon.normal[2] = ((u16::from(normal_vector_param.z()) << 6) as i16) >> 3;
+        self.cur_vtx.color = self.calculate_lighting();
 
         if self.cur_vtx.tex_coord_trans_mode == TextureCoordTransMode::Normal && self.mtx_flags.tex_push() {
             self.tex_matrices.push(self.matrices.tex);
@@ -1062,15 +1151,64 @@ impl Gpu3DRegisters {
     }
 
     fn exe_light_vector(&mut self, params: &[u32; 32]) {
-        // TODO
+        let light_vector_param = LightVector::from(params[0]);
+        let num = u8::from(light_vector_param.num()) as usize;
+        let mut light_vector = Vectori32::<3>::new([
+            (((u16::from(light_vector_param.x()) << 6) as i16) >> 3) as i32,
+            (((u16::from(light_vector_param.y()) << 6) as i16) >> 3) as i32,
+            (((u16::from(light_vector_param.z()) << 6) as i16) >> 3) as i32,
+        ]);
+        light_vector *= &self.matrices.dir;
+        self.light_vectors[num] = light_vector;
+
+        // Half way between the light and the line of sight (0, 0, -1)
+        self.half_vectors[num] = Vectori32::<3>::new([light_vector[0] / 2, light_vector[1] / 2, (light_vector[2] - (1 << 12)) / 2]);
     }
 
     fn exe_light_color(&mut self, params: &[u32; 32]) {
-        // TODO
+        let light_color_param = LightColor::from(params[0]);
+        self.light_colors[u8::from(light_color_param.num()) as usize] = u16::from(light_color_param.color());
     }
 
     fn exe_shininess(&mut self, params: &[u32; 32]) {
-        // TODO
+        self.shininess_table = *params;
+    }
+
+    fn calculate_lighting(&self) -> u16 {
+        let mut normal = Vectori32::<3>::new([self.cur_polygon.normal[0] as i32, self.cur_polygon.normal[1] as i32, self.cur_polygon.normal[2] as i32]);
+        normal *= &self.matrices.dir;
+        let dot = |vector: &Vectori32<3>| (vector[0] * normal[0] + vector[1] * normal[1] + vector[2] * normal[2]) >> 12;
+
+        let channel = |color: u16, i: usize| ((color >> (i * 5)) & 0x1F) as i32;
+        let diffuse = u16::from(self.material_color0.dif());
+        let ambient = u16::from(self.material_color0.amb());
+        let specular = u16::from(self.material_color1.spe());
+        let emission = u16::from(self.material_color1.em());
+
+        let mut color = [channel(emission, 0), channel(emission, 1), channel(emission, 2)];
+        let enabled_lights = u8::from(self.cur_polygon.attr.enable_lights());
+        for light in 0..4 {
+            if enabled_lights & (1 << light) == 0 {
+                continue;
+            }
+
+            let diffuse_level = max(-dot(&self.light_vectors[light]), 0);
+            let shininess_level = max(-dot(&self.half_vectors[light]), 0);
+            let mut shininess_level = min((shininess_level * shininess_level) >> 12, 0xFFF);
+            if self.material_color1.set_shininess() {
+                let index = shininess_level as usize >> 5;
+                shininess_level = (((self.shininess_table[index >> 2] >> ((index & 3) * 8)) & 0xFF) as i32) << 4;
+            }
+
+            for i in 0..3 {
+                let light_color = channel(self.light_colors[light], i);
+                color[i] += (channel(specular, i) * light_color * shininess_level) >> 17;
+                color[i] += (channel(diffuse, i) * light_color * diffuse_level) >> 17;
+                color[i] += (channel(ambient, i) * light_color) >> 5;
+            }
+        }
+
+        (min(color[0], 0x1F) | (min(color[1], 0x1F) << 5) | (min(color[2], 0x1F) << 10)) as u16
     }
 
     fn exe_begin_vtxs(&mut self, cmd: usize, params: &[u32; 32]) {
@@ -1105,12 +1243,71 @@ impl Gpu3DRegisters {
         self.cur_viewport = Viewport::from(params[0]);
     }
 
-    fn exe_box_test(&mut self, _: &[u32; 32]) {
-        self.gx_stat.set_box_test_result(true);
+    fn exe_box_test(&mut self, params: &[u32; 32]) {
+        let x = params[0] as i16 as i32;
+        let y = (params[0] >> 16) as i16 as i32;
+        let z = params[1] as i16 as i32;
+        let w = (params[1] >> 16) as i16 as i32;
+        let h = params[2] as i16 as i32;
+        let d = (params[2] >> 16) as i16 as i32;
+        let coords = [[x, x + w], [y, y + h], [z, z + d]];
+
+        let clip_matrix = *self.get_clip_matrix();
+        let vertices: [Vectori32<4>; 8] = std::array::from_fn(|i| Vectori32::<4>::new([coords[0][i & 1], coords[1][(i >> 1) & 1], coords[2][(i >> 2) & 1], 1 << 12]) * &clip_matrix);
+        self.gx_stat.set_box_test_result(Self::is_box_visible(&vertices));
 
         self.test_queue -= 1;
     }
 
+    // Like hardware only the faces are tested, a box enclosing the whole view volume isn't visible
+    fn is_box_visible(vertices: &[Vectori32<4>; 8]) -> bool {
+        const FACES: [[usize; 4]; 6] = [[0, 1, 3, 2], [4, 5, 7, 6], [0, 1, 5, 4], [2, 3, 7, 6], [0, 2, 6, 4], [1, 3, 7, 5]];
+        FACES.iter().any(|face| Self::is_box_face_visible(face.map(|i| vertices[i])))
+    }
+
+    // Clips a face of the box against the view volume, it's visible if any vertices remain
+    fn is_box_face_visible(face: [Vectori32<4>; 4]) -> bool {
+        // Every plane can add at most one vertex
+        let mut vertices = [[0i64; 4]; 10];
+        let mut len = face.len();
+        for (i, vertex) in face.iter().enumerate() {
+            vertices[i] = [vertex[0] as i64, vertex[1] as i64, vertex[2] as i64, vertex[3] as i64];
+        }
+
+        for axis in 0..3 {
+            for sign in [1, -1] {
+                let mut clipped = [[0i64; 4]; 10];
+                let mut clipped_len = 0;
+
+                for i in 0..len {
+                    let current = vertices[i];
+                    let next = vertices[(i + 1) % len];
+                    let current_dist = current[3] - sign * current[axis];
+                    let next_dist = next[3] - sign * next[axis];
+
+                    if current_dist >= 0 {
+                        clipped[clipped_len] = current;
+                        clipped_len += 1;
+                    }
+
+                    if (current_dist >= 0) != (next_dist >= 0) {
+                        for j in 0..4 {
+                            clipped[clipped_len][j] = current[j] + (next[j] - current[j]) * current_dist / (current_dist - next_dist);
+                        }
+                        clipped_len += 1;
+                    }
+                }
+
+                if clipped_len == 0 {
+                    return false;
+                }
+                vertices = clipped;
+                len = clipped_len;
+            }
+        }
+        true
+    }
+
     fn exe_pos_test(&mut self, params: &[u32; 32]) {
         self.cur_vtx.coords[0] = params[0] as i16 as i32;
         self.cur_vtx.coords[1] = (params[0] >> 16) as i16 as i32;
@@ -1215,3 +1412,41 @@ impl Gpu3DRegisters {
         self.polygons_size += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Gpu3DRegisters;
+    use crate::math::Vectori32;
+
+    const ONE: i32 = 1 << 12;
+
+    // Corners of a box in clip space with w = 1, ordered like exe_box_test
+    fn box_vertices(min: [i32; 3], max: [i32; 3]) -> [Vectori32<4>; 8] {
+        std::array::from_fn(|i| {
+            let pick = |axis: usize| if (i >> axis) & 1 == 0 { min[axis] } else { max[axis] };
+            Vectori32::<4>::new([pick(0), pick(1), pick(2), ONE])
+        })
+    }
+
+    #[test]
+    fn box_inside_view_volume() {
+        assert!(Gpu3DRegisters::is_box_visible(&box_vertices([-ONE / 2; 3], [ONE / 2; 3])));
+    }
+
+    #[test]
+    fn box_outside_view_volume() {
+        assert!(!Gpu3DRegisters::is_box_visible(&box_vertices([ONE * 2, -ONE / 2, -ONE / 2], [ONE * 3, ONE / 2, ONE / 2])));
+        assert!(!Gpu3DRegisters::is_box_visible(&box_vertices([-ONE / 2, -ONE / 2, -ONE * 3], [ONE / 2, ONE / 2, -ONE * 2])));
+    }
+
+    #[test]
+    fn box_straddling_clip_plane() {
+        assert!(Gpu3DRegisters::is_box_visible(&box_vertices([ONE / 2, -ONE / 2, -ONE / 2], [ONE * 3 / 2, ONE / 2, ONE / 2])));
+        assert!(Gpu3DRegisters::is_box_visible(&box_vertices([-ONE / 2, -ONE * 3 / 2, -ONE / 2], [ONE / 2, -ONE / 2, ONE / 2])));
+    }
+
+    #[test]
+    fn box_enclosing_view_volume() {
+        assert!(!Gpu3DRegisters::is_box_visible(&box_vertices([-ONE * 2; 3], [ONE * 2; 3])));
+    }
+}
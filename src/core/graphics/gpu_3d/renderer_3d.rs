@@ -1,12 +1,13 @@
 use crate::core::graphics::gl_utils::{create_mem_texture2d, create_pal_texture2d, create_program, create_shader, shader_source, sub_mem_texture2d, sub_pal_texture2d, GpuFbo};
 use crate::core::graphics::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
-use crate::core::graphics::gpu_3d::registers_3d::{Gpu3DRegisters, Polygon, PrimitiveType, SwapBuffers, TextureCoordTransMode, Vertex};
+use crate::core::graphics::gpu_3d::registers_3d::{Gpu3DRegisters, Polygon, PolygonAttr, PolygonMode, PrimitiveType, SwapBuffers, TextureCoordTransMode, Vertex};
 use crate::core::graphics::gpu_3d::registers_3d::{POLYGON_LIMIT, VERTEX_LIMIT};
 use crate::core::graphics::gpu_renderer::GpuRendererCommon;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::math::{Matrix, Vectori32};
 use crate::utils::{rgb5_to_float8, HeapMem};
 use bilge::prelude::*;
-use gl::types::GLuint;
+use gl::types::{GLenum, GLint, GLuint};
 use static_assertions::const_assert_eq;
 use std::intrinsics::{fdiv_fast, fmul_fast, fsub_fast, unchecked_div};
 use std::mem::MaybeUninit;
@@ -32,7 +33,7 @@ struct Disp3DCnt {
     alpha_blending: bool,
     anti_aliasing: bool,
     edge_marking: bool,
-    alpha_mode: bool,
+    fog_alpha_only: bool,
     fog_master_enable: bool,
     fog_depth_shift: u4,
     color_buf_rdlines_underflow: bool,
@@ -65,6 +66,17 @@ pub struct Gpu3DGl {
     attr_tex: GLuint,
     vertices_buf: GLuint,
     program: GLuint,
+    disp_cnt_loc: GLint,
+    toon_table_loc: GLint,
+    attr_pass_loc: GLint,
+    edge_program: GLuint,
+    edge_colors_loc: GLint,
+    fog_program: GLuint,
+    fog_disp_cnt_loc: GLint,
+    fog_color_loc: GLint,
+    fog_offset_loc: GLint,
+    fog_table_loc: GLint,
+    attr_fbo: GpuFbo,
     pub fbo: GpuFbo,
 }
 
@@ -87,12 +99,45 @@ impl Default for Gpu3DGl {
             gl::Uniform1i(gl::GetUniformLocation(program, c"palTex".as_ptr() as _), 1);
             gl::Uniform1i(gl::GetUniformLocation(program, c"attrTex".as_ptr() as _), 2);
 
+            let disp_cnt_loc = gl::GetUniformLocation(program, c"dispCnt".as_ptr() as _);
+            let toon_table_loc = gl::GetUniformLocation(program, c"toonTable".as_ptr() as _);
+            let attr_pass_loc = gl::GetUniformLocation(program, c"attrPass".as_ptr() as _);
+
             let mut vertices_buf = 0;
             gl::GenBuffers(1, &mut vertices_buf);
             gl::BindBuffer(gl::ARRAY_BUFFER, vertices_buf);
 
             gl::BindBuffer(gl::UNIFORM_BUFFER, 0);
             gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+
+            let screen_vert_shader = create_shader("screen 3d", shader_source!("screen_vert"), gl::VERTEX_SHADER).unwrap();
+
+            let frag_shader = create_shader("edge 3d", shader_source!("edge_frag"), gl::FRAGMENT_SHADER).unwrap();
+            let edge_program = create_program(&[screen_vert_shader, frag_shader]).unwrap();
+            gl::DeleteShader(frag_shader);
+
+            gl::UseProgram(edge_program);
+
+            gl::BindAttribLocation(edge_program, 0, c"position".as_ptr() as _);
+
+            gl::Uniform1i(gl::GetUniformLocation(edge_program, c"attrTex".as_ptr() as _), 0);
+            let edge_colors_loc = gl::GetUniformLocation(edge_program, c"edgeColors".as_ptr() as _);
+
+            let frag_shader = create_shader("fog 3d", shader_source!("fog_frag"), gl::FRAGMENT_SHADER).unwrap();
+            let fog_program = create_program(&[screen_vert_shader, frag_shader]).unwrap();
+            gl::DeleteShader(frag_shader);
+            gl::DeleteShader(screen_vert_shader);
+
+            gl::UseProgram(fog_program);
+
+            gl::BindAttribLocation(fog_program, 0, c"position".as_ptr() as _);
+
+            gl::Uniform1i(gl::GetUniformLocation(fog_program, c"attrTex".as_ptr() as _), 0);
+            let fog_disp_cnt_loc = gl::GetUniformLocation(fog_program, c"dispCnt".as_ptr() as _);
+            let fog_color_loc = gl::GetUniformLocation(fog_program, c"fogColor".as_ptr() as _);
+            let fog_offset_loc = gl::GetUniformLocation(fog_program, c"fogOffset".as_ptr() as _);
+            let fog_table_loc = gl::GetUniformLocation(fog_program, c"fogTable".as_ptr() as _);
+
             gl::UseProgram(0);
 
             Gpu3DGl {
@@ -101,12 +146,25 @@ impl Default for Gpu3DGl {
                 attr_tex: create_mem_texture2d(256, 256),
                 vertices_buf,
                 program,
-                fbo: GpuFbo::new(DISPLAY_WIDTH as _, DISPLAY_HEIGHT as _, true).unwrap(),
+                disp_cnt_loc,
+                toon_table_loc,
+                attr_pass_loc,
+                edge_program,
+                edge_colors_loc,
+                fog_program,
+                fog_disp_cnt_loc,
+                fog_color_loc,
+                fog_offset_loc,
+                fog_table_loc,
+                attr_fbo: GpuFbo::new(DISPLAY_WIDTH as _, DISPLAY_HEIGHT as _, true).unwrap(),
+                fbo: GpuFbo::new_with_stencil(DISPLAY_WIDTH as _, DISPLAY_HEIGHT as _).unwrap(),
             }
         }
     }
 }
 
+const SCREEN_VERTICES: [f32; 2 * 4] = [-1f32, 1f32, 1f32, 1f32, 1f32, -1f32, -1f32, -1f32];
+
 #[derive(Clone)]
 #[repr(C)]
 struct Gpu3DVertex {
@@ -125,6 +183,143 @@ struct Gpu3dPolygonAttr {
 
 const_assert_eq!(size_of::<Gpu3dPolygonAttr>(), 8);
 
+// Bit 0 marks pixels of shadow masks, bits 1-6 hold the polygon id of opaque pixels
+// Bit 7 hides marked pixels from a shadow with the same polygon id while it's drawn
+const STENCIL_SHADOW_MARK: u32 = 1;
+const STENCIL_POLYGON_ID_SHIFT: u32 = 1;
+const STENCIL_POLYGON_ID_MASK: u32 = 0x3F << STENCIL_POLYGON_ID_SHIFT;
+const STENCIL_SAME_POLYGON_ID: u32 = 1 << 7;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Gpu3DDrawKind {
+    Opaque,
+    Translucent,
+    ShadowMask,
+    Shadow,
+}
+
+impl Gpu3DDrawKind {
+    fn new(attr: PolygonAttr) -> Self {
+        match PolygonMode::from(u8::from(attr.mode())) {
+            PolygonMode::Shadow if u8::from(attr.id()) == 0 => Gpu3DDrawKind::ShadowMask,
+            PolygonMode::Shadow => Gpu3DDrawKind::Shadow,
+            _ if u8::from(attr.alpha()) == 31 => Gpu3DDrawKind::Opaque,
+            _ => Gpu3DDrawKind::Translucent,
+        }
+    }
+
+    // Shadow masks and shadows are drawn with the translucent polygons, so they always keep their order
+    fn is_drawn_first(attr: PolygonAttr) -> bool {
+        Self::new(attr) == Gpu3DDrawKind::Opaque
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Gpu3DDrawPass {
+    stencil_func: GLenum,
+    stencil_ref: u32,
+    stencil_mask: u32,
+    stencil_write_mask: u32,
+    // Stencil fail, depth fail and depth pass
+    stencil_ops: [GLenum; 3],
+    write_color: bool,
+    write_depth: bool,
+}
+
+impl Gpu3DDrawPass {
+    const fn new(stencil_func: GLenum, stencil_ref: u32, stencil_mask: u32, stencil_write_mask: u32, stencil_ops: [GLenum; 3], write_color: bool, write_depth: bool) -> Self {
+        Gpu3DDrawPass {
+            stencil_func,
+            stencil_ref,
+            stencil_mask,
+            stencil_write_mask,
+            stencil_ops,
+            write_color,
+            write_depth,
+        }
+    }
+
+    unsafe fn apply(&self) {
+        gl::StencilFunc(self.stencil_func, self.stencil_ref as _, self.stencil_mask);
+        gl::StencilMask(self.stencil_write_mask);
+        gl::StencilOp(self.stencil_ops[0], self.stencil_ops[1], self.stencil_ops[2]);
+        let write_color = if self.write_color { gl::TRUE } else { gl::FALSE };
+        gl::ColorMask(write_color, write_color, write_color, write_color);
+        gl::DepthMask(if self.write_depth { gl::TRUE } else { gl::FALSE });
+    }
+}
+
+// Consecutive indices which are drawn with the same state
+struct Gpu3DDrawRange {
+    kind: Gpu3DDrawKind,
+    // Only kept for opaque polygons and shadows, which need it for their stencil state
+    polygon_id: u8,
+    start: usize,
+    len: usize,
+}
+
+impl Gpu3DDrawRange {
+    fn push(ranges: &mut Vec<Gpu3DDrawRange>, kind: Gpu3DDrawKind, polygon_id: u8, start: usize, len: usize) {
+        let polygon_id = match kind {
+            Gpu3DDrawKind::Opaque | Gpu3DDrawKind::Shadow => polygon_id,
+            Gpu3DDrawKind::Translucent | Gpu3DDrawKind::ShadowMask => 0,
+        };
+        match ranges.last_mut() {
+            Some(range) if range.kind == kind && range.polygon_id == polygon_id => range.len += len,
+            _ => ranges.push(Gpu3DDrawRange { kind, polygon_id, start, len }),
+        }
+    }
+
+    // Opaque polygons store their id in the stencil, shadow masks mark the pixels where they fail the depth test
+    // Shadows hide marked pixels of their own polygon id, draw onto the remaining marked pixels and then unhide them
+    fn passes(&self) -> impl Iterator<Item = Gpu3DDrawPass> {
+        let id = (self.polygon_id as u32) << STENCIL_POLYGON_ID_SHIFT;
+        let passes = match self.kind {
+            Gpu3DDrawKind::Opaque => [
+                Some(Gpu3DDrawPass::new(gl::ALWAYS, id, 0, STENCIL_POLYGON_ID_MASK, [gl::KEEP, gl::KEEP, gl::REPLACE], true, true)),
+                None,
+                None,
+            ],
+            Gpu3DDrawKind::Translucent => [Some(Gpu3DDrawPass::new(gl::ALWAYS, 0, 0, 0, [gl::KEEP, gl::KEEP, gl::KEEP], true, true)), None, None],
+            Gpu3DDrawKind::ShadowMask => [
+                Some(Gpu3DDrawPass::new(
+                    gl::ALWAYS,
+                    STENCIL_SHADOW_MARK,
+                    0,
+                    STENCIL_SHADOW_MARK,
+                    [gl::KEEP, gl::REPLACE, gl::KEEP],
+                    false,
+                    false,
+                )),
+                None,
+                None,
+            ],
+            Gpu3DDrawKind::Shadow => [
+                Some(Gpu3DDrawPass::new(
+                    gl::EQUAL,
+                    STENCIL_SAME_POLYGON_ID | id | STENCIL_SHADOW_MARK,
+                    STENCIL_POLYGON_ID_MASK | STENCIL_SHADOW_MARK,
+                    STENCIL_SAME_POLYGON_ID,
+                    [gl::KEEP, gl::REPLACE, gl::REPLACE],
+                    false,
+                    false,
+                )),
+                Some(Gpu3DDrawPass::new(
+                    gl::EQUAL,
+                    STENCIL_SHADOW_MARK,
+                    STENCIL_SAME_POLYGON_ID | STENCIL_SHADOW_MARK,
+                    STENCIL_SHADOW_MARK,
+                    [gl::KEEP, gl::KEEP, gl::ZERO],
+                    true,
+                    false,
+                )),
+                Some(Gpu3DDrawPass::new(gl::ALWAYS, 0, 0, STENCIL_SAME_POLYGON_ID, [gl::KEEP, gl::ZERO, gl::ZERO], false, false)),
+            ],
+        };
+        passes.into_iter().flatten()
+    }
+}
+
 #[derive(Default)]
 pub struct Gpu3DRendererContent {
     pub vertices: HeapMem<Vertex, VERTEX_LIMIT>,
@@ -145,6 +340,7 @@ pub struct Gpu3DRenderer {
     pub gl: Gpu3DGl,
     vertices_buf: Vec<Gpu3DVertex>,
     indices_buf: Vec<u16>,
+    draw_ranges: Vec<Gpu3DDrawRange>,
     polygon_attrs: HeapMem<Gpu3dPolygonAttr, POLYGON_LIMIT>,
 }
 
@@ -236,6 +432,15 @@ impl Gpu3DRenderer {
         self.invalidate();
     }
 
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.inners[1]);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.inners[1]);
+        self.invalidate();
+    }
+
     pub fn finish_scanline(&mut self, registers: &mut Gpu3DRegisters) {
         self.inners[0] = self.inners[1].clone();
 
@@ -266,6 +471,9 @@ impl Gpu3DRenderer {
             }
         }
 
+        let kind = Gpu3DDrawKind::new(polygon.attr);
+        let indices_start = self.indices_buf.len();
+
         let vertex_index = self.vertices_buf.len() as u16;
         self.indices_buf.push(vertex_index);
         self.indices_buf.push(vertex_index + 1);
@@ -281,6 +489,9 @@ impl Gpu3DRenderer {
             self.indices_buf.push(vertex_index + j);
         }
 
+        let indices_len = self.indices_buf.len() - indices_start;
+        Gpu3DDrawRange::push(&mut self.draw_ranges, kind, u8::from(polygon.attr.id()), indices_start, indices_len);
+
         let x = polygon.viewport.x1();
         let y = 191 - polygon.viewport.y2();
         let w = (polygon.viewport.x2() - polygon.viewport.x1()) as u16 + 1;
@@ -372,7 +583,93 @@ impl Gpu3DRenderer {
 
         self.polygon_attrs[polygon_index].tex_image_param = u32::from(polygon.tex_image_param);
         self.polygon_attrs[polygon_index].pal_addr = polygon.palette_addr;
-        self.polygon_attrs[polygon_index].poly_attr = u16::from(polygon.attr.alpha());
+        self.polygon_attrs[polygon_index].poly_attr =
+            u16::from(polygon.attr.alpha()) | (u16::from(polygon.attr.mode()) << 5) | (u16::from(polygon.attr.id()) << 8) | ((polygon.attr.fog() as u16) << 14);
+    }
+
+    unsafe fn draw_range(&self, range: &Gpu3DDrawRange) {
+        gl::DrawElements(gl::TRIANGLES, range.len as _, gl::UNSIGNED_SHORT, self.indices_buf[range.start..].as_ptr() as _);
+    }
+
+    // Polygons are drawn again to store their id, depth and fog flag per pixel
+    // Translucent polygons only update the depth and fog flag, ids are kept from opaque polygons for edge marking
+    unsafe fn render_attrs(&self) {
+        gl::BindFramebuffer(gl::FRAMEBUFFER, self.gl.attr_fbo.fbo);
+
+        let clear_color = ClearColor::from(self.inners[0].clear_color);
+        gl::ClearColor(u8::from(clear_color.clear_polygon_id()) as f32 / 127f32, 1f32, 1f32, clear_color.fog() as u8 as f32);
+        gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+
+        for range in &self.draw_ranges {
+            match range.kind {
+                Gpu3DDrawKind::Opaque => {
+                    gl::Uniform1i(self.gl.attr_pass_loc, 1);
+                    self.draw_range(range);
+                }
+                Gpu3DDrawKind::Translucent => {
+                    gl::ColorMask(gl::FALSE, gl::TRUE, gl::TRUE, gl::TRUE);
+                    gl::Uniform1i(self.gl.attr_pass_loc, 2);
+                    self.draw_range(range);
+                    gl::ColorMask(gl::TRUE, gl::TRUE, gl::TRUE, gl::TRUE);
+                }
+                Gpu3DDrawKind::ShadowMask | Gpu3DDrawKind::Shadow => {}
+            }
+        }
+
+        gl::Uniform1i(self.gl.attr_pass_loc, 0);
+    }
+
+    // Shadows are only drawn onto pixels marked by a shadow mask, which don't have the polygon id of the shadow, see Gpu3DDrawRange::passes
+    unsafe fn render_ranges(&self) {
+        gl::Enable(gl::STENCIL_TEST);
+        for range in &self.draw_ranges {
+            for pass in range.passes() {
+                pass.apply();
+                self.draw_range(range);
+            }
+        }
+        gl::ColorMask(gl::TRUE, gl::TRUE, gl::TRUE, gl::TRUE);
+        gl::DepthMask(gl::TRUE);
+        gl::StencilMask(0xFF);
+        gl::Disable(gl::STENCIL_TEST);
+    }
+
+    // Edges are drawn where a neighbour has a different id and lies behind
+    unsafe fn render_edges(&self) {
+        gl::UseProgram(self.gl.edge_program);
+
+        let edge_colors = self.inners[0].edge_colors.map(|color| color as i32);
+        gl::Uniform1iv(self.gl.edge_colors_loc, edge_colors.len() as _, edge_colors.as_ptr());
+
+        gl::EnableVertexAttribArray(0);
+        gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, SCREEN_VERTICES.as_ptr() as _);
+        gl::DrawArrays(gl::TRIANGLE_FAN, 0, 4);
+    }
+
+    // The shader outputs the fog color with the density as alpha, the fog alpha is blended in through the constant color
+    unsafe fn render_fog(&self) {
+        gl::UseProgram(self.gl.fog_program);
+
+        let inner = &self.inners[0];
+        let fog_table = inner.fog_table.map(|density| density as i32);
+        gl::Uniform1i(self.gl.fog_disp_cnt_loc, u16::from(inner.disp_cnt) as _);
+        gl::Uniform1i(self.gl.fog_color_loc, (inner.fog_color & 0x7FFF) as _);
+        gl::Uniform1i(self.gl.fog_offset_loc, inner.fog_offset as _);
+        gl::Uniform1iv(self.gl.fog_table_loc, fog_table.len() as _, fog_table.as_ptr());
+
+        gl::Enable(gl::BLEND);
+        gl::BlendColor(0f32, 0f32, 0f32, ((inner.fog_color >> 16) & 0x1F) as f32 / 31f32);
+        gl::BlendFuncSeparate(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA, gl::CONSTANT_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+        if inner.disp_cnt.fog_alpha_only() {
+            gl::ColorMask(gl::FALSE, gl::FALSE, gl::FALSE, gl::TRUE);
+        }
+
+        gl::EnableVertexAttribArray(0);
+        gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, SCREEN_VERTICES.as_ptr() as _);
+        gl::DrawArrays(gl::TRIANGLE_FAN, 0, 4);
+
+        gl::ColorMask(gl::TRUE, gl::TRUE, gl::TRUE, gl::TRUE);
+        gl::Disable(gl::BLEND);
     }
 
     pub unsafe fn render(&mut self, common: &GpuRendererCommon) {
@@ -383,7 +680,8 @@ impl Gpu3DRenderer {
         let (r, g, b) = rgb5_to_float8(u16::from(clear_color.color()));
         gl::ClearColor(r, g, b, u8::from(clear_color.alpha()) as f32 / 31f32);
 
-        gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        gl::ClearStencil(0);
+        gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT);
 
         if self.content.pow_cnt1 != u16::from(common.pow_cnt1[0]) {
             return;
@@ -391,15 +689,16 @@ impl Gpu3DRenderer {
 
         self.vertices_buf.clear();
         self.indices_buf.clear();
+        self.draw_ranges.clear();
 
         for i in 0..self.content.polygons_size {
-            if u8::from(self.content.polygons[i as usize].attr.alpha()) == 31 {
+            if Gpu3DDrawKind::is_drawn_first(self.content.polygons[i as usize].attr) {
                 self.add_vertices(i as usize);
             }
         }
 
         for i in 0..self.content.polygons_size {
-            if u8::from(self.content.polygons[i as usize].attr.alpha()) != 31 {
+            if !Gpu3DDrawKind::is_drawn_first(self.content.polygons[i as usize].attr) {
                 self.add_vertices(i as usize);
             }
         }
@@ -410,6 +709,11 @@ impl Gpu3DRenderer {
 
         gl::UseProgram(self.gl.program);
 
+        let toon_table = self.inners[0].toon_table.map(|color| color as i32);
+        gl::Uniform1i(self.gl.disp_cnt_loc, u16::from(self.inners[0].disp_cnt) as _);
+        gl::Uniform1iv(self.gl.toon_table_loc, toon_table.len() as _, toon_table.as_ptr());
+        gl::Uniform1i(self.gl.attr_pass_loc, 0);
+
         gl::Enable(gl::BLEND);
         gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
         gl::Enable(gl::DEPTH_TEST);
@@ -451,9 +755,36 @@ impl Gpu3DRenderer {
         gl::EnableVertexAttribArray(2);
         gl::VertexAttribPointer(2, 2, gl::FLOAT, gl::FALSE, size_of::<Gpu3DVertex>() as _, (size_of::<f32>() * 8) as _);
 
-        gl::DrawElements(gl::TRIANGLES, self.indices_buf.len() as _, gl::UNSIGNED_SHORT, self.indices_buf.as_ptr() as _);
+        let disp_cnt = self.inners[0].disp_cnt;
+        let edge_marking = disp_cnt.edge_marking() && self.draw_ranges.iter().any(|range| range.kind == Gpu3DDrawKind::Opaque);
+        if edge_marking || disp_cnt.fog_master_enable() {
+            gl::Disable(gl::BLEND);
+            self.render_attrs();
+            gl::Enable(gl::BLEND);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.gl.fbo.fbo);
+        }
+
+        self.render_ranges();
 
         gl::Disable(gl::BLEND);
+
+        if edge_marking || disp_cnt.fog_master_enable() {
+            gl::Disable(gl::DEPTH_TEST);
+            gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+            gl::DisableVertexAttribArray(1);
+            gl::DisableVertexAttribArray(2);
+
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, self.gl.attr_fbo.color);
+
+            if edge_marking {
+                self.render_edges();
+            }
+            if disp_cnt.fog_master_enable() {
+                self.render_fog();
+            }
+        }
+
         gl::Disable(gl::DEPTH_TEST);
         gl::DepthRange(0.0, 1.0);
         gl::BindBuffer(gl::UNIFORM_BUFFER, 0);
@@ -463,3 +794,149 @@ impl Gpu3DRenderer {
         gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Gpu3DDrawKind, Gpu3DDrawPass, Gpu3DDrawRange};
+    use crate::core::graphics::gpu_3d::registers_3d::{PolygonAttr, PolygonMode};
+    use bilge::prelude::*;
+
+    fn attr(mode: PolygonMode, alpha: u8, id: u8) -> PolygonAttr {
+        let mut attr = PolygonAttr::from(0);
+        attr.set_mode(u2::new(mode as u8));
+        attr.set_alpha(u5::new(alpha));
+        attr.set_id(u6::new(id));
+        attr
+    }
+
+    // Draw ranges in the order render builds them, each polygon covers the tested pixel and passes the depth test if set
+    fn draw_ranges(polygons: &[(PolygonAttr, bool)]) -> Vec<(Gpu3DDrawRange, bool)> {
+        let order = polygons
+            .iter()
+            .filter(|(attr, _)| Gpu3DDrawKind::is_drawn_first(*attr))
+            .chain(polygons.iter().filter(|(attr, _)| !Gpu3DDrawKind::is_drawn_first(*attr)));
+        let mut ranges = Vec::new();
+        for (i, (attr, depth_pass)) in order.enumerate() {
+            // Separate indices per polygon, so every polygon keeps its own depth result
+            let mut polygon_ranges = Vec::new();
+            Gpu3DDrawRange::push(&mut polygon_ranges, Gpu3DDrawKind::new(*attr), u8::from(attr.id()), i, 1);
+            ranges.extend(polygon_ranges.into_iter().map(|range| (range, *depth_pass)));
+        }
+        ranges
+    }
+
+    fn stencil_op(op: u32, stencil: u32, pass: &Gpu3DDrawPass) -> u32 {
+        let value = match op {
+            gl::KEEP => stencil,
+            gl::ZERO => 0,
+            gl::REPLACE => pass.stencil_ref,
+            _ => unreachable!(),
+        };
+        (stencil & !pass.stencil_write_mask) | (value & pass.stencil_write_mask)
+    }
+
+    // Runs the stencil test of all passes on a single pixel, returns the kinds and ids which wrote their color
+    fn draw_pixel(ranges: &[(Gpu3DDrawRange, bool)]) -> Vec<(Gpu3DDrawKind, u8)> {
+        let mut stencil = 0;
+        let mut drawn = Vec::new();
+        for (range, depth_pass) in ranges {
+            for pass in range.passes() {
+                let stencil_pass = match pass.stencil_func {
+                    gl::ALWAYS => true,
+                    gl::EQUAL => pass.stencil_ref & pass.stencil_mask == stencil & pass.stencil_mask,
+                    _ => unreachable!(),
+                };
+                let op = match (stencil_pass, depth_pass) {
+                    (false, _) => pass.stencil_ops[0],
+                    (true, false) => pass.stencil_ops[1],
+                    (true, true) => pass.stencil_ops[2],
+                };
+                stencil = stencil_op(op, stencil, &pass);
+                if stencil_pass && *depth_pass && pass.write_color {
+                    drawn.push((range.kind, range.polygon_id));
+                }
+            }
+        }
+        drawn
+    }
+
+    #[test]
+    fn shadow_masks_are_drawn_before_their_shadows() {
+        // The mask is opaque, the shadow translucent, both still keep their order after the opaque polygons
+        let ranges = draw_ranges(&[
+            (attr(PolygonMode::Shadow, 31, 0), false),
+            (attr(PolygonMode::Shadow, 15, 2), true),
+            (attr(PolygonMode::Modulation, 31, 1), true),
+            (attr(PolygonMode::Modulation, 15, 3), true),
+        ]);
+        let kinds = ranges.iter().map(|(range, _)| range.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, [Gpu3DDrawKind::Opaque, Gpu3DDrawKind::ShadowMask, Gpu3DDrawKind::Shadow, Gpu3DDrawKind::Translucent]);
+    }
+
+    #[test]
+    fn draw_ranges_split_on_polygon_ids() {
+        let mut ranges = Vec::new();
+        Gpu3DDrawRange::push(&mut ranges, Gpu3DDrawKind::Opaque, 1, 0, 3);
+        Gpu3DDrawRange::push(&mut ranges, Gpu3DDrawKind::Opaque, 1, 3, 3);
+        Gpu3DDrawRange::push(&mut ranges, Gpu3DDrawKind::Opaque, 2, 6, 3);
+        Gpu3DDrawRange::push(&mut ranges, Gpu3DDrawKind::Translucent, 1, 9, 3);
+        Gpu3DDrawRange::push(&mut ranges, Gpu3DDrawKind::Translucent, 2, 12, 3);
+        Gpu3DDrawRange::push(&mut ranges, Gpu3DDrawKind::Shadow, 1, 15, 3);
+        Gpu3DDrawRange::push(&mut ranges, Gpu3DDrawKind::Shadow, 2, 18, 3);
+        let ranges = ranges.iter().map(|range| (range.kind, range.start, range.len)).collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            [
+                (Gpu3DDrawKind::Opaque, 0, 6),
+                (Gpu3DDrawKind::Opaque, 6, 3),
+                (Gpu3DDrawKind::Translucent, 9, 6),
+                (Gpu3DDrawKind::Shadow, 15, 3),
+                (Gpu3DDrawKind::Shadow, 18, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn shadow_is_drawn_onto_marked_pixels_of_other_polygon_ids() {
+        let drawn = draw_pixel(&draw_ranges(&[
+            (attr(PolygonMode::Modulation, 31, 1), true),
+            (attr(PolygonMode::Shadow, 15, 0), false),
+            (attr(PolygonMode::Shadow, 15, 2), true),
+        ]));
+        assert_eq!(drawn, [(Gpu3DDrawKind::Opaque, 1), (Gpu3DDrawKind::Shadow, 2)]);
+    }
+
+    #[test]
+    fn shadow_is_not_drawn_without_mask() {
+        let drawn = draw_pixel(&draw_ranges(&[
+            (attr(PolygonMode::Modulation, 31, 1), true),
+            // The mask lies in front of the pixel
+            (attr(PolygonMode::Shadow, 15, 0), true),
+            (attr(PolygonMode::Shadow, 15, 2), true),
+        ]));
+        assert_eq!(drawn, [(Gpu3DDrawKind::Opaque, 1)]);
+    }
+
+    #[test]
+    fn shadow_is_not_drawn_onto_its_own_polygon_id() {
+        let drawn = draw_pixel(&draw_ranges(&[
+            (attr(PolygonMode::Modulation, 31, 2), true),
+            (attr(PolygonMode::Shadow, 15, 0), false),
+            (attr(PolygonMode::Shadow, 15, 2), true),
+            // The mark is kept for shadows of other ids
+            (attr(PolygonMode::Shadow, 15, 3), true),
+        ]));
+        assert_eq!(drawn, [(Gpu3DDrawKind::Opaque, 2), (Gpu3DDrawKind::Shadow, 3)]);
+    }
+
+    #[test]
+    fn shadow_consumes_the_mask() {
+        let drawn = draw_pixel(&draw_ranges(&[
+            (attr(PolygonMode::Modulation, 31, 1), true),
+            (attr(PolygonMode::Shadow, 15, 0), false),
+            (attr(PolygonMode::Shadow, 15, 2), true),
+            (attr(PolygonMode::Shadow, 15, 3), true),
+        ]));
+        assert_eq!(drawn, [(Gpu3DDrawKind::Opaque, 1), (Gpu3DDrawKind::Shadow, 2)]);
+    }
+}
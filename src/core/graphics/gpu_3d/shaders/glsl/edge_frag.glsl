@@ -0,0 +1,43 @@
+#version 300 es
+
+precision highp int;
+precision highp float;
+
+layout (location = 0) out vec4 color;
+
+in vec2 screenPos;
+
+uniform sampler2D attrTex;
+uniform int edgeColors[8];
+
+int readId(vec4 value) {
+    return int(value.r * 127.0 + 0.5) & 0x3F;
+}
+
+float readDepth(vec4 value) {
+    return floor(value.g * 255.0 + 0.5) * 256.0 + floor(value.b * 255.0 + 0.5);
+}
+
+vec3 normRgb5(int color) {
+    return vec3(float(color & 0x1F), float((color >> 5) & 0x1F), float((color >> 10) & 0x1F)) / 31.0;
+}
+
+// An edge is drawn when a neighbour has a different polygon id and lies behind
+bool isEdge(vec4 center, vec2 offset) {
+    vec4 value = texture(attrTex, screenPos + offset);
+    return readId(value) != readId(center) && readDepth(center) < readDepth(value);
+}
+
+void main() {
+    vec4 center = texture(attrTex, screenPos);
+    if (int(center.r * 127.0 + 0.5) < 0x40) {
+        discard;
+    }
+
+    vec2 pixel = vec2(1.0 / 256.0, 1.0 / 192.0);
+    if (isEdge(center, vec2(pixel.x, 0.0)) || isEdge(center, vec2(-pixel.x, 0.0)) || isEdge(center, vec2(0.0, pixel.y)) || isEdge(center, vec2(0.0, -pixel.y))) {
+        color = vec4(normRgb5(edgeColors[readId(center) >> 3]), 1.0);
+    } else {
+        discard;
+    }
+}
@@ -0,0 +1,49 @@
+#version 300 es
+
+precision highp int;
+precision highp float;
+
+layout (location = 0) out vec4 color;
+
+in vec2 screenPos;
+
+uniform sampler2D attrTex;
+uniform int dispCnt;
+uniform int fogColor;
+uniform int fogOffset;
+uniform int fogTable[32];
+
+float readDepth(vec4 value) {
+    return floor(value.g * 255.0 + 0.5) * 256.0 + floor(value.b * 255.0 + 0.5);
+}
+
+vec3 normRgb5(int color) {
+    return vec3(float(color & 0x1F), float((color >> 5) & 0x1F), float((color >> 10) & 0x1F)) / 31.0;
+}
+
+void main() {
+    vec4 value = texture(attrTex, screenPos);
+    if (value.a == 0.0) {
+        discard;
+    }
+
+    // Depth is compared in 15 bit units, each table entry covers 0x400 >> shift of them
+    float depth = readDepth(value) / 65535.0 * 32767.0;
+    float step = float(max(0x400 >> ((dispCnt >> 8) & 0xF), 1));
+    float pos = (depth - float(fogOffset)) / step - 1.0;
+
+    float density;
+    if (pos <= 0.0) {
+        density = float(fogTable[0]);
+    } else if (pos >= 31.0) {
+        density = float(fogTable[31]);
+    } else {
+        int index = int(pos);
+        density = mix(float(fogTable[index]), float(fogTable[index + 1]), fract(pos));
+    }
+    if (density >= 127.0) {
+        density = 128.0;
+    }
+
+    color = vec4(normRgb5(fogColor), density / 128.0);
+}
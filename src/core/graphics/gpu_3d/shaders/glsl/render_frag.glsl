@@ -6,10 +6,14 @@ precision highp float;
 uniform sampler2D tex;
 uniform sampler2D palTex;
 uniform sampler2D attrTex;
+uniform int dispCnt;
+uniform int toonTable[32];
+uniform int attrPass;
 
 in vec3 oColor;
 in vec2 oTexCoords;
 in float oPolygonIndex;
+in float oDepth;
 
 layout (location = 0) out vec4 color;
 
@@ -290,6 +294,29 @@ void main() {
         }
     }
 
+    int mode = (polyAttr >> 5) & 0x3;
+    if (mode == 2) {
+        vec3 texel = texFmt == 0 ? vec3(1.0) : color.rgb;
+        vec3 toon = normRgb5(toonTable[int(oColor.r * 31.0 + 0.5)]);
+        if ((dispCnt & 0x2) == 0) {
+            color.rgb = texel * toon;
+        } else {
+            color.rgb = min(texel * oColor.r + toon, 1.0);
+        }
+    }
+
     float alpha = float(polyAttr & 31) / 31.0;
     color.a *= alpha;
+
+    // Write the polygon id, the depth split into two bytes and the fog flag for edge marking and fog
+    if (attrPass != 0) {
+        if ((attrPass == 1 && color.a < 1.0) || color.a == 0.0) {
+            discard;
+        }
+        float depth = floor(clamp(oDepth, 0.0, 1.0) * 65535.0);
+        float depthHigh = floor(depth / 256.0);
+        int polygonId = (polyAttr >> 8) & 0x3F;
+        int fog = (polyAttr >> 14) & 0x1;
+        color = vec4(float(polygonId | 0x40) / 127.0, depthHigh / 255.0, (depth - depthHigh * 256.0) / 255.0, float(fog));
+    }
 }
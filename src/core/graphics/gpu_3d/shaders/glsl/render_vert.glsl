@@ -7,10 +7,12 @@ in vec2 texCoords;
 out vec3 oColor;
 out vec2 oTexCoords;
 out float oPolygonIndex;
+out float oDepth;
 
 void main() {
     oColor = color.rgb;
     oTexCoords = texCoords;
     oPolygonIndex = color.a;
+    oDepth = position.z / position.w * 0.5 + 0.5;
     gl_Position = position;
 }
@@ -1,3 +1,4 @@
+use crate::bitset::Bitset;
 use crate::core::memory::mem::Memory;
 use crate::core::memory::vram::Vram;
 use crate::core::memory::{regions, vram};
@@ -54,7 +55,26 @@ impl GpuMemBuf {
         self.vram.rebuild_maps();
     }
 
-    pub fn read_2d(&mut self, read_lcdc: bool) {
+    // Keeps the 2d memory up to date on every scanline for the scanline renderer
+    pub fn read_2d_scanline(&mut self, mem: &mut Memory) {
+        let sections = mem.vram.banks.copy_scanline_dirty_sections(&mut self.vram.banks);
+        if self.vram.cnt != mem.vram.cnt {
+            self.vram.cnt = mem.vram.cnt;
+            self.vram.rebuild_maps();
+            self.read_2d_vram(true);
+        } else if !sections.is_empty() {
+            self.read_2d_vram_dirty(&sections);
+        }
+
+        let (pal_a, pal_b) = mem.palettes.mem.split_at(mem.palettes.mem.len() / 2);
+        self.pal_a[0].copy_from_slice(pal_a);
+        self.pal_b[0].copy_from_slice(pal_b);
+        let (oam_a, oam_b) = mem.oam.mem.split_at(mem.oam.mem.len() / 2);
+        self.oam_a[0].copy_from_slice(oam_a);
+        self.oam_b[0].copy_from_slice(oam_b);
+    }
+
+    fn read_2d_vram(&mut self, read_lcdc: bool) {
         if read_lcdc {
             self.vram.maps.read_all_lcdc(&mut self.lcdc, self.vram.banks.mem.deref());
         }
@@ -68,6 +88,30 @@ impl GpuMemBuf {
         self.vram.maps.read_all_obj_b(&mut self.obj_b, self.vram.banks.mem.deref());
         self.vram.maps.read_all_bg_b_ext_palette(&mut self.bg_b_ext_palette, self.vram.banks.mem.deref());
         self.vram.maps.read_all_obj_b_ext_palette(&mut self.obj_b_ext_palette, self.vram.banks.mem.deref());
+    }
+
+    // Only rereads the parts of the mappings which overlap the changed bank sections
+    fn read_2d_vram_dirty(&mut self, sections: &Bitset<6>) {
+        let vram = self.vram.banks.mem.deref();
+        self.vram.maps.read_dirty_lcdc(&mut self.lcdc, vram, sections);
+
+        self.vram.maps.read_dirty_bg_a(&mut self.bg_a, vram, sections);
+        self.vram.maps.read_dirty_obj_a(&mut self.obj_a, vram, sections);
+        if self.vram.maps.is_ext_palette_a_dirty(sections) {
+            self.vram.maps.read_all_bg_a_ext_palette(&mut self.bg_a_ext_palette, vram);
+            self.vram.maps.read_all_obj_a_ext_palette(&mut self.obj_a_ext_palette, vram);
+        }
+
+        self.vram.maps.read_dirty_bg_b(&mut self.bg_b, vram, sections);
+        self.vram.maps.read_dirty_obj_b(&mut self.obj_b, vram, sections);
+        if self.vram.maps.is_ext_palette_b_dirty(sections) {
+            self.vram.maps.read_all_bg_b_ext_palette(&mut self.bg_b_ext_palette, vram);
+            self.vram.maps.read_all_obj_b_ext_palette(&mut self.obj_b_ext_palette, vram);
+        }
+    }
+
+    pub fn read_2d(&mut self, read_lcdc: bool) {
+        self.read_2d_vram(read_lcdc);
 
         let pal_a = unsafe { slice::from_raw_parts(self.pal_a[1].as_ptr(), self.pal_a[1].len()) };
         self.pal_a[0].copy_from_slice(pal_a);
@@ -49,19 +49,24 @@ pub struct GpuRenderer {
     vram_read: AtomicBool,
     sample_2d: bool,
     ready_2d: bool,
+    capture: bool,
 
     render_time_measure_count: u8,
     render_time_sum: u32,
     average_render_time: u16,
 
+    headless: bool,
+    screen_captures: [Vec<u32>; 2],
+
     #[cfg(feature = "profiling")]
     frame_capture: HeapMemU8<{ (PRESENTER_SCREEN_WIDTH * PRESENTER_SCREEN_HEIGHT * 4) as usize }>,
 }
 
 impl GpuRenderer {
-    pub fn new() -> Self {
+    pub fn new(headless: bool, scanline_2d: bool) -> Self {
+        let screen_capture = || if headless { vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT] } else { Vec::new() };
         GpuRenderer {
-            renderer_2d: Gpu2DRenderer::new(),
+            renderer_2d: Gpu2DRenderer::new(scanline_2d),
             renderer_3d: Gpu3DRenderer::default(),
 
             common: GpuRendererCommon::new(),
@@ -74,23 +79,28 @@ impl GpuRenderer {
             vram_read: AtomicBool::new(false),
             sample_2d: true,
             ready_2d: false,
+            capture: false,
 
             render_time_measure_count: 0,
             render_time_sum: 0,
             average_render_time: 0,
 
+            headless,
+            screen_captures: [screen_capture(), screen_capture()],
+
             #[cfg(feature = "profiling")]
             frame_capture: HeapMemU8::new(),
         }
     }
 
-    pub fn on_scanline(&mut self, inner_a: &mut Gpu2DRegisters, inner_b: &mut Gpu2DRegisters, line: u8) {
+    pub fn on_scanline(&mut self, inner_a: &mut Gpu2DRegisters, inner_b: &mut Gpu2DRegisters, mem: &mut Memory, line: u8) {
         if self.sample_2d {
-            self.renderer_2d.on_scanline(inner_a, inner_b, line);
+            self.renderer_2d.on_scanline(inner_a, inner_b, mem, line);
         }
     }
 
-    pub fn on_scanline_finish(&mut self, mem: &mut Memory, pow_cnt1: PowCnt1, registers_3d: &mut Gpu3DRegisters) {
+    // Returns true if the capture sources were rendered for this frame
+    pub fn on_scanline_finish(&mut self, mem: &mut Memory, pow_cnt1: PowCnt1, registers_3d: &mut Gpu3DRegisters, capture: bool) -> bool {
         if self.sample_2d {
             self.common.mem_buf.read_vram(&mut mem.vram);
             self.common.mem_buf.read_palettes_oam(mem);
@@ -101,6 +111,13 @@ impl GpuRenderer {
 
         let mut rendering = self.rendering.lock().unwrap();
 
+        // Display capture needs the output of this frame, don't skip it
+        // Without a sampled frame the capture stays pending, see reload_registers
+        let capture = capture && self.ready_2d;
+        if capture {
+            rendering = self.rendering_condvar.wait_while(rendering, |rendering| *rendering).unwrap();
+        }
+
         if !*rendering && self.ready_2d {
             self.common.pow_cnt1[0] = self.common.pow_cnt1[1];
             self.renderer_2d.on_scanline_finish();
@@ -112,13 +129,26 @@ impl GpuRenderer {
             }
 
             self.ready_2d = false;
+            self.capture = capture;
             self.vram_read.store(false, Ordering::SeqCst);
             *rendering = true;
             self.rendering_condvar.notify_one();
+
+            if unlikely(self.headless || capture) {
+                // Keep the cpu in lockstep with the renderer, so every frame gets rendered
+                let _rendering = self.rendering_condvar.wait_while(rendering, |rendering| *rendering).unwrap();
+            }
         }
+        capture
     }
 
-    pub fn reload_registers(&mut self) {
+    pub fn reload_registers(&mut self, capture: bool) {
+        if !self.ready_2d && !self.vram_read.load(Ordering::SeqCst) && capture {
+            // A pending capture needs this frame sampled, wait until the renderer is done with the last one
+            let rendering = self.rendering.lock().unwrap();
+            let _rendering = self.rendering_condvar.wait_while(rendering, |rendering| *rendering).unwrap();
+        }
+
         if !self.ready_2d && self.vram_read.load(Ordering::SeqCst) {
             self.sample_2d = true;
         }
@@ -128,9 +158,30 @@ impl GpuRenderer {
         }
     }
 
+    unsafe fn capture_screen(&mut self, screen: usize) {
+        let capture = &mut self.screen_captures[screen];
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.renderer_2d.common.blend_fbo.fbo);
+        gl::ReadPixels(0, 0, DISPLAY_WIDTH as _, DISPLAY_HEIGHT as _, gl::RGBA, gl::UNSIGNED_BYTE, capture.as_mut_ptr() as _);
+        // Framebuffer rows start at the bottom
+        for y in 0..DISPLAY_HEIGHT / 2 {
+            let (upper, lower) = capture.split_at_mut((DISPLAY_HEIGHT - 1 - y) * DISPLAY_WIDTH);
+            upper[y * DISPLAY_WIDTH..(y + 1) * DISPLAY_WIDTH].swap_with_slice(&mut lower[..DISPLAY_WIDTH]);
+        }
+    }
+
+    // Top and bottom screen as RGBA8, only captured in headless mode
+    pub fn get_screen_captures(&self) -> &[Vec<u32>; 2] {
+        &self.screen_captures
+    }
+
     pub fn render_loop(&mut self, presenter: &mut Presenter, fps: &Arc<AtomicU16>, last_save_time: &Arc<Mutex<Option<(Instant, bool)>>>, settings: &Settings) {
         {
-            let rendering = self.rendering.lock().unwrap();
+            let mut rendering = self.rendering.lock().unwrap();
+            if unlikely(self.headless) && *rendering {
+                // Inputs for this frame are set, release the cpu from the previous frame
+                *rendering = false;
+                self.rendering_condvar.notify_one();
+            }
             let _drawing = self.rendering_condvar.wait_while(rendering, |rendering| !*rendering).unwrap();
         }
 
@@ -188,15 +239,22 @@ impl GpuRenderer {
                     ScreenMode::Rotated => (DISPLAY_HEIGHT, DISPLAY_WIDTH),
                 };
 
+                let soft_2d = settings.soft_2d_renderer();
                 self.renderer_2d
-                    .render::<{ A }>(&self.common, self.renderer_3d.gl.fbo.color, screen_topology.mode == ScreenMode::Rotated);
+                    .render::<{ A }>(&self.common, Some(&self.renderer_3d.gl.fbo), screen_topology.mode == ScreenMode::Rotated, soft_2d, self.capture);
+                if unlikely(self.headless) {
+                    self.capture_screen(!self.common.pow_cnt1[0].display_swap() as usize);
+                }
                 blit_fb(
                     used_fbo,
                     if self.common.pow_cnt1[0].display_swap() { &screen_topology.top } else { &screen_topology.bottom },
                     src_coords.0,
                     src_coords.1,
                 );
-                self.renderer_2d.render::<{ B }>(&self.common, 0, screen_topology.mode == ScreenMode::Rotated);
+                self.renderer_2d.render::<{ B }>(&self.common, None, screen_topology.mode == ScreenMode::Rotated, soft_2d, false);
+                if unlikely(self.headless) {
+                    self.capture_screen(self.common.pow_cnt1[0].display_swap() as usize);
+                }
                 blit_fb(
                     used_fbo,
                     if self.common.pow_cnt1[0].display_swap() { &screen_topology.bottom } else { &screen_topology.top },
@@ -246,9 +304,10 @@ impl GpuRenderer {
 
             presenter.gl_swap_window();
 
-            {
+            if !self.headless {
                 let mut rendering = self.rendering.lock().unwrap();
                 *rendering = false;
+                self.rendering_condvar.notify_one();
             }
 
             #[cfg(feature = "profiling")]
@@ -8,6 +8,7 @@ use crate::core::hle::rtc_hle::RtcHle;
 use crate::core::hle::sound_hle::SoundHle;
 use crate::core::hle::touchscreen_hle::TouchscreenHle;
 use crate::core::hle::wifi_hle::WifiHle;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType::{ARM7, ARM9};
 use crate::logging::debug_println;
 use bilge::prelude::*;
@@ -76,6 +77,26 @@ impl Arm7Hle {
             wifi: WifiHle::new(),
         }
     }
+
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.firmware);
+        state.write(&self.touchscreen);
+        self.sound.save_state(state);
+        state.write(&self.power_manager);
+        state.write(&self.mic);
+        state.write(&self.cart);
+        state.write(&self.wifi);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.firmware);
+        state.read(&mut self.touchscreen);
+        self.sound.load_state(state);
+        state.read(&mut self.power_manager);
+        state.read(&mut self.mic);
+        state.read(&mut self.cart);
+        state.read(&mut self.wifi);
+    }
 }
 
 impl Emu {
@@ -187,7 +208,9 @@ impl Emu {
     }
 
     pub fn arm7_hle_on_frame(&mut self) {
-        self.mem_write::<{ ARM7 }, _>(0x027FFFA8, (self.input.get_ext_key_in() << 10) & 0x2C00);
+        let ext_key_in = self.input.get_ext_key_in();
+        self.mem_write::<{ ARM7 }, _>(0x027FFFA8, ((ext_key_in << 10) & 0x2C00) | ((ext_key_in << 8) & 0x8000));
+        self.power_manager_hle_on_frame();
         let frame_counter = self.mem_read::<{ ARM7 }, u32>(0x27FFC3C);
         self.mem_write::<{ ARM7 }, _>(0x27FFC3C, frame_counter.wrapping_add(1));
     }
@@ -5,6 +5,7 @@ use crate::core::thread_regs::Cpsr;
 use crate::core::CpuType;
 use crate::jit::reg::Reg;
 use crate::logging::debug_println;
+use crate::utils::StrErr;
 use crate::{get_jit_asm_ptr, utils};
 use bilge::prelude::*;
 use std::cmp::min;
@@ -79,8 +80,71 @@ pub fn uninterrupt<const CPU: CpuType>(emu: &mut Emu) {
     emu.thread_set_cpsr(CPU, spsr, false);
 }
 
+// Memory accesses of the unpack and decompression functions, so they can run without an emu in tests
+trait SwiMem {
+    fn read<T: utils::Convert>(&mut self, addr: u32) -> T;
+    fn write<T: utils::Convert>(&mut self, addr: u32, value: T);
+}
+
+struct EmuSwiMem<'a, const CPU: CpuType>(&'a mut Emu);
+
+impl<const CPU: CpuType> SwiMem for EmuSwiMem<'_, CPU> {
+    fn read<T: utils::Convert>(&mut self, addr: u32) -> T {
+        self.0.mem_read::<CPU, T>(addr)
+    }
+
+    fn write<T: utils::Convert>(&mut self, addr: u32, value: T) {
+        self.0.mem_write::<CPU, T>(addr, value)
+    }
+}
+
 pub fn bit_unpack<const CPU: CpuType>(emu: &mut Emu) {
-    todo!()
+    let src_addr = *emu.thread_get_reg(CPU, Reg::R0);
+    let dst_addr = *emu.thread_get_reg(CPU, Reg::R1);
+    let info_addr = *emu.thread_get_reg(CPU, Reg::R2);
+
+    if let Err(err) = bit_unpack_mem(&mut EmuSwiMem::<CPU>(emu), src_addr, dst_addr, info_addr) {
+        debug_println!("{CPU:?} bit unpack failed {err:?}");
+    }
+}
+
+fn bit_unpack_mem(mem: &mut impl SwiMem, src_addr: u32, dst_addr: u32, info_addr: u32) -> Result<(), StrErr> {
+    let src_len = mem.read::<u16>(info_addr) as u32;
+    let src_width = mem.read::<u8>(info_addr + 2) as u32;
+    let dst_width = mem.read::<u8>(info_addr + 3) as u32;
+    let data_offset = mem.read::<u32>(info_addr + 4);
+    let offset_zero = data_offset & (1 << 31) != 0;
+    let data_offset = data_offset & 0x7FFFFFFF;
+
+    if !matches!(src_width, 1 | 2 | 4 | 8) || !matches!(dst_width, 1 | 2 | 4 | 8 | 16 | 32) {
+        return Err(StrErr::new(format!("invalid widths {src_width} {dst_width}")));
+    }
+
+    let src_mask = (1 << src_width) - 1;
+    let mut dst = 0;
+    let mut out = 0u32;
+    let mut out_bits = 0;
+
+    for i in 0..src_len {
+        let byte = mem.read::<u8>(src_addr + i) as u32;
+        for bit in (0..8).step_by(src_width as usize) {
+            let mut value = (byte >> bit) & src_mask;
+            if value != 0 || offset_zero {
+                value = value.wrapping_add(data_offset);
+            }
+            out |= value << out_bits;
+            out_bits += dst_width;
+
+            // Output is always written in words
+            if out_bits == 32 {
+                mem.write(dst_addr + dst, out);
+                dst += 4;
+                out = 0;
+                out_bits = 0;
+            }
+        }
+    }
+    Ok(())
 }
 
 pub fn cpu_fast_set<const CPU: CpuType>(emu: &mut Emu) {
@@ -121,11 +185,35 @@ pub fn cpu_set<const CPU: CpuType>(emu: &mut Emu) {
 }
 
 pub fn diff_unfilt16<const CPU: CpuType>(emu: &mut Emu) {
-    todo!()
+    let src_addr = *emu.thread_get_reg(CPU, Reg::R0);
+    let dst_addr = *emu.thread_get_reg(CPU, Reg::R1);
+    diff_unfilt16_mem(&mut EmuSwiMem::<CPU>(emu), src_addr, dst_addr);
+}
+
+fn diff_unfilt16_mem(mem: &mut impl SwiMem, src_addr: u32, dst_addr: u32) {
+    let size = mem.read::<u32>(src_addr) >> 8;
+
+    let mut value = 0u16;
+    for i in (0..size).step_by(2) {
+        value = value.wrapping_add(mem.read::<u16>(src_addr + 4 + i));
+        mem.write(dst_addr + i, value);
+    }
 }
 
 pub fn diff_unfilt8<const CPU: CpuType>(emu: &mut Emu) {
-    todo!()
+    let src_addr = *emu.thread_get_reg(CPU, Reg::R0);
+    let dst_addr = *emu.thread_get_reg(CPU, Reg::R1);
+    diff_unfilt8_mem(&mut EmuSwiMem::<CPU>(emu), src_addr, dst_addr);
+}
+
+fn diff_unfilt8_mem(mem: &mut impl SwiMem, src_addr: u32, dst_addr: u32) {
+    let size = mem.read::<u32>(src_addr) >> 8;
+
+    let mut value = 0u8;
+    for i in 0..size {
+        value = value.wrapping_add(mem.read::<u8>(src_addr + 4 + i));
+        mem.write(dst_addr + i, value);
+    }
 }
 
 pub fn divide<const CPU: CpuType>(emu: &mut Emu) {
@@ -153,7 +241,76 @@ pub fn halt<const CPU: CpuType>(emu: &mut Emu) {
 }
 
 pub fn huff_uncomp<const CPU: CpuType>(emu: &mut Emu) {
-    todo!()
+    let src_addr = *emu.thread_get_reg(CPU, Reg::R0);
+    let dst_addr = *emu.thread_get_reg(CPU, Reg::R1);
+
+    if let Err(err) = huff_uncomp_mem(&mut EmuSwiMem::<CPU>(emu), src_addr, dst_addr) {
+        debug_println!("{CPU:?} huff uncomp failed {err:?}");
+    }
+}
+
+fn huff_uncomp_mem(mem: &mut impl SwiMem, src_addr: u32, dst_addr: u32) -> Result<(), StrErr> {
+    let header = mem.read::<u32>(src_addr);
+    let data_bits = header & 0xF;
+    let size = header >> 8;
+    if data_bits != 4 && data_bits != 8 {
+        return Err(StrErr::new(format!("invalid data size {data_bits}")));
+    }
+
+    let tree_size = mem.read::<u8>(src_addr + 4) as u32;
+    let root_addr = src_addr + 5;
+    let tree_end = src_addr + 4 + (tree_size + 1) * 2;
+    let mut stream_addr = tree_end;
+    // Every symbol takes at most one bit per tree level, a longer stream means the tree is broken
+    let max_symbol_bits = (tree_size as u64 + 1) * 2;
+    let max_stream_words = ((size as u64 * 8).div_ceil(data_bits as u64) * max_symbol_bits).div_ceil(32);
+    let mut stream_words = 0;
+
+    let mut node_addr = root_addr;
+    let mut node = mem.read::<u8>(root_addr);
+    let mut dst = 0;
+    let mut out = 0u32;
+    let mut out_bits = 0;
+
+    // Bitstream is read in words starting from the msb, output is written in words
+    while dst < size {
+        if stream_words == max_stream_words {
+            return Err(StrErr::new(format!("stream exceeds {max_stream_words} words")));
+        }
+        let bits = mem.read::<u32>(stream_addr);
+        stream_addr += 4;
+        stream_words += 1;
+
+        for bit in (0..32).rev() {
+            let direction = (bits >> bit) & 1;
+            let child_addr = (node_addr & !1) + (node as u32 & 0x3F) * 2 + 2 + direction;
+            if child_addr >= tree_end {
+                return Err(StrErr::new(format!("node at {child_addr:x} outside of the tree")));
+            }
+            let is_data = node & (0x80 >> direction) != 0;
+            node = mem.read::<u8>(child_addr);
+
+            if is_data {
+                out |= (node as u32 & ((1 << data_bits) - 1)) << out_bits;
+                out_bits += data_bits;
+                if out_bits == 32 {
+                    mem.write(dst_addr + dst, out);
+                    dst += 4;
+                    out = 0;
+                    out_bits = 0;
+                    if dst >= size {
+                        return Ok(());
+                    }
+                }
+
+                node_addr = root_addr;
+                node = mem.read::<u8>(root_addr);
+            } else {
+                node_addr = child_addr;
+            }
+        }
+    }
+    Ok(())
 }
 
 pub fn check_wait_flags<const CPU: CpuType>(emu: &mut Emu) {
@@ -371,3 +528,122 @@ pub fn get_volume_table<const CPU: CpuType>(emu: &mut Emu) {
     let reg0 = emu.thread_get_reg_mut(CPU, Reg::R0);
     *reg0 = VOLUME_TABLE[min(*reg0 as usize, VOLUME_TABLE.len() - 1)] as u32;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: u32 = 0x100;
+    const DST: u32 = 0x200;
+    const INFO: u32 = 0x300;
+
+    struct TestMem(Vec<u8>);
+
+    impl TestMem {
+        fn new(src: &[u8]) -> Self {
+            let mut mem = vec![0; 0x400];
+            mem[SRC as usize..SRC as usize + src.len()].copy_from_slice(src);
+            TestMem(mem)
+        }
+
+        fn dst<T: utils::Convert>(&mut self, index: u32) -> T {
+            self.read(DST + index * size_of::<T>() as u32)
+        }
+    }
+
+    impl SwiMem for TestMem {
+        fn read<T: utils::Convert>(&mut self, addr: u32) -> T {
+            utils::read_from_mem(&self.0, addr)
+        }
+
+        fn write<T: utils::Convert>(&mut self, addr: u32, value: T) {
+            utils::write_to_mem(&mut self.0, addr, value)
+        }
+    }
+
+    fn unpack(src: &[u8], src_width: u8, dst_width: u8, data_offset: u32) -> TestMem {
+        let mut mem = TestMem::new(src);
+        mem.write(INFO, src.len() as u16);
+        mem.write(INFO + 2, src_width);
+        mem.write(INFO + 3, dst_width);
+        mem.write(INFO + 4, data_offset);
+        bit_unpack_mem(&mut mem, SRC, DST, INFO).unwrap();
+        mem
+    }
+
+    #[test]
+    fn bit_unpack_1bit() {
+        let mut mem = unpack(&[0xA5, 0x3C], 1, 8, 0x10);
+        assert_eq!([mem.dst::<u32>(0), mem.dst(1), mem.dst(2), mem.dst(3)], [0x00110011, 0x11001100, 0x11110000, 0x00001111]);
+    }
+
+    #[test]
+    fn bit_unpack_2bit() {
+        assert_eq!(unpack(&[0xE4], 2, 8, 1).dst::<u32>(0), 0x04030200);
+        assert_eq!(unpack(&[0xE4], 2, 8, 1 | (1 << 31)).dst::<u32>(0), 0x04030201);
+    }
+
+    #[test]
+    fn bit_unpack_4bit() {
+        let mut mem = unpack(&[0x21, 0x43], 4, 16, 0);
+        assert_eq!([mem.dst::<u32>(0), mem.dst(1)], [0x00020001, 0x00040003]);
+        assert_eq!(unpack(&[0x10], 4, 16, 0x100 | (1 << 31)).dst::<u32>(0), 0x01010100);
+    }
+
+    #[test]
+    fn bit_unpack_8bit() {
+        let mut mem = unpack(&[0x00, 0x7F], 8, 32, 5 | (1 << 31));
+        assert_eq!([mem.dst::<u32>(0), mem.dst(1)], [0x05, 0x84]);
+    }
+
+    #[test]
+    fn bit_unpack_invalid_width() {
+        let mut mem = TestMem::new(&[0xFF]);
+        mem.write(INFO, 1u16);
+        mem.write(INFO + 2, 3u8);
+        mem.write(INFO + 3, 8u8);
+        assert!(bit_unpack_mem(&mut mem, SRC, DST, INFO).is_err());
+        assert_eq!(mem.dst::<u32>(0), 0);
+    }
+
+    #[test]
+    fn huff_uncomp_8bit() {
+        // Root with the data nodes 'A' and 'B', the stream is 0110
+        let mut mem = TestMem::new(&[0x28, 4, 0, 0, 1, 0xC0, b'A', b'B', 0x00, 0x00, 0x00, 0x60]);
+        huff_uncomp_mem(&mut mem, SRC, DST).unwrap();
+        assert_eq!(mem.dst::<u32>(0), u32::from_le_bytes(*b"ABBA"));
+    }
+
+    #[test]
+    fn huff_uncomp_4bit() {
+        // 1 is coded as 0, 2 as 10 and 3 as 11
+        let tree = [0x24, 4, 0, 0, 3, 0x80, 0x01, 0xC0, 0x02, 0x03, 0x00, 0x00];
+        let mut src = tree.to_vec();
+        src.extend_from_slice(&0x59C00000u32.to_le_bytes());
+        let mut mem = TestMem::new(&src);
+        huff_uncomp_mem(&mut mem, SRC, DST).unwrap();
+        assert_eq!(mem.dst::<u32>(0), 0x12311321);
+    }
+
+    #[test]
+    fn huff_uncomp_malformed_tree() {
+        // The root points past the end of the tree
+        let mut mem = TestMem::new(&[0x28, 4, 0, 0, 1, 0xFF, b'A', b'B', 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert!(huff_uncomp_mem(&mut mem, SRC, DST).is_err());
+        assert_eq!(mem.dst::<u32>(0), 0);
+    }
+
+    #[test]
+    fn diff_unfilt_8bit() {
+        let mut mem = TestMem::new(&[0x81, 4, 0, 0, 1, 1, 1, 0xFF]);
+        diff_unfilt8_mem(&mut mem, SRC, DST);
+        assert_eq!([mem.dst::<u8>(0), mem.dst(1), mem.dst(2), mem.dst(3)], [1, 2, 3, 2]);
+    }
+
+    #[test]
+    fn diff_unfilt_16bit() {
+        let mut mem = TestMem::new(&[0x82, 6, 0, 0, 0x00, 0x01, 0x01, 0x00, 0xFF, 0xFF]);
+        diff_unfilt16_mem(&mut mem, SRC, DST);
+        assert_eq!([mem.dst::<u16>(0), mem.dst(1), mem.dst(2)], [0x0100, 0x0101, 0x0100]);
+    }
+}
@@ -61,9 +61,9 @@ pub const ARM7_SWI_LOOKUP_TABLE: [(&str, fn(&mut Emu)); 33] = [
     ("huff_uncomp", huff_uncomp::<{ ARM7 }>),
     ("runlen_uncomp", runlen_uncomp::<{ ARM7 }>),
     ("runlen_uncomp", runlen_uncomp::<{ ARM7 }>),
+    ("diff_unfilt8", diff_unfilt8::<{ ARM7 }>),
     ("unknown", unknown::<{ ARM7 }>),
-    ("unknown", unknown::<{ ARM7 }>),
-    ("unknown", unknown::<{ ARM7 }>),
+    ("diff_unfilt16", diff_unfilt16::<{ ARM7 }>),
     ("unknown", unknown::<{ ARM7 }>),
     ("get_sine_table", get_sine_table::<{ ARM7 }>),
     ("get_pitch_table", get_pitch_table::<{ ARM7 }>),
@@ -1,6 +1,5 @@
 use crate::core::emu::Emu;
 use crate::core::hle::arm7_hle::IpcFifoTag;
-use crate::core::spi;
 use crate::core::CpuType::ARM7;
 
 pub(super) struct FirmwareHle {
@@ -28,41 +27,60 @@ impl Emu {
         }
 
         let cmd = (firmware.data[0] >> 8) - 0x20;
+        let reply = 0x0300A000 | ((cmd as u32) << 8);
         match cmd {
-            0 => self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, 0x0300A000, false),
-            1 => self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, 0x0300A100, false),
+            // Write enable/disable, writes below don't check the latch
+            0 | 1 => self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, reply, false),
             2 => {
                 let addr = (((firmware.data[0] as u32) & 0xFF) << 24) | ((firmware.data[1] as u32) << 8) | (((firmware.data[2] as u32) >> 8) & 0xFF);
                 if (0x02000000..0x02800000).contains(&addr) {
-                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, 0x0300A200, false);
+                    let status = self.spi.firmware_status();
+                    self.mem_write::<{ ARM7 }, _>(addr, status);
+                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, reply, false);
                 } else {
-                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, 0x0300A202, false);
+                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, reply | 2, false);
                 }
             }
-            3 => {
+            // Read, fast read
+            3 | 4 => {
                 let addr = ((firmware.data[4] as u32) << 16) | firmware.data[5] as u32;
                 if (0x02000000..0x02800000).contains(&addr) {
                     let src = (((firmware.data[0] as u32) & 0xFF) << 16) | firmware.data[1] as u32;
                     let len = ((firmware.data[2] as u32) << 16) | firmware.data[3] as u32;
 
                     for i in 0..len {
-                        let val = spi::SPI_FIRMWARE[(src + i) as usize % spi::SPI_FIRMWARE.len()];
+                        let val = self.spi.firmware.read(src + i);
                         self.mem_write::<{ ARM7 }, _>(addr + i, val);
                     }
 
-                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, 0x0300A300, false);
+                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, reply, false);
                 } else {
-                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, 0x0300A302, false);
+                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, reply | 2, false);
                 }
             }
-            5 => {
+            // Page write, page program
+            5 | 6 => {
                 let addr = ((firmware.data[3] as u32) << 16) | firmware.data[4] as u32;
                 if (0x02000000..0x02800000).contains(&addr) {
-                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, 0x0300A500, false);
+                    let dst = (((firmware.data[0] as u32) & 0xFF) << 16) | firmware.data[1] as u32;
+                    let len = firmware.data[2] as u32;
+
+                    for i in 0..len {
+                        let val = self.mem_read::<{ ARM7 }, u8>(addr + i);
+                        self.spi.firmware.program(dst + i, val, cmd == 5);
+                    }
+
+                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, reply, false);
                 } else {
-                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, 0x0300A502, false);
+                    self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, reply | 2, false);
                 }
             }
+            // Page erase, sector erase
+            7 | 8 => {
+                let addr = (((firmware.data[0] as u32) & 0xFF) << 16) | firmware.data[1] as u32;
+                self.spi.firmware.erase(addr, if cmd == 7 { 0x100 } else { 0x10000 });
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Nvram, reply, false);
+            }
             _ => {}
         }
     }
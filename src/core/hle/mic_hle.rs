@@ -1,14 +1,77 @@
+use crate::core::cycle_manager::EventType;
 use crate::core::emu::Emu;
 use crate::core::hle::arm7_hle::IpcFifoTag;
+use crate::core::CpuType::ARM7;
 use crate::logging::debug_println;
 
+const MIC_LAST_ADDRESS: u32 = 0x027FFF90;
+const MIC_SAMPLING_DATA: u32 = 0x027FFF94;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+enum MicSamplingType {
+    Unsigned8 = 0,
+    Unsigned12 = 1,
+    Signed8 = 2,
+    Signed12 = 3,
+    Unsigned12FilterOff = 4,
+    Signed12FilterOff = 5,
+}
+
+impl From<u16> for MicSamplingType {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => MicSamplingType::Unsigned8,
+            2 => MicSamplingType::Signed8,
+            3 => MicSamplingType::Signed12,
+            4 => MicSamplingType::Unsigned12FilterOff,
+            5 => MicSamplingType::Signed12FilterOff,
+            _ => MicSamplingType::Unsigned12,
+        }
+    }
+}
+
+impl MicSamplingType {
+    fn is_8bit(self) -> bool {
+        self == MicSamplingType::Unsigned8 || self == MicSamplingType::Signed8
+    }
+
+    fn convert(self, sample: i16) -> u16 {
+        match self {
+            MicSamplingType::Unsigned8 => ((sample as i32 + 0x8000) >> 8) as u16,
+            MicSamplingType::Signed8 => ((sample >> 8) as u8) as u16,
+            MicSamplingType::Unsigned12 | MicSamplingType::Unsigned12FilterOff => ((sample as i32 + 0x8000) as u16) & 0xFFF0,
+            MicSamplingType::Signed12 | MicSamplingType::Signed12FilterOff => (sample as u16) & 0xFFF0,
+        }
+    }
+}
+
 pub(super) struct MicHle {
     data: [u16; 16],
+    sampling: bool,
+    sampling_type: u8,
+    sampling_loop: bool,
+    buf_addr: u32,
+    buf_size: u32,
+    buf_offset: u32,
+    rate: u32,
+    // Invalidates events of previous auto samplings
+    generation: u16,
 }
 
 impl MicHle {
     pub(super) fn new() -> Self {
-        MicHle { data: [0; 16] }
+        MicHle {
+            data: [0; 16],
+            sampling: false,
+            sampling_type: 0,
+            sampling_loop: false,
+            buf_addr: 0,
+            buf_size: 0,
+            buf_offset: 0,
+            rate: 0,
+            generation: 0,
+        }
     }
 }
 
@@ -27,12 +90,71 @@ impl Emu {
         }
 
         let cmd = (mic.data[0] >> 8) - 0x40;
+        let reply = 0x0300C000 | ((cmd as u32) << 8);
         match cmd {
-            0 => self.arm7_hle_send_ipc_fifo(IpcFifoTag::Mic, 0x0300C000, false),
+            // One shot sampling
+            0 => {
+                let sampling_type = MicSamplingType::from(mic.data[0] & 0xFF);
+                let sample = sampling_type.convert(self.spi.mic.sample(self.cm.get_cycles()));
+                self.mem_write::<{ ARM7 }, u16>(MIC_SAMPLING_DATA, sample);
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Mic, reply, false);
+            }
+            // Auto sampling on, type in the low nibble and loop flag above it
+            1 => {
+                mic.sampling = true;
+                mic.sampling_type = (mic.data[0] & 0xF) as u8;
+                mic.sampling_loop = mic.data[0] & 0x10 != 0;
+                mic.buf_addr = ((mic.data[1] as u32) << 16) | mic.data[2] as u32;
+                mic.buf_size = ((mic.data[3] as u32) << 16) | mic.data[4] as u32;
+                mic.rate = (((mic.data[5] as u32) << 16) | mic.data[6] as u32).max(1);
+                mic.buf_offset = 0;
+                mic.generation = (mic.generation + 1) & 0xFFF;
+
+                let (rate, generation) = (mic.rate, mic.generation);
+                self.cm.schedule(rate, EventType::MicHle, generation);
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Mic, reply, false);
+            }
+            // Auto sampling off
+            2 => {
+                mic.sampling = false;
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Mic, reply, false);
+            }
             _ => {
                 self.arm7_hle_send_ipc_fifo(IpcFifoTag::Mic, 0x0300C000, false); // Just send same reply, seems to work
                 debug_println!("unknown mic request {data:x}");
             }
         }
     }
+
+    pub fn mic_hle_on_sample_event(&mut self, generation: u16) {
+        let mic = &mut self.hle.mic;
+        if !mic.sampling || mic.generation != generation {
+            return;
+        }
+
+        let sampling_type = MicSamplingType::from(mic.sampling_type as u16);
+        let sample = sampling_type.convert(self.spi.mic.sample(self.cm.get_cycles()));
+
+        let mic = &mut self.hle.mic;
+        let addr = mic.buf_addr + mic.buf_offset;
+        if sampling_type.is_8bit() {
+            mic.buf_offset += 1;
+            self.mem_write::<{ ARM7 }, u8>(addr, sample as u8);
+        } else {
+            mic.buf_offset += 2;
+            self.mem_write::<{ ARM7 }, u16>(addr, sample);
+        }
+        self.mem_write::<{ ARM7 }, u32>(MIC_LAST_ADDRESS, addr);
+
+        let mic = &mut self.hle.mic;
+        if mic.buf_offset >= mic.buf_size {
+            mic.buf_offset = 0;
+            if !mic.sampling_loop {
+                mic.sampling = false;
+                return;
+            }
+        }
+        let rate = mic.rate;
+        self.cm.schedule(rate, EventType::MicHle, generation);
+    }
 }
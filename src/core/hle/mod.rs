@@ -8,5 +8,6 @@ mod power_manager_hle;
 mod rtc_hle;
 mod sound_hle;
 pub mod sound_nitro;
+pub mod sound_test;
 mod touchscreen_hle;
 pub mod wifi_hle;
@@ -1,13 +1,24 @@
 use crate::core::emu::Emu;
 use crate::core::hle::arm7_hle::IpcFifoTag;
+use crate::logging::debug_println;
+
+const TRIGGER_KEY: u16 = 1 << 0;
+const TRIGGER_RTC_ALARM: u16 = 1 << 1;
+const TRIGGER_COVER_OPEN: u16 = 1 << 2;
 
 pub(super) struct PowerManagerHle {
     data: [u16; 16],
+    sleeping: bool,
+    wake_triggers: u16,
 }
 
 impl PowerManagerHle {
     pub(super) fn new() -> Self {
-        PowerManagerHle { data: [0; 16] }
+        PowerManagerHle {
+            data: [0; 16],
+            sleeping: false,
+            wake_triggers: 0,
+        }
     }
 }
 
@@ -25,7 +36,15 @@ impl Emu {
 
         let cmd = (self.hle.power_manager.data[0] >> 8) - 0x60;
         match cmd {
-            1 => self.arm7_hle_send_ipc_fifo(IpcFifoTag::PowerManager, 0x0300E300, false),
+            // Sleep, replied once one of the wake up triggers fires
+            1 => {
+                debug_println!("power manager sleep with triggers {:x}", self.hle.power_manager.data[1]);
+                self.hle.power_manager.sleeping = true;
+                self.hle.power_manager.wake_triggers = self.hle.power_manager.data[1];
+                self.spi.set_backlights(false);
+                // Don't wake up from alarms which fired before
+                self.rtc.read_status1();
+            }
             3 => self.arm7_hle_send_ipc_fifo(IpcFifoTag::PowerManager, 0x0300E300, false),
             4 => self.arm7_hle_send_ipc_fifo(IpcFifoTag::PowerManager, 0x03008000 | (((self.hle.power_manager.data[1] as u32 + 0x70) & 0xFF) << 8), false),
             5 => self.arm7_hle_send_ipc_fifo(IpcFifoTag::PowerManager, 0x03008000 | (((self.hle.power_manager.data[1] as u32 + 0x70) & 0xFF) << 8), false),
@@ -33,4 +52,22 @@ impl Emu {
             _ => {}
         }
     }
+
+    pub(super) fn power_manager_hle_on_frame(&mut self) {
+        let power_manager = &self.hle.power_manager;
+        if !power_manager.sleeping {
+            return;
+        }
+
+        let triggers = power_manager.wake_triggers;
+        let wake = (triggers & TRIGGER_COVER_OPEN != 0 && !self.input.is_lid_closed())
+            || (triggers & TRIGGER_KEY != 0 && self.input.get_key_input() & 0x3FF != 0x3FF)
+            || (triggers & TRIGGER_RTC_ALARM != 0 && self.rtc.has_interrupt());
+        if wake {
+            debug_println!("power manager wake up");
+            self.hle.power_manager.sleeping = false;
+            self.spi.set_backlights(true);
+            self.arm7_hle_send_ipc_fifo(IpcFifoTag::PowerManager, 0x0300E300, false);
+        }
+    }
 }
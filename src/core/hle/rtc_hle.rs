@@ -2,6 +2,8 @@ use crate::core::emu::Emu;
 use crate::core::hle::arm7_hle::IpcFifoTag;
 use crate::core::CpuType::ARM7;
 
+const RTC_BUF: u32 = 0x027FFDE8;
+
 pub struct RtcHle;
 
 impl RtcHle {
@@ -22,28 +24,71 @@ impl Emu {
         match cmd {
             0x10 => {
                 // read date and time
-                self.rtc.update_date_time();
+                self.rtc.update_date_time(self.cm.get_cycles());
                 for i in 0..7 {
-                    self.mem_write::<{ ARM7 }, _>(0x027FFDE8 + i, self.rtc.date_time[i as usize]);
+                    self.mem_write::<{ ARM7 }, _>(RTC_BUF + i, self.rtc.date_time[i as usize]);
                 }
                 self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x9000, false);
             }
             0x11 => {
                 // read date
-                self.rtc.update_date_time();
+                self.rtc.update_date_time(self.cm.get_cycles());
                 for i in 0..4 {
-                    self.mem_write::<{ ARM7 }, _>(0x027FFDE8 + i, self.rtc.date_time[i as usize]);
+                    self.mem_write::<{ ARM7 }, _>(RTC_BUF + i, self.rtc.date_time[i as usize]);
                 }
                 self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x9100, false);
             }
             0x12 => {
                 // read time
-                self.rtc.update_date_time();
+                self.rtc.update_date_time(self.cm.get_cycles());
                 for i in 4..7 {
-                    self.mem_write::<{ ARM7 }, _>(0x027FFDE8 + i, self.rtc.date_time[i as usize]);
+                    self.mem_write::<{ ARM7 }, _>(RTC_BUF + i, self.rtc.date_time[i as usize]);
                 }
                 self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x9200, false);
             }
+            0x14 | 0x15 => {
+                // read alarm 1/2
+                let alarm = if cmd == 0x14 { self.rtc.alarm1 } else { self.rtc.alarm2 };
+                for i in 0..3 {
+                    self.mem_write::<{ ARM7 }, _>(RTC_BUF + i, alarm[i as usize]);
+                }
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x8000 | (cmd << 8), false);
+            }
+            0x16 => {
+                // read status 1
+                let status = self.rtc.read_status1();
+                self.mem_write::<{ ARM7 }, _>(RTC_BUF, status);
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x8000 | (cmd << 8), false);
+            }
+            0x17 => {
+                // read status 2
+                self.mem_write::<{ ARM7 }, _>(RTC_BUF, self.rtc.status2);
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x8000 | (cmd << 8), false);
+            }
+            0x24 | 0x25 => {
+                // write alarm 1/2
+                let mut alarm = [0; 3];
+                for i in 0..3 {
+                    alarm[i] = self.mem_read::<{ ARM7 }, u8>(RTC_BUF + i as u32);
+                }
+                if cmd == 0x24 {
+                    self.rtc.alarm1 = alarm;
+                } else {
+                    self.rtc.alarm2 = alarm;
+                }
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x8000 | (cmd << 8), false);
+            }
+            0x26 => {
+                // write status 1
+                let status = self.mem_read::<{ ARM7 }, u8>(RTC_BUF);
+                self.rtc.write_status1(status);
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x8000 | (cmd << 8), false);
+            }
+            0x27 => {
+                // write status 2
+                self.rtc.status2 = self.mem_read::<{ ARM7 }, u8>(RTC_BUF);
+                self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x8000 | (cmd << 8), false);
+            }
             _ => {}
         }
     }
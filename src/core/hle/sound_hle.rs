@@ -1,5 +1,6 @@
 use crate::core::emu::Emu;
 use crate::core::hle::sound_nitro::SoundNitro;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 
 pub struct SoundHle {
     engine: i8,
@@ -13,9 +14,23 @@ impl SoundHle {
             nitro: SoundNitro::default(),
         }
     }
+
+    pub(super) fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.engine);
+        self.nitro.save_state(state);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.engine);
+        self.nitro.load_state(state);
+    }
 }
 
 impl Emu {
+    pub(super) fn sound_hle_use_nitro(&mut self) {
+        self.hle.sound.engine = 0;
+    }
+
     pub fn sound_hle_ipc_recv(&mut self, data: u32) {
         if self.hle.sound.engine == -1 {
             if data >= 0x02000000 {
@@ -2,6 +2,7 @@ use crate::core::cycle_manager::EventType;
 use crate::core::emu::Emu;
 use crate::core::hle::arm7_hle::IpcFifoTag;
 use crate::core::hle::bios::{PITCH_TABLE, VOLUME_TABLE};
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::spu::{MainSoundCnt, SoundCapCnt, SoundChannelFormat, SoundCnt, CHANNEL_COUNT};
 use crate::core::CpuType::ARM7;
 use crate::logging::debug_println;
@@ -311,6 +312,44 @@ pub struct SoundNitro {
     cmd_translate: bool,
 }
 
+impl SoundNitro {
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write_vec_deque(&self.cmd_queue);
+        state.write(&self.counter);
+        state.write(&self.channels);
+        state.write(&self.sequences);
+        state.write(&self.tracks);
+        state.write(&self.alarms);
+        state.write(&self.channel_vol);
+        state.write(&self.channel_pan);
+        state.write(&self.master_pan);
+        state.write(&self.surround_decay);
+        state.write(&self.shared_mem);
+        state.write(&self.locked_channel_mask);
+        state.write(&self.weak_locked_channel_mask);
+        state.write(&self.cmd_offset);
+        state.write(&self.cmd_translate);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read_vec_deque(&mut self.cmd_queue);
+        state.read(&mut self.counter);
+        state.read(&mut self.channels);
+        state.read(&mut self.sequences);
+        state.read(&mut self.tracks);
+        state.read(&mut self.alarms);
+        state.read(&mut self.channel_vol);
+        state.read(&mut self.channel_pan);
+        state.read(&mut self.master_pan);
+        state.read(&mut self.surround_decay);
+        state.read(&mut self.shared_mem);
+        state.read(&mut self.locked_channel_mask);
+        state.read(&mut self.weak_locked_channel_mask);
+        state.read(&mut self.cmd_offset);
+        state.read(&mut self.cmd_translate);
+    }
+}
+
 impl Emu {
     pub fn sound_nitro_reset(&mut self) {
         let sound_nitro = &mut self.hle.sound.nitro;
@@ -2441,6 +2480,18 @@ impl Emu {
         }
     }
 
+    // Plays a sequence without a game driving the engine, used by the sound test
+    pub(super) fn sound_nitro_start_offline(&mut self, seq_id: u8, notedata: u32, sbnk: u32, volume: u8) {
+        self.sound_nitro_reset();
+        self.sound_nitro_prepare_sequence(seq_id, notedata, 0, sbnk);
+        self.hle.sound.nitro.sequences[seq_id as usize].volume = volume;
+        self.sound_nitro_start_sequence(seq_id);
+    }
+
+    pub(super) fn sound_nitro_is_sequence_active(&self, seq_id: u8) -> bool {
+        self.hle.sound.nitro.sequences[seq_id as usize].status_flags.active()
+    }
+
     pub fn sound_nitro_on_cmd_event(&mut self, _: u16) {
         self.sound_nitro_process(1);
     }
@@ -0,0 +1,243 @@
+use crate::core::emu::Emu;
+use crate::core::spu::{SoundCnt, SoundSampler, CHANNEL_COUNT, SAMPLE_RATE};
+use crate::core::CpuType::ARM7;
+use crate::core::ARM7_CLOCK;
+use crate::logging::info_println;
+use crate::utils;
+use crate::utils::StrErr;
+use std::fs;
+use std::path::PathBuf;
+
+// Sound data is placed into main memory, which no game code uses in the sound test
+const LOAD_ADDR: u32 = 0x02000000;
+const LOAD_END: u32 = 0x023FE000;
+
+// Info record types of the SDAT INFO and SYMB blocks
+const RECORD_SEQ: u32 = 0;
+const RECORD_BANK: u32 = 2;
+const RECORD_WAVE_ARC: u32 = 3;
+
+pub struct SoundTest {
+    // Path or file name of the SDAT inside of NitroFS, first one if not set
+    pub sdat: Option<String>,
+    // Sequence index or symbol name, "list" prints all sequences
+    pub sequence: String,
+    pub output: PathBuf,
+    pub max_seconds: u32,
+}
+
+struct SeqInfo {
+    index: usize,
+    name: String,
+    file_id: u32,
+    bank: u16,
+    volume: u8,
+}
+
+struct Sdat {
+    data: Vec<u8>,
+    info_offset: u32,
+    symb_offset: u32,
+    fat_offset: u32,
+}
+
+impl Sdat {
+    fn new(data: Vec<u8>) -> Result<Self, StrErr> {
+        if data.len() < 0x30 || &data[0..4] != b"SDAT" {
+            return Err(StrErr::new("Not a SDAT file"));
+        }
+        let sdat = Sdat {
+            info_offset: utils::read_from_mem(&data, 0x18),
+            symb_offset: utils::read_from_mem(&data, 0x10),
+            fat_offset: utils::read_from_mem(&data, 0x20),
+            data,
+        };
+        if sdat.read_u32(sdat.info_offset).is_none() || sdat.read_u32(sdat.fat_offset).is_none() {
+            return Err(StrErr::new("SDAT is missing its INFO or FAT block"));
+        }
+        Ok(sdat)
+    }
+
+    fn read_u32(&self, offset: u32) -> Option<u32> {
+        let bytes = self.data.get(offset as usize..offset as usize + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_u16(&self, offset: u32) -> Option<u16> {
+        let bytes = self.data.get(offset as usize..offset as usize + 2)?;
+        Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    // Returns the offsets to the entries of a record type, relative to the block
+    fn record(&self, block_offset: u32, typ: u32) -> Vec<u32> {
+        let Some(record_offset) = self.read_u32(block_offset + 8 + typ * 4) else {
+            return Vec::new();
+        };
+        let record = block_offset + record_offset;
+        let count = self.read_u32(record).unwrap_or(0);
+        (0..count).map(|i| self.read_u32(record + 4 + i * 4).unwrap_or(0)).collect()
+    }
+
+    fn symbol(&self, typ: u32, index: usize) -> Option<String> {
+        if self.symb_offset == 0 {
+            return None;
+        }
+        let offset = *self.record(self.symb_offset, typ).get(index)?;
+        if offset == 0 {
+            return None;
+        }
+        let start = (self.symb_offset + offset) as usize;
+        let name = self.data.get(start..)?;
+        let end = name.iter().position(|c| *c == 0)?;
+        Some(String::from_utf8_lossy(&name[..end]).to_string())
+    }
+
+    fn sequences(&self) -> Vec<SeqInfo> {
+        self.record(self.info_offset, RECORD_SEQ)
+            .iter()
+            .enumerate()
+            .filter(|(_, offset)| **offset != 0)
+            .filter_map(|(i, offset)| {
+                let entry = self.info_offset + offset;
+                Some(SeqInfo {
+                    index: i,
+                    name: self.symbol(RECORD_SEQ, i).unwrap_or_else(|| format!("SSEQ_{i}")),
+                    file_id: self.read_u16(entry)? as u32,
+                    bank: self.read_u16(entry + 4)?,
+                    volume: *self.data.get(entry as usize + 6)?,
+                })
+            })
+            .collect()
+    }
+
+    // File id of the bank and the file ids of its wave archives
+    fn bank(&self, index: u16) -> Option<(u32, [Option<u32>; 4])> {
+        let offset = *self.record(self.info_offset, RECORD_BANK).get(index as usize)?;
+        if offset == 0 {
+            return None;
+        }
+        let entry = self.info_offset + offset;
+        let file_id = self.read_u16(entry)? as u32;
+
+        let wave_arcs = self.record(self.info_offset, RECORD_WAVE_ARC);
+        let mut swars = [None; 4];
+        for (i, swar) in swars.iter_mut().enumerate() {
+            let wave_arc = self.read_u16(entry + 4 + i as u32 * 2)?;
+            if let Some(offset) = wave_arcs.get(wave_arc as usize).filter(|offset| **offset != 0) {
+                *swar = Some(self.read_u32(self.info_offset + offset)? & 0xFFFFFF);
+            }
+        }
+        Some((file_id, swars))
+    }
+
+    fn file(&self, file_id: u32) -> Option<&[u8]> {
+        let entry = self.fat_offset + 12 + file_id * 16;
+        let offset = self.read_u32(entry)? as usize;
+        let size = self.read_u32(entry + 4)? as usize;
+        self.data.get(offset..offset + size)
+    }
+}
+
+fn write_wav(path: &PathBuf, samples: &[u32]) -> Result<(), StrErr> {
+    let data_size = samples.len() as u32 * 4;
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&2u16.to_le_bytes()); // Stereo
+    wav.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE as u32 * 4).to_le_bytes());
+    wav.extend_from_slice(&4u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        // Left channel is in the lower half
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    fs::write(path, wav).map_err(|e| StrErr::new(format!("Failed to write {path:?}: {e}")))
+}
+
+impl Emu {
+    fn sound_test_load_file(&mut self, addr: &mut u32, data: &[u8]) -> Result<u32, StrErr> {
+        let start = *addr;
+        if start + data.len() as u32 > LOAD_END {
+            return Err(StrErr::new("Sound data doesn't fit into main memory"));
+        }
+        self.mem_write_multiple_slice::<{ ARM7 }, false, _>(start, data);
+        *addr = utils::align_up(start as usize + data.len(), 4) as u32;
+        Ok(start)
+    }
+
+    fn sound_test_load_sdat(&mut self, sdat_name: Option<&str>) -> Result<Sdat, StrErr> {
+        let files = self.cartridge.io.read_nitro_files().map_err(|e| StrErr::new(format!("Failed to read NitroFS: {e}")))?;
+        let (path, file_id) = files
+            .iter()
+            .find(|(path, _)| match sdat_name {
+                None => path.to_lowercase().ends_with(".sdat"),
+                Some(name) => path == name || path.rsplit('/').next() == Some(name),
+            })
+            .ok_or_else(|| StrErr::new("No SDAT found in NitroFS"))?;
+        info_println!("Using {path}");
+        let data = self.cartridge.io.read_nitro_file(*file_id).map_err(|e| StrErr::new(format!("Failed to read {path}: {e}")))?;
+        Sdat::new(data)
+    }
+
+    fn sound_test_is_playing(&self) -> bool {
+        self.sound_nitro_is_sequence_active(0) || (0..CHANNEL_COUNT).any(|i| SoundCnt::from(self.spu_get_cnt(i)).start_status())
+    }
+
+    // Renders a sequence through the nitro sequencer and the spu, without running any guest code
+    pub fn sound_test_run(&mut self, sound_test: &SoundTest, sound_sampler: &mut SoundSampler) -> Result<(), StrErr> {
+        let sdat = self.sound_test_load_sdat(sound_test.sdat.as_deref())?;
+        let sequences = sdat.sequences();
+
+        if sound_test.sequence == "list" {
+            for seq in &sequences {
+                info_println!("{}: {}", seq.index, seq.name);
+            }
+            return Ok(());
+        }
+
+        let seq = sequences
+            .iter()
+            .find(|seq| seq.name == sound_test.sequence || sound_test.sequence.parse::<usize>() == Ok(seq.index))
+            .ok_or_else(|| StrErr::new(format!("Sequence {} not found", sound_test.sequence)))?;
+        info_println!("Rendering {}", seq.name);
+
+        let missing_file = |file_id| StrErr::new(format!("SDAT file {file_id} not found"));
+        let (bank_id, swar_ids) = sdat.bank(seq.bank).ok_or_else(|| StrErr::new(format!("Bank {} not found", seq.bank)))?;
+
+        let mut addr = LOAD_ADDR;
+        let sseq = self.sound_test_load_file(&mut addr, sdat.file(seq.file_id).ok_or_else(|| missing_file(seq.file_id))?)?;
+        let sbnk = self.sound_test_load_file(&mut addr, sdat.file(bank_id).ok_or_else(|| missing_file(bank_id))?)?;
+        // Link the wave archives into the bank, like the sdk does after loading them
+        for (i, swar_id) in swar_ids.iter().enumerate() {
+            let swar = match swar_id {
+                Some(swar_id) => self.sound_test_load_file(&mut addr, sdat.file(*swar_id).ok_or_else(|| missing_file(*swar_id))?)?,
+                None => 0,
+            };
+            self.mem_write::<{ ARM7 }, u32>(sbnk + 0x18 + (i << 3) as u32, swar);
+            self.mem_write::<{ ARM7 }, u32>(sbnk + 0x1C + (i << 3) as u32, 0);
+        }
+
+        let notedata = sseq + self.mem_read::<{ ARM7 }, u32>(sseq + 0x18);
+        self.spu.audio_enabled = true;
+        self.spu_set_sound_bias(!0, 0x200);
+        self.spu_initialize_schedule();
+        self.sound_hle_use_nitro();
+        self.sound_nitro_start_offline(0, notedata, sbnk, seq.volume);
+
+        let end_cycle = sound_test.max_seconds as u64 * ARM7_CLOCK;
+        while self.cm.get_cycles() < end_cycle && self.sound_test_is_playing() {
+            self.cm.jump_to_next_event();
+            self.cm_check_events();
+        }
+
+        let samples = sound_sampler.take_recording();
+        info_println!("Rendered {} samples, hash {:x}", samples.len(), sound_sampler.get_sample_hash().unwrap_or(0));
+        write_wav(&sound_test.output, &samples)
+    }
+}
@@ -1,3 +1,7 @@
+use crate::core::cpu_regs::InterruptFlag;
+use crate::core::emu::Emu;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
+use crate::core::CpuType::ARM7;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
@@ -16,12 +20,17 @@ pub enum Keycode {
     TriggerL = 9,
     X = 10,
     Y = 11,
+    // Held while the lid is closed
+    Hinge = 17,
+    // Not a DS key, holds down the mic input
+    Mic = 24,
 }
 
 pub struct Input {
     key_input: u16,
     ext_key_in: u16,
     key_map: Arc<AtomicU32>,
+    lid_closed: bool,
 }
 
 impl Input {
@@ -30,9 +39,20 @@ impl Input {
             key_input: 0x3FF,
             ext_key_in: 0x007F,
             key_map,
+            lid_closed: false,
         }
     }
 
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.key_input);
+        state.write(&self.ext_key_in);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.key_input);
+        state.read(&mut self.ext_key_in);
+    }
+
     pub fn get_key_input(&self) -> u16 {
         let key_map = self.key_map.load(Ordering::Relaxed);
         (self.key_input & !0x3FF) | (key_map & 0x3FF) as u16
@@ -40,6 +60,26 @@ impl Input {
 
     pub fn get_ext_key_in(&self) -> u16 {
         let key_map = self.key_map.load(Ordering::Relaxed);
-        (self.ext_key_in & !0x43) | ((key_map >> 10) & 0x43) as u16
+        // Unlike the keys, the hinge bit is set while the lid is closed
+        (self.ext_key_in & !0xC3) | ((key_map >> 10) & 0x43) as u16 | ((!key_map >> 10) & 0x80) as u16
+    }
+
+    pub fn is_lid_closed(&self) -> bool {
+        self.key_map.load(Ordering::Relaxed) & (1 << Keycode::Hinge as u32) == 0
+    }
+}
+
+impl Emu {
+    pub fn input_on_frame(&mut self) {
+        let lid_closed = self.input.is_lid_closed();
+        if lid_closed == self.input.lid_closed {
+            return;
+        }
+        self.input.lid_closed = lid_closed;
+
+        // Games poll the hinge bit for closing, opening also wakes up from sleep
+        if !lid_closed {
+            self.cpu_send_interrupt(ARM7, InterruptFlag::ScreensUnfolding);
+        }
     }
 }
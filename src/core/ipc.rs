@@ -1,6 +1,7 @@
 use crate::core::cpu_regs::InterruptFlag;
 use crate::core::emu::Emu;
 use crate::core::hle::arm7_hle::{IpcFifoMessage, IpcFifoTag};
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType;
 use crate::core::CpuType::{ARM7, ARM9};
 use crate::fixed_fifo::FixedFifo;
@@ -212,6 +213,16 @@ impl Ipc {
             },
         }
     }
+
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.sync_regs);
+        state.write(&self.fifo);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.sync_regs);
+        state.read(&mut self.fifo);
+    }
 }
 
 impl Emu {
@@ -3,13 +3,15 @@ use crate::core::cpu_regs::InterruptFlag;
 use crate::core::cycle_manager::EventType;
 use crate::core::emu::Emu;
 use crate::core::memory::dma::DmaTransferMode;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType;
 use crate::core::CpuType::{ARM7, ARM9};
-use crate::logging::debug_println;
+use crate::fat_image::FatImage;
+use crate::logging::{debug_println, info_println};
 use crate::utils;
 use crate::utils::HeapMemU8;
 use bilge::prelude::*;
-use std::ops::Deref;
+use std::ops::{Deref, DerefMut};
 
 #[bitsize(16)]
 #[derive(Copy, Clone, FromBits)]
@@ -54,12 +56,37 @@ impl Default for RomCtrl {
     }
 }
 
+// Status register of the save chip
+const AUX_STATUS_WRITE_ENABLE: u8 = 1 << 1;
+const AUX_STATUS_BLOCK_PROTECT: u8 = 3 << 2;
+
+fn is_save_writable(aux_status: u8, save_size: u32, addr: u32) -> bool {
+    let protected_size = match (aux_status & AUX_STATUS_BLOCK_PROTECT) >> 2 {
+        0 => 0,
+        1 => save_size / 4,
+        2 => save_size / 2,
+        _ => save_size,
+    };
+    aux_status & AUX_STATUS_WRITE_ENABLE != 0 && addr < save_size - protected_size
+}
+
+fn flash_jedec_id(save_size: u32) -> [u8; 3] {
+    let capacity = match save_size {
+        0x40000 => 0x12,
+        0x80000 => 0x13,
+        0x100000 => 0x14,
+        _ => 0x17,
+    };
+    [0x20, 0x40, capacity]
+}
+
 #[derive(Debug)]
 enum CmdMode {
     Header,
     Chip,
     Secure,
     Data,
+    DldiWrite,
     None,
 }
 
@@ -85,6 +112,10 @@ pub struct Cartridge {
     cmd_mode: CmdMode,
     inner: [CartridgeInner; 2],
     read_buf: HeapMemU8<{ 16 * 1024 }>,
+    aux_status: u8,
+    pub fat_image: Option<FatImage>,
+    fat_sector: u32,
+    fat_error: bool,
 }
 
 impl Cartridge {
@@ -94,8 +125,31 @@ impl Cartridge {
             inner: [CartridgeInner::default(), CartridgeInner::default()],
             cmd_mode: CmdMode::None,
             read_buf: HeapMemU8::new(),
+            aux_status: 0,
+            fat_image: None,
+            fat_sector: 0,
+            fat_error: false,
         }
     }
+
+    // Backup memory is not part of the state, loading a state never rolls back the save file
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.cmd_mode);
+        state.write(&self.inner);
+        state.write(&*self.read_buf);
+        state.write(&self.aux_status);
+        state.write(&self.fat_sector);
+        state.write(&self.fat_error);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.cmd_mode);
+        state.read(&mut self.inner);
+        state.read(&mut *self.read_buf);
+        state.read(&mut self.aux_status);
+        state.read(&mut self.fat_sector);
+        state.read(&mut self.fat_error);
+    }
 }
 
 impl Emu {
@@ -128,22 +182,7 @@ impl Emu {
             return 0;
         }
 
-        inner.rom_ctrl.set_data_word_status(false);
-        inner.read_count += 4;
-        if inner.read_count == inner.block_size {
-            inner.rom_ctrl.set_block_start_status(false);
-            if inner.aux_spi_cnt.transfer_ready_irq() {
-                self.cpu_send_interrupt(cpu, InterruptFlag::NdsSlotTransferCompletion);
-            }
-        } else {
-            self.cm.schedule_imm(
-                match cpu {
-                    ARM9 => EventType::CartridgeWordReadArm9,
-                    ARM7 => EventType::CartridgeWordReadArm7,
-                },
-                0,
-            );
-        }
+        self.cartridge_next_word(cpu);
 
         let inner = &mut self.cartridge.inner[cpu];
         match self.cartridge.cmd_mode {
@@ -152,10 +191,7 @@ impl Emu {
                 utils::read_from_mem(self.cartridge.read_buf.deref(), offset)
             }
             CmdMode::Chip => 0x00001FC2,
-            CmdMode::Secure => {
-                todo!()
-            }
-            CmdMode::Data => {
+            CmdMode::Secure | CmdMode::Data => {
                 let offset = inner.read_count as u32 - 4;
                 if offset + 3 < inner.block_size as u32 {
                     utils::read_from_mem(self.cartridge.read_buf.deref(), offset)
@@ -163,7 +199,47 @@ impl Emu {
                     0xFFFFFFFF
                 }
             }
-            CmdMode::None => 0xFFFFFFFF,
+            CmdMode::DldiWrite | CmdMode::None => 0xFFFFFFFF,
+        }
+    }
+
+    pub fn cartridge_set_rom_data_out(&mut self, cpu: CpuType, mask: u32, value: u32) {
+        let inner = &mut self.cartridge.inner[cpu];
+        if !matches!(self.cartridge.cmd_mode, CmdMode::DldiWrite) || !inner.rom_ctrl.data_word_status() || !inner.rom_ctrl.block_start_status() {
+            return;
+        }
+
+        let offset = inner.read_count as u32;
+        utils::write_to_mem(self.cartridge.read_buf.deref_mut(), offset, value & mask);
+        if offset + 4 == inner.block_size as u32 {
+            let data = &self.cartridge.read_buf[..inner.block_size as usize];
+            if let Some(fat_image) = &mut self.cartridge.fat_image {
+                if let Err(err) = fat_image.write_sectors(self.cartridge.fat_sector, data) {
+                    info_println!("Failed to write FAT image sector {:x}: {err}", self.cartridge.fat_sector);
+                    self.cartridge.fat_error = true;
+                }
+            }
+        }
+        self.cartridge_next_word(cpu);
+    }
+
+    fn cartridge_next_word(&mut self, cpu: CpuType) {
+        let inner = &mut self.cartridge.inner[cpu];
+        inner.rom_ctrl.set_data_word_status(false);
+        inner.read_count += 4;
+        if inner.read_count == inner.block_size {
+            inner.rom_ctrl.set_block_start_status(false);
+            if inner.aux_spi_cnt.transfer_ready_irq() {
+                self.cpu_send_interrupt(cpu, InterruptFlag::NdsSlotTransferCompletion);
+            }
+        } else {
+            self.cm.schedule_imm(
+                match cpu {
+                    ARM9 => EventType::CartridgeWordReadArm9,
+                    ARM7 => EventType::CartridgeWordReadArm7,
+                },
+                0,
+            );
         }
     }
 
@@ -182,6 +258,17 @@ impl Emu {
             inner.aux_command = value;
             inner.aux_address = 0;
             inner.aux_spi_data = 0;
+            match value {
+                0x06 => self.cartridge.aux_status |= AUX_STATUS_WRITE_ENABLE,
+                0x04 => self.cartridge.aux_status &= !AUX_STATUS_WRITE_ENABLE,
+                0xC7 if self.cartridge.io.save_file_size >= 0x40000 => {
+                    if self.cartridge.aux_status & AUX_STATUS_WRITE_ENABLE != 0 {
+                        self.cartridge.io.erase_save_buf(0, self.cartridge.io.save_file_size);
+                    }
+                    self.cartridge.aux_status &= !AUX_STATUS_WRITE_ENABLE;
+                }
+                _ => {}
+            }
         } else {
             if self.cartridge.io.save_file_size == 0 {
                 match inner.aux_command {
@@ -220,12 +307,21 @@ impl Emu {
                             inner.aux_spi_data = 0;
                         } else {
                             let addr_offset = if inner.aux_command == 0x0A { 0x100 } else { 0 };
-                            self.cartridge.io.write_save_buf((inner.aux_address + addr_offset) & (save_size - 1), value);
+                            let addr = (inner.aux_address + addr_offset) & (save_size - 1);
+                            if is_save_writable(self.cartridge.aux_status, save_size, addr) {
+                                self.cartridge.io.write_save_buf(addr, value);
+                            }
                             inner.aux_address += 1;
                             inner.aux_spi_data = 0;
                         }
                     }
-                    0x01 | 0x05 => inner.aux_spi_data = 0,
+                    0x01 => {
+                        if self.cartridge.aux_status & AUX_STATUS_WRITE_ENABLE != 0 {
+                            self.cartridge.aux_status = (self.cartridge.aux_status & !AUX_STATUS_BLOCK_PROTECT) | (value & AUX_STATUS_BLOCK_PROTECT);
+                        }
+                        inner.aux_spi_data = 0;
+                    }
+                    0x05 => inner.aux_spi_data = self.cartridge.aux_status,
                     _ => {
                         debug_println!("Unknown EEPROM 0.5KB command {:x}", inner.aux_command);
                         inner.aux_spi_data = 0xFF;
@@ -246,40 +342,69 @@ impl Emu {
                             inner.aux_address |= (value as u32) << ((if save_size == 0x20000 { 3 } else { 2 } - inner.aux_write_count) * 8);
                             inner.aux_spi_data = 0;
                         } else {
-                            if inner.aux_address < save_size {
+                            if is_save_writable(self.cartridge.aux_status, save_size, inner.aux_address) {
                                 self.cartridge.io.write_save_buf(inner.aux_address, value);
                             }
                             inner.aux_address += 1;
                             inner.aux_spi_data = 0;
                         }
                     }
+                    0x01 => {
+                        if self.cartridge.aux_status & AUX_STATUS_WRITE_ENABLE != 0 {
+                            self.cartridge.aux_status = (self.cartridge.aux_status & !AUX_STATUS_BLOCK_PROTECT) | (value & AUX_STATUS_BLOCK_PROTECT);
+                        }
+                        inner.aux_spi_data = 0;
+                    }
+                    0x05 => inner.aux_spi_data = self.cartridge.aux_status,
                     _ => {
                         debug_println!("Unknown EEPROM/FRAM command {:x}", inner.aux_command);
                         inner.aux_spi_data = 0;
                     }
                 },
                 0x40000 | 0x80000 | 0x100000 | 0x800000 => match inner.aux_command {
-                    0x03 => {
+                    0x03 | 0x0B => {
+                        // FAST READ has a dummy byte after the address
+                        let data_start = if inner.aux_command == 0x0B { 5 } else { 4 };
                         if inner.aux_write_count < 4 {
                             inner.aux_address |= (value as u32) << ((3 - inner.aux_write_count) * 8);
                             inner.aux_spi_data = 0;
+                        } else if inner.aux_write_count < data_start {
+                            inner.aux_spi_data = 0;
                         } else {
                             inner.aux_spi_data = if inner.aux_address < save_size { self.cartridge.io.read_save_buf(inner.aux_address) } else { 0 };
                             inner.aux_address += 1;
                         }
                     }
-                    0x0A => {
+                    0x02 | 0x0A => {
                         if inner.aux_write_count < 4 {
                             inner.aux_address |= (value as u32) << ((3 - inner.aux_write_count) * 8);
                             inner.aux_spi_data = 0;
                         } else {
-                            if inner.aux_address < save_size {
+                            if is_save_writable(self.cartridge.aux_status, save_size, inner.aux_address) {
+                                // Page program can only clear bits, page write erases first
+                                let value = if inner.aux_command == 0x02 {
+                                    self.cartridge.io.read_save_buf(inner.aux_address) & value
+                                } else {
+                                    value
+                                };
                                 self.cartridge.io.write_save_buf(inner.aux_address, value);
                             }
                             inner.aux_address += 1;
                             inner.aux_spi_data = 0;
                         }
                     }
+                    0xDB | 0xD8 => {
+                        if inner.aux_write_count < 4 {
+                            inner.aux_address |= (value as u32) << ((3 - inner.aux_write_count) * 8);
+                            if inner.aux_write_count == 3 && is_save_writable(self.cartridge.aux_status, save_size, inner.aux_address) {
+                                let erase_size = if inner.aux_command == 0xDB { 0x100 } else { 0x10000 };
+                                self.cartridge.io.erase_save_buf(inner.aux_address & !(erase_size - 1), erase_size);
+                            }
+                        }
+                        inner.aux_spi_data = 0;
+                    }
+                    0x05 => inner.aux_spi_data = self.cartridge.aux_status,
+                    0x9F => inner.aux_spi_data = flash_jedec_id(save_size).get(inner.aux_write_count as usize - 1).copied().unwrap_or(0xFF),
                     0x08 => inner.aux_spi_data = if self.cartridge.io.header.game_code[0] == b'I' { 0xAA } else { 0 },
                     _ => {
                         debug_println!("Unknown FLASH command {:x}", inner.aux_command);
@@ -295,6 +420,10 @@ impl Emu {
         if inner.aux_spi_cnt.hold_chipselect() {
             inner.aux_write_count += 1;
         } else {
+            // Write enable latch resets after every completed write
+            if inner.aux_write_count > 0 && matches!(inner.aux_command, 0x01 | 0x02 | 0x0A | 0xD8 | 0xDB) {
+                self.cartridge.aux_status &= !AUX_STATUS_WRITE_ENABLE;
+            }
             inner.aux_write_count = 0;
         }
     }
@@ -328,9 +457,9 @@ impl Emu {
             _ => 0x100 << data_block_size,
         };
 
-        let cmd = u64::from_be(inner.bus_cmd_out);
+        let mut cmd = u64::from_be(inner.bus_cmd_out);
         if inner.encrypted {
-            todo!()
+            cmd = self.cartridge.io.key1_decrypt_cmd(cmd);
         }
 
         self.cartridge.cmd_mode = CmdMode::None;
@@ -343,7 +472,10 @@ impl Emu {
             inner.encrypted = true;
         } else if (cmd >> 60) == 0x2 {
             self.cartridge.cmd_mode = CmdMode::Secure;
-            todo!()
+            let read_addr = (((cmd >> 44) & 0xF) as u32) << 12;
+            self.cartridge.io.read_secure_area(read_addr, &mut self.cartridge.read_buf[..inner.block_size as usize]).unwrap();
+        } else if inner.encrypted && (cmd >> 60) == 0x4 {
+            // KEY2 is applied by the slot hardware on both ends, commands and data are passed through as is
         } else if (cmd >> 60) == 0xA {
             inner.encrypted = false;
         } else if (cmd >> 56) == 0xB7 {
@@ -353,6 +485,24 @@ impl Emu {
                 read_addr = 0x8000 + (read_addr & 0x1FF);
             }
             self.cartridge.io.read_slice(read_addr, &mut self.cartridge.read_buf[..inner.block_size as usize]).unwrap();
+        } else if self.cartridge.fat_image.is_some() && (cmd >> 56) == 0xC0 {
+            // Sector reads of the patched DLDI driver, see dldi.rs
+            self.cartridge.cmd_mode = CmdMode::Data;
+            let sector = (cmd as u32).swap_bytes();
+            let data = &mut self.cartridge.read_buf[..inner.block_size as usize];
+            if let Err(err) = self.cartridge.fat_image.as_mut().unwrap().read_sectors(sector, data) {
+                info_println!("Failed to read FAT image sector {sector:x}: {err}");
+                data.fill(0);
+                self.cartridge.fat_error = true;
+            }
+        } else if self.cartridge.fat_image.is_some() && (cmd >> 56) == 0xC1 {
+            self.cartridge.cmd_mode = CmdMode::DldiWrite;
+            self.cartridge.fat_sector = (cmd as u32).swap_bytes();
+        } else if self.cartridge.fat_image.is_some() && (cmd >> 56) == 0xC2 {
+            // Failed sector transfers since the last query
+            self.cartridge.cmd_mode = CmdMode::Data;
+            utils::write_to_mem(self.cartridge.read_buf.deref_mut(), 0, self.cartridge.fat_error as u32);
+            self.cartridge.fat_error = false;
         } else if cmd != 0x9F00000000000000 {
             debug_println!("Unknown rom transfer command {:x}", cmd);
         }
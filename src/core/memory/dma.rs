@@ -1,6 +1,7 @@
 use crate::core::cpu_regs::InterruptFlag;
 use crate::core::cycle_manager::EventType;
 use crate::core::emu::Emu;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType;
 use crate::logging::debug_println;
 use crate::utils;
@@ -116,6 +117,14 @@ impl Dma {
             src_buf: Vec::new(),
         }
     }
+
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.channels);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.channels);
+    }
 }
 
 impl Emu {
@@ -71,7 +71,10 @@ io_read!(
     ]
 );
 
-io_read!(IoArm7ReadLutUpper, [(io32(0x100000), |emu| emu.ipc_fifo_recv(ARM7)), (io32(0x100010), |emu| todo!())]);
+io_read!(
+    IoArm7ReadLutUpper,
+    [(io32(0x100000), |emu| emu.ipc_fifo_recv(ARM7)), (io32(0x100010), |emu| emu.cartridge_get_rom_data_in(ARM7))]
+);
 
 io_read!(
     IoArm7ReadLutWifi,
@@ -173,7 +176,7 @@ io_write!(
         (io16(0x10A), |mask, value, emu| emu.timers_set_cnt_h(ARM7, 2, mask, value)),
         (io16(0x10C), |mask, value, emu| emu.timers_set_cnt_l(ARM7, 3, mask, value)),
         (io16(0x10E), |mask, value, emu| emu.timers_set_cnt_h(ARM7, 3, mask, value)),
-        (io8(0x138), |value, emu| emu.rtc.set_rtc(value)),
+        (io8(0x138), |value, emu| emu.rtc.set_rtc(value, emu.cm.get_cycles())),
         (io16(0x180), |mask, value, emu| emu.ipc_set_sync_reg(ARM7, mask, value)),
         (io16(0x184), |mask, value, emu| emu.ipc_set_fifo_cnt(ARM7, mask, value)),
         (io32(0x188), |mask, value, emu| emu.ipc_fifo_send(ARM7, mask, value)),
@@ -183,12 +186,12 @@ io_write!(
         (io32(0x1A8), |mask, value, emu| emu.cartridge_set_bus_cmd_out_l(ARM7, mask, value)),
         (io32(0x1AC), |mask, value, emu| emu.cartridge_set_bus_cmd_out_h(ARM7, mask, value)),
         (io16(0x1C0), |mask, value, emu| emu.spi.set_cnt(mask, value)),
-        (io8(0x1C2), |value, emu| emu.spi.set_data(value)),
+        (io8(0x1C2), |value, emu| emu.spi.set_data(value, emu.cm.get_cycles())),
         (io8(0x208), |value, emu| emu.cpu_set_ime(ARM7, value)),
         (io32(0x210), |mask, value, emu| emu.cpu_set_ie(ARM7, mask, value)),
         (io32(0x214), |mask, value, emu| emu.cpu_set_irf(ARM7, mask, value)),
         (io8(0x300), |value, emu| emu.cpu_set_post_flg(ARM7, value)),
-        (io8(0x301), |value, emu| todo!()),
+        (io8(0x301), |value, emu| emu.cpu_set_halt_cnt(ARM7, value)),
         (io32(0x400), |mask, value, emu| emu.spu_set_cnt(0, mask, value)),
         (io32(0x404), |mask, value, emu| emu.spu_set_sad(0, mask, value)),
         (io16(0x408), |mask, value, emu| emu.spu_set_tmr(0, mask, value)),
@@ -280,6 +283,8 @@ io_write!(
     ]
 );
 
+io_write!(IoArm7WriteLutUpper, [(io32(0x100010), |mask, value, emu| emu.cartridge_set_rom_data_out(ARM7, mask, value))]);
+
 io_write!(
     IoArm7WriteLutWifi,
     [
@@ -375,6 +380,26 @@ impl IoArm7WriteLut {
     }
 }
 
+impl IoArm7WriteLutUpper {
+    pub fn write_fixed_slice<T: Convert>(addr: u32, slice: &[T], emu: &mut Emu) {
+        let lut_addr = addr - Self::MIN_ADDR;
+        let (func, write_size, offset) = unsafe { Self::_LUT.get_unchecked(lut_addr as usize) };
+
+        if *write_size < size_of::<T>() as u8 {
+            for value in slice {
+                Self::write((*value).into(), addr, size_of::<T>() as u8, emu);
+            }
+        } else {
+            let mask = 0xFFFFFFFF >> ((4 - size_of::<T>()) << 3);
+            let mask = mask << *offset;
+            for value in slice {
+                let value = (*value).into() << *offset;
+                func(mask, value, emu)
+            }
+        }
+    }
+}
+
 impl IoArm7WriteLutWifi {
     pub fn write_fixed_slice<T: Convert>(addr: u32, slice: &[T], emu: &mut Emu) {
         let lut_addr = addr - Self::MIN_ADDR;
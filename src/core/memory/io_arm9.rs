@@ -1,5 +1,5 @@
 use crate::core::emu::Emu;
-use crate::core::memory::io_arm9_lut::{IoArm9ReadLut, IoArm9ReadLutUpper, IoArm9WriteLut};
+use crate::core::memory::io_arm9_lut::{IoArm9ReadLut, IoArm9ReadLutUpper, IoArm9WriteLut, IoArm9WriteLutUpper};
 use crate::utils::Convert;
 use std::intrinsics::likely;
 
@@ -15,12 +15,16 @@ impl Emu {
     pub fn io_arm9_write<T: Convert>(&mut self, addr_offset: u32, value: T) {
         if likely(IoArm9WriteLut::is_in_range(addr_offset)) {
             IoArm9WriteLut::write(value.into(), addr_offset, size_of::<T>() as u8, self);
+        } else if IoArm9WriteLutUpper::is_in_range(addr_offset) {
+            IoArm9WriteLutUpper::write(value.into(), addr_offset, size_of::<T>() as u8, self);
         }
     }
 
     pub fn io_arm9_write_fixed_slice<T: Convert>(&mut self, addr_offset: u32, slice: &[T]) {
         if likely(IoArm9WriteLut::is_in_range(addr_offset)) {
             IoArm9WriteLut::write_fixed_slice(addr_offset, slice, self);
+        } else if IoArm9WriteLutUpper::is_in_range(addr_offset) {
+            IoArm9WriteLutUpper::write_fixed_slice(addr_offset, slice, self);
         }
     }
 }
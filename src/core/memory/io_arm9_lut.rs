@@ -399,6 +399,28 @@ io_write!(
     ]
 );
 
+io_write!(IoArm9WriteLutUpper, [(io32(0x100010), |mask, value, emu| emu.cartridge_set_rom_data_out(ARM9, mask, value))]);
+
+impl IoArm9WriteLutUpper {
+    pub fn write_fixed_slice<T: Convert>(addr: u32, slice: &[T], emu: &mut Emu) {
+        let lut_addr = addr - Self::MIN_ADDR;
+        let (func, write_size, offset) = unsafe { Self::_LUT.get_unchecked(lut_addr as usize) };
+
+        if *write_size < size_of::<T>() as u8 {
+            for value in slice {
+                Self::write((*value).into(), addr, size_of::<T>() as u8, emu);
+            }
+        } else {
+            let mask = 0xFFFFFFFF >> ((4 - size_of::<T>()) << 3);
+            let mask = mask << *offset;
+            for value in slice {
+                let value = (*value).into() << *offset;
+                func(mask, value, emu)
+            }
+        }
+    }
+}
+
 impl IoArm9WriteLut {
     pub fn write_fixed_slice<T: Convert>(addr: u32, slice: &[T], emu: &mut Emu) {
         let lut_addr = addr - Self::MIN_ADDR;
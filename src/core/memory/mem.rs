@@ -7,6 +7,7 @@ use crate::core::memory::regions;
 use crate::core::memory::vram::Vram;
 use crate::core::memory::wifi::Wifi;
 use crate::core::memory::wram::Wram;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType;
 use crate::core::CpuType::ARM9;
 use crate::logging::debug_println;
@@ -28,6 +29,8 @@ pub struct Memory {
     pub oam: Oam,
     pub mmu_arm9: MmuArm9,
     pub mmu_arm7: MmuArm7,
+    pub bios9: Vec<u8>,
+    pub bios7: Vec<u8>,
 }
 
 macro_rules! create_io_read_lut {
@@ -82,7 +85,7 @@ macro_rules! read_dtcm {
 }
 
 macro_rules! read_itcm {
-    ($cpu:expr, $tcm:expr, $addr:expr, $emu:expr, $shm_offset:ident, $read:block, $read_zero:block) => {{
+    ($cpu:expr, $tcm:expr, $addr:expr, $emu:expr, $shm_offset:ident, $read:block, $read_zero:block, $read_bios:block) => {{
         match $cpu {
             ARM9 => {
                 if $tcm {
@@ -94,7 +97,7 @@ macro_rules! read_itcm {
                 }
                 $read_zero
             }
-            ARM7 => $read_zero,
+            ARM7 => $read_bios,
         }
     }};
 }
@@ -219,7 +222,9 @@ impl<const CPU: CpuType, const TCM: bool, T: Convert> MemoryIo<CPU, TCM, T> {
     }
 
     fn read_itcm(addr: u32, emu: &mut Emu) -> T {
-        read_itcm!(CPU, TCM, addr, emu, shm_offset, { utils::read_from_mem(&emu.mem.shm, shm_offset) }, { T::from(0) })
+        read_itcm!(CPU, TCM, addr, emu, shm_offset, { utils::read_from_mem(&emu.mem.shm, shm_offset) }, { T::from(0) }, {
+            emu.mem.read_bios::<CPU, T>(addr)
+        })
     }
 
     fn read_main(addr: u32, emu: &mut Emu) -> T {
@@ -265,9 +270,9 @@ impl<const CPU: CpuType, const TCM: bool, T: Convert> MemoryIo<CPU, TCM, T> {
         unsafe { unreachable_unchecked() }
     }
 
-    fn read_bios(_: u32, _: &mut Emu) -> T {
+    fn read_bios(addr: u32, emu: &mut Emu) -> T {
         match CPU {
-            ARM9 => T::from(0),
+            ARM9 => emu.mem.read_bios::<CPU, T>(addr),
             ARM7 => unsafe { unreachable_unchecked() },
         }
     }
@@ -347,6 +352,9 @@ impl<const CPU: CpuType, const TCM: bool, T: Convert> MemoryMultipleSliceIo<CPU,
             },
             {
                 slice.fill(T::from(0));
+            },
+            {
+                emu.mem.read_bios_slice::<CPU, T>(addr, slice);
             }
         );
     }
@@ -406,8 +414,8 @@ impl<const CPU: CpuType, const TCM: bool, T: Convert> MemoryMultipleSliceIo<CPU,
         unsafe { unreachable_unchecked() }
     }
 
-    fn read_bios(_: u32, slice: &mut [T], _: &mut Emu) {
-        slice.fill(T::from(0));
+    fn read_bios(addr: u32, slice: &mut [T], emu: &mut Emu) {
+        emu.mem.read_bios_slice::<CPU, T>(addr, slice);
     }
 
     fn write(addr: u32, slice: &[T], emu: &mut Emu) {
@@ -499,6 +507,9 @@ impl<const CPU: CpuType, const TCM: bool, T: Convert> MemoryFixedSliceIo<CPU, TC
             },
             {
                 slice.fill(T::from(0));
+            },
+            {
+                slice.fill(emu.mem.read_bios::<CPU, T>(addr));
             }
         )
     }
@@ -554,8 +565,8 @@ impl<const CPU: CpuType, const TCM: bool, T: Convert> MemoryFixedSliceIo<CPU, TC
         unsafe { unreachable_unchecked() }
     }
 
-    fn read_bios(_: u32, slice: &mut [T], _: &mut Emu) {
-        slice.fill(T::from(0));
+    fn read_bios(addr: u32, slice: &mut [T], emu: &mut Emu) {
+        slice.fill(emu.mem.read_bios::<CPU, T>(addr));
     }
 
     fn write(addr: u32, slice: &[T], emu: &mut Emu) {
@@ -701,8 +712,49 @@ impl Memory {
             oam: Oam::new(),
             mmu_arm9: MmuArm9::new(),
             mmu_arm7: MmuArm7::new(),
+            bios9: Vec::new(),
+            bios7: Vec::new(),
         }
     }
+
+    // Reads zero when no bios dump was loaded
+    pub fn read_bios<const CPU: CpuType, T: Convert>(&self, addr: u32) -> T {
+        let bios = match CPU {
+            ARM9 => &self.bios9,
+            ARM7 => &self.bios7,
+        };
+        if bios.is_empty() {
+            T::from(0)
+        } else {
+            utils::read_from_mem(bios, addr & (bios.len() as u32 - 1) & !(size_of::<T>() as u32 - 1))
+        }
+    }
+
+    pub fn read_bios_slice<const CPU: CpuType, T: Convert>(&self, addr: u32, slice: &mut [T]) {
+        for (i, value) in slice.iter_mut().enumerate() {
+            *value = self.read_bios::<CPU, T>(addr + (i * size_of::<T>()) as u32);
+        }
+    }
+
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write_slice(&self.shm);
+        self.wram.save_state(state);
+        state.write(&*self.wifi.mem);
+        state.write(&*self.palettes.mem);
+        state.write(&*self.oam.mem);
+        self.vram.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read_slice(&mut self.shm);
+        self.wram.load_state(state);
+        state.read(&mut *self.wifi.mem);
+        state.read(&mut *self.palettes.mem);
+        self.palettes.dirty = true;
+        state.read(&mut *self.oam.mem);
+        self.oam.dirty = true;
+        self.vram.load_state(state);
+    }
 }
 
 impl Emu {
@@ -1,5 +1,6 @@
 use crate::bitset::Bitset;
 use crate::core::emu::Emu;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType;
 use crate::core::CpuType::{ARM7, ARM9};
 use crate::logging::debug_println;
@@ -55,9 +56,14 @@ impl<const SIZE: usize> VramMap<SIZE> {
         let end = (self.offset + index as usize + size) >> BANK_SECTION_SHIFT;
         for section in start..end + 1 {
             vram_banks.dirty_sections += section;
+            vram_banks.scanline_dirty_sections += section;
         }
     }
 
+    pub fn is_dirty(&self, sections: &Bitset<6>) -> bool {
+        !self.is_null() && ((self.offset >> BANK_SECTION_SHIFT)..((self.offset + SIZE - 1) >> BANK_SECTION_SHIFT) + 1).any(|section| sections.contains(section))
+    }
+
     pub fn as_ref(&self, vram: &[u8; TOTAL_SIZE]) -> &[u8; SIZE] {
         unsafe { (vram.as_ptr().add(self.offset) as *const [u8; SIZE]).as_ref_unchecked() }
     }
@@ -99,6 +105,11 @@ impl<const SIZE: usize, const MAX_OVERLAP: usize> OverlapSection<SIZE, MAX_OVERL
         T::from(ret)
     }
 
+    fn is_dirty(&self, sections: &Bitset<6>) -> bool {
+        unsafe { assert_unchecked((self.count as usize) <= MAX_OVERLAP) };
+        self.overlaps[..self.count as usize].iter().any(|map| map.is_dirty(sections))
+    }
+
     fn read_all(&self, index: u32, buf: &mut [u8; SIZE], vram: &[u8; TOTAL_SIZE]) {
         unsafe { assert_unchecked((self.count as usize) <= MAX_OVERLAP) };
         if self.count == 1 {
@@ -195,6 +206,16 @@ where
         }
     }
 
+    // Only rereads the chunks mapped to the given bank sections, chunks without mapping are already zeroed
+    fn read_dirty(&self, buf: &mut [u8; SIZE], vram: &[u8; TOTAL_SIZE], sections: &Bitset<6>) {
+        for (i, section) in self.sections.iter().enumerate() {
+            if section.is_dirty(sections) {
+                let chunk_buf = unsafe { (buf[i * CHUNK_SIZE..(i + 1) * CHUNK_SIZE].as_mut_ptr() as *mut [u8; CHUNK_SIZE]).as_mut_unchecked() };
+                section.read_all(0, chunk_buf, vram);
+            }
+        }
+    }
+
     fn write<T: utils::Convert>(&mut self, mut addr: u32, value: T, vram_banks: &mut VramBanks) {
         addr %= SIZE as u32;
         let section_index = addr as usize / CHUNK_SIZE;
@@ -257,6 +278,8 @@ const_assert_eq!(TOTAL_SIZE, 656 * 1024);
 pub struct VramBanks {
     pub mem: HeapMemU8<TOTAL_SIZE>,
     dirty_sections: Bitset<6>,
+    // Tracked separately, so the scanline renderer doesn't interfere with the per frame copy
+    scanline_dirty_sections: Bitset<6>,
 }
 
 macro_rules! create_vram_bank {
@@ -284,6 +307,22 @@ impl VramBanks {
         }
     }
 
+    // Returns the copied sections
+    pub fn copy_scanline_dirty_sections(&mut self, other: &mut VramBanks) -> Bitset<6> {
+        let sections = self.scanline_dirty_sections;
+        if sections.is_empty() {
+            return sections;
+        }
+        for i in 0..TOTAL_SIZE / BANK_SECTION_SIZE {
+            if sections.contains(i) {
+                let offset = i << BANK_SECTION_SHIFT;
+                other.mem[offset..offset + BANK_SECTION_SIZE].copy_from_slice(&self.mem[offset..offset + BANK_SECTION_SIZE]);
+            }
+        }
+        self.scanline_dirty_sections.clear();
+        sections
+    }
+
     create_vram_bank!(a, 0, BANK_A_SIZE);
     create_vram_bank!(b, BANK_A_SIZE, BANK_B_SIZE);
     create_vram_bank!(c, BANK_A_SIZE * 2, BANK_C_SIZE);
@@ -407,6 +446,34 @@ impl VramMaps {
         }
     }
 
+    pub fn read_dirty_lcdc(&self, buf: &mut [u8; TOTAL_SIZE], vram: &[u8; TOTAL_SIZE], sections: &Bitset<6>) {
+        self.lcdc.read_dirty(buf, vram, sections)
+    }
+
+    pub fn read_dirty_bg_a(&self, buf: &mut [u8; BG_A_SIZE as usize], vram: &[u8; TOTAL_SIZE], sections: &Bitset<6>) {
+        self.bg_a.read_dirty(buf, vram, sections)
+    }
+
+    pub fn read_dirty_obj_a(&self, buf: &mut [u8; OBJ_A_SIZE as usize], vram: &[u8; TOTAL_SIZE], sections: &Bitset<6>) {
+        self.obj_a.read_dirty(buf, vram, sections)
+    }
+
+    pub fn read_dirty_bg_b(&self, buf: &mut [u8; BG_B_SIZE as usize], vram: &[u8; TOTAL_SIZE], sections: &Bitset<6>) {
+        self.bg_b.read_dirty(buf, vram, sections)
+    }
+
+    pub fn read_dirty_obj_b(&self, buf: &mut [u8; OBJ_B_SIZE as usize], vram: &[u8; TOTAL_SIZE], sections: &Bitset<6>) {
+        self.obj_b.read_dirty(buf, vram, sections)
+    }
+
+    pub fn is_ext_palette_a_dirty(&self, sections: &Bitset<6>) -> bool {
+        self.bg_ext_palette_a.iter().any(|map| map.is_dirty(sections)) || self.obj_ext_palette_a.is_dirty(sections)
+    }
+
+    pub fn is_ext_palette_b_dirty(&self, sections: &Bitset<6>) -> bool {
+        self.bg_ext_palette_b.iter().any(|map| map.is_dirty(sections)) || self.obj_ext_palette_b.is_dirty(sections)
+    }
+
     pub fn read_all_tex_rear_plane_img(&self, buf: &mut [u8; TEX_REAR_PLANE_IMAGE_SIZE as usize], vram: &[u8; TOTAL_SIZE]) {
         for i in 0..self.tex_rear_plane_img.len() {
             let map = &self.tex_rear_plane_img[i];
@@ -442,6 +509,21 @@ pub struct Vram {
 }
 
 impl Vram {
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.stat);
+        state.write(&self.cnt);
+        state.write(&*self.banks.mem);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.stat);
+        state.read(&mut self.cnt);
+        state.read(&mut *self.banks.mem);
+        self.banks.dirty_sections = !Bitset::new();
+        self.banks.scanline_dirty_sections = !Bitset::new();
+        self.rebuild_maps();
+    }
+
     pub fn rebuild_maps(&mut self) {
         self.maps.reset();
         self.arm7.reset();
@@ -1,5 +1,6 @@
 use crate::core::emu::Emu;
 use crate::core::memory::regions;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType;
 use crate::core::CpuType::ARM7;
 use std::hint::unreachable_unchecked;
@@ -67,6 +68,15 @@ impl Wram {
         }
     }
 
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.cnt);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.cnt);
+        self.init_maps();
+    }
+
     pub fn get_shm_offset<const CPU: CpuType>(&self, addr: u32) -> usize {
         match CPU {
             ARM9 => self.arm9_map.shm_offset + (addr as usize & (self.arm9_map.size - 1)),
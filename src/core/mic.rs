@@ -0,0 +1,195 @@
+use crate::core::input::Keycode;
+use crate::utils::StrErr;
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+pub const MIC_SAMPLE_RATE: u32 = 32768;
+const MIC_SAMPLE_CYCLES: u64 = 1024;
+
+pub trait MicBackend: Send {
+    // Produces the next sample at MIC_SAMPLE_RATE
+    fn next_sample(&mut self) -> i16;
+
+    // Live sources are always sampled, others only while the mic key is held
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {}
+}
+
+pub struct SilentMic;
+
+impl MicBackend for SilentMic {
+    fn next_sample(&mut self) -> i16 {
+        0
+    }
+}
+
+pub struct ToneMic {
+    freq: f32,
+    phase: f32,
+}
+
+impl ToneMic {
+    pub fn new(freq: f32) -> Self {
+        ToneMic { freq, phase: 0f32 }
+    }
+}
+
+impl MicBackend for ToneMic {
+    fn next_sample(&mut self) -> i16 {
+        self.phase = (self.phase + self.freq / MIC_SAMPLE_RATE as f32) % 1f32;
+        ((self.phase * 2f32 * PI).sin() * i16::MAX as f32) as i16
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0f32;
+    }
+}
+
+// Loud white noise, what games expect when blowing into the mic
+pub struct NoiseMic {
+    seed: u32,
+}
+
+impl NoiseMic {
+    pub fn new() -> Self {
+        NoiseMic { seed: 0x12345678 }
+    }
+}
+
+impl MicBackend for NoiseMic {
+    fn next_sample(&mut self) -> i16 {
+        self.seed = self.seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (self.seed >> 16) as i16
+    }
+}
+
+pub struct WavMic {
+    samples: Vec<i16>,
+    // 16.16 fixed point
+    pos: u64,
+    step: u64,
+}
+
+impl WavMic {
+    pub fn new(path: &Path) -> Result<Self, StrErr> {
+        let data = fs::read(path).map_err(|e| StrErr::new(format!("Failed to read {path:?}: {e}")))?;
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Err(StrErr::new(format!("{path:?} is not a wav file")));
+        }
+
+        let mut format = None;
+        let mut samples = None;
+        let mut offset = 12;
+        while offset + 8 <= data.len() {
+            let id = &data[offset..offset + 4];
+            let size = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            let chunk = &data[offset + 8..(offset + 8 + size).min(data.len())];
+            match id {
+                b"fmt " if chunk.len() >= 16 => {
+                    let audio_format = u16::from_le_bytes([chunk[0], chunk[1]]);
+                    let channels = u16::from_le_bytes([chunk[2], chunk[3]]) as usize;
+                    let sample_rate = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+                    let bits = u16::from_le_bytes([chunk[14], chunk[15]]);
+                    if audio_format != 1 || channels == 0 || (bits != 8 && bits != 16) {
+                        return Err(StrErr::new(format!("{path:?} must be 8 or 16 bit pcm")));
+                    }
+                    format = Some((channels, sample_rate, bits));
+                }
+                b"data" => {
+                    let Some((channels, _, bits)) = format else {
+                        return Err(StrErr::new(format!("{path:?} has no format before its data")));
+                    };
+                    // Mix all channels down to mono
+                    let frame_size = channels * bits as usize / 8;
+                    samples = Some(
+                        chunk
+                            .chunks_exact(frame_size)
+                            .map(|frame| {
+                                let sum: i32 = if bits == 8 {
+                                    frame.iter().map(|sample| ((*sample as i32) - 0x80) << 8).sum()
+                                } else {
+                                    frame.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as i32).sum()
+                                };
+                                (sum / channels as i32) as i16
+                            })
+                            .collect::<Vec<_>>(),
+                    );
+                }
+                _ => {}
+            }
+            offset += 8 + size + (size & 1);
+        }
+
+        match (format, samples) {
+            (Some((_, sample_rate, _)), Some(samples)) if !samples.is_empty() => Ok(WavMic {
+                samples,
+                pos: 0,
+                step: ((sample_rate as u64) << 16) / MIC_SAMPLE_RATE as u64,
+            }),
+            _ => Err(StrErr::new(format!("{path:?} has no samples"))),
+        }
+    }
+}
+
+impl MicBackend for WavMic {
+    fn next_sample(&mut self) -> i16 {
+        let sample = self.samples[(self.pos >> 16) as usize % self.samples.len()];
+        self.pos += self.step;
+        sample
+    }
+
+    fn reset(&mut self) {
+        self.pos = 0;
+    }
+}
+
+pub struct Mic {
+    backend: Box<dyn MicBackend>,
+    key_map: Arc<AtomicU32>,
+    last_cycle: u64,
+    sample: i16,
+}
+
+impl Mic {
+    pub fn new(backend: Box<dyn MicBackend>, key_map: Arc<AtomicU32>) -> Self {
+        Mic {
+            backend,
+            key_map,
+            last_cycle: 0,
+            sample: 0,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.backend.is_live() || self.key_map.load(Ordering::Relaxed) & (1 << Keycode::Mic as u32) == 0
+    }
+
+    // Returns the sample at the given cycle count
+    pub fn sample(&mut self, cycle: u64) -> i16 {
+        let elapsed = cycle.saturating_sub(self.last_cycle) / MIC_SAMPLE_CYCLES;
+        self.last_cycle += elapsed * MIC_SAMPLE_CYCLES;
+
+        if !self.is_active() {
+            self.backend.reset();
+            self.sample = 0;
+            return 0;
+        }
+
+        // Don't generate more than a second worth of samples after long pauses
+        for _ in 0..elapsed.min(MIC_SAMPLE_RATE as u64) {
+            self.sample = self.backend.next_sample();
+        }
+        self.sample
+    }
+
+    // 12 bit unsigned, as read from the touchscreen controller
+    pub fn sample_12bit(&mut self, cycle: u64) -> u16 {
+        ((self.sample(cycle) as i32 + 0x8000) >> 4) as u16
+    }
+}
@@ -15,9 +15,12 @@ pub mod hle;
 pub mod input;
 pub mod ipc;
 pub mod memory;
+pub mod mic;
 pub mod rtc;
+pub mod save_state;
 pub mod spi;
 pub mod spu;
+pub mod system_files;
 pub mod thread_regs;
 pub mod timers;
 mod wifi;
@@ -31,6 +34,8 @@ const JIT_ASM_ARM7_ADDR: usize = if cfg!(target_os = "vita") { 0xA2000000 } else
 const MMU_TCM_ARM9_ADDR: usize = if cfg!(target_os = "vita") { 0xB0000000 } else { 0x80000000 };
 const MMU_TCM_ARM7_ADDR: usize = if cfg!(target_os = "vita") { 0xC0000000 } else { 0x90000000 };
 
+pub const ARM7_CLOCK: u64 = 33513982;
+
 #[derive(ConstParamTy, Copy, Clone, Debug, Eq, PartialEq)]
 #[repr(u8)]
 pub enum CpuType {
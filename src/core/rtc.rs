@@ -1,7 +1,21 @@
+use crate::core::cpu_regs::InterruptFlag;
+use crate::core::emu::Emu;
+use crate::core::hle::arm7_hle::IpcFifoTag;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
+use crate::core::CpuType::ARM7;
+use crate::core::ARM7_CLOCK;
 use crate::logging::debug_println;
-use crate::IS_DEBUG;
+use crate::settings::{Arm7Emu, RtcMode, Settings};
 use bilge::prelude::*;
-use chrono::{Datelike, Timelike};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, TimeDelta, Timelike};
+
+const STATUS1_INT1: u8 = 1 << 4;
+const STATUS1_INT2: u8 = 1 << 5;
+const STATUS2_INT2_ENABLE: u8 = 1 << 6;
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
 
 #[bitsize(8)]
 #[derive(FromBits)]
@@ -16,7 +30,6 @@ struct RtcReg {
     not_used1: u1,
 }
 
-#[derive(Default)]
 pub struct Rtc {
     rtc: u8,
     select_out: bool,
@@ -25,12 +38,107 @@ pub struct Rtc {
     write_count: u8,
     cmd: u8,
     cnt: u8,
+    pub status2: u8,
+    pub alarm1: [u8; 3],
+    pub alarm2: [u8; 3],
+    clock_adjust: u8,
+    free: u8,
     pub date_time: [u8; 7],
+    mode: RtcMode,
+    base_time: NaiveDateTime,
+    offset: TimeDelta,
+    last_minute: i64,
+    int_lines: u8,
 }
 
 impl Rtc {
-    pub fn new() -> Self {
-        Rtc::default()
+    pub fn new(settings: &Settings) -> Self {
+        let base_time = NaiveDateTime::parse_from_str(settings.rtc_time().trim(), "%Y-%m-%d %H:%M:%S").unwrap_or_else(|_| {
+            debug_println!("Invalid rtc time {}", settings.rtc_time());
+            NaiveDate::from_ymd_opt(2000, 1, 1).unwrap().and_hms_opt(11, 0, 0).unwrap()
+        });
+        let offset_hours = settings.rtc_offset().trim().parse::<f32>().unwrap_or(0f32);
+        Rtc {
+            rtc: 0,
+            select_out: false,
+            clock_out: false,
+            data_io: false,
+            write_count: 0,
+            cmd: 0,
+            cnt: 0,
+            status2: 0,
+            alarm1: [0; 3],
+            alarm2: [0; 3],
+            clock_adjust: 0,
+            free: 0,
+            date_time: [0; 7],
+            mode: settings.rtc_mode(),
+            base_time,
+            offset: TimeDelta::seconds((offset_hours * 3600f32) as i64),
+            last_minute: -1,
+            int_lines: 0,
+        }
+    }
+
+    // Mode, base time and offset come from the settings and are kept on load
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.rtc);
+        state.write(&self.select_out);
+        state.write(&self.clock_out);
+        state.write(&self.data_io);
+        state.write(&self.write_count);
+        state.write(&self.cmd);
+        state.write(&self.cnt);
+        state.write(&self.status2);
+        state.write(&self.alarm1);
+        state.write(&self.alarm2);
+        state.write(&self.clock_adjust);
+        state.write(&self.free);
+        state.write(&self.date_time);
+        state.write(&self.last_minute);
+        state.write(&self.int_lines);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.rtc);
+        state.read(&mut self.select_out);
+        state.read(&mut self.clock_out);
+        state.read(&mut self.data_io);
+        state.read(&mut self.write_count);
+        state.read(&mut self.cmd);
+        state.read(&mut self.cnt);
+        state.read(&mut self.status2);
+        state.read(&mut self.alarm1);
+        state.read(&mut self.alarm2);
+        state.read(&mut self.clock_adjust);
+        state.read(&mut self.free);
+        state.read(&mut self.date_time);
+        state.read(&mut self.last_minute);
+        state.read(&mut self.int_lines);
+    }
+
+    pub fn has_interrupt(&self) -> bool {
+        self.cnt & (STATUS1_INT1 | STATUS1_INT2) != 0
+    }
+
+    // Reading status 1 acknowledges the interrupt flags
+    pub fn read_status1(&mut self) -> u8 {
+        let value = self.cnt;
+        self.cnt &= !(STATUS1_INT1 | STATUS1_INT2);
+        value
+    }
+
+    pub fn write_status1(&mut self, value: u8) {
+        self.cnt = (self.cnt & !0xE) | (value & 0xE);
+    }
+
+    fn now(&self, cycle: u64) -> NaiveDateTime {
+        match self.mode {
+            RtcMode::Host => chrono::Local::now().naive_local(),
+            RtcMode::HostOffset => chrono::Local::now().naive_local() + self.offset,
+            RtcMode::Fixed => self.base_time,
+            RtcMode::Emulated => self.base_time + TimeDelta::milliseconds((cycle as u128 * 1000 / ARM7_CLOCK as u128) as i64),
+        }
     }
 
     pub fn get_rtc(&self) -> u8 {
@@ -47,7 +155,7 @@ impl Rtc {
         u8::from(reg)
     }
 
-    pub fn set_rtc(&mut self, value: u8) {
+    pub fn set_rtc(&mut self, value: u8, cycle: u64) {
         self.rtc = value & !0x7;
 
         let dir_reg = RtcReg::from(self.rtc);
@@ -57,10 +165,10 @@ impl Rtc {
         let sck = if dir_reg.clock_dir_write() { !data_reg.clock_out() } else { self.clock_out };
         let sio = if dir_reg.data_dir_write() { data_reg.data_io() } else { self.data_io };
 
-        self.update_rtc(cs, sck, sio);
+        self.update_rtc(cs, sck, sio, cycle);
     }
 
-    fn update_rtc(&mut self, select_out: bool, clock_out: bool, mut data_io: bool) {
+    fn update_rtc(&mut self, select_out: bool, clock_out: bool, mut data_io: bool, cycle: u64) {
         if select_out {
             if !self.clock_out && clock_out {
                 if self.write_count < 8 {
@@ -70,7 +178,7 @@ impl Rtc {
                         self.cmd = self.cmd.reverse_bits();
                     }
                 } else if self.cmd & 1 == 1 {
-                    data_io = self.read_reg((self.cmd >> 1) & 0x7);
+                    data_io = self.read_reg((self.cmd >> 1) & 0x7, cycle);
                 } else {
                     self.write_reg((self.cmd >> 1) & 0x7, data_io);
                 }
@@ -87,30 +195,54 @@ impl Rtc {
     }
 
     fn reset(&mut self) {
-        self.update_rtc(false, false, false);
+        self.update_rtc(false, false, false, 0);
         self.cnt = 0;
+        self.status2 = 0;
+        self.alarm1 = [0; 3];
+        self.alarm2 = [0; 3];
+        self.clock_adjust = 0;
         self.rtc = 0;
     }
 
-    fn read_reg(&mut self, index: u8) -> bool {
+    fn read_bit(bytes: &[u8], write_count: u8) -> bool {
+        match bytes.get((write_count / 8 - 1) as usize) {
+            Some(byte) => (byte >> (write_count & 7)) & 1 == 1,
+            None => false,
+        }
+    }
+
+    fn write_bit(bytes: &mut [u8], write_count: u8, value: bool) {
+        if let Some(byte) = bytes.get_mut((write_count / 8 - 1) as usize) {
+            *byte = (*byte & !(1 << (write_count & 7))) | ((value as u8) << (write_count & 7));
+        }
+    }
+
+    fn read_reg(&mut self, index: u8, cycle: u64) -> bool {
         match index {
             0 => {
-                self.reset();
-                false
+                let value = if self.write_count < 16 { self.cnt } else { 0 };
+                if self.write_count == 15 {
+                    self.read_status1();
+                }
+                (value >> (self.write_count & 7)) & 1 == 1
             }
-            1 => (self.cnt >> (self.write_count & 7)) & 1 == 1,
+            1 => Self::read_bit(&[self.status2], self.write_count),
             2 => {
                 if self.write_count == 8 {
-                    self.update_date_time();
+                    self.update_date_time(cycle);
                 }
-                (self.date_time[(self.write_count / 8 - 1) as usize] >> (self.write_count & 7)) & 1 == 1
+                Self::read_bit(&self.date_time, self.write_count)
             }
             3 => {
                 if self.write_count == 8 {
-                    self.update_date_time();
+                    self.update_date_time(cycle);
                 }
-                (self.date_time[(self.write_count / 8 + 3) as usize] >> (self.write_count & 7)) & 1 == 1
+                Self::read_bit(&self.date_time[4..], self.write_count)
             }
+            4 => Self::read_bit(&self.alarm1, self.write_count),
+            5 => Self::read_bit(&self.alarm2, self.write_count),
+            6 => Self::read_bit(&[self.clock_adjust], self.write_count),
+            7 => Self::read_bit(&[self.free], self.write_count),
             _ => {
                 debug_println!("Read from unknown rtc register: {}", index);
                 false
@@ -127,39 +259,192 @@ impl Rtc {
                     self.cnt = (self.cnt & !(1 << (self.write_count & 7))) | ((value as u8) << (self.write_count & 7));
                 }
             }
+            1 => Self::write_bit(std::slice::from_mut(&mut self.status2), self.write_count, value),
+            4 => Self::write_bit(&mut self.alarm1, self.write_count, value),
+            5 => Self::write_bit(&mut self.alarm2, self.write_count, value),
+            6 => Self::write_bit(std::slice::from_mut(&mut self.clock_adjust), self.write_count, value),
+            7 => Self::write_bit(std::slice::from_mut(&mut self.free), self.write_count, value),
             _ => {
                 debug_println!("Write to unknown rtc register: {}", index);
             }
         }
     }
 
-    pub fn update_date_time(&mut self) {
-        let (year, month, day, weekday, hour, is_pm, min, sec) = if IS_DEBUG {
-            (2000, 1, 1, 0, 11, false, 0, 0)
+    pub fn update_date_time(&mut self, cycle: u64) {
+        let now = self.now(cycle);
+
+        let hour = now.hour() as u8;
+        self.date_time[0] = to_bcd((now.year() % 100) as u8);
+        self.date_time[1] = to_bcd(now.month() as u8);
+        self.date_time[2] = to_bcd(now.day() as u8);
+        self.date_time[3] = to_bcd(now.weekday().num_days_from_monday() as u8);
+        self.date_time[4] = to_bcd(if self.cnt & 0x2 == 0 { hour % 12 } else { hour });
+        self.date_time[4] |= ((hour >= 12) as u8) << 6;
+        self.date_time[5] = to_bcd(now.minute() as u8);
+        self.date_time[6] = to_bcd(now.second() as u8);
+    }
+
+    // Weekday, hour and minute each only compared when their enable bit is set
+    fn alarm_matches(&self, alarm: &[u8; 3]) -> bool {
+        let hour_mask = if self.cnt & 0x2 == 0 { 0x7F } else { 0x3F };
+        alarm.iter().any(|value| value & 0x80 != 0)
+            && (alarm[0] & 0x80 == 0 || alarm[0] & 0x7 == self.date_time[3])
+            && (alarm[1] & 0x80 == 0 || alarm[1] & hour_mask == self.date_time[4] & hour_mask)
+            && (alarm[2] & 0x80 == 0 || alarm[2] & 0x7F == self.date_time[5])
+    }
+
+    // INT1 mode in bits 0-3 of status 2
+    fn int1_line(&self, now: NaiveDateTime, minute_edge: bool) -> bool {
+        match self.status2 & 0xF {
+            // Selected frequency steady interrupt, alarm 1 holds the 1, 2, 4, 8 and 16Hz selection
+            // which map to the upper bits of the 32kHz subsecond counter
+            0b0001 | 0b0101 => {
+                let mask = ((self.alarm1[0] & 0x1F).reverse_bits() as u32) << 7;
+                let ticks = (now.nanosecond().min(999_999_999) as u64 * 32768 / 1_000_000_000) as u32;
+                mask != 0 && ticks & mask != mask
+            }
+            // Per minute edge interrupt
+            0b0010 | 0b0110 => minute_edge,
+            // Per minute steady interrupt, held for the first 30 seconds
+            0b0011 | 0b0111 => now.second() < 30,
+            0b0100 => minute_edge && self.alarm_matches(&self.alarm1),
+            // Disabled or 32kHz clock output
+            _ => false,
+        }
+    }
+
+    // Returns the newly raised interrupt flags, status 1 keeps them while the lines are held
+    fn check_alarms(&mut self, cycle: u64) -> u8 {
+        let now = self.now(cycle);
+        let minute = now.and_utc().timestamp().div_euclid(60);
+        let minute_edge = minute != self.last_minute && self.last_minute != -1;
+        if minute != self.last_minute {
+            self.last_minute = minute;
+            self.update_date_time(cycle);
+        }
+
+        let mut lines = 0;
+        if self.int1_line(now, minute_edge) {
+            lines |= STATUS1_INT1;
+        }
+        if minute_edge && self.status2 & STATUS2_INT2_ENABLE != 0 && self.alarm_matches(&self.alarm2) {
+            lines |= STATUS1_INT2;
+        }
+        let flags = lines & !self.int_lines;
+        self.int_lines = lines;
+        self.cnt |= lines;
+        flags
+    }
+}
+
+impl Emu {
+    pub fn rtc_on_frame(&mut self) {
+        let flags = self.rtc.check_alarms(self.cm.get_cycles());
+        if flags == 0 {
+            return;
+        }
+
+        if self.settings.arm7_hle() == Arm7Emu::Hle {
+            // Interrupt notification of the sdk, alarm number in the result byte
+            self.arm7_hle_send_ipc_fifo(IpcFifoTag::Rtc, 0x8000 | (0x30 << 8) | (flags >> 4) as u32, false);
         } else {
-            let local_now = chrono::Local::now();
-
-            let year = local_now.year() as u32 % 100;
-            let month = local_now.month() as u8;
-            let day = local_now.day() as u8;
-            let weekday = local_now.weekday() as u8;
-            let (hour, is_pm) = {
-                let hour = local_now.hour();
-                ((if self.cnt & 0x2 == 0 { hour % 12 } else { hour }) as u8, hour >= 12)
-            };
-            let min = local_now.minute() as u8;
-            let sec = local_now.second() as u8;
-
-            (year, month, day, weekday, hour, is_pm, min, sec)
-        };
-
-        self.date_time[0] = (((year / 10) << 4) | (year % 10)) as u8;
-        self.date_time[1] = ((month / 10) << 4) | (month % 10);
-        self.date_time[2] = ((day / 10) << 4) | (day % 10);
-        self.date_time[3] = ((weekday / 10) << 4) | (weekday % 10);
-        self.date_time[4] = ((hour / 10) << 4) | (hour % 10);
-        self.date_time[4] |= (is_pm as u8) << 6;
-        self.date_time[5] = ((min / 10) << 4) | (min % 10);
-        self.date_time[6] = ((sec / 10) << 4) | (sec % 10);
+            self.cpu_send_interrupt(ARM7, InterruptFlag::Rtc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rtc, STATUS1_INT1};
+    use crate::core::ARM7_CLOCK;
+    use crate::settings::{RtcMode, DEFAULT_SETTINGS};
+    use chrono::NaiveDate;
+
+    fn rtc(status2: u8) -> Rtc {
+        let mut rtc = Rtc::new(&DEFAULT_SETTINGS);
+        rtc.mode = RtcMode::Emulated;
+        rtc.base_time = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap().and_hms_opt(10, 59, 45).unwrap();
+        rtc.status2 = status2;
+        assert_eq!(rtc.check_alarms(0), 0);
+        rtc
+    }
+
+    fn cycle(ms: u64) -> u64 {
+        (ms * ARM7_CLOCK).div_ceil(1000)
+    }
+
+    #[test]
+    fn int1_disabled_and_32khz_output_never_interrupt() {
+        for status2 in [0b0000, 0b1000, 0b1100, 0b1111] {
+            let mut rtc = rtc(status2);
+            assert_eq!(rtc.check_alarms(cycle(15_000)), 0);
+            assert_eq!(rtc.check_alarms(cycle(75_000)), 0);
+            assert!(!rtc.has_interrupt());
+        }
+    }
+
+    #[test]
+    fn int1_frequency_steady_follows_the_selected_clock() {
+        for status2 in [0b0001, 0b0101] {
+            let mut rtc = rtc(status2);
+            // 1Hz
+            rtc.alarm1[0] = 1;
+            assert_eq!(rtc.check_alarms(cycle(250)), STATUS1_INT1);
+            assert_eq!(rtc.check_alarms(cycle(400)), 0);
+            assert_eq!(rtc.read_status1() & STATUS1_INT1, STATUS1_INT1);
+            assert_eq!(rtc.check_alarms(cycle(750)), 0);
+            assert_eq!(rtc.read_status1() & STATUS1_INT1, 0);
+            assert_eq!(rtc.check_alarms(cycle(1250)), STATUS1_INT1);
+
+            // 16Hz, low in the second half of each 62.5ms period
+            rtc.alarm1[0] = 1 << 4;
+            assert_eq!(rtc.check_alarms(cycle(2040)), 0);
+            assert_eq!(rtc.check_alarms(cycle(2070)), STATUS1_INT1);
+
+            // Nothing selected
+            rtc.alarm1[0] = 0;
+            assert_eq!(rtc.check_alarms(cycle(3250)), 0);
+            assert_eq!(rtc.check_alarms(cycle(3750)), 0);
+        }
+    }
+
+    #[test]
+    fn int1_per_minute_edge_fires_once() {
+        for status2 in [0b0010, 0b0110] {
+            let mut rtc = rtc(status2);
+            assert_eq!(rtc.check_alarms(cycle(14_000)), 0);
+            assert_eq!(rtc.check_alarms(cycle(15_000)), STATUS1_INT1);
+            assert!(rtc.has_interrupt());
+            rtc.read_status1();
+            assert_eq!(rtc.check_alarms(cycle(16_000)), 0);
+            assert!(!rtc.has_interrupt());
+            assert_eq!(rtc.check_alarms(cycle(75_000)), STATUS1_INT1);
+        }
+    }
+
+    #[test]
+    fn int1_per_minute_steady_is_held_for_30_seconds() {
+        for status2 in [0b0011, 0b0111] {
+            let mut rtc = rtc(status2);
+            assert_eq!(rtc.check_alarms(cycle(15_000)), STATUS1_INT1);
+            rtc.read_status1();
+            assert_eq!(rtc.check_alarms(cycle(30_000)), 0);
+            assert!(rtc.has_interrupt());
+            rtc.read_status1();
+            assert_eq!(rtc.check_alarms(cycle(45_000)), 0);
+            assert!(!rtc.has_interrupt());
+            assert_eq!(rtc.check_alarms(cycle(75_000)), STATUS1_INT1);
+        }
+    }
+
+    #[test]
+    fn int1_alarm_fires_on_the_matching_minute() {
+        let mut rtc = rtc(0b0100);
+        // 11:01, 12 hour mode
+        rtc.alarm1 = [0, 0x80 | 0x11, 0x80 | 0x01];
+        assert_eq!(rtc.check_alarms(cycle(15_000)), 0);
+        assert_eq!(rtc.check_alarms(cycle(75_000)), STATUS1_INT1);
+        assert_eq!(rtc.check_alarms(cycle(80_000)), 0);
+        assert_eq!(rtc.check_alarms(cycle(135_000)), 0);
     }
 }
@@ -0,0 +1,257 @@
+use crate::core::emu::Emu;
+use crate::core::CpuType::{ARM7, ARM9};
+use crate::logging::info_println;
+use crate::utils::StrErr;
+use std::collections::VecDeque;
+use std::fs;
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering;
+use std::{ptr, slice};
+use xxhash_rust::xxh32::xxh32;
+
+const SAVE_STATE_MAGIC: [u8; 4] = *b"DSVS";
+const SAVE_STATE_VERSION: u32 = 11;
+
+#[repr(C)]
+struct SaveStateHeader {
+    magic: [u8; 4],
+    version: u32,
+    game_code: [u8; 4],
+    arm7_emu: u8,
+    padding: [u8; 3],
+    payload_size: u32,
+    payload_hash: u32,
+}
+
+const HEADER_SIZE: usize = size_of::<SaveStateHeader>();
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SaveStateRequest {
+    None = 0,
+    Save = 1,
+    Load = 2,
+}
+
+impl From<u8> for SaveStateRequest {
+    fn from(value: u8) -> Self {
+        debug_assert!(value <= SaveStateRequest::Load as u8);
+        unsafe { std::mem::transmute(value) }
+    }
+}
+
+// Values are copied byte by byte, only use these with plain data (no pointers, boxes or vecs)
+pub struct SaveStateWriter {
+    buf: Vec<u8>,
+}
+
+impl SaveStateWriter {
+    fn new() -> Self {
+        SaveStateWriter { buf: Vec::new() }
+    }
+
+    pub fn write<T>(&mut self, value: &T) {
+        self.write_slice(slice::from_ref(value));
+    }
+
+    pub fn write_slice<T>(&mut self, values: &[T]) {
+        let bytes = unsafe { slice::from_raw_parts(values.as_ptr() as *const u8, size_of_val(values)) };
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn write_vec<T>(&mut self, values: &[T]) {
+        self.write(&(values.len() as u32));
+        self.write_slice(values);
+    }
+
+    pub fn write_vec_deque<T: Copy>(&mut self, values: &VecDeque<T>) {
+        let (front, back) = values.as_slices();
+        self.write(&((front.len() + back.len()) as u32));
+        self.write_slice(front);
+        self.write_slice(back);
+    }
+}
+
+pub struct SaveStateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SaveStateReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        SaveStateReader { buf, pos: 0 }
+    }
+
+    pub fn read<T>(&mut self, value: &mut T) {
+        self.read_slice(slice::from_mut(value));
+    }
+
+    pub fn read_value<T>(&mut self) -> T {
+        let mut value = MaybeUninit::<T>::uninit();
+        self.read(&mut value);
+        unsafe { value.assume_init() }
+    }
+
+    pub fn read_slice<T>(&mut self, values: &mut [T]) {
+        let size = size_of_val(values);
+        let bytes = &self.buf[self.pos..self.pos + size];
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), values.as_mut_ptr() as *mut u8, size) };
+        self.pos += size;
+    }
+
+    pub fn read_vec<T: Copy + Default>(&mut self, values: &mut Vec<T>) {
+        let len = self.read_value::<u32>() as usize;
+        values.clear();
+        values.resize(len, T::default());
+        self.read_slice(values.as_mut_slice());
+    }
+
+    pub fn read_vec_deque<T: Copy + Default>(&mut self, values: &mut VecDeque<T>) {
+        let mut vec = Vec::new();
+        self.read_vec(&mut vec);
+        values.clear();
+        values.extend(vec);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+}
+
+impl Emu {
+    fn save_state_write_payload(&self, state: &mut SaveStateWriter) {
+        state.write(ARM9.thread_regs());
+        state.write(ARM7.thread_regs());
+        state.write(&self.cpu);
+        state.write(&self.cp15);
+        state.write(&self.div_sqrt);
+        state.write(&self.timers);
+        self.rtc.save_state(state);
+        self.cm.save_state(state);
+        self.mem.save_state(state);
+        self.gpu.save_state(state);
+        self.spu.save_state(state);
+        for dma in &self.dma {
+            dma.save_state(state);
+        }
+        self.ipc.save_state(state);
+        self.spi.save_state(state);
+        self.input.save_state(state);
+        self.cartridge.save_state(state);
+        self.wifi.save_state(state);
+        self.hle.save_state(state);
+    }
+
+    fn save_state_read_payload(&mut self, state: &mut SaveStateReader) {
+        state.read(ARM9.thread_regs());
+        state.read(ARM7.thread_regs());
+        state.read(&mut self.cpu);
+        state.read(&mut self.cp15);
+        state.read(&mut self.div_sqrt);
+        state.read(&mut self.timers);
+        self.rtc.load_state(state);
+        self.cm.load_state(state);
+        self.mem.load_state(state);
+        self.gpu.load_state(state);
+        self.spu.load_state(state);
+        for dma in &mut self.dma {
+            dma.load_state(state);
+        }
+        self.ipc.load_state(state);
+        self.spi.load_state(state);
+        self.input.load_state(state);
+        self.cartridge.load_state(state);
+        self.wifi.load_state(state);
+        self.hle.load_state(state);
+    }
+
+    pub fn save_state_serialize(&self) -> Vec<u8> {
+        let mut state = SaveStateWriter::new();
+        self.save_state_write_payload(&mut state);
+
+        let header = SaveStateHeader {
+            magic: SAVE_STATE_MAGIC,
+            version: SAVE_STATE_VERSION,
+            game_code: self.cartridge.io.header.game_code,
+            arm7_emu: self.settings.arm7_hle() as u8,
+            padding: [0; 3],
+            payload_size: state.buf.len() as u32,
+            payload_hash: xxh32(&state.buf, 0),
+        };
+
+        let mut data = Vec::with_capacity(HEADER_SIZE + state.buf.len());
+        data.extend_from_slice(unsafe { slice::from_raw_parts(&header as *const _ as *const u8, HEADER_SIZE) });
+        data.extend_from_slice(&state.buf);
+        data
+    }
+
+    pub fn save_state_deserialize(&mut self, data: &[u8]) -> Result<(), StrErr> {
+        if data.len() < HEADER_SIZE {
+            return Err(StrErr::new("Save state is too small"));
+        }
+
+        let header: SaveStateHeader = SaveStateReader::new(&data[..HEADER_SIZE]).read_value();
+        if header.magic != SAVE_STATE_MAGIC {
+            return Err(StrErr::new("Not a save state"));
+        }
+        if header.version != SAVE_STATE_VERSION {
+            return Err(StrErr::new(format!("Unsupported save state version {}, expected {SAVE_STATE_VERSION}", header.version)));
+        }
+        if header.game_code != self.cartridge.io.header.game_code {
+            return Err(StrErr::new("Save state belongs to a different game"));
+        }
+        if header.arm7_emu != self.settings.arm7_hle() as u8 {
+            return Err(StrErr::new("Save state was created with a different arm7 emulation mode"));
+        }
+
+        let payload = &data[HEADER_SIZE..];
+        if payload.len() != header.payload_size as usize || xxh32(payload, 0) != header.payload_hash {
+            return Err(StrErr::new("Save state is corrupted"));
+        }
+
+        let mut state = SaveStateReader::new(payload);
+        self.save_state_read_payload(&mut state);
+        debug_assert!(state.is_finished());
+
+        self.jit.invalidate_all();
+        self.mmu_update_all::<{ ARM9 }>();
+        self.mmu_update_all::<{ ARM7 }>();
+        Ok(())
+    }
+
+    // Returns true if a state was loaded and the execution state has to be reset
+    pub fn save_state_process_request(&mut self) -> bool {
+        let request = SaveStateRequest::from(self.save_state_request.swap(SaveStateRequest::None as u8, Ordering::Relaxed));
+        let path = self.cartridge.io.save_state_path();
+        match request {
+            SaveStateRequest::None => false,
+            SaveStateRequest::Save => {
+                match fs::write(&path, self.save_state_serialize()) {
+                    Ok(_) => {
+                        info_println!("Saved state to {path:?}");
+                    }
+                    Err(err) => {
+                        info_println!("Failed to write state to {path:?}: {err}");
+                    }
+                }
+                false
+            }
+            SaveStateRequest::Load => match fs::read(&path) {
+                Ok(data) => match self.save_state_deserialize(&data) {
+                    Ok(_) => {
+                        info_println!("Loaded state from {path:?}");
+                        true
+                    }
+                    Err(err) => {
+                        info_println!("Failed to load state from {path:?}: {err}");
+                        false
+                    }
+                },
+                Err(err) => {
+                    info_println!("Failed to read state from {path:?}: {err}");
+                    false
+                }
+            },
+        }
+    }
+}
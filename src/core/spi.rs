@@ -1,22 +1,19 @@
+use crate::core::mic::Mic;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::logging::debug_println;
+use crate::settings::{Language, Settings};
 use crate::utils;
+use crate::utils::StrErr;
 use bilge::prelude::*;
-use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU16, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::{fs, mem};
 
-const FIRMWARE_SIZE: usize = 128 * 1024;
-pub const USER_SETTINGS_1_ADDR: usize = 0x1FF00;
-
-#[repr(u8)]
-enum Language {
-    JAP = 0,
-    ENG = 1,
-    FR = 2,
-    GER = 3,
-    ITA = 4,
-    SPA = 5,
-}
+pub const FIRMWARE_SIZE: usize = 128 * 1024;
+const USER_SETTINGS_1_ADDR: usize = 0x1FF00;
 
 const fn get_firmware() -> [u8; FIRMWARE_SIZE] {
     let mut firmware = [0u8; FIRMWARE_SIZE];
@@ -81,8 +78,8 @@ const fn get_firmware() -> [u8; FIRMWARE_SIZE] {
         firmware[addr + 0x62] = 0xFF; // SCR X2
         firmware[addr + 0x63] = 0xBF; // SCR Y2
 
-        // Set the language specified by the frontend
-        firmware[addr + 0x64] = Language::ENG as u8;
+        // Overwritten by the frontend settings on startup
+        firmware[addr + 0x64] = Language::English as u8;
 
         // Calculate the user settings CRC
         let crc = utils::crc16(0xFFFF, &firmware, addr, 0x70);
@@ -95,7 +92,7 @@ const fn get_firmware() -> [u8; FIRMWARE_SIZE] {
     firmware
 }
 
-pub const SPI_FIRMWARE: [u8; FIRMWARE_SIZE] = get_firmware();
+const SPI_FIRMWARE: [u8; FIRMWARE_SIZE] = get_firmware();
 
 #[repr(u8)]
 #[derive(Debug)]
@@ -127,6 +124,152 @@ struct SpiCnt {
     spi_bus_enable: bool,
 }
 
+const USER_SETTINGS_SIZE: usize = 0x70;
+
+const FLASH_WRSR: u8 = 0x01;
+const FLASH_PP: u8 = 0x02;
+const FLASH_READ: u8 = 0x03;
+const FLASH_WRDI: u8 = 0x04;
+const FLASH_RDSR: u8 = 0x05;
+const FLASH_WREN: u8 = 0x06;
+const FLASH_PW: u8 = 0x0A;
+const FLASH_FAST_READ: u8 = 0x0B;
+const FLASH_RDID: u8 = 0x9F;
+const FLASH_PE: u8 = 0xDB;
+const FLASH_SE: u8 = 0xD8;
+
+const FLASH_STATUS_WEL: u8 = 1 << 1;
+
+// Sound amplifier and both backlights on
+const POWER_MAN_CONTROL_DEFAULT: u8 = 0x0D;
+const POWER_MAN_BACKLIGHTS: u8 = 0x0C;
+const FLASH_ID: [u8; 3] = [0x20, 0x40, 0x12];
+
+pub struct Firmware {
+    buf: Mutex<(Vec<u8>, bool)>,
+    save_path: Option<PathBuf>,
+    // False when a firmware dump or firmware written by games was loaded
+    generated: bool,
+}
+
+impl Firmware {
+    fn new() -> Self {
+        Firmware {
+            buf: Mutex::new((SPI_FIRMWARE.to_vec(), false)),
+            save_path: None,
+            generated: true,
+        }
+    }
+
+    pub fn set(&mut self, buf: Vec<u8>) -> Result<(), StrErr> {
+        debug_assert!(buf.len().is_power_of_two());
+        if Self::user_settings_base_addr(&buf).is_none() {
+            return Err(StrErr::new(format!("Firmware user settings pointer {:x} is out of bounds", u16::from_le_bytes([buf[0x20], buf[0x21]]))));
+        }
+        *self.buf.get_mut().unwrap() = (buf, false);
+        self.generated = false;
+        Ok(())
+    }
+
+    pub fn set_save_path(&mut self, path: PathBuf) {
+        self.save_path = Some(path);
+    }
+
+    pub fn read(&self, addr: u32) -> u8 {
+        let lock = self.buf.lock().unwrap();
+        let (buf, _) = lock.deref();
+        buf[addr as usize & (buf.len() - 1)]
+    }
+
+    // Page writes replace the bytes, page programs can only clear bits
+    pub fn program(&self, addr: u32, value: u8, write: bool) {
+        let mut lock = self.buf.lock().unwrap();
+        let (buf, dirty) = lock.deref_mut();
+        let addr = addr as usize & (buf.len() - 1);
+        buf[addr] = if write { value } else { buf[addr] & value };
+        *dirty = true;
+    }
+
+    pub fn erase(&self, addr: u32, size: usize) {
+        let mut lock = self.buf.lock().unwrap();
+        let (buf, dirty) = lock.deref_mut();
+        let addr = addr as usize & (buf.len() - 1) & !(size - 1);
+        buf[addr..addr + size].fill(0xFF);
+        *dirty = true;
+    }
+
+    // Both copies of the user settings have to fit into the flash
+    fn user_settings_base_addr(buf: &[u8]) -> Option<usize> {
+        let addr = u16::from_le_bytes([buf[0x20], buf[0x21]]) as usize * 8;
+        (addr + 0x200 <= buf.len()).then_some(addr)
+    }
+
+    // Pick the user settings copy with the newest update counter
+    fn user_settings_addr(buf: &[u8]) -> usize {
+        // Validated in set
+        let addr = Self::user_settings_base_addr(buf).unwrap();
+        let count0 = u16::from_le_bytes([buf[addr + 0x70], buf[addr + 0x71]]);
+        let count1 = u16::from_le_bytes([buf[addr + 0x170], buf[addr + 0x171]]);
+        if count0.wrapping_sub(count1) & 0x7F == 1 {
+            addr
+        } else {
+            addr + 0x100
+        }
+    }
+
+    // Loaded firmware keeps the user settings written by games, unless they were changed in the launcher
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        if self.generated || settings.firmware_user_settings_changed() {
+            self.set_user_settings(settings);
+        }
+    }
+
+    fn set_user_settings(&mut self, settings: &Settings) {
+        let (buf, _) = self.buf.get_mut().unwrap();
+        let addr = Self::user_settings_base_addr(buf).unwrap();
+        for addr in [addr, addr + 0x100] {
+            let user_settings = &mut buf[addr..addr + USER_SETTINGS_SIZE];
+            user_settings[0x02] = settings.favorite_color();
+            user_settings[0x03] = settings.birthday_month();
+            user_settings[0x04] = settings.birthday_day();
+
+            let mut len = 0;
+            user_settings[0x06..0x1A].fill(0);
+            for (i, c) in settings.nickname().encode_utf16().take(10).enumerate() {
+                user_settings[0x06 + i * 2..0x08 + i * 2].copy_from_slice(&c.to_le_bytes());
+                len += 1;
+            }
+            user_settings[0x1A] = len;
+
+            user_settings[0x64] = (user_settings[0x64] & !0x7) | settings.language() as u8;
+
+            let crc = utils::crc16(0xFFFF, buf, addr, USER_SETTINGS_SIZE);
+            buf[addr + 0x72] = (crc & 0xFF) as u8;
+            buf[addr + 0x73] = ((crc & 0xFF00) >> 8) as u8;
+        }
+    }
+
+    pub fn user_settings(&self) -> [u8; USER_SETTINGS_SIZE] {
+        let lock = self.buf.lock().unwrap();
+        let (buf, _) = lock.deref();
+        let addr = Self::user_settings_addr(buf);
+        buf[addr..addr + USER_SETTINGS_SIZE].try_into().unwrap()
+    }
+
+    pub fn flush(&self, last_save_time: &Arc<Mutex<Option<(Instant, bool)>>>) {
+        let Some(save_path) = &self.save_path else {
+            return;
+        };
+        let mut lock = self.buf.lock().unwrap();
+        let (buf, dirty) = lock.deref_mut();
+        if *dirty {
+            let success = save_path.parent().is_none_or(|dir| fs::create_dir_all(dir).is_ok()) && utils::write_file_atomic(save_path, buf).is_ok();
+            *last_save_time.lock().unwrap() = Some((Instant::now(), success));
+            *dirty = false;
+        }
+    }
+}
+
 pub struct Spi {
     pub cnt: u16,
     pub data: u8,
@@ -134,10 +277,15 @@ pub struct Spi {
     cmd: u8,
     addr: u32,
     touch_points: Arc<AtomicU16>,
+    pub mic: Mic,
+    pub firmware: Firmware,
+    firmware_status: u8,
+    // Control, battery, mic amplifier, mic gain and backlight level
+    power_man: [u8; 5],
 }
 
 impl Spi {
-    pub fn new(touch_points: Arc<AtomicU16>) -> Self {
+    pub fn new(touch_points: Arc<AtomicU16>, mic: Mic) -> Self {
         Spi {
             cnt: 0,
             data: 0,
@@ -145,42 +293,77 @@ impl Spi {
             cmd: 0,
             addr: 0,
             touch_points,
+            mic,
+            firmware: Firmware::new(),
+            firmware_status: 0,
+            power_man: [POWER_MAN_CONTROL_DEFAULT, 0, 0, 0, 0x01],
+        }
+    }
+
+    pub fn backlights_on(&self) -> bool {
+        self.power_man[0] & POWER_MAN_BACKLIGHTS != 0
+    }
+
+    pub fn set_backlights(&mut self, on: bool) {
+        if on {
+            self.power_man[0] |= POWER_MAN_BACKLIGHTS;
+        } else {
+            self.power_man[0] &= !POWER_MAN_BACKLIGHTS;
         }
     }
 
+    pub fn firmware_status(&self) -> u8 {
+        self.firmware_status
+    }
+
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.cnt);
+        state.write(&self.data);
+        state.write(&self.write_count);
+        state.write(&self.cmd);
+        state.write(&self.addr);
+        state.write(&self.firmware_status);
+        state.write(&self.power_man);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.cnt);
+        state.read(&mut self.data);
+        state.read(&mut self.write_count);
+        state.read(&mut self.cmd);
+        state.read(&mut self.addr);
+        state.read(&mut self.firmware_status);
+        state.read(&mut self.power_man);
+    }
+
     pub fn set_cnt(&mut self, mut mask: u16, value: u16) {
         mask &= 0xCF03;
         self.cnt = (self.cnt & !mask) | (value & mask);
     }
 
-    pub fn set_data(&mut self, value: u8) {
+    pub fn set_data(&mut self, value: u8, cycle: u64) {
         let cnt = SpiCnt::from(self.cnt);
         if !cnt.spi_bus_enable() {
             self.data = 0;
             return;
         }
 
+        let device = SpiDevice::from(u8::from(cnt.device_select()));
         if self.write_count == 0 {
             self.cmd = value;
             self.addr = 0;
             self.data = 0;
+            if matches!(device, SpiDevice::Firmware) {
+                match value {
+                    FLASH_WREN => self.firmware_status |= FLASH_STATUS_WEL,
+                    FLASH_WRDI => self.firmware_status &= !FLASH_STATUS_WEL,
+                    _ => {}
+                }
+            }
         } else {
-            let device = SpiDevice::from(u8::from(cnt.device_select()));
             match device {
-                SpiDevice::Firmware => {
-                    if self.cmd == 3 {
-                        if self.write_count < 4 {
-                            self.addr <<= 8;
-                            self.addr |= value as u32;
-                        } else {
-                            self.data = if self.addr < FIRMWARE_SIZE as u32 { SPI_FIRMWARE[self.addr as usize] } else { 0 };
-                            self.addr += u32::from(cnt.transfer_size()) + 1;
-                        }
-                    } else {
-                        debug_println!("Unknown spi {:?} command {:x}", device, self.cmd);
-                        self.data = 0;
-                    }
-                }
+                SpiDevice::Firmware => self.firmware_transfer(value, u32::from(cnt.transfer_size()) + 1),
+                SpiDevice::PowerManager => self.power_man_transfer(value),
                 SpiDevice::Touchscreen => {
                     self.data = match (self.cmd & 0x70) >> 4 {
                         1 => {
@@ -199,7 +382,14 @@ impl Spi {
                                 (x << 3) as u8
                             }
                         }
-                        6 => 0,
+                        6 => {
+                            let sample = self.mic.sample_12bit(cycle);
+                            if self.write_count & 1 != 0 {
+                                (sample >> 5) as u8
+                            } else {
+                                (sample << 3) as u8
+                            }
+                        }
                         _ => 0,
                     }
                 }
@@ -213,6 +403,10 @@ impl Spi {
         if cnt.chip_select_hold() {
             self.write_count += 1;
         } else {
+            // Write enable latch resets once a write finished
+            if matches!(device, SpiDevice::Firmware) && matches!(self.cmd, FLASH_PW | FLASH_PP | FLASH_PE | FLASH_SE | FLASH_WRSR) {
+                self.firmware_status &= !FLASH_STATUS_WEL;
+            }
             self.write_count = 0;
         }
 
@@ -221,15 +415,84 @@ impl Spi {
         }
     }
 
+    // First byte selects the register and the read bit, second one is the data
+    fn power_man_transfer(&mut self, value: u8) {
+        self.data = 0;
+        if self.write_count != 1 {
+            return;
+        }
+        let index = (self.cmd & 0x7) as usize;
+        if index >= self.power_man.len() {
+            return;
+        }
+        if self.cmd & 0x80 != 0 {
+            self.data = self.power_man[index];
+        } else {
+            match index {
+                // Battery status is read only, power off bit is ignored
+                0 => self.power_man[0] = value & 0x3F,
+                1 => {}
+                _ => self.power_man[index] = value,
+            }
+        }
+    }
+
+    fn firmware_transfer(&mut self, value: u8, read_size: u32) {
+        self.data = 0;
+        match self.cmd {
+            FLASH_RDSR => self.data = self.firmware_status,
+            FLASH_RDID => {
+                if self.write_count <= FLASH_ID.len() {
+                    self.data = FLASH_ID[self.write_count - 1];
+                }
+            }
+            FLASH_WRSR => {}
+            FLASH_READ | FLASH_FAST_READ | FLASH_PW | FLASH_PP | FLASH_PE | FLASH_SE => {
+                if self.write_count < 4 {
+                    self.addr <<= 8;
+                    self.addr |= value as u32;
+
+                    if self.write_count == 3 && self.firmware_status & FLASH_STATUS_WEL != 0 {
+                        match self.cmd {
+                            FLASH_PE => self.firmware.erase(self.addr, 0x100),
+                            FLASH_SE => self.firmware.erase(self.addr, 0x10000),
+                            _ => {}
+                        }
+                    }
+                } else {
+                    match self.cmd {
+                        // Fast read has a dummy byte after the address
+                        FLASH_READ | FLASH_FAST_READ => {
+                            if self.cmd == FLASH_READ || self.write_count > 4 {
+                                self.data = self.firmware.read(self.addr);
+                                self.addr += read_size;
+                            }
+                        }
+                        FLASH_PW | FLASH_PP => {
+                            if self.firmware_status & FLASH_STATUS_WEL != 0 {
+                                self.firmware.program(self.addr, value, self.cmd == FLASH_PW);
+                                // Writes wrap around within the page
+                                self.addr = (self.addr & !0xFF) | (self.addr.wrapping_add(1) & 0xFF);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => debug_println!("Unknown spi firmware command {:x}", self.cmd),
+        }
+    }
+
     pub fn get_touch_coordinates(&self) -> (u16, u16) {
-        const ADC_X1: i32 = u16::from_le_bytes([SPI_FIRMWARE[FIRMWARE_SIZE - 0xA8], SPI_FIRMWARE[FIRMWARE_SIZE - 0xA7]]) as i32;
-        const ADC_Y1: i32 = u16::from_le_bytes([SPI_FIRMWARE[FIRMWARE_SIZE - 0xA6], SPI_FIRMWARE[FIRMWARE_SIZE - 0xA5]]) as i32;
-        const SCR_X1: i32 = SPI_FIRMWARE[FIRMWARE_SIZE - 0xA4] as i32;
-        const SCR_Y1: i32 = SPI_FIRMWARE[FIRMWARE_SIZE - 0xA3] as i32;
-        const ADC_X2: i32 = u16::from_le_bytes([SPI_FIRMWARE[FIRMWARE_SIZE - 0xA2], SPI_FIRMWARE[FIRMWARE_SIZE - 0xA1]]) as i32;
-        const ADC_Y2: i32 = u16::from_le_bytes([SPI_FIRMWARE[FIRMWARE_SIZE - 0xA0], SPI_FIRMWARE[FIRMWARE_SIZE - 0x9F]]) as i32;
-        const SCR_X2: i32 = SPI_FIRMWARE[FIRMWARE_SIZE - 0x9E] as i32;
-        const SCR_Y2: i32 = SPI_FIRMWARE[FIRMWARE_SIZE - 0x9D] as i32;
+        let user_settings = self.firmware.user_settings();
+        let adc_x1 = u16::from_le_bytes([user_settings[0x58], user_settings[0x59]]) as i32;
+        let adc_y1 = u16::from_le_bytes([user_settings[0x5A], user_settings[0x5B]]) as i32;
+        let scr_x1 = user_settings[0x5C] as i32;
+        let scr_y1 = user_settings[0x5D] as i32;
+        let adc_x2 = u16::from_le_bytes([user_settings[0x5E], user_settings[0x5F]]) as i32;
+        let adc_y2 = u16::from_le_bytes([user_settings[0x60], user_settings[0x61]]) as i32;
+        let scr_x2 = user_settings[0x62] as i32;
+        let scr_y2 = user_settings[0x63] as i32;
 
         let points = self.touch_points.load(Ordering::Relaxed);
         let x = points & 0xFF;
@@ -237,9 +500,50 @@ impl Spi {
         let y = points >> 8;
         let y = y.clamp(1, 190) as i32;
 
-        let touch_x = (x - SCR_X1 + 1) * (ADC_X2 - ADC_X1) / (SCR_X2 - SCR_X1) + ADC_X1;
-        let touch_y = (y - SCR_Y1 + 1) * (ADC_Y2 - ADC_Y1) / (SCR_Y2 - SCR_Y1) + ADC_Y1;
+        let touch_x = (x - scr_x1 + 1) * (adc_x2 - adc_x1) / (scr_x2 - scr_x1).max(1) + adc_x1;
+        let touch_y = (y - scr_y1 + 1) * (adc_y2 - adc_y1) / (scr_y2 - scr_y1).max(1) + adc_y1;
 
         (touch_x as u16, touch_y as u16)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Firmware;
+    use crate::core::system_files::SystemFiles;
+    use crate::settings::DEFAULT_SETTINGS;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn written_byte_survives_reload() {
+        let save_path = std::env::temp_dir().join(format!("dsvita_firmware_test_{}.bin", std::process::id()));
+        let _ = fs::remove_file(&save_path);
+
+        let mut firmware = Firmware::new();
+        firmware.apply_settings(&DEFAULT_SETTINGS);
+        firmware.set_save_path(save_path.clone());
+        // First nickname character of the current user settings
+        let addr = Firmware::user_settings_addr(&firmware.buf.lock().unwrap().0) as u32 + 0x06;
+        firmware.program(addr, b'Z', true);
+        let last_save_time = Arc::new(Mutex::new(None));
+        firmware.flush(&last_save_time);
+        assert!(last_save_time.lock().unwrap().unwrap().1);
+
+        let system_files = SystemFiles::load(None, save_path.clone());
+        let mut reloaded = Firmware::new();
+        reloaded.set(system_files.firmware.unwrap()).unwrap();
+        assert!(!reloaded.generated);
+        // Unchanged settings don't replace the nickname written by the game
+        reloaded.apply_settings(&DEFAULT_SETTINGS);
+        assert_eq!(reloaded.read(addr), b'Z');
+        assert_eq!(reloaded.user_settings()[0x06], b'Z');
+
+        let mut changed_settings = DEFAULT_SETTINGS.clone();
+        changed_settings.set_firmware_user_settings_changed(true);
+        reloaded.apply_settings(&changed_settings);
+        assert_eq!(reloaded.read(addr), DEFAULT_SETTINGS.nickname().as_bytes()[0]);
+
+        fs::remove_file(&save_path).unwrap();
+    }
+}
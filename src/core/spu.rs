@@ -1,5 +1,6 @@
 use crate::core::cycle_manager::EventType;
 use crate::core::emu::Emu;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType::ARM7;
 use crate::logging::debug_println;
 use crate::presenter::{PRESENTER_AUDIO_BUF_SIZE, PRESENTER_AUDIO_SAMPLE_RATE};
@@ -13,9 +14,10 @@ use std::ptr::NonNull;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::Thread;
 use std::{mem, slice, thread};
+use xxhash_rust::xxh32::Xxh32;
 
 pub const CHANNEL_COUNT: usize = 16;
-const SAMPLE_RATE: usize = 32768;
+pub const SAMPLE_RATE: usize = 32768;
 pub const SAMPLE_BUFFER_SIZE: usize = SAMPLE_RATE * PRESENTER_AUDIO_BUF_SIZE / PRESENTER_AUDIO_SAMPLE_RATE;
 
 pub struct SoundSampler {
@@ -30,6 +32,8 @@ pub struct SoundSampler {
     stretch_ratio: f32,
     average_size: usize,
     size_count: usize,
+    sample_hash: Option<Xxh32>,
+    recording: Option<Vec<u32>>,
 }
 
 impl SoundSampler {
@@ -51,10 +55,37 @@ impl SoundSampler {
             stretch_ratio: 1.0,
             average_size: 0,
             size_count: 0,
+            sample_hash: None,
+            recording: None,
         }
     }
 
+    // Hash every sample coming out of the spu, independent of what the audio thread consumes
+    pub fn enable_sample_hash(&mut self) {
+        self.sample_hash = Some(Xxh32::new(0));
+    }
+
+    pub fn get_sample_hash(&self) -> Option<u32> {
+        self.sample_hash.as_ref().map(|hash| hash.digest())
+    }
+
+    // Keep every sample, for writing them out offline
+    pub fn enable_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    pub fn take_recording(&mut self) -> Vec<u32> {
+        self.recording.as_mut().map(mem::take).unwrap_or_default()
+    }
+
     fn push(&mut self, sample: u32) {
+        if let Some(hash) = &mut self.sample_hash {
+            hash.update(&sample.to_le_bytes());
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.push(sample);
+        }
+
         while self.busy.compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire).is_err() {}
 
         unsafe { assert_unchecked(self.busy_queue <= 1) };
@@ -324,6 +355,24 @@ impl Spu {
             sound_sampler,
         }
     }
+
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.channels);
+        state.write(&self.sound_cap_channels);
+        state.write(&self.main_sound_cnt);
+        state.write(&self.sound_bias);
+        state.write(&self.duty_cycles);
+        state.write(&self.noise_values);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.channels);
+        state.read(&mut self.sound_cap_channels);
+        state.read(&mut self.main_sound_cnt);
+        state.read(&mut self.sound_bias);
+        state.read(&mut self.duty_cycles);
+        state.read(&mut self.noise_values);
+    }
 }
 
 impl Emu {
@@ -0,0 +1,82 @@
+use crate::core::emu::Emu;
+use crate::core::spi::FIRMWARE_SIZE;
+use crate::logging::info_println;
+use crate::utils;
+use crate::utils::StrErr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const BIOS9_SIZE: usize = 4 * 1024;
+const BIOS7_SIZE: usize = 16 * 1024;
+const FIRMWARE_SIZES: [usize; 3] = [FIRMWARE_SIZE, FIRMWARE_SIZE * 2, FIRMWARE_SIZE * 4];
+
+#[derive(Default)]
+pub struct SystemFiles {
+    pub bios9: Option<Vec<u8>>,
+    pub bios7: Option<Vec<u8>>,
+    pub firmware: Option<Vec<u8>>,
+    pub firmware_save_path: Option<PathBuf>,
+}
+
+impl SystemFiles {
+    // Missing, unreadable or truncated files are reported and replaced by the HLE bios or the generated firmware
+    fn load_file_or_fallback(path: &Path, sizes: &[usize]) -> Option<Vec<u8>> {
+        Self::load_file(path, sizes).unwrap_or_else(|err| {
+            info_println!("{err:?}, falling back to the built-in replacement");
+            None
+        })
+    }
+
+    fn load_file(path: &Path, sizes: &[usize]) -> Result<Option<Vec<u8>>, StrErr> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let data = fs::read(path).map_err(|e| StrErr::new(format!("Failed to read {path:?}: {e}")))?;
+        if !sizes.contains(&data.len()) {
+            return Err(StrErr::new(format!("{path:?} has unexpected size {:x}", data.len())));
+        }
+        info_println!("Loaded {path:?}");
+        Ok(Some(data))
+    }
+
+    pub fn load(dir: Option<&Path>, firmware_save_path: PathBuf) -> Self {
+        let mut system_files = SystemFiles::default();
+        if let Some(dir) = dir {
+            system_files.bios9 = Self::load_file_or_fallback(&dir.join("bios9.bin"), &[BIOS9_SIZE]);
+            system_files.bios7 = Self::load_file_or_fallback(&dir.join("bios7.bin"), &[BIOS7_SIZE]);
+            system_files.firmware = Self::load_file_or_fallback(&dir.join("firmware.bin"), &FIRMWARE_SIZES);
+        }
+        // Firmware written by games in previous sessions takes priority
+        utils::recover_tmp_file(&firmware_save_path);
+        if let Some(firmware) = Self::load_file_or_fallback(&firmware_save_path, &FIRMWARE_SIZES) {
+            system_files.firmware = Some(firmware);
+        }
+        system_files.firmware_save_path = Some(firmware_save_path);
+        system_files
+    }
+}
+
+impl Emu {
+    pub fn system_files_apply(&mut self, system_files: SystemFiles) {
+        if let Some(bios9) = system_files.bios9 {
+            self.mem.bios9 = bios9;
+        }
+        if let Some(bios7) = system_files.bios7 {
+            self.cartridge.io.set_key1_table(&bios7);
+            self.mem.bios7 = bios7;
+        }
+        if let Some(firmware) = system_files.firmware {
+            if let Err(err) = self.spi.firmware.set(firmware) {
+                info_println!("{err:?}, falling back to the generated firmware");
+            }
+        }
+        if self.mem.bios9.len() + self.mem.bios7.len() != 0 {
+            // TODO Run the bios lle, which is required for booting through the firmware menu
+            // Until then the dumps are only read as data, bios calls stay HLE'd
+            info_println!("BIOS dumps are not executed yet, booting directly with the HLE BIOS");
+        }
+        if let Some(firmware_save_path) = system_files.firmware_save_path {
+            self.spi.firmware.set_save_path(firmware_save_path);
+        }
+    }
+}
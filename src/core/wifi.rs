@@ -1,5 +1,6 @@
 use crate::core::cpu_regs::InterruptFlag;
 use crate::core::emu::Emu;
+use crate::core::save_state::{SaveStateReader, SaveStateWriter};
 use crate::core::CpuType::ARM7;
 use crate::utils::HeapMemU8;
 use bilge::prelude::*;
@@ -82,6 +83,98 @@ impl Wifi {
         instance.bb_registers[0x64] = 0xFF;
         instance
     }
+
+    pub fn save_state(&self, state: &mut SaveStateWriter) {
+        state.write(&self.w_mode_wep);
+        state.write(&self.w_txstat_cnt);
+        state.write(&self.w_irf);
+        state.write(&self.w_ie);
+        state.write(&self.w_macaddr);
+        state.write(&self.w_bssid);
+        state.write(&self.w_aid_full);
+        state.write(&self.w_rxcnt);
+        state.write(&self.w_powerstate);
+        state.write(&self.w_powerforce);
+        state.write(&self.w_rxbuf_begin);
+        state.write(&self.w_rxbuf_end);
+        state.write(&self.w_rxbuf_wrcsr);
+        state.write(&self.w_rxbuf_wr_addr);
+        state.write(&self.w_rxbuf_rd_addr);
+        state.write(&self.w_rxbuf_readcsr);
+        state.write(&self.w_rxbuf_gap);
+        state.write(&self.w_rxbuf_gapdisp);
+        state.write(&self.w_txbuf_loc);
+        state.write(&self.w_beacon_int);
+        state.write(&self.w_txbuf_reply1);
+        state.write(&self.w_txbuf_reply2);
+        state.write(&self.w_txreq_read);
+        state.write(&self.w_txstat);
+        state.write(&self.w_us_countcnt);
+        state.write(&self.w_us_comparecnt);
+        state.write(&self.w_cmd_countcnt);
+        state.write(&self.w_us_compare);
+        state.write(&self.w_us_count);
+        state.write(&self.w_pre_beacon);
+        state.write(&self.w_cmd_count);
+        state.write(&self.w_beacon_count);
+        state.write(&self.w_rxbuf_count);
+        state.write(&self.w_txbuf_wr_addr);
+        state.write(&self.w_txbuf_count);
+        state.write(&self.w_txbuf_gap);
+        state.write(&self.w_txbuf_gapdisp);
+        state.write(&self.w_post_beacon);
+        state.write(&self.w_bb_write);
+        state.write(&self.w_bb_read);
+        state.write(&self.w_tx_seqno);
+        state.write(&*self.bb_registers);
+        state.write(&self.w_config);
+    }
+
+    pub fn load_state(&mut self, state: &mut SaveStateReader) {
+        state.read(&mut self.w_mode_wep);
+        state.read(&mut self.w_txstat_cnt);
+        state.read(&mut self.w_irf);
+        state.read(&mut self.w_ie);
+        state.read(&mut self.w_macaddr);
+        state.read(&mut self.w_bssid);
+        state.read(&mut self.w_aid_full);
+        state.read(&mut self.w_rxcnt);
+        state.read(&mut self.w_powerstate);
+        state.read(&mut self.w_powerforce);
+        state.read(&mut self.w_rxbuf_begin);
+        state.read(&mut self.w_rxbuf_end);
+        state.read(&mut self.w_rxbuf_wrcsr);
+        state.read(&mut self.w_rxbuf_wr_addr);
+        state.read(&mut self.w_rxbuf_rd_addr);
+        state.read(&mut self.w_rxbuf_readcsr);
+        state.read(&mut self.w_rxbuf_gap);
+        state.read(&mut self.w_rxbuf_gapdisp);
+        state.read(&mut self.w_txbuf_loc);
+        state.read(&mut self.w_beacon_int);
+        state.read(&mut self.w_txbuf_reply1);
+        state.read(&mut self.w_txbuf_reply2);
+        state.read(&mut self.w_txreq_read);
+        state.read(&mut self.w_txstat);
+        state.read(&mut self.w_us_countcnt);
+        state.read(&mut self.w_us_comparecnt);
+        state.read(&mut self.w_cmd_countcnt);
+        state.read(&mut self.w_us_compare);
+        state.read(&mut self.w_us_count);
+        state.read(&mut self.w_pre_beacon);
+        state.read(&mut self.w_cmd_count);
+        state.read(&mut self.w_beacon_count);
+        state.read(&mut self.w_rxbuf_count);
+        state.read(&mut self.w_txbuf_wr_addr);
+        state.read(&mut self.w_txbuf_count);
+        state.read(&mut self.w_txbuf_gap);
+        state.read(&mut self.w_txbuf_gapdisp);
+        state.read(&mut self.w_post_beacon);
+        state.read(&mut self.w_bb_write);
+        state.read(&mut self.w_bb_read);
+        state.read(&mut self.w_tx_seqno);
+        state.read(&mut *self.bb_registers);
+        state.read(&mut self.w_config);
+    }
 }
 
 impl Emu {
@@ -0,0 +1,160 @@
+use crate::logging::info_println;
+
+const DLDI_MAGIC: &[u8] = b"\xED\xA5\x8D\xBF Chishm\0";
+const DLDI_HEADER_SIZE: usize = 0x80;
+
+const DLDI_DRIVER_SIZE: usize = 0x0D;
+const DLDI_FIX_SECTIONS: usize = 0x0E;
+const DLDI_ALLOCATED_SIZE: usize = 0x0F;
+const DLDI_NAME: usize = 0x10;
+const DLDI_DATA_START: usize = 0x40;
+const DLDI_IO_TYPE: usize = 0x60;
+const DLDI_FEATURES: usize = 0x64;
+const DLDI_STARTUP: usize = 0x68;
+
+const FEATURE_CAN_READ: u32 = 1 << 0;
+const FEATURE_CAN_WRITE: u32 = 1 << 1;
+const FEATURE_SLOT_NDS: u32 = 1 << 5;
+
+// Sector reads and writes go through the custom cartridge commands 0xC0 and 0xC1,
+// with the sector number in the second command word, 0x200 bytes are transferred per command
+// Afterwards command 0xC2 returns a non zero word if any of the transfers failed
+// See cartridge_set_rom_ctrl
+//
+// Position independent, the driver has no data, glue, got or bss sections to relocate
+const DRIVER_CODE: [u32; 69] = [
+    // startup, is_inserted, clear_status, shutdown
+    0xE3A00001, // mov r0, #1
+    0xE12FFF1E, // bx lr
+    // read_sectors(sector, count, buf)
+    0xE92D4010, // push {r4, lr}
+    0xE59FC0FC, // ldr r12, =0x040001A4
+    0xE3A04641, // mov r4, #0x04100000
+    0xE2511001, // subs r1, r1, #1
+    0x4A000014, // bmi done
+    0xE3A030C0, // mov r3, #0xC0
+    0xE58C3004, // str r3, [r12, #4]
+    0xE58C0008, // str r0, [r12, #8]
+    0xE3A034A1, // mov r3, #0xA1000000
+    0xE58C3000, // str r3, [r12]
+    0xE59C3000, // ldr r3, [r12]
+    0xE3130102, // tst r3, #0x80000000
+    0x0A00000A, // beq read_next
+    0xE3130502, // tst r3, #0x00800000
+    0x0AFFFFFA, // beq read_word
+    0xE5943010, // ldr r3, [r4, #0x10]
+    0xE4C23001, // strb r3, [r2], #1
+    0xE1A03423, // lsr r3, r3, #8
+    0xE4C23001, // strb r3, [r2], #1
+    0xE1A03423, // lsr r3, r3, #8
+    0xE4C23001, // strb r3, [r2], #1
+    0xE1A03423, // lsr r3, r3, #8
+    0xE4C23001, // strb r3, [r2], #1
+    0xEAFFFFF1, // b read_word
+    0xE2800001, // read_next: add r0, r0, #1
+    0xEAFFFFE8, // b read_sector
+    0xE3A030C2, // done: mov r3, #0xC2
+    0xE58C3004, // str r3, [r12, #4]
+    0xE3A03000, // mov r3, #0
+    0xE58C3008, // str r3, [r12, #8]
+    0xE3A034A7, // mov r3, #0xA7000000
+    0xE58C3000, // str r3, [r12]
+    0xE59C3000, // status_word: ldr r3, [r12]
+    0xE3130502, // tst r3, #0x00800000
+    0x0AFFFFFC, // beq status_word
+    0xE5943010, // ldr r3, [r4, #0x10]
+    0xE3530000, // cmp r3, #0
+    0x03A00001, // moveq r0, #1
+    0x13A00000, // movne r0, #0
+    0xE8BD8010, // pop {r4, pc}
+    // write_sectors(sector, count, buf)
+    0xE92D4010, // push {r4, lr}
+    0xE59FC05C, // ldr r12, =0x040001A4
+    0xE3A04641, // mov r4, #0x04100000
+    0xE2511001, // subs r1, r1, #1
+    0x4AFFFFEC, // bmi done
+    0xE3A030C1, // mov r3, #0xC1
+    0xE58C3004, // str r3, [r12, #4]
+    0xE58C0008, // str r0, [r12, #8]
+    0xE3A034E1, // mov r3, #0xE1000000
+    0xE58C3000, // str r3, [r12]
+    0xE59C3000, // ldr r3, [r12]
+    0xE3130102, // tst r3, #0x80000000
+    0x0A00000A, // beq write_next
+    0xE3130502, // tst r3, #0x00800000
+    0x0AFFFFFA, // beq write_word
+    0xE4D23001, // ldrb r3, [r2], #1
+    0xE4D2E001, // ldrb lr, [r2], #1
+    0xE183340E, // orr r3, r3, lr, lsl #8
+    0xE4D2E001, // ldrb lr, [r2], #1
+    0xE183380E, // orr r3, r3, lr, lsl #16
+    0xE4D2E001, // ldrb lr, [r2], #1
+    0xE1833C0E, // orr r3, r3, lr, lsl #24
+    0xE5843010, // str r3, [r4, #0x10]
+    0xEAFFFFF1, // b write_word
+    0xE2800001, // write_next: add r0, r0, #1
+    0xEAFFFFE8, // b write_sector
+    0x040001A4, // literal pool
+];
+const DRIVER_STARTUP_OFFSET: u32 = 0x00;
+const DRIVER_READ_SECTORS_OFFSET: u32 = 0x08;
+const DRIVER_WRITE_SECTORS_OFFSET: u32 = 0xA8;
+
+fn write_u32(stub: &mut [u8], offset: usize, value: u32) {
+    stub[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn find_dldi_stub(code: &[u8]) -> Option<usize> {
+    code.windows(DLDI_MAGIC.len())
+        .position(|window| window == DLDI_MAGIC)
+        .filter(|offset| offset + DLDI_HEADER_SIZE <= code.len())
+}
+
+// Replaces the DLDI driver stub of homebrew with a driver talking to the emulated cartridge
+pub fn patch_dldi(code: &mut [u8], cpu_name: &str) -> bool {
+    let Some(stub_offset) = find_dldi_stub(code) else {
+        return false;
+    };
+
+    let driver_size = DLDI_HEADER_SIZE + size_of_val(&DRIVER_CODE);
+    let driver_size_shift = driver_size.next_power_of_two().trailing_zeros() as u8;
+    let allocated_size_shift = code[stub_offset + DLDI_ALLOCATED_SIZE];
+    if driver_size_shift > allocated_size_shift || stub_offset + driver_size > code.len() {
+        info_println!("DLDI stub in {cpu_name} code is too small for the driver, {driver_size:x} bytes needed");
+        return false;
+    }
+
+    let stub = &mut code[stub_offset..];
+    let mem_start = u32::from_le_bytes(stub[DLDI_DATA_START..DLDI_DATA_START + 4].try_into().unwrap());
+    let mem_end = mem_start + driver_size as u32;
+    let code_start = mem_start + DLDI_HEADER_SIZE as u32;
+
+    stub[DLDI_DRIVER_SIZE] = driver_size_shift;
+    stub[DLDI_FIX_SECTIONS] = 0;
+    let mut name = [0u8; 0x30];
+    name[..15].copy_from_slice(b"DSVita FAT disk");
+    stub[DLDI_NAME..DLDI_NAME + name.len()].copy_from_slice(&name);
+    // Data, glue, got and bss are empty ranges after the driver
+    for (i, addr) in [mem_start, mem_end, mem_end, mem_end, mem_end, mem_end, mem_end, mem_end].into_iter().enumerate() {
+        write_u32(stub, DLDI_DATA_START + i * 4, addr);
+    }
+    stub[DLDI_IO_TYPE..DLDI_IO_TYPE + 4].copy_from_slice(b"DSVT");
+    write_u32(stub, DLDI_FEATURES, FEATURE_CAN_READ | FEATURE_CAN_WRITE | FEATURE_SLOT_NDS);
+    let functions = [
+        DRIVER_STARTUP_OFFSET,
+        DRIVER_STARTUP_OFFSET,
+        DRIVER_READ_SECTORS_OFFSET,
+        DRIVER_WRITE_SECTORS_OFFSET,
+        DRIVER_STARTUP_OFFSET,
+        DRIVER_STARTUP_OFFSET,
+    ];
+    for (i, offset) in functions.into_iter().enumerate() {
+        write_u32(stub, DLDI_STARTUP + i * 4, code_start + offset);
+    }
+    for (i, opcode) in DRIVER_CODE.into_iter().enumerate() {
+        write_u32(stub, DLDI_HEADER_SIZE + i * 4, opcode);
+    }
+
+    info_println!("Patched DLDI driver into {cpu_name} code at {mem_start:x}");
+    true
+}
@@ -0,0 +1,382 @@
+use crate::logging::info_println;
+use chrono::{DateTime, Datelike, Local, Timelike};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{fs, io};
+
+pub const SECTOR_SIZE: usize = 0x200;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ROOT_DIR_ENTRIES: usize = 512;
+const RESERVED_SECTORS: u32 = 1;
+const FAT_COUNT: u32 = 2;
+// Free space of generated images, so homebrew can still write files
+const FREE_SPACE: u64 = 32 * 1024 * 1024;
+const FAT16_MIN_CLUSTERS: u64 = 4085;
+const FAT16_MAX_CLUSTERS: u64 = 65524;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+enum Node {
+    File { name: String, path: PathBuf, size: u64, modified: SystemTime },
+    Dir { name: String, children: Vec<Node>, modified: SystemTime },
+}
+
+impl Node {
+    fn scan(name: String, path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        if metadata.is_dir() {
+            let mut children = Vec::new();
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                children.push(Node::scan(name, &entry.path())?);
+            }
+            children.sort_by(|a, b| a.name().cmp(b.name()));
+            Ok(Node::Dir { name, children, modified })
+        } else {
+            if metadata.len() > u32::MAX as u64 {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("{path:?} is too big for FAT")));
+            }
+            Ok(Node::File {
+                name,
+                path: path.to_path_buf(),
+                size: metadata.len(),
+                modified,
+            })
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Node::File { name, .. } | Node::Dir { name, .. } => name,
+        }
+    }
+
+    // Including the . and .. entries of directories
+    fn dir_entries(children: &[Node], is_root: bool) -> usize {
+        let dots = if is_root { 0 } else { 2 };
+        dots + children.iter().map(|child| 1 + lfn_entry_count(child.name())).sum::<usize>()
+    }
+
+    fn clusters(&self, cluster_size: u64) -> u64 {
+        match self {
+            Node::File { size, .. } => size.div_ceil(cluster_size),
+            Node::Dir { children, .. } => {
+                let dir_size = (Node::dir_entries(children, false) * DIR_ENTRY_SIZE) as u64;
+                dir_size.div_ceil(cluster_size) + children.iter().map(|child| child.clusters(cluster_size)).sum::<u64>()
+            }
+        }
+    }
+}
+
+fn is_short_name(name: &str) -> bool {
+    let valid = |part: &str, max_len: usize| !part.is_empty() && part.len() <= max_len && part.bytes().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c));
+    match name.split_once('.') {
+        Some((base, ext)) => valid(base, 8) && valid(ext, 3),
+        None => valid(name, 8),
+    }
+}
+
+fn lfn_entry_count(name: &str) -> usize {
+    if is_short_name(name) {
+        0
+    } else {
+        name.encode_utf16().count().div_ceil(13)
+    }
+}
+
+fn short_name(name: &str, index: usize) -> [u8; 11] {
+    let mut short_name = [b' '; 11];
+    if is_short_name(name) {
+        let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+        short_name[..base.len()].copy_from_slice(base.as_bytes());
+        short_name[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+        return short_name;
+    }
+
+    let sanitize = |part: &str, max_len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() as u8 } else { b'_' })
+            .take(max_len)
+            .collect()
+    };
+    let (base, ext) = name.rsplit_once('.').filter(|(base, _)| !base.is_empty()).unwrap_or((name, ""));
+    // Numbered tails keep the short names unique within a directory
+    let tail = format!("~{index}");
+    let base = sanitize(base, 8 - tail.len());
+    let ext = sanitize(ext, 3);
+    short_name[..base.len()].copy_from_slice(&base);
+    short_name[base.len()..base.len() + tail.len()].copy_from_slice(tail.as_bytes());
+    short_name[8..8 + ext.len()].copy_from_slice(&ext);
+    short_name
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*c))
+}
+
+fn fat_date_time(time: SystemTime) -> (u16, u16) {
+    let time = DateTime::<Local>::from(time);
+    let year = time.year().clamp(1980, 2107) as u16 - 1980;
+    let date = (year << 9) | ((time.month() as u16) << 5) | time.day() as u16;
+    let time = ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() as u16 / 2);
+    (date, time)
+}
+
+fn push_dir_entry(entries: &mut Vec<u8>, short_name: &[u8; 11], attr: u8, cluster: u16, size: u32, modified: SystemTime) {
+    let (date, time) = fat_date_time(modified);
+    let mut entry = [0u8; DIR_ENTRY_SIZE];
+    entry[..11].copy_from_slice(short_name);
+    entry[11] = attr;
+    entry[14..16].copy_from_slice(&time.to_le_bytes());
+    entry[16..18].copy_from_slice(&date.to_le_bytes());
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+    entry[22..24].copy_from_slice(&time.to_le_bytes());
+    entry[24..26].copy_from_slice(&date.to_le_bytes());
+    entry[26..28].copy_from_slice(&cluster.to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entries.extend_from_slice(&entry);
+}
+
+fn push_lfn_entries(entries: &mut Vec<u8>, name: &str, checksum: u8) {
+    let mut chars = name.encode_utf16().collect::<Vec<_>>();
+    let count = chars.len().div_ceil(13);
+    // Terminated by 0 and padded with 0xFFFF
+    if chars.len() % 13 != 0 {
+        chars.push(0);
+        chars.resize(count * 13, 0xFFFF);
+    }
+
+    for i in (0..count).rev() {
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[0] = (i + 1) as u8 | if i == count - 1 { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let name_part = &chars[i * 13..(i + 1) * 13];
+        for (j, c) in name_part.iter().enumerate() {
+            let offset = match j {
+                0..5 => 1 + j * 2,
+                5..11 => 14 + (j - 5) * 2,
+                _ => 28 + (j - 11) * 2,
+            };
+            entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entries.extend_from_slice(&entry);
+    }
+}
+
+enum ClusterData {
+    Dir(Vec<u8>),
+    File(PathBuf),
+}
+
+struct FatBuilder {
+    cluster_size: u64,
+    fat: Vec<u16>,
+    next_cluster: u16,
+    data: Vec<(u16, ClusterData)>,
+}
+
+impl FatBuilder {
+    fn alloc(&mut self, clusters: u64) -> u16 {
+        if clusters == 0 {
+            return 0;
+        }
+        let start = self.next_cluster;
+        for i in 0..clusters as u16 {
+            self.fat[(start + i) as usize] = if i as u64 == clusters - 1 { 0xFFFF } else { start + i + 1 };
+        }
+        self.next_cluster += clusters as u16;
+        start
+    }
+
+    // Allocates the clusters of all children and returns the directory entries pointing to them
+    fn build_dir(&mut self, children: &[Node], dir_cluster: u16, parent_cluster: u16, modified: SystemTime) -> Vec<u8> {
+        let mut entries = Vec::new();
+        if dir_cluster != 0 {
+            push_dir_entry(&mut entries, b".          ", ATTR_DIRECTORY, dir_cluster, 0, modified);
+            push_dir_entry(&mut entries, b"..         ", ATTR_DIRECTORY, parent_cluster, 0, modified);
+        }
+
+        let mut short_name_index = 1;
+        for child in children {
+            let name = child.name();
+            let short_name = short_name(name, short_name_index);
+            if !is_short_name(name) {
+                short_name_index += 1;
+                push_lfn_entries(&mut entries, name, short_name_checksum(&short_name));
+            }
+
+            match child {
+                Node::File { path, size, modified, .. } => {
+                    let cluster = self.alloc(size.div_ceil(self.cluster_size));
+                    if cluster != 0 {
+                        self.data.push((cluster, ClusterData::File(path.clone())));
+                    }
+                    push_dir_entry(&mut entries, &short_name, ATTR_ARCHIVE, cluster, *size as u32, *modified);
+                }
+                Node::Dir { children, modified, .. } => {
+                    let dir_size = (Node::dir_entries(children, false) * DIR_ENTRY_SIZE) as u64;
+                    let cluster = self.alloc(dir_size.div_ceil(self.cluster_size));
+                    let child_entries = self.build_dir(children, cluster, dir_cluster, *modified);
+                    self.data.push((cluster, ClusterData::Dir(child_entries)));
+                    push_dir_entry(&mut entries, &short_name, ATTR_DIRECTORY, cluster, 0, *modified);
+                }
+            }
+        }
+        entries
+    }
+}
+
+// Newest modification time of the path and everything below it
+fn newest_modified(path: &Path) -> io::Result<SystemTime> {
+    let metadata = fs::metadata(path)?;
+    let mut newest = metadata.modified()?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            newest = newest.max(newest_modified(&entry?.path())?);
+        }
+    }
+    Ok(newest)
+}
+
+// Generates a FAT16 image holding the contents of the directory
+fn build_image(dir: &Path, image_path: &Path) -> io::Result<()> {
+    let Node::Dir { children, modified, .. } = Node::scan(String::new(), dir)? else {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("{dir:?} is not a directory")));
+    };
+    if Node::dir_entries(&children, true) > ROOT_DIR_ENTRIES {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("Too many files in the root of {dir:?}")));
+    }
+
+    let (sectors_per_cluster, cluster_count) = (0..=6)
+        .map(|shift| {
+            let cluster_size = (SECTOR_SIZE << shift) as u64;
+            let used = children.iter().map(|child| child.clusters(cluster_size)).sum::<u64>();
+            (1u64 << shift, (used + FREE_SPACE / cluster_size).max(FAT16_MIN_CLUSTERS))
+        })
+        .find(|(_, cluster_count)| *cluster_count <= FAT16_MAX_CLUSTERS)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("{dir:?} is too big for a FAT16 image")))?;
+
+    let fat_sectors = ((cluster_count + 2) * 2).div_ceil(SECTOR_SIZE as u64) as u32;
+    let root_dir_sectors = (ROOT_DIR_ENTRIES * DIR_ENTRY_SIZE / SECTOR_SIZE) as u32;
+    let data_start = RESERVED_SECTORS + FAT_COUNT * fat_sectors + root_dir_sectors;
+    let total_sectors = data_start + (cluster_count * sectors_per_cluster) as u32;
+
+    let mut builder = FatBuilder {
+        cluster_size: sectors_per_cluster * SECTOR_SIZE as u64,
+        fat: vec![0; fat_sectors as usize * SECTOR_SIZE / 2],
+        next_cluster: 2,
+        data: Vec::new(),
+    };
+    builder.fat[0] = 0xFFF8;
+    builder.fat[1] = 0xFFFF;
+    let root_entries = builder.build_dir(&children, 0, 0, modified);
+
+    let mut boot_sector = [0u8; SECTOR_SIZE];
+    boot_sector[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot_sector[3..11].copy_from_slice(b"DSVITA  ");
+    boot_sector[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot_sector[13] = sectors_per_cluster as u8;
+    boot_sector[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot_sector[16] = FAT_COUNT as u8;
+    boot_sector[17..19].copy_from_slice(&(ROOT_DIR_ENTRIES as u16).to_le_bytes());
+    if total_sectors < 0x10000 {
+        boot_sector[19..21].copy_from_slice(&(total_sectors as u16).to_le_bytes());
+    } else {
+        boot_sector[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+    }
+    boot_sector[21] = 0xF8;
+    boot_sector[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+    boot_sector[24..26].copy_from_slice(&63u16.to_le_bytes());
+    boot_sector[26..28].copy_from_slice(&255u16.to_le_bytes());
+    boot_sector[36] = 0x80;
+    boot_sector[38] = 0x29;
+    boot_sector[39..43].copy_from_slice(&0x44535654u32.to_le_bytes());
+    boot_sector[43..54].copy_from_slice(b"DSVITA     ");
+    boot_sector[54..62].copy_from_slice(b"FAT16   ");
+    boot_sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    let mut file = File::create(image_path)?;
+    file.set_len(total_sectors as u64 * SECTOR_SIZE as u64)?;
+    file.write_all(&boot_sector)?;
+    let fat = builder.fat.iter().flat_map(|entry| entry.to_le_bytes()).collect::<Vec<_>>();
+    for _ in 0..FAT_COUNT {
+        file.write_all(&fat)?;
+    }
+    file.write_all(&root_entries)?;
+
+    for (cluster, data) in &builder.data {
+        let offset = (data_start as u64 + (*cluster as u64 - 2) * sectors_per_cluster) * SECTOR_SIZE as u64;
+        file.seek(SeekFrom::Start(offset))?;
+        match data {
+            ClusterData::Dir(entries) => file.write_all(entries)?,
+            ClusterData::File(path) => {
+                io::copy(&mut File::open(path)?, &mut file)?;
+            }
+        }
+    }
+    file.sync_all()?;
+
+    info_println!("Generated FAT16 image {image_path:?} from {dir:?} with {} of {cluster_count} clusters used", builder.next_cluster - 2);
+    Ok(())
+}
+
+pub struct FatImage {
+    file: File,
+    sector_count: u32,
+}
+
+impl FatImage {
+    // Directories are converted into an image next to them, writes only end up in that image
+    // The image is kept across boots until anything in the directory is changed
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let image_path = if path.is_dir() {
+            let mut image_path = path.as_os_str().to_owned();
+            image_path.push(".dldi.img");
+            let image_path = PathBuf::from(image_path);
+            let image_modified = fs::metadata(&image_path).and_then(|metadata| metadata.modified());
+            match (image_modified, newest_modified(path)) {
+                (Ok(image_modified), Ok(dir_modified)) if image_modified >= dir_modified => {
+                    info_println!("Reusing FAT image {image_path:?}, {path:?} didn't change since it was generated");
+                }
+                _ => build_image(path, &image_path)?,
+            }
+            image_path
+        } else {
+            path.to_path_buf()
+        };
+
+        let file = OpenOptions::new().read(true).write(true).open(&image_path)?;
+        let sector_count = (file.metadata()?.len() / SECTOR_SIZE as u64) as u32;
+        info_println!("Opened FAT image {image_path:?} with {sector_count} sectors");
+        Ok(FatImage { file, sector_count })
+    }
+
+    pub fn read_sectors(&mut self, sector: u32, buf: &mut [u8]) -> io::Result<()> {
+        buf.fill(0);
+        if sector >= self.sector_count {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(sector as u64 * SECTOR_SIZE as u64))?;
+        let available = (self.sector_count - sector) as usize * SECTOR_SIZE;
+        let len = buf.len().min(available);
+        self.file.read_exact(&mut buf[..len])
+    }
+
+    pub fn write_sectors(&mut self, sector: u32, buf: &[u8]) -> io::Result<()> {
+        if sector >= self.sector_count {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(sector as u64 * SECTOR_SIZE as u64))?;
+        let available = (self.sector_count - sector) as usize * SECTOR_SIZE;
+        self.file.write_all(&buf[..buf.len().min(available)])
+    }
+}
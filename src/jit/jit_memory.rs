@@ -397,6 +397,13 @@ impl JitMemory {
         invalidate!(guest_addr + size as u32 - 1);
     }
 
+    pub fn invalidate_all(&mut self) {
+        self.jit_entries.reset();
+        self.jit_live_ranges.itcm.fill(0);
+        self.jit_live_ranges.main.fill(0);
+        self.jit_live_ranges.vram.fill(0);
+    }
+
     pub fn invalidate_vram(&mut self) {
         for live_range in self.jit_live_ranges.vram.deref() {
             if *live_range != 0 {
@@ -20,9 +20,14 @@ use crate::cartridge_io::CartridgeIo;
 use crate::core::emu::Emu;
 use crate::core::graphics::gpu::Gpu;
 use crate::core::graphics::gpu_renderer::GpuRenderer;
+use crate::core::hle::sound_test::SoundTest;
+use crate::core::mic::{MicBackend, SilentMic};
+use crate::core::save_state::SaveStateRequest;
 use crate::core::spu::{SoundSampler, SAMPLE_BUFFER_SIZE};
+use crate::core::system_files::SystemFiles;
 use crate::core::thread_regs::ThreadRegs;
-use crate::core::{spi, CpuType};
+use crate::core::CpuType;
+use crate::fat_image::FatImage;
 use crate::jit::jit_asm::{JitAsm, MAX_STACK_DEPTH_SIZE};
 use crate::jit::jit_memory::JitMemory;
 use crate::logging::{debug_println, info_println};
@@ -30,12 +35,12 @@ use crate::mmap::{register_abort_handler, ArmContext, Mmap, PAGE_SIZE};
 use crate::presenter::{PresentEvent, Presenter, PRESENTER_AUDIO_BUF_SIZE};
 use crate::profiling::{profiling_init, profiling_set_thread_name};
 use crate::settings::{Arm7Emu, Settings};
-use crate::utils::{const_str_equal, set_thread_prio_affinity, HeapMemU32, ThreadAffinity, ThreadPriority};
+use crate::utils::{const_str_equal, set_thread_prio_affinity, HeapMemU32, StrErr, ThreadAffinity, ThreadPriority};
 use std::cell::UnsafeCell;
 use std::cmp::min;
 use std::intrinsics::unlikely;
 use std::ptr::NonNull;
-use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::Thread;
 use std::time::{Duration, Instant};
@@ -46,6 +51,8 @@ mod bitset;
 mod cartridge_io;
 mod cartridge_metadata;
 mod core;
+mod dldi;
+mod fat_image;
 mod fixed_fifo;
 mod jit;
 mod linked_list;
@@ -54,6 +61,7 @@ mod math;
 mod mmap;
 mod presenter;
 mod profiling;
+mod save_formats;
 mod settings;
 mod soundtouch;
 mod utils;
@@ -72,6 +80,10 @@ fn run_cpu(
     settings: Settings,
     gpu_renderer: NonNull<GpuRenderer>,
     last_save_time: Arc<Mutex<Option<(Instant, bool)>>>,
+    save_state_request: Arc<AtomicU8>,
+    system_files: SystemFiles,
+    mic_backend: Box<dyn MicBackend>,
+    fat_image: Option<FatImage>,
 ) {
     let arm9_ram_addr = cartridge_io.header.arm9_values.ram_address;
     let arm9_entry_addr = cartridge_io.header.arm9_values.entry_address;
@@ -90,9 +102,12 @@ fn run_cpu(
     // Initializing jit mem inside of emu, breaks kubridge for some reason
     // Might be caused by initialize shared mem? Initialize here and pass it to emu
     let jit_mem = JitMemory::new(&settings);
-    let mut emu_unsafe = UnsafeCell::new(Emu::new(cartridge_io, fps, key_map, touch_points, sound_sampler, jit_mem, settings));
+    let mut emu_unsafe = UnsafeCell::new(Emu::new(cartridge_io, fps, key_map, touch_points, sound_sampler, jit_mem, settings, save_state_request, mic_backend));
     let emu_ptr = emu_unsafe.get() as u32;
     let emu = emu_unsafe.get_mut();
+    emu.system_files_apply(system_files);
+    emu.cartridge.io.save_backups = emu.settings.save_backups();
+    emu.spi.firmware.apply_settings(&emu.settings);
 
     info_println!("Initialize mmu");
     emu.mmu_update_all::<{ ARM9 }>();
@@ -121,8 +136,8 @@ fn run_cpu(
         emu.mem_write_no_tcm::<{ ARM9 }, _>(0x27FFC04, 0x00001FC2u32); // Copy of chip ID 2
 
         // User settings
-        let user_settings = &spi::SPI_FIRMWARE[spi::USER_SETTINGS_1_ADDR..spi::USER_SETTINGS_1_ADDR + 0x70];
-        emu.mem_write_multiple_slice::<{ ARM9 }, false, _>(0x27FFC80, user_settings);
+        let user_settings = emu.spi.firmware.user_settings();
+        emu.mem_write_multiple_slice::<{ ARM9 }, false, _>(0x27FFC80, &user_settings);
     }
 
     // unsafe {
@@ -169,8 +184,18 @@ fn run_cpu(
     }
 
     {
-        let arm9_code = emu.cartridge.io.read_arm9_code();
-        let arm7_code = emu.cartridge.io.read_arm7_code();
+        let mut arm9_code = emu.cartridge.io.read_arm9_code();
+        let mut arm7_code = emu.cartridge.io.read_arm7_code();
+
+        if let Some(fat_image) = fat_image {
+            let arm9_patched = dldi::patch_dldi(&mut arm9_code, "ARM9");
+            let arm7_patched = dldi::patch_dldi(&mut arm7_code, "ARM7");
+            if arm9_patched || arm7_patched {
+                emu.cartridge.fat_image = Some(fat_image);
+            } else {
+                info_println!("No DLDI driver found, FAT image is not used");
+            }
+        }
 
         info_println!("write ARM9 code at {:x}", arm9_ram_addr);
         for (i, value) in arm9_code.iter().enumerate() {
@@ -202,6 +227,7 @@ fn run_cpu(
             let emu = unsafe { (emu_ptr as *mut Emu).as_mut().unwrap_unchecked() };
             loop {
                 emu.cartridge.io.flush_save_buf(&last_save_time);
+                emu.spi.firmware.flush(&last_save_time);
                 thread::sleep(Duration::from_secs(3));
             }
         })
@@ -218,6 +244,29 @@ fn run_cpu(
     save_thread.join().unwrap();
 }
 
+// Only sets up what the sound engine needs, no guest code is executed
+fn run_sound_test(cartridge_io: CartridgeIo, settings: Settings, sound_test: SoundTest) -> Result<(), StrErr> {
+    let mut sound_sampler = SoundSampler::new(false);
+    sound_sampler.enable_sample_hash();
+    sound_sampler.enable_recording();
+    let mut sound_sampler_ptr = NonNull::from(&mut sound_sampler);
+
+    let jit_mem = JitMemory::new(&settings);
+    let mut emu = Emu::new(
+        cartridge_io,
+        Arc::new(AtomicU16::new(0)),
+        Arc::new(AtomicU32::new(0xFFFFFFFF)),
+        Arc::new(AtomicU16::new(0)),
+        sound_sampler_ptr,
+        jit_mem,
+        settings,
+        Arc::new(AtomicU8::new(SaveStateRequest::None as u8)),
+        Box::new(SilentMic),
+    );
+    emu.mmu_update_all::<{ ARM7 }>();
+    emu.sound_test_run(&sound_test, unsafe { sound_sampler_ptr.as_mut() })
+}
+
 pub static mut CURRENT_RUNNING_CPU: CpuType = ARM9;
 
 pub unsafe fn get_jit_asm_ptr<'a, const CPU: CpuType>() -> *mut JitAsm<'a> {
@@ -295,6 +344,10 @@ fn execute_jit<const ARM7_HLE: bool>(emu: &mut UnsafeCell<Emu>) {
             jit_asm_arm7.runtime_data.set_idle_loop(false);
         }
 
+        if unlikely(emu.save_state_request.load(Ordering::Relaxed) != SaveStateRequest::None as u8) && emu.save_state_process_request() && !ARM7_HLE {
+            jit_asm_arm7.runtime_data.set_idle_loop(false);
+        }
+
         emu.regs_3d_run_cmds(emu.cm.get_cycles());
     }
 }
@@ -409,6 +462,31 @@ pub fn actual_main() {
     presenter.destroy_ui();
     info_println!("{} Settings: {settings:?}", cartridge_io.file_name);
 
+    if let Some(sound_test) = presenter.get_sound_test() {
+        let result = thread::Builder::new()
+            .name("sound_test".to_owned())
+            .stack_size(MAX_STACK_DEPTH_SIZE + 1024 * 1024)
+            .spawn(move || run_sound_test(cartridge_io, settings, sound_test))
+            .unwrap()
+            .join()
+            .unwrap();
+        if let Err(err) = result {
+            eprintln!("Sound test failed: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mic_backend = presenter.get_mic_backend();
+    let fat_image = presenter.get_dldi_path().and_then(|path| match FatImage::open(&path) {
+        Ok(fat_image) => Some(fat_image),
+        Err(err) => {
+            info_println!("Failed to open DLDI image {path:?}: {err}, booting without DLDI");
+            None
+        }
+    });
+    let system_files = SystemFiles::load(presenter.get_system_files_dir().as_deref(), presenter.get_firmware_save_path());
+
     let fps = Arc::new(AtomicU16::new(0));
     let fps_clone = fps.clone();
 
@@ -418,17 +496,25 @@ pub fn actual_main() {
     let touch_points = Arc::new(AtomicU16::new(0));
     let touch_points_clone = touch_points.clone();
 
+    let headless = presenter.is_headless();
+
     let sound_sampler = UnsafeCell::new(SoundSampler::new(settings.framelimit()));
     let sound_sampler_ptr = sound_sampler.get() as usize;
+    if headless {
+        unsafe { sound_sampler.get().as_mut_unchecked().enable_sample_hash() };
+    }
 
     let presenter_audio = presenter.get_presenter_audio();
 
-    let gpu_renderer = UnsafeCell::new(GpuRenderer::new());
+    let gpu_renderer = UnsafeCell::new(GpuRenderer::new(headless, settings.scanline_2d_renderer()));
     let gpu_renderer_ptr = gpu_renderer.get() as usize;
 
     let last_save_time = Arc::new(Mutex::new(None));
     let last_save_time_clone = last_save_time.clone();
 
+    let save_state_request = Arc::new(AtomicU8::new(SaveStateRequest::None as u8));
+    let save_state_request_clone = save_state_request.clone();
+
     let settings_clone = settings.clone();
 
     let cpu_thread = thread::Builder::new()
@@ -447,36 +533,54 @@ pub fn actual_main() {
                 settings_clone,
                 NonNull::new(gpu_renderer_ptr as *mut GpuRenderer).unwrap(),
                 last_save_time_clone,
+                save_state_request_clone,
+                system_files,
+                mic_backend,
+                fat_image,
             );
         })
         .unwrap();
 
     let cpu_thread_ptr = cpu_thread.thread() as *const _ as usize;
-    let audio_thread = thread::Builder::new()
-        .name("audio".to_owned())
-        .spawn(move || {
-            set_thread_prio_affinity(ThreadPriority::Default, ThreadAffinity::Core0);
-            let mut guest_buffer = HeapMemU32::<{ SAMPLE_BUFFER_SIZE }>::new();
-            let mut audio_buffer = HeapMemU32::<{ PRESENTER_AUDIO_BUF_SIZE }>::new();
-            let sound_sampler = unsafe { (sound_sampler_ptr as *mut SoundSampler).as_mut_unchecked() };
-            let cpu_thread = unsafe { (cpu_thread_ptr as *const Thread).as_ref_unchecked() };
-            loop {
-                sound_sampler.consume(cpu_thread, &mut guest_buffer, &mut audio_buffer);
-                presenter_audio.play(&audio_buffer);
-            }
-        })
-        .unwrap();
+    // Headless mode only hashes the samples, nothing to play
+    let audio_thread = (!headless).then(|| {
+        thread::Builder::new()
+            .name("audio".to_owned())
+            .spawn(move || {
+                set_thread_prio_affinity(ThreadPriority::Default, ThreadAffinity::Core0);
+                let mut guest_buffer = HeapMemU32::<{ SAMPLE_BUFFER_SIZE }>::new();
+                let mut audio_buffer = HeapMemU32::<{ PRESENTER_AUDIO_BUF_SIZE }>::new();
+                let sound_sampler = unsafe { (sound_sampler_ptr as *mut SoundSampler).as_mut_unchecked() };
+                let cpu_thread = unsafe { (cpu_thread_ptr as *const Thread).as_ref_unchecked() };
+                loop {
+                    sound_sampler.consume(cpu_thread, &mut guest_buffer, &mut audio_buffer);
+                    presenter_audio.play(&audio_buffer);
+                }
+            })
+            .unwrap()
+    });
 
     let gpu_renderer = unsafe { gpu_renderer.get().as_mut().unwrap() };
-    while let PresentEvent::Inputs { keymap, touch } = presenter.poll_event(settings.screenmode()) {
+    while let PresentEvent::Inputs { keymap, touch, save_state } = presenter.poll_event(settings.screenmode()) {
         if let Some((x, y)) = touch {
             touch_points.store(((y as u16) << 8) | (x as u16), Ordering::Relaxed);
         }
         key_map.store(keymap, Ordering::Relaxed);
+        if save_state != SaveStateRequest::None {
+            save_state_request.store(save_state as u8, Ordering::Relaxed);
+        }
 
         gpu_renderer.render_loop(&mut presenter, &fps, &last_save_time, &settings);
     }
 
-    audio_thread.join().unwrap();
+    if headless {
+        // Cpu is parked in lockstep with the last rendered frame
+        presenter.finish_headless(gpu_renderer, unsafe { sound_sampler.get().as_ref_unchecked() });
+        std::process::exit(0);
+    }
+
+    if let Some(audio_thread) = audio_thread {
+        audio_thread.join().unwrap();
+    }
     cpu_thread.join().unwrap();
 }
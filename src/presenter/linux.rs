@@ -1,43 +1,249 @@
+use crate::cartridge_io;
 use crate::cartridge_io::{CartridgeIo, CartridgePreview};
+use crate::cartridge_metadata::get_game_compatibility;
 use crate::core::graphics::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::core::graphics::gpu_renderer::GpuRenderer;
+use crate::core::hle::sound_test::SoundTest;
 use crate::core::input;
+use crate::core::mic::{MicBackend, NoiseMic, SilentMic, ToneMic, WavMic, MIC_SAMPLE_RATE};
+use crate::core::save_state::SaveStateRequest;
+use crate::core::spu::SoundSampler;
+use crate::logging::info_println;
 use crate::presenter::{PresentEvent, PRESENTER_AUDIO_BUF_SIZE, PRESENTER_AUDIO_SAMPLE_RATE, PRESENTER_SCREEN_HEIGHT, PRESENTER_SCREEN_WIDTH, PRESENTER_SUB_BOTTOM_SCREEN};
-use crate::settings::{Arm7Emu, ScreenMode, SettingValue, Settings, DEFAULT_SETTINGS};
-use crate::utils::BuildNoHasher;
-use clap::{arg, command, value_parser, ArgAction};
+use crate::save_formats;
+use crate::save_formats::SaveFormat;
+use crate::settings::{Arm7Emu, Language, RtcMode, ScreenMode, SettingValue, Settings, DEFAULT_SETTINGS};
+use crate::utils::{BuildNoHasher, StrErr};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{arg, command, value_parser, ArgAction, ArgMatches};
 use gl::types::GLuint;
-use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::mouse::MouseButton;
 use sdl2::video::{GLContext, GLProfile, Window};
 use sdl2::{keyboard, EventPump};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::{slice, thread};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::{process, slice, thread};
 
 #[derive(Clone)]
 pub struct PresenterAudio {
-    audio_queue: Rc<AudioQueue<i16>>,
+    audio_queue: Option<Rc<AudioQueue<i16>>>,
 }
 
 unsafe impl Send for PresenterAudio {}
 
 impl PresenterAudio {
-    fn new(audio_queue: AudioQueue<i16>) -> Self {
-        PresenterAudio { audio_queue: Rc::new(audio_queue) }
+    fn new(audio_queue: Option<AudioQueue<i16>>) -> Self {
+        PresenterAudio {
+            audio_queue: audio_queue.map(Rc::new),
+        }
     }
 
     pub fn play(&self, buffer: &[u32; PRESENTER_AUDIO_BUF_SIZE]) {
-        let raw = unsafe { slice::from_raw_parts(buffer.as_ptr() as *const i16, PRESENTER_AUDIO_BUF_SIZE * 2) };
-        self.audio_queue.queue_audio(raw).unwrap();
-        while self.audio_queue.size() != 0 {
-            thread::yield_now();
+        if let Some(audio_queue) = &self.audio_queue {
+            let raw = unsafe { slice::from_raw_parts(buffer.as_ptr() as *const i16, PRESENTER_AUDIO_BUF_SIZE * 2) };
+            audio_queue.queue_audio(raw).unwrap();
+            while audio_queue.size() != 0 {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+struct MicCapture {
+    samples: Arc<Mutex<VecDeque<i16>>>,
+}
+
+impl AudioCallback for MicCapture {
+    type Channel = i16;
+
+    fn callback(&mut self, input: &mut [i16]) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.extend(input.iter());
+        // Drop the oldest samples when the emulator falls behind
+        let max_len = MIC_SAMPLE_RATE as usize / 8;
+        if samples.len() > max_len {
+            let excess = samples.len() - max_len;
+            samples.drain(..excess);
+        }
+    }
+}
+
+struct SdlMic {
+    samples: Arc<Mutex<VecDeque<i16>>>,
+    last_sample: i16,
+}
+
+impl MicBackend for SdlMic {
+    fn next_sample(&mut self) -> i16 {
+        if let Some(sample) = self.samples.lock().unwrap().pop_front() {
+            self.last_sample = sample;
         }
+        self.last_sample
     }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+struct HeadlessInput {
+    frame: u32,
+    keymap: u32,
+    touch: Option<(u8, u8)>,
+}
+
+// Runs for a fixed amount of frames without a visible window or audio device
+// Input script lines look like "<frame> <keys|-> [<x>,<y>]", e.g. "120 A,START" or "300 - 128,96"
+// Each line holds until the next one
+struct Headless {
+    frames: u32,
+    frame: u32,
+    inputs: Vec<HeadlessInput>,
+    input_pos: usize,
+    keymap: u32,
+    touch: Option<(u8, u8)>,
+    output_dir: PathBuf,
+}
+
+impl Headless {
+    fn new(frames: u32, input_path: Option<&String>, output_dir: PathBuf) -> Self {
+        Headless {
+            frames,
+            frame: 0,
+            inputs: input_path.map(|path| Self::parse_inputs(Path::new(path)).unwrap()).unwrap_or_default(),
+            input_pos: 0,
+            keymap: 0xFFFFFFFF,
+            touch: None,
+            output_dir,
+        }
+    }
+
+    fn parse_key(name: &str) -> Option<input::Keycode> {
+        match name.to_uppercase().as_str() {
+            "A" => Some(input::Keycode::A),
+            "B" => Some(input::Keycode::B),
+            "SELECT" => Some(input::Keycode::Select),
+            "START" => Some(input::Keycode::Start),
+            "RIGHT" => Some(input::Keycode::Right),
+            "LEFT" => Some(input::Keycode::Left),
+            "UP" => Some(input::Keycode::Up),
+            "DOWN" => Some(input::Keycode::Down),
+            "R" => Some(input::Keycode::TriggerR),
+            "L" => Some(input::Keycode::TriggerL),
+            "X" => Some(input::Keycode::X),
+            "Y" => Some(input::Keycode::Y),
+            "LID" => Some(input::Keycode::Hinge),
+            _ => None,
+        }
+    }
+
+    fn parse_inputs(path: &Path) -> Result<Vec<HeadlessInput>, StrErr> {
+        let content = fs::read_to_string(path).map_err(|err| StrErr::new(format!("Failed to read input script {path:?}: {err}")))?;
+        let mut inputs = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || StrErr::new(format!("Invalid input script line {}: {line}", i + 1));
+            let mut parts = line.split_whitespace();
+            let frame = parts.next().and_then(|frame| frame.parse::<u32>().ok()).ok_or_else(invalid)?;
+
+            let mut keymap = 0xFFFFFFFF;
+            let keys = parts.next().ok_or_else(invalid)?;
+            if keys != "-" {
+                for key in keys.split(',') {
+                    let key = Self::parse_key(key).ok_or_else(invalid)?;
+                    keymap &= !(1 << key as u8);
+                }
+            }
+
+            let touch = match parts.next() {
+                Some(touch) => {
+                    let (x, y) = touch.split_once(',').ok_or_else(invalid)?;
+                    let x = x.parse::<u8>().map_err(|_| invalid())?;
+                    let y = y.parse::<u8>().map_err(|_| invalid())?;
+                    if y as usize >= DISPLAY_HEIGHT {
+                        return Err(invalid());
+                    }
+                    keymap &= !(1 << 16);
+                    Some((x, y))
+                }
+                None => None,
+            };
+
+            if parts.next().is_some() || inputs.last().is_some_and(|last: &HeadlessInput| last.frame > frame) {
+                return Err(invalid());
+            }
+            inputs.push(HeadlessInput { frame, keymap, touch });
+        }
+        Ok(inputs)
+    }
+
+    fn poll_event(&mut self) -> PresentEvent {
+        if self.frame == self.frames {
+            return PresentEvent::Quit;
+        }
+
+        while self.input_pos < self.inputs.len() && self.inputs[self.input_pos].frame <= self.frame {
+            let input = &self.inputs[self.input_pos];
+            self.keymap = input.keymap;
+            self.touch = input.touch;
+            self.input_pos += 1;
+        }
+        self.frame += 1;
+
+        PresentEvent::Inputs {
+            keymap: self.keymap,
+            touch: self.touch,
+            save_state: SaveStateRequest::None,
+        }
+    }
+
+    fn write_screen(&self, name: &str, capture: &[u32]) {
+        let mut ppm = format!("P6\n{DISPLAY_WIDTH} {DISPLAY_HEIGHT}\n255\n").into_bytes();
+        for pixel in capture {
+            ppm.extend_from_slice(&pixel.to_le_bytes()[..3]);
+        }
+        let path = self.output_dir.join(name);
+        fs::write(&path, ppm).unwrap();
+        info_println!("Written {path:?}");
+    }
+
+    fn finish(&self, gpu_renderer: &GpuRenderer, sound_sampler: &SoundSampler) {
+        fs::create_dir_all(&self.output_dir).unwrap();
+        let [top, bottom] = gpu_renderer.get_screen_captures();
+        self.write_screen("top.ppm", top);
+        self.write_screen("bottom.ppm", bottom);
+
+        let audio_hash = format!("{:08x}", sound_sampler.get_sample_hash().unwrap());
+        fs::write(self.output_dir.join("audio_hash.txt"), format!("{audio_hash}\n")).unwrap();
+        info_println!("Audio hash: {audio_hash}");
+    }
+}
+
+// The 3D renderer always needs GLES, offscreen runs get it through EGL without a display
+fn exit_without_gl(offscreen: bool, err: String) -> ! {
+    if !offscreen {
+        panic!("{err}");
+    }
+    eprintln!("Failed to create an offscreen GLES 3.0 context: {err}");
+    eprintln!("Headless runs and the sound test need EGL and a GLES 3.0 driver, on machines without a GPU install Mesa's llvmpipe (e.g. libegl-mesa0)");
+    process::exit(1);
 }
 
 pub struct Presenter {
+    args: ArgMatches,
+    headless: Option<Headless>,
     presenter_audio: PresenterAudio,
     window: Window,
     _gl_ctx: GLContext,
@@ -46,34 +252,183 @@ pub struct Presenter {
     mouse_pressed: bool,
     mouse_id: Option<u32>,
     keymap: u32,
+    mic_capture: Option<AudioDevice<MicCapture>>,
+    mic_samples: Arc<Mutex<VecDeque<i16>>>,
 }
 
 impl Presenter {
     #[cold]
     pub fn new() -> Self {
+        let args = command!()
+            .arg(arg!(framelimit: -f "Enable framelimit").required(false).action(ArgAction::SetTrue))
+            .arg(arg!(audio: -a "Enable audio").required(false).action(ArgAction::SetTrue))
+            .arg(
+                arg!(-e <arm7_emu> "0: Accurate, 1: Partial, 2: Partial with Sound, 3: Hle")
+                    .num_args(1)
+                    .required(false)
+                    .value_parser(value_parser!(u8)),
+            )
+            .arg(arg!(enable_arm7_block_validation: -b "Enable arm7 block validation").required(false).action(ArgAction::SetTrue))
+            .arg(arg!(soft_2d_renderer: -s "Use the software 2D renderer").required(false).action(ArgAction::SetTrue))
+            .arg(arg!(scanline_2d_renderer: -l "Render the 2D engines line by line").required(false).action(ArgAction::SetTrue))
+            .arg(
+                arg!(--headless <frames> "Run without window and audio for the given amount of frames, then dump both screens and an audio hash")
+                    .required(false)
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(arg!(--input <script> "Input script for headless mode").required(false).value_parser(value_parser!(String)))
+            .arg(
+                arg!(--output <dir> "Output directory for headless mode, the sound test and save exports")
+                    .required(false)
+                    .default_value(".")
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--language <language> "Firmware language: Japanese, English, French, German, Italian or Spanish")
+                    .required(false)
+                    .default_value("English")
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--nickname <nickname> "Firmware nickname")
+                    .required(false)
+                    .default_value("DSVita")
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--birthday <birthday> "Firmware birthday as month/day")
+                    .required(false)
+                    .default_value("5/25")
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(arg!(--color <color> "Firmware favorite color, 0-15").required(false).default_value("2").value_parser(value_parser!(u8)))
+            .arg(
+                arg!(--rtc <mode> "RTC mode: Host, HostOffset, Fixed or Emulated")
+                    .required(false)
+                    .default_value("Host")
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--rtc_time <time> "RTC start time for Fixed and Emulated, as YYYY-MM-DD HH:MM:SS")
+                    .required(false)
+                    .default_value("2000-01-01 11:00:00")
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--rtc_offset <hours> "Hours added to the host time for HostOffset")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--save_backups <count> "Number of timestamped save file backups")
+                    .required(false)
+                    .default_value("3")
+                    .value_parser(value_parser!(u8)),
+            )
+            .arg(
+                arg!(restore_save: --restore_save "Restore the newest save file backup before starting")
+                    .required(false)
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                arg!(--export_save <format> "Export the save to the output directory and exit, as Raw, Desmume or NoCash")
+                    .required(false)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--dldi <path> "FAT image or directory used as the SD card of homebrew with a DLDI driver")
+                    .required(false)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--mic <source> "Mic input while holding M: none, blow, tone, sdl (always live) or a wav file")
+                    .required(false)
+                    .default_value("none")
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(
+                arg!(--sound_test <sequence> "Render a sequence index or name of the SDAT to a wav file, list shows all sequences")
+                    .required(false)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(arg!(--sdat <name> "SDAT file in the rom used by the sound test").required(false).value_parser(value_parser!(String)))
+            .arg(
+                arg!(--seconds <seconds> "Maximum length of the sound test")
+                    .required(false)
+                    .default_value("120")
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                arg!(--bios <dir> "Directory containing bios9.bin, bios7.bin and firmware.bin")
+                    .required(false)
+                    .value_parser(value_parser!(String)),
+            )
+            .arg(arg!([nds_rom] "NDS rom to run").num_args(1).required(true).value_parser(value_parser!(String)))
+            .get_matches();
+
+        let headless = args
+            .get_one::<u32>("headless")
+            .map(|frames| Headless::new(*frames, args.get_one::<String>("input"), PathBuf::from(args.get_one::<String>("output").unwrap())));
+
         sdl2::hint::set("SDL_NO_SIGNAL_HANDLERS", "1");
+        let offscreen = headless.is_some() || args.contains_id("sound_test");
+        if offscreen {
+            // Render into an offscreen EGL surface, doesn't need a display
+            sdl2::hint::set("SDL_VIDEODRIVER", "offscreen");
+        }
         let sdl = sdl2::init().unwrap();
-        let sdl_video = sdl.video().unwrap();
-        let sdl_audio = sdl.audio().unwrap();
-        let audio_queue = sdl_audio
-            .open_queue(
-                None,
-                &AudioSpecDesired {
-                    freq: Some(PRESENTER_AUDIO_SAMPLE_RATE as i32),
-                    channels: Some(2),
-                    samples: Some(PRESENTER_AUDIO_BUF_SIZE as u16),
-                },
-            )
-            .unwrap();
-        audio_queue.resume();
+        let sdl_video = sdl.video().unwrap_or_else(|err| exit_without_gl(offscreen, err));
+        let sdl_audio = if offscreen { None } else { Some(sdl.audio().unwrap()) };
+        let audio_queue = if let Some(sdl_audio) = &sdl_audio {
+            let audio_queue = sdl_audio
+                .open_queue(
+                    None,
+                    &AudioSpecDesired {
+                        freq: Some(PRESENTER_AUDIO_SAMPLE_RATE as i32),
+                        channels: Some(2),
+                        samples: Some(PRESENTER_AUDIO_BUF_SIZE as u16),
+                    },
+                )
+                .unwrap();
+            audio_queue.resume();
+            Some(audio_queue)
+        } else {
+            None
+        };
+
+        let mic_samples = Arc::new(Mutex::new(VecDeque::new()));
+        let mic_capture = match &sdl_audio {
+            Some(sdl_audio) if args.get_one::<String>("mic").unwrap() == "sdl" => {
+                let mic_capture = sdl_audio
+                    .open_capture(
+                        None,
+                        &AudioSpecDesired {
+                            freq: Some(MIC_SAMPLE_RATE as i32),
+                            channels: Some(1),
+                            samples: None,
+                        },
+                        |_| MicCapture { samples: mic_samples.clone() },
+                    )
+                    .unwrap();
+                mic_capture.resume();
+                Some(mic_capture)
+            }
+            _ => None,
+        };
 
         let gl_attr = sdl_video.gl_attr();
         gl_attr.set_context_profile(GLProfile::GLES);
         gl_attr.set_context_version(3, 0);
 
-        let window = sdl_video.window("DSVita", PRESENTER_SCREEN_WIDTH, PRESENTER_SCREEN_HEIGHT).opengl().build().unwrap();
+        let mut window_builder = sdl_video.window("DSVita", PRESENTER_SCREEN_WIDTH, PRESENTER_SCREEN_HEIGHT);
+        window_builder.opengl();
+        if offscreen {
+            window_builder.hidden();
+        }
+        let window = window_builder.build().unwrap_or_else(|err| exit_without_gl(offscreen, err.to_string()));
 
-        let gl_ctx = window.gl_create_context().unwrap();
+        let gl_ctx = window.gl_create_context().unwrap_or_else(|err| exit_without_gl(offscreen, err));
         gl::load_with(|name| sdl_video.gl_get_proc_address(name) as *const _);
 
         assert_eq!(gl_attr.context_profile(), GLProfile::GLES);
@@ -94,9 +449,15 @@ impl Presenter {
         key_code_mapping.insert(keyboard::Keycode::U, input::Keycode::Y);
         key_code_mapping.insert(keyboard::Keycode::Num8, input::Keycode::TriggerL);
         key_code_mapping.insert(keyboard::Keycode::Num9, input::Keycode::TriggerR);
+        key_code_mapping.insert(keyboard::Keycode::M, input::Keycode::Mic);
+        key_code_mapping.insert(keyboard::Keycode::C, input::Keycode::Hinge);
 
         Presenter {
+            args,
+            headless,
             presenter_audio: PresenterAudio::new(audio_queue),
+            mic_capture,
+            mic_samples,
             window,
             _gl_ctx: gl_ctx,
             key_code_mapping,
@@ -108,37 +469,108 @@ impl Presenter {
     }
 
     pub fn present_ui(&self) -> (CartridgeIo, Settings) {
-        let matches = command!()
-            .arg(arg!(framelimit: -f "Enable framelimit").required(false).action(ArgAction::SetTrue))
-            .arg(arg!(audio: -a "Enable audio").required(false).action(ArgAction::SetTrue))
-            .arg(
-                arg!(-e <arm7_emu> "0: Accurate, 1: Partial, 2: Partial with Sound, 3: Hle")
-                    .num_args(1)
-                    .required(false)
-                    .default_value("0")
-                    .value_parser(value_parser!(u8)),
-            )
-            .arg(arg!(enable_arm7_block_validation: -b "Enable arm7 block validation").required(false).action(ArgAction::SetTrue))
-            .arg(arg!([nds_rom] "NDS rom to run").num_args(1).required(true).value_parser(value_parser!(String)))
-            .get_matches();
-
-        let mut settings = DEFAULT_SETTINGS.clone();
-        settings.setting_framelimit_mut().value = SettingValue::Bool(matches.get_flag("framelimit"));
-        settings.setting_audio_mut().value = SettingValue::Bool(matches.get_flag("audio"));
-        settings.setting_arm7_hle_mut().value = SettingValue::Arm7Emu(Arm7Emu::from(*matches.get_one::<u8>("arm7_emu").unwrap_or(&0)));
-        settings.setting_arm7_block_validation_mut().value = SettingValue::Bool(matches.get_flag("enable_arm7_block_validation"));
+        let matches = &self.args;
 
         let file_path = PathBuf::from(matches.get_one::<String>("nds_rom").unwrap());
         let file_name = file_path.file_name().unwrap().to_str().unwrap();
         let save_path = file_path.parent().unwrap().join(format!("{file_name}.sav"));
+        if matches.get_flag("restore_save") {
+            cartridge_io::restore_save_backup(&save_path).unwrap();
+        }
+        if let Some(format) = matches.get_one::<String>("export_save") {
+            let format = SaveFormat::from_str(format).expect("Unknown save format");
+            let stem = file_path.file_stem().unwrap().to_str().unwrap();
+            let export_path = PathBuf::from(matches.get_one::<String>("output").unwrap()).join(format!("{stem}.{}", format.file_extension()));
+            save_formats::export_save_file(&save_path, &export_path, format).unwrap();
+            info_println!("Exported save to {export_path:?}");
+            process::exit(0);
+        }
         let preview = CartridgePreview::new(file_path).unwrap();
+
+        let mut settings = DEFAULT_SETTINGS.clone();
+        // Game specific defaults, only overridden by arguments which are given
+        if let Some(compatibility) = get_game_compatibility(preview.game_code()) {
+            compatibility.apply_defaults(&mut settings);
+        }
+        settings.setting_framelimit_mut().value = SettingValue::Bool(matches.get_flag("framelimit"));
+        settings.setting_audio_mut().value = SettingValue::Bool(matches.get_flag("audio"));
+        if let Some(arm7_emu) = matches.get_one::<u8>("arm7_emu") {
+            settings.setting_arm7_hle_mut().value = SettingValue::Arm7Emu(Arm7Emu::from(*arm7_emu));
+        }
+        if matches.get_flag("enable_arm7_block_validation") {
+            settings.setting_arm7_block_validation_mut().value = SettingValue::Bool(true);
+        }
+        settings.setting_soft_2d_renderer_mut().value = SettingValue::Bool(matches.get_flag("soft_2d_renderer"));
+        if matches.get_flag("scanline_2d_renderer") {
+            settings.setting_scanline_2d_renderer_mut().value = SettingValue::Bool(true);
+        }
+        settings.setting_language_mut().value = SettingValue::Language(Language::from_str(matches.get_one::<String>("language").unwrap()).unwrap_or(Language::English));
+        settings.setting_nickname_mut().value = SettingValue::Text(Cow::Owned(matches.get_one::<String>("nickname").unwrap().clone()));
+        if let Some((month, day)) = matches.get_one::<String>("birthday").unwrap().split_once('/') {
+            settings.setting_birthday_month_mut().value.set_number(u8::from_str(month).unwrap_or(1));
+            settings.setting_birthday_day_mut().value.set_number(u8::from_str(day).unwrap_or(1));
+        }
+        settings.setting_favorite_color_mut().value.set_number(*matches.get_one::<u8>("color").unwrap());
+        settings.setting_rtc_mode_mut().value = SettingValue::RtcMode(RtcMode::from_str(matches.get_one::<String>("rtc").unwrap()).unwrap_or(RtcMode::Host));
+        settings.setting_rtc_time_mut().value = SettingValue::Text(Cow::Owned(matches.get_one::<String>("rtc_time").unwrap().clone()));
+        settings.setting_rtc_offset_mut().value = SettingValue::Text(Cow::Owned(matches.get_one::<String>("rtc_offset").unwrap().clone()));
+        settings.setting_save_backups_mut().value.set_number(*matches.get_one::<u8>("save_backups").unwrap());
+        let firmware_user_settings_changed = ["language", "nickname", "birthday", "color"]
+            .into_iter()
+            .any(|id| matches.value_source(id) == Some(ValueSource::CommandLine));
+        settings.set_firmware_user_settings_changed(firmware_user_settings_changed);
+        if self.headless.is_some() {
+            // Run as fast as possible and always produce samples for the audio hash
+            settings.setting_framelimit_mut().value = SettingValue::Bool(false);
+            settings.setting_audio_mut().value = SettingValue::Bool(true);
+            // Don't litter the rom directories of test runs
+            settings.setting_save_backups_mut().value.set_number(0);
+            // Keep the clock deterministic between runs
+            if settings.rtc_mode() != RtcMode::Fixed {
+                settings.setting_rtc_mode_mut().value = SettingValue::RtcMode(RtcMode::Emulated);
+            }
+        }
+
         (CartridgeIo::from_preview(preview, save_path).unwrap(), settings)
     }
 
     pub fn destroy_ui(&self) {}
 
+    pub fn get_system_files_dir(&self) -> Option<PathBuf> {
+        self.args.get_one::<String>("bios").map(PathBuf::from)
+    }
+
+    pub fn get_dldi_path(&self) -> Option<PathBuf> {
+        self.args.get_one::<String>("dldi").map(PathBuf::from)
+    }
+
+    pub fn get_mic_backend(&self) -> Box<dyn MicBackend> {
+        if self.mic_capture.is_some() {
+            return Box::new(SdlMic {
+                samples: self.mic_samples.clone(),
+                last_sample: 0,
+            });
+        }
+        match self.args.get_one::<String>("mic").unwrap().as_str() {
+            "none" | "sdl" => Box::new(SilentMic),
+            "blow" => Box::new(NoiseMic::new()),
+            "tone" => Box::new(ToneMic::new(440f32)),
+            path => Box::new(WavMic::new(Path::new(path)).unwrap_or_else(|err| clap::Error::raw(ErrorKind::InvalidValue, format!("Invalid --mic wav file: {err}\n")).exit())),
+        }
+    }
+
+    pub fn get_firmware_save_path(&self) -> PathBuf {
+        let data_dir = std::env::var_os("HOME").map_or_else(|| PathBuf::from("."), |home| PathBuf::from(home).join(".local/share/dsvita"));
+        data_dir.join("firmware.bin")
+    }
+
     pub fn poll_event(&mut self, _: ScreenMode) -> PresentEvent {
+        if let Some(headless) = &mut self.headless {
+            return headless.poll_event();
+        }
+
         let mut touch = None;
+        let mut save_state = SaveStateRequest::None;
 
         let mut sample_touch_points = |x, y| {
             let (x, y) = PRESENTER_SUB_BOTTOM_SCREEN.normalize(x as _, y as _);
@@ -149,11 +581,15 @@ impl Presenter {
 
         for event in self.event_pump.poll_iter() {
             match event {
-                Event::KeyDown { keycode: Some(code), .. } => {
-                    if let Some(code) = self.key_code_mapping.get(&code) {
-                        self.keymap &= !(1 << *code as u8);
+                Event::KeyDown { keycode: Some(code), .. } => match code {
+                    keyboard::Keycode::F5 => save_state = SaveStateRequest::Save,
+                    keyboard::Keycode::F8 => save_state = SaveStateRequest::Load,
+                    _ => {
+                        if let Some(code) = self.key_code_mapping.get(&code) {
+                            self.keymap &= !(1 << *code as u8);
+                        }
                     }
-                }
+                },
                 Event::KeyUp { keycode: Some(code), .. } => {
                     if let Some(code) = self.key_code_mapping.get(&code) {
                         self.keymap |= 1 << *code as u8;
@@ -188,7 +624,11 @@ impl Presenter {
                 _ => {}
             }
         }
-        PresentEvent::Inputs { keymap: self.keymap, touch }
+        PresentEvent::Inputs {
+            keymap: self.keymap,
+            touch,
+            save_state,
+        }
     }
 
     pub fn gl_swap_window(&self) {
@@ -201,6 +641,26 @@ impl Presenter {
 
     pub fn wait_vsync(&self) {}
 
+    pub fn is_headless(&self) -> bool {
+        self.headless.is_some()
+    }
+
+    pub fn get_sound_test(&self) -> Option<SoundTest> {
+        let sequence = self.args.get_one::<String>("sound_test")?;
+        Some(SoundTest {
+            sdat: self.args.get_one::<String>("sdat").cloned(),
+            sequence: sequence.clone(),
+            output: PathBuf::from(self.args.get_one::<String>("output").unwrap()).join(format!("{sequence}.wav")),
+            max_seconds: *self.args.get_one::<u32>("seconds").unwrap(),
+        })
+    }
+
+    pub fn finish_headless(&self, gpu_renderer: &GpuRenderer, sound_sampler: &SoundSampler) {
+        if let Some(headless) = &self.headless {
+            headless.finish(gpu_renderer, sound_sampler);
+        }
+    }
+
     pub fn gl_create_depth_tex() -> GLuint {
         0
     }
@@ -1,6 +1,7 @@
 pub use self::platform::*;
 use crate::core::graphics::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
 use crate::core::graphics::gpu_renderer::ScreenTopology;
+use crate::core::save_state::SaveStateRequest;
 use crate::settings::ScreenMode;
 
 #[cfg(target_os = "linux")]
@@ -15,7 +16,7 @@ pub const PRESENTER_SCREEN_WIDTH: u32 = 960;
 pub const PRESENTER_SCREEN_HEIGHT: u32 = 544;
 
 pub enum PresentEvent {
-    Inputs { keymap: u32, touch: Option<(u8, u8)> },
+    Inputs { keymap: u32, touch: Option<(u8, u8)>, save_state: SaveStateRequest },
     Quit,
 }
 
@@ -1,6 +1,13 @@
+use crate::cartridge_io;
 use crate::cartridge_io::{CartridgeIo, CartridgePreview};
+use crate::cartridge_metadata::get_game_compatibility;
 use crate::core::graphics::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::core::graphics::gpu_renderer::GpuRenderer;
+use crate::core::hle::sound_test::SoundTest;
 use crate::core::input::Keycode;
+use crate::core::mic::{MicBackend, NoiseMic, WavMic};
+use crate::core::save_state::SaveStateRequest;
+use crate::core::spu::SoundSampler;
 use crate::logging::info_println;
 use crate::presenter::platform::imgui::{
     vglGetProcAddress, ImFontAtlas_AddFontFromMemoryTTF, ImFontAtlas_GetGlyphRangesDefault, ImFontConfig, ImFontConfig_ImFontConfig, ImGuiCond__ImGuiSetCond_Always,
@@ -11,17 +18,19 @@ use crate::presenter::platform::imgui::{
     ImGui_EndCombo, ImGui_EndMainMenuBar, ImGui_GetContentRegionAvail, ImGui_GetCursorPosX, ImGui_GetDrawData, ImGui_GetIO, ImGui_GetStyle, ImGui_Image, ImGui_ImplVitaGL_GamepadUsage,
     ImGui_ImplVitaGL_Init, ImGui_ImplVitaGL_MouseStickUsage, ImGui_ImplVitaGL_NewFrame, ImGui_ImplVitaGL_RenderDrawData, ImGui_ImplVitaGL_TouchUsage, ImGui_IsItemHovered, ImGui_IsWindowFocused,
     ImGui_PopID, ImGui_PopItemFlag, ImGui_PopStyleVar, ImGui_PushID3, ImGui_PushItemFlag, ImGui_PushStyleVar, ImGui_PushStyleVar1, ImGui_Render, ImGui_SameLine, ImGui_Selectable, ImGui_SetCursorPosX,
-    ImGui_SetItemDefaultFocus, ImGui_SetNextWindowPos, ImGui_SetNextWindowSize, ImGui_SetWindowFocus, ImGui_StyleColorsDark, ImGui_Text, ImVec2, ImVec4,
+    ImGui_SetItemDefaultFocus, ImGui_SetNextWindowPos, ImGui_SetNextWindowSize, ImGui_SetWindowFocus, ImGui_StyleColorsDark, ImGui_Text, ImGui_TextWrapped, ImVec2, ImVec4,
 };
 use crate::presenter::{
     PresentEvent, PRESENTER_AUDIO_BUF_SIZE, PRESENTER_AUDIO_SAMPLE_RATE, PRESENTER_SCREEN_HEIGHT, PRESENTER_SCREEN_WIDTH, PRESENTER_SUB_BOTTOM_SCREEN, PRESENTER_SUB_RESIZED_BOTTOM_SCREEN,
     PRESENTER_SUB_ROTATED_BOTTOM_SCREEN,
 };
-use crate::settings::{Arm7Emu, ScreenMode, SettingValue, Settings, SettingsConfig};
+use crate::save_formats;
+use crate::save_formats::SaveFormat;
+use crate::settings::{Arm7Emu, Language, RtcMode, ScreenMode, SettingValue, Settings, SettingsConfig};
 use gl::types::{GLboolean, GLenum, GLuint};
 use std::ffi::{CStr, CString};
 use std::mem::MaybeUninit;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::{fs, mem, ptr};
 use strum::IntoEnumIterator;
@@ -35,6 +44,10 @@ mod imgui {
 const ROM_PATH: &str = "ux0:data/dsvita";
 const SAVES_PATH: &str = "ux0:data/dsvita/saves";
 const SETTINGS_PATH: &str = "ux0:data/dsvita/settings";
+const BIOS_PATH: &str = "ux0:data/dsvita/bios";
+const MIC_WAV_PATH: &str = "ux0:data/dsvita/mic.wav";
+const DLDI_IMAGE_PATH: &str = "ux0:data/dsvita/sd.img";
+const DLDI_DIR_PATH: &str = "ux0:data/dsvita/sd";
 pub const LOG_PATH: &str = "ux0:data/dsvita/log";
 pub const LOG_FILE: &str = "ux0:data/dsvita/log/log.txt";
 
@@ -99,6 +112,7 @@ unsafe impl Send for PresenterAudio {}
 pub struct Presenter {
     presenter_audio: PresenterAudio,
     keymap: u32,
+    buttons: u32,
 }
 
 impl Presenter {
@@ -122,6 +136,8 @@ impl Presenter {
             });
 
             sceTouchSetSamplingState(SCE_TOUCH_PORT_FRONT, SCE_TOUCH_SAMPLING_STATE_STOP);
+            // Rear touchpad holds down the mic
+            sceTouchSetSamplingState(SCE_TOUCH_PORT_BACK, SCE_TOUCH_SAMPLING_STATE_START);
 
             info_println!("Initialize ImGui");
             ImGui_CreateContext(ptr::null_mut());
@@ -156,12 +172,14 @@ impl Presenter {
             Presenter {
                 presenter_audio: PresenterAudio::new(),
                 keymap: 0xFFFFFFFF,
+                buttons: 0,
             }
         }
     }
 
     pub fn poll_event(&mut self, screenmode: ScreenMode) -> PresentEvent {
         let mut touch = None;
+        let mut save_state = SaveStateRequest::None;
 
         unsafe {
             let pressed = MaybeUninit::<SceCtrlData>::uninit();
@@ -176,6 +194,17 @@ impl Presenter {
                 }
             }
 
+            // Select + L saves, Select + R loads the state, only triggered once per press
+            let newly_pressed = pressed.buttons & !self.buttons;
+            if pressed.buttons & SCE_CTRL_SELECT != 0 {
+                if newly_pressed & SCE_CTRL_LTRIGGER != 0 {
+                    save_state = SaveStateRequest::Save;
+                } else if newly_pressed & SCE_CTRL_RTRIGGER != 0 {
+                    save_state = SaveStateRequest::Load;
+                }
+            }
+            self.buttons = pressed.buttons;
+
             let touch_report = MaybeUninit::<SceTouchData>::uninit();
             let mut touch_report = touch_report.assume_init();
             sceTouchPeek(SCE_TOUCH_PORT_FRONT, &mut touch_report, 1);
@@ -215,8 +244,27 @@ impl Presenter {
             } else {
                 self.keymap |= 1 << 16;
             }
+
+            let back_touch_report = MaybeUninit::<SceTouchData>::uninit();
+            let mut back_touch_report = back_touch_report.assume_init();
+            sceTouchPeek(SCE_TOUCH_PORT_BACK, &mut back_touch_report, 1);
+            // One finger blows into the mic, two close the lid
+            if back_touch_report.reportNum == 1 {
+                self.keymap &= !(1 << Keycode::Mic as u8);
+            } else {
+                self.keymap |= 1 << Keycode::Mic as u8;
+            }
+            if back_touch_report.reportNum > 1 {
+                self.keymap &= !(1 << Keycode::Hinge as u8);
+            } else {
+                self.keymap |= 1 << Keycode::Hinge as u8;
+            }
+        }
+        PresentEvent::Inputs {
+            keymap: self.keymap,
+            touch,
+            save_state,
         }
-        PresentEvent::Inputs { keymap: self.keymap, touch }
     }
 
     pub fn present_ui(&self) -> (CartridgeIo, Settings) {
@@ -237,7 +285,8 @@ impl Presenter {
                                 let save_file = PathBuf::from(SAVES_PATH).join(format!("{name}.sav"));
                                 let settings_file = PathBuf::from(SETTINGS_PATH).join(format!("{name}.ini"));
                                 let preview = CartridgePreview::new(path).unwrap();
-                                return (CartridgeIo::from_preview(preview, save_file).unwrap(), SettingsConfig::new(settings_file).settings);
+                                let settings = SettingsConfig::new(settings_file, preview.game_code()).settings;
+                                return (CartridgeIo::from_preview(preview, save_file).unwrap(), settings);
                             }
                         }
                     }
@@ -279,7 +328,7 @@ impl Presenter {
             let mut settings_configs = Vec::new();
             for cartridge in &cartridges {
                 let path = PathBuf::from(SETTINGS_PATH).join(format!("{}.ini", cartridge.file_name));
-                settings_configs.push(SettingsConfig::new(path));
+                settings_configs.push(SettingsConfig::new(path, cartridge.game_code()));
             }
 
             let mut selected = None;
@@ -389,6 +438,32 @@ impl Presenter {
                         Err(_) => ImGui_Text(c"Couldn't read game title".as_ptr() as _),
                     }
 
+                    if let Some(compatibility) = get_game_compatibility(cartridge.game_code()).filter(|compatibility| !compatibility.known_issues.is_empty()) {
+                        let known_issues = CString::new(format!("Known issues: {}", compatibility.known_issues)).unwrap();
+                        ImGui_TextWrapped(known_issues.as_ptr() as _);
+                    }
+
+                    let save_file = PathBuf::from(SAVES_PATH).join(format!("{}.sav", cartridge.file_name));
+                    if cartridge_io::is_save_file_corrupt(&save_file, cartridge.game_code()) && !cartridge_io::save_backup_paths(&save_file).is_empty() {
+                        ImGui_TextWrapped(c"The save file looks corrupt".as_ptr() as _);
+                        let vec = ImVec2 { x: -1f32, y: 0f32 };
+                        if ImGui_Button(c"Restore newest save backup".as_ptr() as _, &vec) {
+                            let _ = cartridge_io::restore_save_backup(&save_file);
+                        }
+                    }
+                    if save_file.is_file() {
+                        let vec = ImVec2 { x: -1f32, y: 0f32 };
+                        if ImGui_Button(c"Export save".as_ptr() as _, &vec) {
+                            // Every format is written, the user picks the one needed by the other emulator or flash cart
+                            let export_dir = PathBuf::from(SAVES_PATH).join("export");
+                            let _ = fs::create_dir(&export_dir);
+                            let stem = Path::new(&cartridge.file_name).file_stem().unwrap().to_str().unwrap();
+                            for format in SaveFormat::iter() {
+                                let _ = save_formats::export_save_file(&save_file, &export_dir.join(format!("{stem}.{}", format.file_extension())), format);
+                            }
+                        }
+                    }
+
                     let vec = ImVec2 { x: 0f32, y: 10f32 };
                     ImGui_Dummy(&vec);
 
@@ -415,7 +490,7 @@ impl Presenter {
                         ImGui_PushID3(i as _);
 
                         match setting.value {
-                            SettingValue::Bool(_) => {
+                            SettingValue::Bool(_) | SettingValue::Number { .. } => {
                                 ImGui_SetCursorPosX(ImGui_GetCursorPosX() + ImGui_GetContentRegionAvail().x - 50f32);
 
                                 let value = CString::new(setting.value.to_string()).unwrap();
@@ -470,6 +545,55 @@ impl Presenter {
                                     ImGui_EndCombo();
                                 }
                             }
+                            SettingValue::Language(_) => {
+                                let value = CString::new(setting.value.to_string()).unwrap();
+
+                                ImGui_SetCursorPosX(ImGui_GetCursorPosX() + ImGui_GetContentRegionAvail().x - 125f32);
+
+                                if ImGui_BeginCombo(c"##language".as_ptr() as _, value.as_ptr() as _, 0) {
+                                    for value in Language::iter() {
+                                        let is_selected = setting.value.as_language() == Some(value);
+                                        let value_str: &str = value.into();
+                                        let value_cstr = CString::from_str(value_str).unwrap();
+                                        let size = ImVec2 { x: 0f32, y: 0f32 };
+                                        if ImGui_Selectable(value_cstr.as_ptr() as _, is_selected, 0, &size) {
+                                            setting.value = SettingValue::Language(value);
+                                            settings_config.dirty = true;
+                                        }
+                                        if is_selected {
+                                            ImGui_SetItemDefaultFocus();
+                                        }
+                                    }
+                                    ImGui_EndCombo();
+                                }
+                            }
+                            SettingValue::RtcMode(_) => {
+                                let value = CString::new(setting.value.to_string()).unwrap();
+
+                                ImGui_SetCursorPosX(ImGui_GetCursorPosX() + ImGui_GetContentRegionAvail().x - 125f32);
+
+                                if ImGui_BeginCombo(c"##rtc_mode".as_ptr() as _, value.as_ptr() as _, 0) {
+                                    for value in RtcMode::iter() {
+                                        let is_selected = setting.value.as_rtc_mode() == Some(value);
+                                        let value_str: &str = value.into();
+                                        let value_cstr = CString::from_str(value_str).unwrap();
+                                        let size = ImVec2 { x: 0f32, y: 0f32 };
+                                        if ImGui_Selectable(value_cstr.as_ptr() as _, is_selected, 0, &size) {
+                                            setting.value = SettingValue::RtcMode(value);
+                                            settings_config.dirty = true;
+                                        }
+                                        if is_selected {
+                                            ImGui_SetItemDefaultFocus();
+                                        }
+                                    }
+                                    ImGui_EndCombo();
+                                }
+                            }
+                            SettingValue::Text(_) => {
+                                let value = CString::new(setting.value.to_string()).unwrap();
+                                ImGui_SetCursorPosX(ImGui_GetCursorPosX() + ImGui_GetContentRegionAvail().x - 125f32);
+                                ImGui_Text(value.as_ptr() as _);
+                            }
                         }
 
                         ImGui_PopID();
@@ -508,7 +632,7 @@ impl Presenter {
 
             let preview = cartridges.remove(selected.unwrap());
             let save_file = PathBuf::from(SAVES_PATH).join(format!("{}.sav", preview.file_name));
-            (CartridgeIo::from_preview(preview, save_file).unwrap(), settings_configs.remove(selected.unwrap()).settings)
+            (CartridgeIo::from_preview(preview, save_file).unwrap(), settings_configs.remove(selected.unwrap()).into_settings())
         }
     }
 
@@ -525,6 +649,31 @@ impl Presenter {
         }
     }
 
+    pub fn get_system_files_dir(&self) -> Option<PathBuf> {
+        Some(PathBuf::from(BIOS_PATH))
+    }
+
+    pub fn get_dldi_path(&self) -> Option<PathBuf> {
+        [DLDI_IMAGE_PATH, DLDI_DIR_PATH].into_iter().map(PathBuf::from).find(|path| path.exists())
+    }
+
+    pub fn get_mic_backend(&self) -> Box<dyn MicBackend> {
+        let wav_path = PathBuf::from(MIC_WAV_PATH);
+        if wav_path.is_file() {
+            match WavMic::new(&wav_path) {
+                Ok(wav_mic) => return Box::new(wav_mic),
+                Err(err) => {
+                    info_println!("{err:?}, falling back to noise");
+                }
+            }
+        }
+        Box::new(NoiseMic::new())
+    }
+
+    pub fn get_firmware_save_path(&self) -> PathBuf {
+        PathBuf::from(SAVES_PATH).join("firmware.bin")
+    }
+
     pub fn get_presenter_audio(&self) -> PresenterAudio {
         self.presenter_audio.clone()
     }
@@ -537,6 +686,16 @@ impl Presenter {
         unsafe { sceDisplayWaitVblankStart() };
     }
 
+    pub fn is_headless(&self) -> bool {
+        false
+    }
+
+    pub fn get_sound_test(&self) -> Option<SoundTest> {
+        None
+    }
+
+    pub fn finish_headless(&self, _: &GpuRenderer, _: &SoundSampler) {}
+
     pub unsafe fn gl_create_depth_tex() -> GLuint {
         let mut tex = 0;
         gl::GenTextures(1, &mut tex);
@@ -0,0 +1,142 @@
+use crate::cartridge_io::SAVE_SIZES;
+use crate::utils;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::{fs, io};
+use strum_macros::{EnumIter, EnumString, IntoStaticStr};
+
+const DESMUME_FOOTER_TEXT: &[u8] = b"|<--Snip above here to create a raw sav by excluding this DeSmuME savedata footer:";
+const DESMUME_COOKIE: &[u8] = b"|-DESMUME SAVE-|";
+const DESMUME_FOOTER_SIZE: usize = DESMUME_FOOTER_TEXT.len() + 6 * 4 + DESMUME_COOKIE.len();
+
+const NO_CASH_ID: &[u8] = b"NocashGbaBackupMediaSavDataFile\x1A";
+const NO_CASH_SRAM_ID: &[u8] = b"SRAM";
+const NO_CASH_HEADER_SIZE: usize = 0x40;
+
+#[derive(Copy, Clone, Debug, EnumIter, EnumString, Eq, IntoStaticStr, PartialEq)]
+#[strum(ascii_case_insensitive)]
+pub enum SaveFormat {
+    Raw,
+    Desmume,
+    NoCash,
+}
+
+impl SaveFormat {
+    pub fn file_extension(self) -> &'static str {
+        match self {
+            SaveFormat::Raw => "sav",
+            SaveFormat::Desmume => "dsv",
+            SaveFormat::NoCash => "nocash.sav",
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = data.get(offset..offset + 4).ok_or_else(|| invalid_data("Unexpected end of save file"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// Smallest save size fitting the data, raw saves of other emulators might have been trimmed
+pub fn fit_save_size(len: usize) -> u32 {
+    if len == 0 {
+        return 0;
+    }
+    SAVE_SIZES.iter().copied().find(|size| *size as usize >= len).unwrap_or(*SAVE_SIZES.last().unwrap())
+}
+
+fn import_desmume(data: &[u8]) -> io::Result<Vec<u8>> {
+    let footer_start = data.len() - DESMUME_FOOTER_SIZE;
+    let actual_size = read_u32(data, footer_start + DESMUME_FOOTER_TEXT.len())? as usize;
+    Ok(data[..actual_size.min(footer_start)].to_vec())
+}
+
+fn import_no_cash(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.get(NO_CASH_HEADER_SIZE..NO_CASH_HEADER_SIZE + 4) != Some(NO_CASH_SRAM_ID) {
+        return Err(invalid_data("Unsupported no$gba save type"));
+    }
+    match read_u32(data, 0x44)? {
+        0 => {
+            let size = read_u32(data, 0x48)? as usize;
+            data.get(0x4C..0x4C + size).map(|data| data.to_vec()).ok_or_else(|| invalid_data("no$gba save is cut off"))
+        }
+        1 => {
+            let size = read_u32(data, 0x4C)? as usize;
+            let mut raw = Vec::with_capacity(size);
+            let mut pos = 0x50;
+            let byte = |pos: usize| data.get(pos).copied().ok_or_else(|| invalid_data("no$gba save is cut off"));
+            // Run length encoded, 0 ends the stream
+            loop {
+                match byte(pos)? {
+                    0 => break,
+                    0x80 => {
+                        let count = u16::from_le_bytes([byte(pos + 1)?, byte(pos + 2)?]) as usize;
+                        raw.resize(raw.len() + count, byte(pos + 3)?);
+                        pos += 4;
+                    }
+                    count @ 0x81.. => {
+                        raw.resize(raw.len() + (count - 0x80) as usize, byte(pos + 1)?);
+                        pos += 2;
+                    }
+                    count => {
+                        let count = count as usize;
+                        raw.extend_from_slice(data.get(pos + 1..pos + 1 + count).ok_or_else(|| invalid_data("no$gba save is cut off"))?);
+                        pos += 1 + count;
+                    }
+                }
+            }
+            Ok(raw)
+        }
+        method => Err(invalid_data(&format!("Unknown no$gba compression {method}"))),
+    }
+}
+
+pub fn import_save(data: Vec<u8>) -> io::Result<(Vec<u8>, SaveFormat)> {
+    if data.len() >= DESMUME_FOOTER_SIZE && data.ends_with(DESMUME_COOKIE) {
+        Ok((import_desmume(&data)?, SaveFormat::Desmume))
+    } else if data.starts_with(NO_CASH_ID) {
+        Ok((import_no_cash(&data)?, SaveFormat::NoCash))
+    } else {
+        Ok((data, SaveFormat::Raw))
+    }
+}
+
+pub fn export_save(raw: &[u8], format: SaveFormat) -> Vec<u8> {
+    let size = raw.len() as u32;
+    match format {
+        SaveFormat::Raw => raw.to_vec(),
+        SaveFormat::Desmume => {
+            let addr_size = match size {
+                0x200 => 1,
+                0x2000 | 0x8000 | 0x10000 => 2,
+                _ => 3,
+            };
+            let mut data = raw.to_vec();
+            data.extend_from_slice(DESMUME_FOOTER_TEXT);
+            // Actual size, padded size, type (autodetect), address size, memory size and version
+            for value in [size, size, 0, addr_size, size, 0] {
+                data.extend_from_slice(&u32::to_le_bytes(value));
+            }
+            data.extend_from_slice(DESMUME_COOKIE);
+            data
+        }
+        SaveFormat::NoCash => {
+            let mut data = NO_CASH_ID.to_vec();
+            data.resize(NO_CASH_HEADER_SIZE, 0);
+            data.extend_from_slice(NO_CASH_SRAM_ID);
+            data.extend_from_slice(&0u32.to_le_bytes());
+            data.extend_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(raw);
+            data
+        }
+    }
+}
+
+// Reads a save of any supported format and writes it in the requested one
+pub fn export_save_file(save_file_path: &Path, export_path: &Path, format: SaveFormat) -> io::Result<()> {
+    let (raw, _) = import_save(fs::read(save_file_path)?)?;
+    utils::write_file_atomic(export_path, &export_save(&raw, format))
+}
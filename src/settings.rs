@@ -1,4 +1,6 @@
+use crate::cartridge_metadata::get_game_compatibility;
 use ini::Ini;
+use std::borrow::Cow;
 use std::fmt::{Debug, Display, Formatter};
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -34,11 +36,49 @@ impl From<u8> for ScreenMode {
     }
 }
 
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, EnumIter, EnumString, Eq, IntoStaticStr, PartialEq)]
+pub enum Language {
+    Japanese = 0,
+    English = 1,
+    French = 2,
+    German = 3,
+    Italian = 4,
+    Spanish = 5,
+}
+
+impl From<u8> for Language {
+    fn from(value: u8) -> Self {
+        debug_assert!(value <= Language::Spanish as u8);
+        unsafe { std::mem::transmute(value) }
+    }
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, EnumIter, EnumString, Eq, IntoStaticStr, PartialEq)]
+pub enum RtcMode {
+    Host = 0,
+    HostOffset = 1,
+    Fixed = 2,
+    Emulated = 3,
+}
+
+impl From<u8> for RtcMode {
+    fn from(value: u8) -> Self {
+        debug_assert!(value <= RtcMode::Emulated as u8);
+        unsafe { std::mem::transmute(value) }
+    }
+}
+
 #[derive(Clone)]
 pub enum SettingValue {
     Bool(bool),
     Arm7Emu(Arm7Emu),
     ScreenMode(ScreenMode),
+    Language(Language),
+    RtcMode(RtcMode),
+    Number { value: u8, min: u8, max: u8 },
+    Text(Cow<'static, str>),
 }
 
 impl SettingValue {
@@ -47,6 +87,15 @@ impl SettingValue {
             SettingValue::Bool(value) => SettingValue::Bool(!*value),
             SettingValue::Arm7Emu(value) => SettingValue::Arm7Emu(Arm7Emu::from((value.clone() as u8 + 1) % (Arm7Emu::Hle as u8 + 1))),
             SettingValue::ScreenMode(value) => SettingValue::ScreenMode(ScreenMode::from((value.clone() as u8 + 1) % (ScreenMode::Resized as u8 + 1))),
+            SettingValue::Language(value) => SettingValue::Language(Language::from((*value as u8 + 1) % (Language::Spanish as u8 + 1))),
+            SettingValue::RtcMode(value) => SettingValue::RtcMode(RtcMode::from((*value as u8 + 1) % (RtcMode::Emulated as u8 + 1))),
+            SettingValue::Number { value, min, max } => SettingValue::Number {
+                value: if *value >= *max { *min } else { *value + 1 },
+                min: *min,
+                max: *max,
+            },
+            // Only editable through the settings file
+            SettingValue::Text(_) => return,
         }
     }
 
@@ -71,11 +120,49 @@ impl SettingValue {
         }
     }
 
+    pub fn as_language(&self) -> Option<Language> {
+        match self {
+            SettingValue::Language(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_rtc_mode(&self) -> Option<RtcMode> {
+        match self {
+            SettingValue::RtcMode(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<u8> {
+        match self {
+            SettingValue::Number { value, .. } => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            SettingValue::Text(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn set_number(&mut self, new_value: u8) {
+        if let SettingValue::Number { value, min, max } = self {
+            *value = new_value.clamp(*min, *max);
+        }
+    }
+
     fn parse_str(&mut self, str: &str) {
         match self {
             SettingValue::Bool(value) => *value = bool::from_str(str).unwrap_or(false),
             SettingValue::Arm7Emu(value) => *value = Arm7Emu::from_str(str).unwrap_or(Arm7Emu::AccurateLle),
             SettingValue::ScreenMode(value) => *value = ScreenMode::from_str(str).unwrap_or(ScreenMode::Regular),
+            SettingValue::Language(value) => *value = Language::from_str(str).unwrap_or(Language::English),
+            SettingValue::RtcMode(value) => *value = RtcMode::from_str(str).unwrap_or(RtcMode::Host),
+            SettingValue::Number { value, min, max } => *value = u8::from_str(str).map_or(*min, |str_value| str_value.clamp(*min, *max)),
+            SettingValue::Text(value) => *value = Cow::Owned(str.to_string()),
         }
     }
 
@@ -84,31 +171,25 @@ impl SettingValue {
             SettingValue::Bool(value) => value.to_string(),
             SettingValue::Arm7Emu(value) => Into::<&str>::into(value).to_string(),
             SettingValue::ScreenMode(value) => Into::<&str>::into(value).to_string(),
+            SettingValue::Language(value) => Into::<&str>::into(value).to_string(),
+            SettingValue::RtcMode(value) => Into::<&str>::into(value).to_string(),
+            SettingValue::Number { value, .. } => value.to_string(),
+            SettingValue::Text(value) => value.to_string(),
         }
     }
 }
 
 impl Display for SettingValue {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                SettingValue::Bool(value) => {
-                    if *value {
-                        "on"
-                    } else {
-                        "off"
-                    }
-                }
-                SettingValue::Arm7Emu(value) => {
-                    value.into()
-                }
-                SettingValue::ScreenMode(value) => {
-                    value.into()
-                }
-            }
-        )
+        match self {
+            SettingValue::Bool(value) => write!(f, "{}", if *value { "on" } else { "off" }),
+            SettingValue::Arm7Emu(value) => write!(f, "{}", Into::<&str>::into(value)),
+            SettingValue::ScreenMode(value) => write!(f, "{}", Into::<&str>::into(value)),
+            SettingValue::Language(value) => write!(f, "{}", Into::<&str>::into(value)),
+            SettingValue::RtcMode(value) => write!(f, "{}", Into::<&str>::into(value)),
+            SettingValue::Number { value, .. } => write!(f, "{value}"),
+            SettingValue::Text(value) => write!(f, "{value}"),
+        }
     }
 }
 
@@ -153,12 +234,71 @@ pub const DEFAULT_SETTINGS: Settings = Settings {
         need to have this enabled.",
             SettingValue::Bool(false),
         ),
+        Setting::new(
+            "Software 2D renderer",
+            "Renders the 2D engines on the cpu instead\n\
+        of the gpu. Slower, but more accurate.",
+            SettingValue::Bool(false),
+        ),
+        Setting::new(
+            "Scanline 2D renderer",
+            "Renders every line of the 2D engines with\n\
+        the memory of that line. Needed for games\n\
+        changing vram mid frame, very slow.",
+            SettingValue::Bool(false),
+        ),
+        Setting::new(
+            "Language",
+            "Firmware language, picked by games\n\
+        supporting multiple languages",
+            SettingValue::Language(Language::English),
+        ),
+        Setting::new(
+            "Nickname",
+            "Firmware nickname, up to 10 characters.\n\
+        Can be changed in the settings file.",
+            SettingValue::Text(Cow::Borrowed("DSVita")),
+        ),
+        Setting::new("Birthday month", "Firmware birthday month", SettingValue::Number { value: 5, min: 1, max: 12 }),
+        Setting::new("Birthday day", "Firmware birthday day", SettingValue::Number { value: 25, min: 1, max: 31 }),
+        Setting::new("Favorite color", "Firmware favorite color", SettingValue::Number { value: 2, min: 0, max: 15 }),
+        Setting::new(
+            "RTC mode",
+            "Host: Time of the device\n\
+        HostOffset: Time of the device plus the RTC\noffset\n\
+        Fixed: Always the RTC time\n\
+        Emulated: RTC time advancing with the\nemulated game speed",
+            SettingValue::RtcMode(RtcMode::Host),
+        ),
+        Setting::new(
+            "RTC time",
+            "Start time for Fixed and Emulated, formatted\n\
+        as YYYY-MM-DD HH:MM:SS. Can be changed in\n\
+        the settings file.",
+            SettingValue::Text(Cow::Borrowed("2000-01-01 11:00:00")),
+        ),
+        Setting::new(
+            "RTC offset",
+            "Hours added to the device time for HostOffset,\n\
+        can be negative. Can be changed in the\n\
+        settings file.",
+            SettingValue::Text(Cow::Borrowed("0")),
+        ),
+        Setting::new(
+            "Save backups",
+            "Number of timestamped save file backups,\n\
+        a new one is made at most every 30 minutes",
+            SettingValue::Number { value: 3, min: 0, max: 10 },
+        ),
     ],
+    firmware_user_settings_changed: false,
 };
 
 #[derive(Clone)]
 pub struct Settings {
-    values: [Setting; 5],
+    values: [Setting; 16],
+    // Not persisted, set when the firmware user settings were changed in the launcher
+    firmware_user_settings_changed: bool,
 }
 
 impl Settings {
@@ -182,6 +322,50 @@ impl Settings {
         unsafe { self.values[4].value.as_bool().unwrap_unchecked() }
     }
 
+    pub fn soft_2d_renderer(&self) -> bool {
+        unsafe { self.values[5].value.as_bool().unwrap_unchecked() }
+    }
+
+    pub fn scanline_2d_renderer(&self) -> bool {
+        unsafe { self.values[6].value.as_bool().unwrap_unchecked() }
+    }
+
+    pub fn language(&self) -> Language {
+        unsafe { self.values[7].value.as_language().unwrap_unchecked() }
+    }
+
+    pub fn nickname(&self) -> &str {
+        unsafe { self.values[8].value.as_text().unwrap_unchecked() }
+    }
+
+    pub fn birthday_month(&self) -> u8 {
+        unsafe { self.values[9].value.as_number().unwrap_unchecked() }
+    }
+
+    pub fn birthday_day(&self) -> u8 {
+        unsafe { self.values[10].value.as_number().unwrap_unchecked() }
+    }
+
+    pub fn favorite_color(&self) -> u8 {
+        unsafe { self.values[11].value.as_number().unwrap_unchecked() }
+    }
+
+    pub fn rtc_mode(&self) -> RtcMode {
+        unsafe { self.values[12].value.as_rtc_mode().unwrap_unchecked() }
+    }
+
+    pub fn rtc_time(&self) -> &str {
+        unsafe { self.values[13].value.as_text().unwrap_unchecked() }
+    }
+
+    pub fn rtc_offset(&self) -> &str {
+        unsafe { self.values[14].value.as_text().unwrap_unchecked() }
+    }
+
+    pub fn save_backups(&self) -> u8 {
+        unsafe { self.values[15].value.as_number().unwrap_unchecked() }
+    }
+
     pub fn setting_screenmode_mut(&mut self) -> &mut Setting {
         &mut self.values[0]
     }
@@ -202,9 +386,67 @@ impl Settings {
         &mut self.values[4]
     }
 
-    pub fn get_all_mut(&mut self) -> &mut [Setting; 5] {
+    pub fn setting_soft_2d_renderer_mut(&mut self) -> &mut Setting {
+        &mut self.values[5]
+    }
+
+    pub fn setting_scanline_2d_renderer_mut(&mut self) -> &mut Setting {
+        &mut self.values[6]
+    }
+
+    pub fn setting_language_mut(&mut self) -> &mut Setting {
+        &mut self.values[7]
+    }
+
+    pub fn setting_nickname_mut(&mut self) -> &mut Setting {
+        &mut self.values[8]
+    }
+
+    pub fn setting_birthday_month_mut(&mut self) -> &mut Setting {
+        &mut self.values[9]
+    }
+
+    pub fn setting_birthday_day_mut(&mut self) -> &mut Setting {
+        &mut self.values[10]
+    }
+
+    pub fn setting_favorite_color_mut(&mut self) -> &mut Setting {
+        &mut self.values[11]
+    }
+
+    pub fn setting_rtc_mode_mut(&mut self) -> &mut Setting {
+        &mut self.values[12]
+    }
+
+    pub fn setting_rtc_time_mut(&mut self) -> &mut Setting {
+        &mut self.values[13]
+    }
+
+    pub fn setting_rtc_offset_mut(&mut self) -> &mut Setting {
+        &mut self.values[14]
+    }
+
+    pub fn setting_save_backups_mut(&mut self) -> &mut Setting {
+        &mut self.values[15]
+    }
+
+    pub fn get_all_mut(&mut self) -> &mut [Setting; 16] {
         &mut self.values
     }
+
+    // Language, nickname, birthday and favorite color
+    fn firmware_user_settings(&self) -> impl Iterator<Item = String> + '_ {
+        self.values[7..12].iter().map(|setting| setting.value.to_parse_string())
+    }
+
+    // Firmware dumps and firmware written by games keep their user settings, unless they were changed
+    pub fn firmware_user_settings_changed(&self) -> bool {
+        self.firmware_user_settings_changed
+    }
+
+    pub fn set_firmware_user_settings_changed(&mut self, changed: bool) {
+        self.firmware_user_settings_changed = changed;
+    }
 }
 
 impl Debug for Settings {
@@ -221,29 +463,45 @@ pub struct SettingsConfig {
     pub settings: Settings,
     pub settings_file_path: PathBuf,
     pub dirty: bool,
+    loaded_settings: Settings,
 }
 
 impl SettingsConfig {
-    pub fn new(path: PathBuf) -> Self {
+    pub fn new(path: PathBuf, game_code: u32) -> Self {
         let mut settings = DEFAULT_SETTINGS.clone();
 
-        if let Ok(ini) = Ini::load_from_file(&path) {
-            if let Some(section) = ini.section(None::<String>) {
-                for setting in settings.get_all_mut() {
-                    if let Some(value) = section.get(setting.title) {
-                        setting.value.parse_str(value);
+        match Ini::load_from_file(&path) {
+            Ok(ini) => {
+                if let Some(section) = ini.section(None::<String>) {
+                    for setting in settings.get_all_mut() {
+                        if let Some(value) = section.get(setting.title) {
+                            setting.value.parse_str(value);
+                        }
                     }
                 }
             }
+            Err(_) => {
+                if let Some(compatibility) = get_game_compatibility(game_code) {
+                    compatibility.apply_defaults(&mut settings);
+                }
+            }
         }
 
         SettingsConfig {
+            loaded_settings: settings.clone(),
             settings,
             settings_file_path: path,
             dirty: false,
         }
     }
 
+    pub fn into_settings(self) -> Settings {
+        let mut settings = self.settings;
+        let changed = !settings.firmware_user_settings().eq(self.loaded_settings.firmware_user_settings());
+        settings.set_firmware_user_settings_changed(changed);
+        settings
+    }
+
     pub fn flush(&mut self) {
         if self.dirty {
             let mut ini = Ini::new();
@@ -1,14 +1,46 @@
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::ffi::OsString;
 use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
 use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
 use std::ops::{Deref, DerefMut};
-use std::slice;
+use std::path::{Path, PathBuf};
+use std::{fs, io, slice};
 
 pub const fn align_up(n: usize, align: usize) -> usize {
     (n + align - 1) & !(align - 1)
 }
 
+pub fn tmp_file_path(path: &Path) -> PathBuf {
+    let mut tmp_path = OsString::from(path.as_os_str());
+    tmp_path.push(".tmp");
+    PathBuf::from(tmp_path)
+}
+
+// Writes a temporary file and renames it afterwards, a crash mid write never leaves a truncated file behind
+pub fn write_file_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = tmp_file_path(path);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    // Renaming onto an existing file fails on the Vita, the temporary file is recovered on the next load
+    fs::rename(&tmp_path, path).or_else(|_| {
+        fs::remove_file(path)?;
+        fs::rename(&tmp_path, path)
+    })
+}
+
+// Finishes a write_file_atomic which was interrupted between removing the old file and renaming the new one
+pub fn recover_tmp_file(path: &Path) {
+    let tmp_path = tmp_file_path(path);
+    if !path.exists() && tmp_path.is_file() {
+        let _ = fs::rename(tmp_path, path);
+    }
+}
+
 pub trait Convert: Copy + Into<u32> {
     fn from(value: u32) -> Self;
 }